@@ -0,0 +1,884 @@
+use crate::frame::Frame;
+use crate::glob;
+use crate::persistence;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_USER: &str = "default";
+
+//Redis' default acllog-max-len.
+pub const DEFAULT_LOG_MAX_LEN: u64 = 128;
+
+pub const NO_ACLFILE: &str = "This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue an ACL SAVE once an aclfile is configured.";
+
+//Denials of the same kind are merged into one log entry when they happen
+//within this many milliseconds of each other.
+const LOG_GROUP_MS: u64 = 60000;
+
+//Every category ACL CAT lists. Those without any command here are still
+//accepted in rules, so that ACL files written for Redis load.
+pub const CATEGORIES: &[&str] = &[
+    "keyspace", "read", "write", "set", "sortedset", "list", "hash", "string", "bitmap", "hyperloglog", "geo",
+    "stream", "pubsub", "admin", "fast", "slow", "blocking", "dangerous", "connection", "transaction", "scripting",
+];
+
+//The categories of each command. A `command|subcommand` entry overrides the
+//command's own for that subcommand.
+const COMMANDS: &[(&str, &[&str])] = &[
+    ("ping", &["fast", "connection"]),
+    ("get", &["read", "string", "fast"]),
+    ("set", &["write", "string", "slow"]),
+    ("rpush", &["write", "list", "fast"]),
+    ("sadd", &["write", "set", "fast"]),
+    ("hset", &["write", "hash", "fast"]),
+    ("zadd", &["write", "sortedset", "fast"]),
+    ("pexpireat", &["write", "keyspace", "fast"]),
+    ("keys", &["keyspace", "read", "slow", "dangerous"]),
+    ("flushdb", &["keyspace", "write", "slow", "dangerous"]),
+    ("flushall", &["keyspace", "write", "slow", "dangerous"]),
+    ("multi", &["fast", "transaction"]),
+    ("exec", &["slow", "transaction"]),
+    ("discard", &["fast", "transaction"]),
+    ("watch", &["fast", "transaction"]),
+    ("unwatch", &["fast", "transaction"]),
+    ("eval", &["slow", "scripting"]),
+    ("evalsha", &["slow", "scripting"]),
+    ("script", &["slow", "scripting"]),
+    ("function", &["slow", "scripting"]),
+    ("function|load", &["write", "slow", "scripting"]),
+    ("function|delete", &["write", "slow", "scripting"]),
+    ("function|restore", &["write", "slow", "scripting"]),
+    ("function|flush", &["write", "slow", "scripting"]),
+    ("fcall", &["slow", "scripting"]),
+    ("fcall_ro", &["slow", "scripting"]),
+    ("save", &["admin", "slow", "dangerous"]),
+    ("bgsave", &["admin", "slow", "dangerous"]),
+    ("lastsave", &["admin", "fast", "dangerous"]),
+    ("bgrewriteaof", &["admin", "slow", "dangerous"]),
+    ("replicaof", &["admin", "slow", "dangerous"]),
+    ("replconf", &["admin", "slow", "dangerous"]),
+    ("psync", &["admin", "slow", "dangerous"]),
+    ("wait", &["slow", "connection"]),
+    ("role", &["admin", "fast", "dangerous"]),
+    ("info", &["slow", "dangerous"]),
+    ("config", &["admin", "slow", "dangerous"]),
+    ("shutdown", &["admin", "slow", "dangerous"]),
+    ("auth", &["fast", "connection"]),
+    ("acl", &["admin", "slow", "dangerous"]),
+    ("acl|whoami", &["slow"]),
+    ("acl|cat", &["slow"]),
+    ("client", &["admin", "slow", "dangerous", "connection"]),
+    ("client|id", &["slow", "connection"]),
+    ("client|info", &["slow", "connection"]),
+    ("client|setname", &["slow", "connection"]),
+    ("client|getname", &["slow", "connection"]),
+    ("client|reply", &["slow", "connection"]),
+    ("client|no-touch", &["slow", "connection"]),
+    ("slowlog", &["admin", "slow", "dangerous"]),
+    ("latency", &["admin", "slow", "dangerous"]),
+    ("monitor", &["admin", "slow", "dangerous"]),
+];
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum AclCmd {
+    SETUSER(String, Vec<String>),
+    GETUSER(String),
+    DELUSER(Vec<String>),
+    LIST,
+    USERS,
+    WHOAMI,
+    CAT(Option<String>),
+    LOG(Option<usize>),
+    LOGRESET,
+    LOAD,
+    SAVE,
+}
+
+impl AclCmd {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AclCmd::SETUSER(..) => "setuser",
+            AclCmd::GETUSER(_) => "getuser",
+            AclCmd::DELUSER(_) => "deluser",
+            AclCmd::LIST => "list",
+            AclCmd::USERS => "users",
+            AclCmd::WHOAMI => "whoami",
+            AclCmd::CAT(_) => "cat",
+            AclCmd::LOG(_) | AclCmd::LOGRESET => "log",
+            AclCmd::LOAD => "load",
+            AclCmd::SAVE => "save",
+        }
+    }
+}
+
+//Why a command was refused, as reported in the ACL LOG.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Denial {
+    Command(String),
+    Key(String),
+}
+
+impl Denial {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Denial::Command(_) => "command",
+            Denial::Key(_) => "key",
+        }
+    }
+
+    pub fn object(&self) -> &str {
+        match self {
+            Denial::Command(name) | Denial::Key(name) => name,
+        }
+    }
+
+    pub fn error(&self, username: &str) -> String {
+        match self {
+            Denial::Command(name) => format!("NOPERM User {} has no permissions to run the '{}' command", username, name),
+            Denial::Key(_) => "NOPERM No permissions to access a key".to_string(),
+        }
+    }
+}
+
+//A key a command touches, and whether it reads and/or writes it.
+pub struct KeyAccess<'a> {
+    pub key: &'a str,
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+//One set of command, key and channel permissions. A user has a root
+//selector plus any number of extra ones given in parentheses; a command is
+//allowed if one of them allows both the command and all of its keys.
+#[derive(PartialEq, Debug, Clone, Default)]
+struct Selector {
+    //Command rules in the order they were given. They are replayed on each
+    //check so that later rules override earlier ones, as in Redis.
+    commands: Vec<String>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<String>,
+}
+
+impl Selector {
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "allkeys" => return self.apply("~*"),
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => return self.apply("&*"),
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => return self.apply("+@all"),
+            "nocommands" => return self.apply("-@all"),
+            _ => match rule.as_bytes()[0] {
+                b'~' | b'%' => {
+                    let (flags, pattern) = rule.split_once('~').ok_or("Syntax error")?;
+                    let flags = flags.strip_prefix('%').unwrap_or(flags).to_uppercase();
+                    if rule.starts_with('%') && (flags.is_empty() || flags.chars().any(|c| c != 'R' && c != 'W')) {
+                        return Err("Syntax error".to_string());
+                    }
+                    let (read, write) = if rule.starts_with('~') { (true, true) } else { (flags.contains('R'), flags.contains('W')) };
+                    if pattern == "*" && read && write {
+                        self.keys.clear();
+                    } else if self.keys.iter().any(|key| key.pattern == "*" && key.read && key.write) {
+                        return Err("Adding a pattern after the * pattern (or the 'allkeys' flag) is not valid and does not have any effect. Try 'resetkeys' to start with an empty list of patterns".to_string());
+                    }
+                    self.keys.push(KeyPattern { pattern: pattern.to_string(), read, write });
+                },
+                b'&' => {
+                    if &rule[1..] == "*" {
+                        self.channels.clear();
+                    } else if self.channels.iter().any(|channel| channel == "*") {
+                        return Err("Adding a pattern after the * pattern (or the 'allchannels' flag) is not valid and does not have any effect. Try 'resetchannels' to start with an empty list of channels".to_string());
+                    }
+                    self.channels.push(rule[1..].to_string());
+                },
+                b'+' | b'-' => {
+                    let target = rule[1..].to_lowercase();
+                    let known = match target.strip_prefix('@') {
+                        Some(category) => category == "all" || CATEGORIES.contains(&category),
+                        None => {
+                            let command = target.split('|').next().unwrap();
+                            COMMANDS.iter().any(|(name, _)| *name == command)
+                        },
+                    };
+                    if !known {
+                        return Err("Unknown command or category name in ACL".to_string());
+                    }
+                    //+@all and -@all override everything before them, and
+                    //no rules at all means -@all.
+                    if target == "@all" {
+                        self.commands.clear();
+                        if rule.starts_with('-') {
+                            return Ok(());
+                        }
+                    }
+                    self.commands.push(format!("{}{}", &rule[..1], target));
+                },
+                _ => return Err("Syntax error".to_string()),
+            },
+        }
+        Ok(())
+    }
+
+    fn allows_command(&self, name: &str, subcommand: Option<&str>) -> bool {
+        let full = subcommand.map(|sub| format!("{}|{}", name, sub));
+        let categories = categories(name, subcommand);
+        let mut allowed = false;
+        for rule in &self.commands {
+            let target = &rule[1..];
+            let matches = match target.strip_prefix('@') {
+                Some(category) => category == "all" || categories.contains(&category),
+                None => target == name || Some(target) == full.as_deref(),
+            };
+            if matches {
+                allowed = rule.starts_with('+');
+            }
+        }
+        allowed
+    }
+
+    fn allows_key(&self, access: &KeyAccess) -> bool {
+        self.keys.iter().any(|key| {
+            (key.read || !access.read) && (key.write || !access.write) && glob::matches(&key.pattern, access.key)
+        })
+    }
+
+    fn check(&self, name: &str, subcommand: Option<&str>, keys: &[KeyAccess]) -> Result<(), Denial> {
+        if !self.allows_command(name, subcommand) {
+            return Err(Denial::Command(full_name(name, subcommand)));
+        }
+        match keys.iter().find(|access| !self.allows_key(access)) {
+            Some(access) => Err(Denial::Key(access.key.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    fn describe_keys(&self) -> String {
+        let keys: Vec<String> = self.keys.iter().map(|key| match (key.read, key.write) {
+            (true, false) => format!("%R~{}", key.pattern),
+            (false, true) => format!("%W~{}", key.pattern),
+            _ => format!("~{}", key.pattern),
+        }).collect();
+        keys.join(" ")
+    }
+
+    fn describe_channels(&self) -> String {
+        let channels: Vec<String> = self.channels.iter().map(|channel| format!("&{}", channel)).collect();
+        channels.join(" ")
+    }
+
+    fn describe_commands(&self) -> String {
+        match self.commands.first().map(|rule| rule.as_str()) {
+            None => "-@all".to_string(),
+            Some("+@all") => self.commands.join(" "),
+            Some(_) => format!("-@all {}", self.commands.join(" ")),
+        }
+    }
+
+    //The selector as rules that recreate it, the way ACL LIST shows it.
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.keys.is_empty() {
+            parts.push(self.describe_keys());
+        }
+        parts.push(if self.channels.is_empty() { "resetchannels".to_string() } else { self.describe_channels() });
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+
+    fn to_frame(&self) -> Frame {
+        let mut frame = Frame::array();
+        for (field, value) in [("commands", self.describe_commands()), ("keys", self.describe_keys()), ("channels", self.describe_channels())] {
+            frame.push_bulk(Bytes::from(field));
+            frame.push_bulk(Bytes::from(value));
+        }
+        frame
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct User {
+    name: String,
+    enabled: bool,
+    nopass: bool,
+    //SHA-256 hashes, hex encoded.
+    passwords: Vec<String>,
+    root: Selector,
+    selectors: Vec<Selector>,
+}
+
+impl User {
+    //New users start disabled, without passwords and without permissions.
+    pub fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            root: Selector::default(),
+            selectors: Vec::new(),
+        }
+    }
+
+    //The default user can run anything without a password until told otherwise.
+    fn default_user() -> User {
+        let mut user = User::new(DEFAULT_USER);
+        for rule in ["on", "nopass", "~*", "&*", "+@all"] {
+            user.apply(rule).unwrap();
+        }
+        user
+    }
+
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        if rule.is_empty() {
+            return Err("Syntax error".to_string());
+        }
+        if let Some(inner) = rule.strip_prefix('(').and_then(|rule| rule.strip_suffix(')')) {
+            let mut selector = Selector::default();
+            for rule in inner.split_whitespace() {
+                selector.apply(rule)?;
+            }
+            self.selectors.push(selector);
+            return Ok(());
+        }
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.passwords.clear();
+                self.nopass = true;
+            },
+            "resetpass" => {
+                self.passwords.clear();
+                self.nopass = false;
+            },
+            "reset" => *self = User::new(&self.name),
+            "clearselectors" => self.selectors.clear(),
+            _ => match rule.as_bytes()[0] {
+                b'>' => self.add_password(hash_password(&rule[1..])),
+                b'#' => self.add_password(parse_hash(&rule[1..])?),
+                b'<' => self.remove_password(&hash_password(&rule[1..]))?,
+                b'!' => self.remove_password(&parse_hash(&rule[1..])?)?,
+                _ => self.root.apply(rule)?,
+            },
+        }
+        Ok(())
+    }
+
+    fn add_password(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    fn remove_password(&mut self, hash: &str) -> Result<(), String> {
+        let len = self.passwords.len();
+        self.passwords.retain(|password| password != hash);
+        if self.passwords.len() == len {
+            return Err("no such password".to_string());
+        }
+        Ok(())
+    }
+
+    fn authenticate(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash_password(password)))
+    }
+
+    //A key denial is reported over a command denial, since it means some
+    //selector did allow the command.
+    pub fn check(&self, name: &str, subcommand: Option<&str>, keys: &[KeyAccess]) -> Result<(), Denial> {
+        let mut denial = None;
+        for selector in std::iter::once(&self.root).chain(&self.selectors) {
+            match selector.check(name, subcommand, keys) {
+                Ok(()) => return Ok(()),
+                Err(e @ Denial::Key(_)) => denial = Some(e),
+                Err(e) => {
+                    denial.get_or_insert(e);
+                },
+            }
+        }
+        Err(denial.unwrap())
+    }
+
+    //The user as an ACL LIST / aclfile line.
+    pub fn describe(&self) -> String {
+        let mut parts = vec!["user".to_string(), self.name.clone()];
+        parts.push(if self.enabled { "on" } else { "off" }.to_string());
+        if self.nopass {
+            parts.push("nopass".to_string());
+        }
+        parts.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        parts.push(self.root.describe());
+        parts.extend(self.selectors.iter().map(|selector| format!("({})", selector.describe())));
+        parts.join(" ")
+    }
+
+    fn to_frame(&self) -> Frame {
+        let mut flags = Frame::array();
+        flags.push_bulk(Bytes::from(if self.enabled { "on" } else { "off" }));
+        if self.nopass {
+            flags.push_bulk(Bytes::from("nopass"));
+        }
+        let mut passwords = Frame::array();
+        for hash in &self.passwords {
+            passwords.push_bulk(Bytes::from(hash.clone()));
+        }
+        let mut selectors = Frame::array();
+        for selector in &self.selectors {
+            selectors.push_frame(selector.to_frame());
+        }
+
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("flags"));
+        frame.push_frame(flags);
+        frame.push_bulk(Bytes::from("passwords"));
+        frame.push_frame(passwords);
+        if let Frame::Array(root) = self.root.to_frame() {
+            for field in root {
+                frame.push_frame(field);
+            }
+        }
+        frame.push_bulk(Bytes::from("selectors"));
+        frame.push_frame(selectors);
+        frame
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+struct LogEntry {
+    id: u64,
+    count: u64,
+    reason: &'static str,
+    context: &'static str,
+    object: String,
+    username: String,
+    client_info: String,
+    //Unix milliseconds.
+    created: u64,
+    updated: u64,
+}
+
+impl LogEntry {
+    fn to_frame(&self, now: u64) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("count"));
+        frame.push_int(self.count as i64);
+        for (field, value) in [
+            ("reason", self.reason.to_string()),
+            ("context", self.context.to_string()),
+            ("object", self.object.clone()),
+            ("username", self.username.clone()),
+            ("age-seconds", format!("{:.3}", now.saturating_sub(self.created) as f64 / 1000.0)),
+            ("client-info", self.client_info.clone()),
+        ] {
+            frame.push_bulk(Bytes::from(field));
+            frame.push_bulk(Bytes::from(value));
+        }
+        for (field, value) in [("entry-id", self.id), ("timestamp-created", self.created), ("timestamp-last-updated", self.updated)] {
+            frame.push_bulk(Bytes::from(field));
+            frame.push_int(value as i64);
+        }
+        frame
+    }
+}
+
+#[derive(Debug, Default)]
+struct Log {
+    //Newest first.
+    entries: VecDeque<LogEntry>,
+    next_id: u64,
+}
+
+//Users and the log of denied commands, shared by every connection.
+#[derive(Debug)]
+pub struct Acl {
+    users: Mutex<BTreeMap<String, User>>,
+    log: Mutex<Log>,
+    log_max_len: Mutex<u64>,
+}
+
+impl Default for Acl {
+    fn default() -> Acl {
+        let mut users = BTreeMap::new();
+        users.insert(DEFAULT_USER.to_string(), User::default_user());
+        Acl {
+            users: Mutex::new(users),
+            log: Mutex::new(Log::default()),
+            log_max_len: Mutex::new(DEFAULT_LOG_MAX_LEN),
+        }
+    }
+}
+
+impl Acl {
+    //Whether new connections have to AUTH before running commands.
+    pub fn auth_required(&self) -> bool {
+        let users = self.users.lock().unwrap();
+        let default = &users[DEFAULT_USER];
+        !default.enabled || !default.nopass
+    }
+
+    pub fn default_nopass(&self) -> bool {
+        self.users.lock().unwrap()[DEFAULT_USER].nopass
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        self.users.lock().unwrap().get(username).is_some_and(|user| user.authenticate(password))
+    }
+
+    //requirepass is a shortcut for the default user's password.
+    pub fn set_default_password(&self, password: &str) {
+        let mut users = self.users.lock().unwrap();
+        let default = users.get_mut(DEFAULT_USER).unwrap();
+        default.apply("resetpass").unwrap();
+        if password.is_empty() {
+            default.apply("nopass").unwrap();
+        } else {
+            default.add_password(hash_password(password));
+        }
+    }
+
+    //A user deleted while connected has no permissions left.
+    pub fn check(&self, username: &str, name: &str, subcommand: Option<&str>, keys: &[KeyAccess]) -> Result<(), Denial> {
+        match self.users.lock().unwrap().get(username) {
+            Some(user) => user.check(name, subcommand, keys),
+            None => Err(Denial::Command(full_name(name, subcommand))),
+        }
+    }
+
+    //Rules are applied to a copy, so a bad rule leaves the user untouched.
+    pub fn setuser(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = self.users.lock().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply(rule).map_err(|e| format!("Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    pub fn deluser(&self, names: &[String]) -> Result<usize, String> {
+        if names.iter().any(|name| name == DEFAULT_USER) {
+            return Err("The 'default' user cannot be removed".to_string());
+        }
+        let mut users = self.users.lock().unwrap();
+        Ok(names.iter().filter(|name| users.remove(name.as_str()).is_some()).count())
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.users.lock().unwrap().values().map(|user| user.describe()).collect()
+    }
+
+    pub fn set_log_max_len(&self, len: u64) {
+        *self.log_max_len.lock().unwrap() = len;
+        let mut log = self.log.lock().unwrap();
+        log.entries.truncate(len as usize);
+    }
+
+    //Records a denied command or a failed AUTH.
+    pub fn log(&self, reason: &'static str, context: &'static str, object: &str, username: &str, client_info: String) {
+        let max_len = *self.log_max_len.lock().unwrap() as usize;
+        let now = now_ms();
+        let mut log = self.log.lock().unwrap();
+        let same = log.entries.iter().position(|entry| {
+            entry.reason == reason && entry.context == context && entry.object == object
+                && entry.username == username && now.saturating_sub(entry.updated) < LOG_GROUP_MS
+        });
+        let entry = match same {
+            Some(i) => {
+                let mut entry = log.entries.remove(i).unwrap();
+                entry.count += 1;
+                entry.updated = now;
+                entry.client_info = client_info;
+                entry
+            },
+            None => {
+                log.next_id += 1;
+                LogEntry {
+                    id: log.next_id - 1,
+                    count: 1,
+                    reason,
+                    context,
+                    object: object.to_string(),
+                    username: username.to_string(),
+                    client_info,
+                    created: now,
+                    updated: now,
+                }
+            },
+        };
+        log.entries.push_front(entry);
+        log.entries.truncate(max_len);
+    }
+
+    //Replaces every user with those of an ACL file, all or nothing. The
+    //default user is recreated if the file does not define it.
+    pub fn load(&self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Error loading ACLs, opening file '{}': {}", path.display(), e))?;
+        let mut users = BTreeMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |e: String| format!("{}:{}: {}. ", path.display(), i + 1, e);
+            let words = split_rules(line).map_err(error)?;
+            if words.len() < 2 || words[0] != "user" {
+                return Err(error("line should start with user keyword".to_string()));
+            }
+            if users.contains_key(&words[1]) {
+                return Err(error(format!("Duplicate user '{}' found", words[1])));
+            }
+            let mut user = User::new(&words[1]);
+            for rule in &words[2..] {
+                user.apply(rule).map_err(|e| error(format!("Error in user declaration '{}': {}", rule, e)))?;
+            }
+            users.insert(words[1].clone(), user);
+        }
+        users.entry(DEFAULT_USER.to_string()).or_insert_with(User::default_user);
+        *self.users.lock().unwrap() = users;
+        Ok(())
+    }
+
+    //Written to a temporary file first, so that a failed save leaves the
+    //old file in place.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut text = self.list().join("\n");
+        text.push('\n');
+        let tmp = path.with_extension("tmp");
+        persistence::write_synced(&tmp, text.as_bytes())
+            .and_then(|_| fs::rename(&tmp, path))
+            .and_then(|_| persistence::sync_dir(path))
+            .map_err(|e| format!("There was an error trying to save the ACLs. Please check the server logs for more information: {}", e))
+    }
+}
+
+//Runs an ACL subcommand for the connection authenticated as `username`.
+pub fn execute(cmd: &AclCmd, acl: &Acl, username: &str, aclfile: Option<&Path>) -> Result<Frame, String> {
+    match cmd {
+        AclCmd::SETUSER(name, rules) => {
+            acl.setuser(name, rules)?;
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        AclCmd::GETUSER(name) => match acl.users.lock().unwrap().get(name) {
+            Some(user) => Ok(user.to_frame()),
+            None => Ok(Frame::Null),
+        },
+        AclCmd::DELUSER(names) => Ok(Frame::Integer(acl.deluser(names)? as i64)),
+        AclCmd::LIST => Ok(bulk_array(acl.list())),
+        AclCmd::USERS => Ok(bulk_array(acl.users.lock().unwrap().keys().cloned().collect())),
+        AclCmd::WHOAMI => Ok(Frame::Bulk(Bytes::from(username.to_string()))),
+        AclCmd::CAT(None) => Ok(bulk_array(CATEGORIES.iter().map(|c| c.to_string()).collect())),
+        AclCmd::CAT(Some(category)) => {
+            let category = category.to_lowercase();
+            if !CATEGORIES.contains(&category.as_str()) {
+                return Err(format!("Unknown category '{}'", category));
+            }
+            let commands = COMMANDS.iter()
+                .filter(|(_, categories)| categories.contains(&category.as_str()))
+                .map(|(name, _)| name.to_string())
+                .collect();
+            Ok(bulk_array(commands))
+        },
+        AclCmd::LOG(count) => {
+            let now = now_ms();
+            let mut output = Frame::array();
+            for entry in acl.log.lock().unwrap().entries.iter().take(count.unwrap_or(10)) {
+                output.push_frame(entry.to_frame(now));
+            }
+            Ok(output)
+        },
+        AclCmd::LOGRESET => {
+            acl.log.lock().unwrap().entries.clear();
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        AclCmd::LOAD => {
+            acl.load(aclfile.ok_or(NO_ACLFILE)?)?;
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        AclCmd::SAVE => {
+            acl.save(aclfile.ok_or(NO_ACLFILE)?)?;
+            Ok(Frame::Simple("OK".to_string()))
+        },
+    }
+}
+
+//Whether the command is in `category`, e.g. "fast" for the latency monitor.
+pub fn has_category(name: &str, subcommand: Option<&str>, category: &str) -> bool {
+    categories(name, subcommand).contains(&category)
+}
+
+//HELPER FN
+
+fn categories(name: &str, subcommand: Option<&str>) -> &'static [&'static str] {
+    let full = full_name(name, subcommand);
+    COMMANDS.iter()
+        .find(|(command, _)| *command == full)
+        .or_else(|| COMMANDS.iter().find(|(command, _)| *command == name))
+        .map(|(_, categories)| *categories)
+        .unwrap_or(&[])
+}
+
+fn full_name(name: &str, subcommand: Option<&str>) -> String {
+    match subcommand {
+        Some(sub) => format!("{}|{}", name, sub),
+        None => name.to_string(),
+    }
+}
+
+fn hash_password(password: &str) -> String {
+    format!("{:x}", Sha256::digest(password.as_bytes()))
+}
+
+fn parse_hash(hash: &str) -> Result<String, String> {
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+    }
+    Ok(hash.to_string())
+}
+
+//Splits an ACL file line on whitespace, keeping a parenthesized selector
+//together as one rule.
+fn split_rules(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut selector: Option<String> = None;
+    for word in line.split_whitespace() {
+        match selector.as_mut() {
+            Some(rules) => {
+                rules.push(' ');
+                rules.push_str(word);
+            },
+            None if word.starts_with('(') => selector = Some(word.to_string()),
+            None => words.push(word.to_string()),
+        }
+        if selector.as_ref().is_some_and(|rules| rules.ends_with(')')) {
+            words.push(selector.take().unwrap());
+        }
+    }
+    match selector {
+        Some(_) => Err("Unmatched parenthesis in acl selector starting at '('".to_string()),
+        None => Ok(words),
+    }
+}
+
+fn bulk_array(items: Vec<String>) -> Frame {
+    let mut frame = Frame::array();
+    for item in items {
+        frame.push_bulk(Bytes::from(item));
+    }
+    frame
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::acl::*;
+
+    fn user(rules: &[&str]) -> User {
+        let mut user = User::new("alice");
+        for rule in rules {
+            user.apply(rule).unwrap();
+        }
+        user
+    }
+
+    fn read(key: &str) -> KeyAccess<'_> {
+        KeyAccess { key, read: true, write: false }
+    }
+
+    fn write(key: &str) -> KeyAccess<'_> {
+        KeyAccess { key, read: false, write: true }
+    }
+
+    #[test]
+    fn rules_describe_and_reload() {
+        let alice = user(&["on", ">secret", "~cache:*", "%R~logs:*", "&news", "+@read", "-keys", "(%W~tmp:* +set)"]);
+        let line = alice.describe();
+        assert_eq!(line, format!(
+            "user alice on #{} ~cache:* %R~logs:* &news -@all +@read -keys (%W~tmp:* resetchannels -@all +set)",
+            hash_password("secret"),
+        ));
+
+        let words = split_rules(&line).unwrap();
+        let mut reloaded = User::new("alice");
+        for rule in &words[2..] {
+            reloaded.apply(rule).unwrap();
+        }
+        assert_eq!(reloaded, alice);
+
+        assert!(alice.authenticate("secret"));
+        assert!(!alice.authenticate("wrong"));
+        assert!(!user(&["off", "nopass"]).authenticate(""));
+        assert!(User::new("bob").apply("+nosuchcommand").is_err());
+        assert!(User::new("bob").apply("#abc").is_err());
+        assert!(user(&["allkeys"]).apply("~other").is_err());
+    }
+
+    #[test]
+    fn commands_keys_and_selectors() {
+        let alice = user(&["+@all", "-@dangerous", "+config|get", "~app:*", "%R~shared:*", "(+set %W~tmp:*)"]);
+
+        assert_eq!(alice.check("get", None, &[read("app:1")]), Ok(()));
+        assert_eq!(alice.check("get", None, &[read("shared:1")]), Ok(()));
+        assert_eq!(alice.check("set", None, &[write("shared:1")]), Err(Denial::Key("shared:1".to_string())));
+        assert_eq!(alice.check("set", None, &[write("tmp:1")]), Ok(()));
+        assert_eq!(alice.check("get", None, &[read("tmp:1")]), Err(Denial::Key("tmp:1".to_string())));
+        assert_eq!(alice.check("flushall", None, &[]), Err(Denial::Command("flushall".to_string())));
+        assert_eq!(alice.check("config", Some("get"), &[]), Ok(()));
+        assert_eq!(alice.check("config", Some("set"), &[]), Err(Denial::Command("config|set".to_string())));
+    }
+
+    #[test]
+    fn log_groups_repeated_denials() {
+        let acl = Acl::default();
+        acl.log("command", "toplevel", "get", "alice", "addr=a".to_string());
+        acl.log("command", "toplevel", "get", "alice", "addr=b".to_string());
+        acl.log("key", "multi", "k", "alice", "addr=b".to_string());
+
+        let log = acl.log.lock().unwrap();
+        assert_eq!(log.entries.len(), 2);
+        assert_eq!(log.entries[0].reason, "key");
+        assert_eq!((log.entries[1].count, log.entries[1].client_info.as_str()), (2, "addr=b"));
+        drop(log);
+
+        acl.set_log_max_len(1);
+        assert_eq!(acl.log.lock().unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn aclfile_save_and_load() {
+        let path = std::env::temp_dir().join(format!("my-redis-acl-{}.acl", std::process::id()));
+        let acl = Acl::default();
+        acl.set_default_password("secret");
+        acl.setuser("bob", &["on".to_string(), ">pw".to_string(), "+get".to_string(), "~*".to_string()]).unwrap();
+        assert!(acl.setuser("bob", &["off".to_string(), "+bad".to_string()]).is_err());
+        acl.save(&path).unwrap();
+
+        let loaded = Acl::default();
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.list(), acl.list());
+        assert!(loaded.authenticate("bob", "pw"));
+        assert!(loaded.auth_required());
+
+        fs::write(&path, "user bob on\nuser bob off\n").unwrap();
+        assert!(loaded.load(&path).unwrap_err().contains(":2: Duplicate user"));
+        assert!(loaded.authenticate("bob", "pw"));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,480 @@
+use crate::db::{Db, Value};
+use crate::frame::{self, Frame};
+use crate::handler::{self, parse_command, Command};
+use crate::log;
+use crate::rdb::{self, Snapshot};
+use bytes::Bytes;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Fsync {
+    ALWAYS,
+    EVERYSEC,
+    NO,
+}
+
+impl Fsync {
+    pub fn parse(value: &str) -> Result<Fsync, String> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(Fsync::ALWAYS),
+            "everysec" => Ok(Fsync::EVERYSEC),
+            "no" => Ok(Fsync::NO),
+            _ => Err(format!("argument must be 'always', 'everysec' or 'no', got '{}'", value)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fsync::ALWAYS => "always",
+            Fsync::EVERYSEC => "everysec",
+            Fsync::NO => "no",
+        }
+    }
+}
+
+//Open append only file. While a rewrite runs, every write is also kept in
+//`rewrite_buffer` so it can be appended to the rewritten file at the end.
+#[derive(Debug)]
+pub struct AofWriter {
+    file: File,
+    fsync: Fsync,
+    last_fsync: Instant,
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+impl AofWriter {
+    pub fn open(path: &Path, fsync: Fsync) -> Result<AofWriter, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Can't open the append-only file {}: {}", path.display(), e))?;
+        Ok(AofWriter {
+            file,
+            fsync,
+            last_fsync: Instant::now(),
+            rewrite_buffer: None,
+        })
+    }
+
+    pub fn set_fsync(&mut self, fsync: Fsync) {
+        self.fsync = fsync;
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if let Some(buffer) = &mut self.rewrite_buffer {
+            buffer.extend_from_slice(bytes);
+        }
+        self.file.write_all(bytes).map_err(|e| format!("Error writing to the AOF file: {}", e))?;
+        match self.fsync {
+            Fsync::ALWAYS => self.sync(),
+            Fsync::EVERYSEC => self.sync_if_due(),
+            Fsync::NO => Ok(()),
+        }
+    }
+
+    //With `appendfsync everysec` the file is synced at most once a second,
+    //from the write path or from the server cron.
+    pub fn sync_if_due(&mut self) -> Result<(), String> {
+        if self.fsync == Fsync::EVERYSEC && self.last_fsync.elapsed() >= Duration::from_secs(1) {
+            self.sync()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn sync(&mut self) -> Result<(), String> {
+        self.last_fsync = Instant::now();
+        self.file.sync_data().map_err(|e| format!("Error syncing the AOF file: {}", e))
+    }
+
+    pub fn start_rewrite(&mut self) {
+        self.rewrite_buffer = Some(Vec::new());
+    }
+
+    //Appends the writes captured during the rewrite to the rewritten file,
+    //moves it over the live AOF and continues appending to it.
+    pub fn finish_rewrite(&mut self, tmp: &Path, path: &Path) -> Result<(), String> {
+        let buffer = self.rewrite_buffer.take().unwrap_or_default();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(tmp)
+            .map_err(|e| format!("Can't open the rewritten AOF: {}", e))?;
+        file.write_all(&buffer).map_err(|e| format!("Error writing the rewritten AOF: {}", e))?;
+        file.sync_data().map_err(|e| format!("Error syncing the rewritten AOF: {}", e))?;
+        fs::rename(tmp, path).map_err(|e| format!("Error renaming the rewritten AOF: {}", e))?;
+        self.file = file;
+        Ok(())
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buffer = None;
+    }
+}
+
+//Outcome of replaying an AOF.
+#[derive(PartialEq, Debug)]
+pub struct Loaded {
+    pub commands: usize,
+    pub truncated: bool,
+}
+
+//Replays every command in the file against the database, after loading the
+//RDB preamble that AOFs written by Redis with aof-use-rdb-preamble start
+//with. A command cut off at the end of
+//the file (a crash mid-write) is dropped with a warning and the file
+//truncated to the last complete command, or to the start of a MULTI
+//that never got its EXEC; corruption anywhere else is an error.
+pub fn load(path: &Path, db: &mut Db) -> Result<Loaded, String> {
+    let data = fs::read(path).map_err(|e| format!("Error opening {}: {}", path.display(), e))?;
+    let mut cursor = Cursor::new(&data[..]);
+    let mut commands = 0;
+    let mut transaction = None;
+    //Offset and command count at the MULTI of an open transaction.
+    let mut multi = None;
+
+    if data.starts_with(b"REDIS") {
+        let (snapshot, len) = rdb::decode_prefix(&data)
+            .map_err(|e| format!("Bad RDB preamble in the append only file: {}", e))?;
+        db.restore(snapshot)?;
+        cursor.set_position(len as u64);
+    }
+
+    while (cursor.position() as usize) < data.len() {
+        let start = cursor.position();
+        match Frame::serialize(&mut cursor) {
+            Ok(frame) => {
+                let cmd = parse_command(frame)
+                    .map_err(|e| format!("Bad command in the AOF at offset {}: {}", start, e))?;
+                if matches!(cmd, Command::MULTI) {
+                    multi = Some((start, commands));
+                }
+                handler::replay(cmd, &mut transaction, db)
+                    .map_err(|e| format!("Error replaying the AOF at offset {}: {}", start, e))?;
+                if transaction.is_none() {
+                    multi = None;
+                }
+                commands += 1;
+            },
+            Err(e) if e == frame::INCOMPLETE => {
+                log::warning(&format!("!!! Warning: short read while loading the AOF file {}!!!", path.display()));
+                let (offset, commands) = multi.unwrap_or((start, commands));
+                return truncate(path, offset, commands);
+            },
+            Err(e) => return Err(format!("Bad file format reading the append only file at offset {}: {}", start, e)),
+        }
+    }
+    match multi {
+        Some((offset, commands)) => {
+            log::warning("Revert incomplete MULTI/EXEC transaction in AOF file");
+            truncate(path, offset, commands)
+        },
+        None => Ok(Loaded { commands, truncated: false }),
+    }
+}
+
+fn truncate(path: &Path, offset: u64, commands: usize) -> Result<Loaded, String> {
+    log::warning(&format!("AOF loaded anyway because aof-load-truncated is enabled, truncating to offset {}", offset));
+    let file = OpenOptions::new().write(true).open(path).map_err(|e| e.to_string())?;
+    file.set_len(offset).map_err(|e| e.to_string())?;
+    Ok(Loaded { commands, truncated: true })
+}
+
+//Result of checking an AOF without loading it. `valid_up_to` is the offset
+//right after the last well-formed command.
+#[derive(PartialEq, Debug)]
+pub struct Check {
+    pub preamble: bool,
+    pub commands: usize,
+    pub valid_up_to: u64,
+    pub error: Option<String>,
+}
+
+impl Check {
+    //Whether truncating the file to `valid_up_to` repairs it. A bad RDB
+    //preamble can not be cut short: truncating would empty the file.
+    pub fn fixable(&self) -> bool {
+        !(self.preamble && self.valid_up_to == 0)
+    }
+}
+
+//Walks the file frame by frame with the RESP codec, stopping at the first
+//frame that is truncated, malformed or not a command. A MULTI without its
+//EXEC is only valid up to the MULTI.
+pub fn check(data: &[u8]) -> Check {
+    let mut cursor = Cursor::new(data);
+    let mut commands = 0;
+    let mut multi = None;
+
+    let preamble = data.starts_with(b"REDIS");
+    if preamble {
+        match rdb::decode_prefix(data) {
+            Ok((_, len)) => cursor.set_position(len as u64),
+            Err(e) => {
+                let error = Some(format!("Bad RDB preamble: {}", e));
+                return Check { preamble, commands, valid_up_to: 0, error };
+            },
+        }
+    }
+
+    while (cursor.position() as usize) < data.len() {
+        let start = cursor.position();
+        let error = match Frame::serialize(&mut cursor) {
+            Ok(Frame::Array(args)) if !args.is_empty() && args.iter().all(|arg| matches!(arg, Frame::Bulk(_))) => {
+                let name = match &args[0] {
+                    Frame::Bulk(name) => name.to_ascii_uppercase(),
+                    _ => Vec::new(),
+                };
+                match (name.as_slice(), multi) {
+                    (b"MULTI", Some(offset)) => Some(format!("Nested MULTI at offset {} inside the MULTI at offset {}", start, offset)),
+                    (b"MULTI", None) => {
+                        multi = Some(start);
+                        None
+                    },
+                    (b"EXEC", None) => Some(format!("EXEC without MULTI at offset {}", start)),
+                    (b"EXEC", Some(_)) => {
+                        multi = None;
+                        None
+                    },
+                    _ => None,
+                }
+            },
+            Ok(frame) => Some(format!("Expected a command at offset {}, got {:?}", start, frame)),
+            Err(e) if e == frame::INCOMPLETE => Some(format!("Truncated command at offset {}", start)),
+            Err(e) => Some(format!("Bad frame at offset {}: {}", start, e)),
+        };
+        if error.is_some() {
+            return Check { preamble, commands, valid_up_to: multi.unwrap_or(start), error };
+        }
+        commands += 1;
+    }
+    if let Some(offset) = multi {
+        let error = Some(format!("Reached EOF before reading EXEC for MULTI at offset {}", offset));
+        return Check { preamble, commands, valid_up_to: offset, error };
+    }
+    Check { preamble, commands, valid_up_to: data.len() as u64, error: None }
+}
+
+//Collections are rebuilt with at most this many items per command, as in Redis.
+const ITEMS_PER_CMD: usize = 64;
+
+//The smallest command list that rebuilds the snapshot. Collections imported
+//from Redis and expiry times are written with the commands Redis rewrites
+//them with.
+pub fn rewrite_commands(snapshot: &Snapshot) -> Vec<u8> {
+    let mut output = Vec::new();
+    for library in &snapshot.libraries {
+        output.append(&mut command(&["FUNCTION", "LOAD", "REPLACE", library]));
+    }
+    for (key, val, expire) in &snapshot.entries {
+        let (name, args): (&str, Vec<String>) = match val {
+            Value::String(val) => ("SET", vec![val.clone()]),
+            Value::List(items) => ("RPUSH", items.clone()),
+            Value::Set(members) => ("SADD", members.iter().cloned().collect()),
+            Value::Hash(fields) => ("HSET", fields.iter().flat_map(|(f, v)| [f.clone(), v.clone()]).collect()),
+            Value::ZSet(members) => ("ZADD", members.iter().flat_map(|(m, s)| [s.to_string(), m.clone()]).collect()),
+        };
+        let per_item = if matches!(val, Value::Hash(_) | Value::ZSet(_)) { 2 } else { 1 };
+        for chunk in args.chunks(ITEMS_PER_CMD * per_item) {
+            let mut cmd = vec![name, key.as_str()];
+            cmd.extend(chunk.iter().map(String::as_str));
+            output.append(&mut command(&cmd));
+        }
+        if let Some(at) = expire {
+            output.append(&mut command(&["PEXPIREAT", key, &at.to_string()]));
+        }
+    }
+    output
+}
+
+pub fn write_rewrite(snapshot: &Snapshot, tmp: &Path) -> Result<(), String> {
+    let mut file = File::create(tmp).map_err(|e| format!("Opening the temp file for AOF rewrite failed: {}", e))?;
+    file.write_all(&rewrite_commands(snapshot)).map_err(|e| format!("Error writing the AOF rewrite: {}", e))?;
+    file.sync_data().map_err(|e| format!("Error syncing the AOF rewrite: {}", e))
+}
+
+//HELPER FN
+
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut frame = Frame::array();
+    for arg in args {
+        frame.push_bulk(Bytes::from(arg.to_string()));
+    }
+    frame.deserialize()
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::aof::*;
+    use crate::db::{Db, Value};
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("my-redis-{}-{}.aof", name, std::process::id()))
+    }
+
+    #[test]
+    fn rewrite_then_load() {
+        let path = temp_path("rewrite");
+        let snapshot = Snapshot {
+            entries: vec![
+                ("a".to_string(), Value::String("1".to_string()), None),
+                ("l".to_string(), Value::List((0..100).map(|i| i.to_string()).collect()), Some(4102444800000)),
+                ("s".to_string(), Value::Set(HashSet::from(["x".to_string(), "y".to_string()])), None),
+                ("h".to_string(), Value::Hash(HashMap::from([("f".to_string(), "v".to_string())])), None),
+                ("z".to_string(), Value::ZSet(vec![("m".to_string(), -1.5), ("n".to_string(), 2.0)]), None),
+            ],
+            libraries: vec!["#!lua name=lib\nredis.register_function('f', function() return 1 end)".to_string()],
+        };
+        write_rewrite(&snapshot, &path).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data.append(&mut command(&["SET", "b", "2"]));
+        fs::write(&path, &data).unwrap();
+
+        let mut db = Db::new();
+        let loaded = load(&path, &mut db).unwrap();
+        //The library, one SET, two RPUSH, one PEXPIREAT, SADD, HSET, ZADD and SET b.
+        assert_eq!(loaded, Loaded { commands: 9, truncated: false });
+        assert_eq!(db.get("a"), Some(&"1".to_string()));
+        assert_eq!(db.get("b"), Some(&"2".to_string()));
+        for (key, val, _) in &snapshot.entries {
+            assert_eq!(db.value(key), Some(val));
+        }
+        assert_eq!(db.expire_at("l"), Some(4102444800000));
+        assert!(db.find_function("f").is_some());
+        assert!(!check(&data).preamble);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn redis_rdb_preamble_is_loaded() {
+        let path = temp_path("preamble");
+        let snapshot = Snapshot {
+            entries: vec![("l".to_string(), Value::List(vec!["x".to_string()]), Some(4102444800000))],
+            libraries: vec![],
+        };
+        let mut data = rdb::encode(&snapshot);
+        data.append(&mut command(&["SET", "b", "2"]));
+        fs::write(&path, &data).unwrap();
+
+        let mut db = Db::new();
+        assert_eq!(load(&path, &mut db).unwrap(), Loaded { commands: 1, truncated: false });
+        assert_eq!(db.value("l"), Some(&Value::List(vec!["x".to_string()])));
+        assert_eq!(db.expire_at("l"), Some(4102444800000));
+        assert_eq!(db.get("b"), Some(&"2".to_string()));
+        assert!(check(&data).preamble);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_tail_is_dropped() {
+        let path = temp_path("truncated");
+        let mut data = command(&["SET", "a", "1"]);
+        let complete = data.len() as u64;
+        data.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nb");
+        fs::write(&path, &data).unwrap();
+
+        let mut db = Db::new();
+        let loaded = load(&path, &mut db).unwrap();
+        assert_eq!(loaded, Loaded { commands: 1, truncated: true });
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+
+        fs::write(&path, b"*1\r\n$3\r\nSET\r\n").unwrap();
+        assert!(load(&path, &mut Db::new()).is_err());
+        fs::write(&path, b"garbage").unwrap();
+        assert!(load(&path, &mut Db::new()).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn transactions_are_replayed_whole() {
+        let path = temp_path("multi");
+        let mut data = command(&["SET", "a", "1"]);
+        for args in [&["MULTI"][..], &["SET", "b", "2"], &["SET", "c", "3"], &["EXEC"]] {
+            data.append(&mut command(args));
+        }
+        let complete = data.len() as u64;
+        data.append(&mut command(&["MULTI"]));
+        data.append(&mut command(&["SET", "d", "4"]));
+        fs::write(&path, &data).unwrap();
+
+        let report = check(&data);
+        assert_eq!(report.valid_up_to, complete);
+        assert!(report.error.unwrap().contains("before reading EXEC"));
+
+        let mut db = Db::new();
+        assert_eq!(load(&path, &mut db).unwrap(), Loaded { commands: 5, truncated: true });
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+        assert_eq!(db.get("c"), Some(&"3".to_string()));
+        assert_eq!(db.get("d"), None);
+
+        fs::write(&path, command(&["EXEC"])).unwrap();
+        assert!(load(&path, &mut Db::new()).is_err());
+        assert!(check(&command(&["EXEC"])).error.is_some());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn check_reports_first_bad_offset() {
+        let mut data = command(&["SET", "a", "1"]);
+        let complete = data.len() as u64;
+        assert_eq!(check(&data), Check { preamble: false, commands: 1, valid_up_to: complete, error: None });
+
+        data.extend_from_slice(b"+OK\r\n");
+        let report = check(&data);
+        assert_eq!((report.commands, report.valid_up_to), (1, complete));
+        assert!(report.error.is_some());
+
+        data.truncate(complete as usize);
+        data.extend_from_slice(b"*2\r\n$3\r\nGET");
+        assert_eq!(check(&data).valid_up_to, complete);
+        assert!(check(&data).fixable());
+
+        let mut data = rdb::encode(&Snapshot { entries: vec![], libraries: vec![] });
+        data.truncate(data.len() - 3);
+        data.append(&mut command(&["SET", "a", "1"]));
+        let report = check(&data);
+        assert!(report.preamble && report.error.is_some());
+        assert_eq!(report.valid_up_to, 0);
+        assert!(!report.fixable());
+    }
+
+    #[test]
+    fn writer_captures_rewrite_buffer() {
+        let path = temp_path("writer");
+        let tmp = temp_path("writer-tmp");
+        let _ = fs::remove_file(&path);
+        let mut writer = AofWriter::open(&path, Fsync::ALWAYS).unwrap();
+
+        writer.feed(&command(&["SET", "a", "1"])).unwrap();
+        writer.start_rewrite();
+        let snapshot = Snapshot { entries: vec![("a".to_string(), Value::String("1".to_string()), None)], libraries: vec![] };
+        write_rewrite(&snapshot, &tmp).unwrap();
+        writer.feed(&command(&["SET", "b", "2"])).unwrap();
+        writer.finish_rewrite(&tmp, &path).unwrap();
+        writer.feed(&command(&["SET", "c", "3"])).unwrap();
+
+        let mut tail = command(&["SET", "b", "2"]);
+        tail.append(&mut command(&["SET", "c", "3"]));
+        let data = fs::read(&path).unwrap();
+        assert!(data.starts_with(&command(&["SET", "a", "1"])));
+        assert!(data.ends_with(&tail));
+
+        let mut db = Db::new();
+        assert_eq!(load(&path, &mut db).unwrap(), Loaded { commands: 3, truncated: false });
+        assert_eq!(db.len(), 3);
+
+        fs::remove_file(path).unwrap();
+    }
+}
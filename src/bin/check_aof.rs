@@ -0,0 +1,75 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::process;
+use my_redis::aof;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (fix, path) = match args.as_slice() {
+        [path] => (false, path),
+        [flag, path] if flag == "--fix" => (true, path),
+        _ => {
+            println!("Usage: redis-check-aof [--fix] <file.aof>");
+            process::exit(1);
+        },
+    };
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Cannot open file {}: {}", path, e);
+            process::exit(1);
+        },
+    };
+
+    let report = aof::check(&data);
+    if report.preamble && report.valid_up_to > 0 {
+        println!("RDB preamble is OK, proceeding with AOF tail...");
+    }
+    let diff = data.len() as u64 - report.valid_up_to;
+    println!("AOF analyzed: filename={}, size={}, ok_up_to={}, commands={}, diff={}",
+        path, data.len(), report.valid_up_to, report.commands, diff);
+
+    match &report.error {
+        None => println!("AOF {} is valid", path),
+        Some(error) => {
+            println!("{}", error);
+            if !fix {
+                println!("AOF {} is not valid. Use the --fix option to try fixing it.", path);
+                process::exit(1);
+            }
+            if !report.fixable() {
+                println!("The RDB preamble of AOF {} is not valid and can not be fixed by truncating it.", path);
+                process::exit(1);
+            }
+            println!("This will shrink the AOF {} from {} bytes, with {} bytes, to {} bytes", path, data.len(), diff, report.valid_up_to);
+            if !confirm() {
+                println!("Aborting...");
+                process::exit(1);
+            }
+            let truncated = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .and_then(|file| file.set_len(report.valid_up_to));
+            match truncated {
+                Ok(()) => println!("Successfully truncated AOF {} ({} bytes removed)", path, diff),
+                Err(e) => {
+                    println!("Failed to truncate AOF {}: {}", path, e);
+                    process::exit(1);
+                },
+            }
+        },
+    }
+}
+
+//Anything but an answer starting with y, end of input included, is a no.
+fn confirm() -> bool {
+    print!("Continue? [y/N]: ");
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => answer.trim_start().to_lowercase().starts_with('y'),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,110 @@
+use std::env;
+use std::fs;
+use std::process;
+use my_redis::rdb::{self, Reader};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let path = match args.as_slice() {
+        [path] => path,
+        _ => {
+            println!("Usage: redis-check-rdb <rdb-file-name>");
+            process::exit(1);
+        },
+    };
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Cannot open file {}: {}", path, e);
+            process::exit(1);
+        },
+    };
+
+    println!("[offset 0] Checking RDB file {}", path);
+    let mut reader = Reader::new(&data);
+    match check(&mut reader) {
+        Ok(()) => println!("\\o/ RDB looks OK! \\o/"),
+        Err(e) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("[offset {}] {}", reader.pos, e);
+            process::exit(1);
+        },
+    }
+}
+
+//Walks every opcode of the file, decoding each length and string so that a
+//bad encoding is caught where it starts, then verifies the CRC64 trailer.
+fn check(reader: &mut Reader) -> Result<(), String> {
+    let version = reader.header()?;
+    println!("[offset {}] RDB version {}", reader.pos, version);
+    let mut keys = 0;
+    let mut expires = 0;
+
+    loop {
+        let offset = reader.pos;
+        match reader.u8()? {
+            rdb::OPCODE_EOF => break,
+            rdb::OPCODE_AUX => {
+                let key = reader.string()?;
+                let val = reader.string()?;
+                println!("[offset {}] AUX FIELD {} = '{}'", offset,
+                    String::from_utf8_lossy(&key), String::from_utf8_lossy(&val));
+            },
+            rdb::OPCODE_MODULE_AUX => {
+                let module = reader.module_aux()?;
+                println!("[offset {}] Module aux data of module {:#x}", offset, module);
+            },
+            rdb::OPCODE_FUNCTION2 => {
+                reader.string()?;
+                println!("[offset {}] Function library", offset);
+            },
+            rdb::OPCODE_FUNCTION_PRE_GA => {
+                reader.pos = offset;
+                return Err("Pre-GA function format is not supported".to_string());
+            },
+            rdb::OPCODE_SELECTDB => {
+                let db = reader.length()?;
+                println!("[offset {}] Selecting DB ID {}", offset, db);
+            },
+            rdb::OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            },
+            rdb::OPCODE_SLOT_INFO => {
+                reader.length()?;
+                reader.length()?;
+                reader.length()?;
+            },
+            rdb::OPCODE_EXPIRETIME_MS => {
+                reader.u64_le()?;
+                expires += 1;
+            },
+            rdb::OPCODE_EXPIRETIME => {
+                reader.u32_le()?;
+                expires += 1;
+            },
+            rdb::OPCODE_IDLE => {
+                reader.length()?;
+            },
+            rdb::OPCODE_FREQ => {
+                reader.u8()?;
+            },
+            value_type => {
+                reader.string()?;
+                if reader.value(value_type)?.is_none() {
+                    println!("[offset {}] Value of type {} walked but not loadable", offset, value_type);
+                }
+                keys += 1;
+            },
+        }
+    }
+
+    if version >= 5 {
+        reader.checksum()?;
+        println!("[offset {}] Checksum OK", reader.pos);
+    }
+    println!("[info] {} keys read", keys);
+    println!("[info] {} expires", expires);
+    Ok(())
+}
@@ -1,62 +1,229 @@
 use std::io;
 use std::io::Write;
-use tokio::net::TcpStream;
+use std::path::Path;
+use std::process;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
 use std::error::Error;
-use my_redis::frame::Frame;
+use bytes::Bytes;
+use my_redis::frame::{self, Frame};
 use my_redis::parser::*;
+use my_redis::tls;
+
+//`redis-cli [-h host] [-p port] [-s socket] [-a password] [--user name]
+//[--tls --cacert file [--cert file --key file] [--sni name]]`. The socket,
+//when given, is used instead of TCP.
+struct Options {
+    host: String,
+    port: u16,
+    socket: Option<String>,
+    tls: bool,
+    cacert: Option<String>,
+    cert: Option<String>,
+    key: Option<String>,
+    sni: Option<String>,
+    user: Option<String>,
+    pass: Option<String>,
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Connection for T {}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let stream = TcpStream::connect("127.0.0.1:6379").await?;
-    println!("Succesfully connected to redis server");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
+    let mut stream = match connect(&options).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            let target = match &options.socket {
+                Some(path) => path.clone(),
+                None => format!("{}:{}", options.host, options.port),
+            };
+            eprintln!("Could not connect to Redis at {}: {}", target, e);
+            process::exit(1);
+        },
+    };
+
+    if let Some(pass) = &options.pass {
+        eprintln!("Warning: Using a password with '-a' or '-u' option on the command line interface may not be safe.");
+        let mut auth = Frame::array();
+        auth.push_bulk(Bytes::from("AUTH"));
+        if let Some(user) = &options.user {
+            auth.push_bulk(Bytes::from(user.clone()));
+        }
+        auth.push_bulk(Bytes::from(pass.clone()));
+        stream.write_all(&auth.deserialize()).await?;
+        stream.flush().await?;
+        if let Some(Frame::Error(e)) = read_reply(&mut stream).await? {
+            eprintln!("AUTH failed: {}", e);
+        }
+    }
 
     loop {
         print!("my-redis$ ");
         io::stdout().flush().unwrap();
         let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        if input == "END" {
+        if io::stdin().read_line(&mut input)? == 0 || input.trim_end() == "END" {
             break;
-        } else {
-            match parse(input) {
-                Ok(ref mut frame) => {
-                    stream.writable().await?;
-                    match stream.try_write(&mut frame.deserialize()) {
-                        Ok(_) => {
-                            println!("Succesfully wrote to stream");
-                        },
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                        Err(e) => return Err(e.into()),
-                    }
-                },
-                Err(e) => {
-                    println!("{e}");
-                    continue;
-                }
-            }
         }
-        
-        stream.readable().await?;
-        let mut buf = vec![0; 1024];
-        loop {
-            match stream.try_read(&mut buf) {
-                Ok(n) => {
-                    buf.truncate(n);
-                    println!("Read {n} chars");
-                    break;
-                },
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    continue;
-                },
-                Err(e) => return Err(e.into()),
+        let monitor = input.split_whitespace().next().is_some_and(|cmd| cmd.eq_ignore_ascii_case("MONITOR"));
+        match parse(input) {
+            Ok(ref mut frame) => {
+                stream.write_all(&frame.deserialize()).await?;
+                stream.flush().await?;
+            },
+            Err(e) => {
+                println!("{e}");
+                continue;
             }
         }
 
-        let mut response = Frame::serialize(&mut io::Cursor::new(&buf)).unwrap();
-        println!("{}", response.to_string().unwrap());
+        match read_reply(&mut stream).await? {
+            Some(Frame::Simple(ok)) if monitor && ok == "OK" => {
+                println!("OK");
+                return stream_monitor(&mut stream).await;
+            },
+            Some(mut response) => println!("{}", display(&mut response)),
+            None => {
+                println!("Server closed the connection");
+                return Ok(());
+            },
+        }
     }
 
     println!("/nDisconnected from server");
 
     Ok(())
 }
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        host: "127.0.0.1".to_string(),
+        port: 6379,
+        socket: None,
+        tls: false,
+        cacert: None,
+        cert: None,
+        key: None,
+        sni: None,
+        user: None,
+        pass: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(format!("Missing value for option '{}'", arg));
+        match arg.as_str() {
+            "-h" => options.host = value()?,
+            "-p" => options.port = value()?.parse().map_err(|_| "Invalid port".to_string())?,
+            "-s" => options.socket = Some(value()?),
+            "--tls" => options.tls = true,
+            "--cacert" => options.cacert = Some(value()?),
+            "--cert" => options.cert = Some(value()?),
+            "--key" => options.key = Some(value()?),
+            "--sni" => options.sni = Some(value()?),
+            "-a" | "--pass" => options.pass = Some(value()?),
+            "--user" => options.user = Some(value()?),
+            _ => return Err(format!("Unrecognized option or bad number of args for: '{}'", arg)),
+        }
+    }
+    if options.tls && options.cacert.is_none() {
+        return Err("--tls requires --cacert".to_string());
+    }
+    if options.user.is_some() && options.pass.is_none() {
+        return Err("--user requires a password given with -a or --pass".to_string());
+    }
+    if options.cert.is_some() != options.key.is_some() {
+        return Err("--cert and --key must be given together".to_string());
+    }
+    Ok(options)
+}
+
+async fn connect(options: &Options) -> Result<Box<dyn Connection>, Box<dyn Error>> {
+    if let Some(path) = &options.socket {
+        return Ok(Box::new(UnixStream::connect(path).await?));
+    }
+    let stream = TcpStream::connect((options.host.as_str(), options.port)).await?;
+    match &options.cacert {
+        Some(cacert) if options.tls => {
+            let identity = options.cert.as_ref().zip(options.key.as_ref())
+                .map(|(cert, key)| (Path::new(cert), Path::new(key)));
+            let connector = tls::connector(Path::new(cacert), identity)?;
+            let name = tls::server_name(options.sni.as_ref().unwrap_or(&options.host))?;
+            Ok(Box::new(connector.connect(name, stream).await?))
+        },
+        _ => Ok(Box::new(stream)),
+    }
+}
+
+//Reads until a whole reply came in. None when the server closed the connection.
+async fn read_reply(stream: &mut Box<dyn Connection>) -> Result<Option<Frame>, Box<dyn Error>> {
+    let mut buf = Vec::new();
+    let mut chunk = vec![0; 1024];
+    loop {
+        //TLS servers may close without a close_notify, which is an EOF all the same.
+        let n = match stream.read(&mut chunk).await {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        match Frame::serialize(&mut io::Cursor::new(&buf[..])) {
+            Ok(frame) => return Ok(Some(frame)),
+            Err(e) if e == frame::INCOMPLETE => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+//Prints the MONITOR feed, one command per line, until the server closes the
+//connection.
+async fn stream_monitor(stream: &mut Box<dyn Connection>) -> Result<(), Box<dyn Error>> {
+    let mut buf = Vec::new();
+    let mut chunk = vec![0; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        loop {
+            let mut cursor = io::Cursor::new(&buf[..]);
+            match Frame::serialize(&mut cursor) {
+                Ok(mut frame) => {
+                    let len = cursor.position() as usize;
+                    buf.drain(..len);
+                    println!("{}", display(&mut frame));
+                },
+                Err(e) if e == frame::INCOMPLETE => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+fn display(frame: &mut Frame) -> String {
+    match frame {
+        Frame::Null => "(nil)".to_string(),
+        Frame::Error(e) => format!("(error) {e}"),
+        Frame::Array(vec) if vec.is_empty() => "(empty array)".to_string(),
+        Frame::Array(vec) => {
+            let lines: Vec<String> = vec.iter_mut()
+                .enumerate()
+                .map(|(i, f)| format!("{}) {}", i + 1, display(f)))
+                .collect();
+            lines.join("\n")
+        },
+        frame => frame.to_string().unwrap(),
+    }
+}
@@ -1,69 +1,529 @@
-use tokio::net::TcpListener;
-use tokio::task;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
+use tokio::task::{self, JoinSet};
 use std::error::Error;
+use std::fs::{self, Permissions};
 use std::io;
-use std::collections::HashMap;
-use tokio::net::TcpStream;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::process;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
 use std::sync::{Arc, Mutex};
+use my_redis::client;
+use my_redis::Db;
+use my_redis::Shared;
 use my_redis::Frame;
+use my_redis::frame;
+use my_redis::log;
+use my_redis::metrics;
 use my_redis::Handler;
+use my_redis::persistence;
+use my_redis::config::{Config, REDIS_VERSION};
+use my_redis::replication::{self, ReplicaSync};
+use my_redis::shutdown::{self, Options};
+use my_redis::tls;
+use tokio_rustls::TlsAcceptor;
+use socket2::{SockRef, TcpKeepalive};
+use std::time::Duration;
+use bytes::Bytes;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let db: HashMap<String, String> = HashMap::new();
-    let db = Arc::new(Mutex::new(db));
-    let mut tasks = Vec::new();
-    //let mut handler = Handler::new(&mut db);
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
+    if let Err(e) = log::configure(config.loglevel, &config.logfile) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    log::notice(&format!("Redis version={}, bits={}, pid={}, just started", REDIS_VERSION, usize::BITS, process::id()));
+
+    let db = Arc::new(Mutex::new(Db::new()));
+    let shared = db.lock().unwrap().shared();
+    {
+        let db = db.lock().unwrap();
+        db.persistence().set_settings(config.persistence.clone());
+        db.busy().set_threshold_ms(config.busy_reply_threshold);
+        db.replication().set_listening_port(config.port);
+        db.acl().set_default_password(&config.requirepass);
+        db.acl().set_log_max_len(config.acllog_max_len);
+        db.slowlog().set_config(config.slowlog_log_slower_than, config.slowlog_max_len);
+        db.latency().set_threshold(config.latency_monitor_threshold);
+        *db.config().lock().unwrap() = config.clone();
+    }
+    //Users from the ACL file replace the default user set by requirepass.
+    if let Some(path) = &config.aclfile {
+        if let Err(e) = db.lock().unwrap().acl().load(path) {
+            log::warning(&format!("Aborting Redis startup because of ACL errors: {}", e));
+            process::exit(1);
+        }
+    }
+    match persistence::load(&mut db.lock().unwrap()) {
+        Ok(true) => log::notice("DB loaded from disk"),
+        Ok(false) => (),
+        Err(e) => return Err(e.into()),
+    }
+    if config.persistence.appendonly {
+        persistence::start_aof(&db.lock().unwrap())?;
+    }
+    if let Some((host, port)) = &config.replicaof {
+        replication::replicaof(db.clone(), shared.clone(), host.clone(), *port)?;
+    }
+
+    let cron_db = db.clone();
+    let cron_shared = shared.clone();
+    task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            //Keys do not expire while writes are paused, so that the dataset
+            //stays still during a failover. While a script holds the
+            //database, the expired keys wait for a later tick.
+            if !cron_shared.clients.paused(true) {
+                if let Ok(mut db) = cron_db.try_lock() {
+                    db.purge_expired();
+                }
+            }
+            let timeout = cron_shared.config.lock().unwrap().timeout;
+            if timeout > 0 && cron_shared.clients.close_idle(Duration::from_secs(timeout)) > 0 {
+                log::verbose("Closing idle client(s)");
+            }
+            persistence::cron(&cron_db, &cron_shared);
+            cron_shared.replication.cron();
+            cron_shared.stats.sample();
+        }
+    });
+
+    let shutdown = shared.shutdown.clone();
+    let mut listeners = Vec::new();
+    if config.port != 0 {
+        for addr in &config.bind {
+            let listener = TcpListener::bind((addr.as_str(), config.port)).await
+                .map_err(|e| format!("Could not create server TCP listening socket {}:{}: {}", addr, config.port, e))?;
+            listeners.push(task::spawn(accept_loop(Listener::Tcp(listener), db.clone(), shared.clone(), shutdown.subscribe())));
+        }
+    }
+    if config.tls.port != 0 {
+        let acceptor = tls::acceptor(&config.tls)?;
+        for addr in &config.bind {
+            let listener = TcpListener::bind((addr.as_str(), config.tls.port)).await
+                .map_err(|e| format!("Could not create server TLS listening socket {}:{}: {}", addr, config.tls.port, e))?;
+            listeners.push(task::spawn(accept_loop(Listener::Tls(listener, acceptor.clone()), db.clone(), shared.clone(), shutdown.subscribe())));
+        }
+    }
+    if let Some(path) = &config.unixsocket {
+        let listener = bind_unix(path, config.unixsocketperm)?;
+        log::notice(&format!("The server is now ready to accept connections at {}", path.display()));
+        listeners.push(task::spawn(accept_loop(Listener::Unix(listener), db.clone(), shared.clone(), shutdown.subscribe())));
+    }
+    //The metrics listener is not a client listener: it does not keep the
+    //server alive nor delay its shutdown.
+    if config.metrics_port != 0 {
+        for addr in &config.bind {
+            let listener = TcpListener::bind((addr.as_str(), config.metrics_port)).await
+                .map_err(|e| format!("Could not create metrics listening socket {}:{}: {}", addr, config.metrics_port, e))?;
+            task::spawn(metrics::serve(listener, db.clone(), shared.busy.clone()));
+        }
+        log::notice(&format!("Serving metrics on port {} at /metrics", config.metrics_port));
+    }
+    if listeners.is_empty() {
+        log::warning("Configured to not listen anywhere, exiting.");
+        process::exit(1);
+    }
+    log::notice("Ready to accept connections");
+    task::spawn(handle_signals(db.clone(), shared.clone()));
+
+    //Each accept loop returns once the shutdown was triggered and its
+    //connections are closed.
+    for listener in listeners {
+        listener.await??;
+    }
+    if let Some(path) = &config.unixsocket {
+        let _ = fs::remove_file(path);
+    }
+    log::warning("Redis is now ready to exit, bye bye...");
+    process::exit(shutdown.exit_code());
+}
 
+//SIGTERM and SIGINT run the same sequence as a plain SHUTDOWN. If it fails,
+//for example because the final save could not be written, the server keeps
+//running and a second signal forces the exit.
+async fn handle_signals(db: Arc<Mutex<Db>>, shared: Shared) -> Result<(), io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut options = Options::default();
     loop {
-        let (stream, _) = listener.accept().await?;
-        println!("Got a connection");
+        tokio::select! {
+            _ = terminate.recv() => log::warning("Received SIGTERM scheduling shutdown..."),
+            _ = interrupt.recv() => log::warning("Received SIGINT scheduling shutdown..."),
+        }
+        let result = replication::block(|| shutdown::prepare(&db, &shared, options));
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                log::warning(&e);
+                options.force = true;
+            },
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, TlsAcceptor),
+    Unix(UnixListener),
+}
+
+//The TLS handshake is left to the connection task, so that a slow client
+//does not hold up the accept loop.
+enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    Tls(TcpStream, SocketAddr, TlsAcceptor),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> Result<Connection, io::Error> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().await.map(|(stream, addr)| Connection::Tcp(stream, addr)),
+            Listener::Tls(listener, acceptor) => {
+                listener.accept().await.map(|(stream, addr)| Connection::Tls(stream, addr, acceptor.clone()))
+            },
+            Listener::Unix(listener) => listener.accept().await.map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+}
 
-        let db = db.clone();
+//A socket file left over by a previous run is replaced, but nothing else
+//is deleted to make room for the socket.
+fn bind_unix(path: &Path, perm: u32) -> Result<UnixListener, String> {
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        let _ = fs::remove_file(path);
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| format!("Failed opening Unix socket {}: {}", path.display(), e))?;
+    if perm != 0 {
+        fs::set_permissions(path, Permissions::from_mode(perm))
+            .map_err(|e| format!("Failed setting permissions of Unix socket {}: {}", path.display(), e))?;
+    }
+    Ok(listener)
+}
 
-        let tsk = task::spawn(async {
-            handle_connexion(stream, db).await;
-        });
+//Finished connection tasks are reaped as the loop goes, and once the server
+//shuts down it stops accepting and waits for the open connections to close.
+async fn accept_loop(listener: Listener, db: Arc<Mutex<Db>>, shared: Shared, mut shutdown: watch::Receiver<bool>) -> Result<(), io::Error> {
+    let mut tasks = JoinSet::new();
+    while !*shutdown.borrow() {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let connection = accepted?;
 
-        tasks.push(tsk);
+                let db = db.clone();
+                let shared = shared.clone();
+                let shutdown = shutdown.clone();
+                tasks.spawn(async move {
+                    let result = match connection {
+                        Connection::Tcp(stream, addr) => {
+                            log::verbose(&format!("Accepted {}", addr));
+                            set_keepalive(&stream, &shared.config);
+                            let local = tcp_local(&stream);
+                            handle_connexion(stream, Some(addr), local, db, shared, shutdown).await
+                        },
+                        Connection::Tls(stream, addr, acceptor) => {
+                            log::verbose(&format!("Accepted {}", addr));
+                            set_keepalive(&stream, &shared.config);
+                            let local = tcp_local(&stream);
+                            match acceptor.accept(stream).await {
+                                Ok(stream) => handle_connexion(stream, Some(addr), local, db, shared, shutdown).await,
+                                Err(e) => Err(format!("TLS handshake failed: {}", e).into()),
+                            }
+                        },
+                        Connection::Unix(stream) => {
+                            let local = stream.local_addr().ok()
+                                .and_then(|addr| addr.as_pathname().map(|path| format!("{}:0", path.display())))
+                                .unwrap_or_default();
+                            log::verbose(&format!("Accepted connection to {}", local));
+                            handle_connexion(stream, None, local, db, shared, shutdown).await
+                        },
+                    };
+                    if let Err(e) = result {
+                        log::verbose(&format!("Connexion error: {e}"));
+                    }
+                });
+            },
+            Some(_) = tasks.join_next(), if !tasks.is_empty() => (),
+            _ = shutdown.changed() => (),
+        }
     }
+    while tasks.join_next().await.is_some() {}
+    Ok(())
+}
+
+fn tcp_local(stream: &TcpStream) -> String {
+    stream.local_addr().map(|addr| addr.to_string()).unwrap_or_default()
+}
 
-    //Ok(())
+//tcp-keepalive is the time before the first probe, as in Redis, which then
+//probes three times at a third of that interval. 0 leaves keepalive off.
+fn set_keepalive(stream: &TcpStream, config: &Mutex<Config>) {
+    let seconds = config.lock().unwrap().tcp_keepalive;
+    if seconds == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(seconds);
+    let keepalive = TcpKeepalive::new()
+        .with_time(interval)
+        .with_interval((interval / 3).max(Duration::from_secs(1)))
+        .with_retries(3);
+    if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        log::warning(&format!("Failed to enable TCP keepalive: {}", e));
+    }
 }
 
-async fn handle_connexion(stream: TcpStream, DB: Arc<Mutex<HashMap<String, String>>>) -> Result<(), Box<dyn Error>> {
-    let mut handler = Handler::new(DB);
+//Serves one client, over TCP, TLS or a Unix socket. Unix socket clients have no
+//peer address. Commands run off the async workers, so that a long script
+//does not keep the other connections from answering -BUSY.
+async fn handle_connexion<S>(mut stream: S, peer: Option<SocketAddr>, local: String, db: Arc<Mutex<Db>>, shared: Shared, mut shutdown: watch::Receiver<bool>) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stats = shared.stats.clone();
+    let maxclients = shared.config.lock().unwrap().maxclients;
+    if shared.clients.len() as u64 >= maxclients {
+        stats.rejected_connection();
+        send(&mut stream, &Frame::Error(client::MAXCLIENTS.to_string()).deserialize()).await?;
+        stream.shutdown().await?;
+        return Ok(());
+    }
+    stats.connection();
+    let mut handler = Handler::with_shared(db.clone(), shared.clone());
+    handler.set_addresses(peer, local);
+    let client = handler.client();
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = vec![0; 4096];
     loop {
-        let mut buf = vec![0; 4096];
-        stream.readable().await?;
+        //Pipelined commands can arrive in one read and a command can span
+        //several, so frames are parsed out of an accumulating buffer.
+        loop {
+            let mut cursor = io::Cursor::new(&buf[..]);
+            let command = match Frame::serialize(&mut cursor) {
+                Ok(frame) => frame,
+                Err(e) if e == frame::INCOMPLETE => break,
+                Err(e) => {
+                    send(&mut stream, &Frame::Error(format!("Protocol error: {}", e)).deserialize()).await?;
+                    return Ok(());
+                },
+            };
+            let len = cursor.position() as usize;
+            buf.drain(..len);
+            client.set_buffers(buf.len(), buf.capacity() - buf.len(), len);
+
+            let result = replication::block(|| handler.get_command(command).and_then(|_| handler.execute_cmd()));
+            let mut response = match result {
+                Ok(frame) => frame,
+                Err(e) => Frame::Error(e),
+            };
+            client.set_buffers(buf.len(), buf.capacity() - buf.len(), 0);
+            if handler.closing() {
+                stream.shutdown().await?;
+                return Ok(());
+            }
+            if handler.should_reply() {
+                let reply = response.deserialize();
+                stats.net_output(reply.len());
+                send(&mut stream, &reply).await?;
+            }
+            //A client killed by its own command still gets the reply.
+            if client.is_killed() {
+                stream.shutdown().await?;
+                return Ok(());
+            }
+
+            if let Some(sync) = handler.take_sync() {
+                return serve_replica(stream, handler, sync, buf, shared, shutdown).await;
+            }
+            if let Some(feed) = handler.take_monitor() {
+                return serve_monitor(stream, handler, feed, shutdown).await;
+            }
+        }
+
+        if *shutdown.borrow() {
+            send(&mut stream, &Frame::Error(shutdown::SHUTTING_DOWN.to_string()).deserialize()).await?;
+            stream.shutdown().await?;
+            return Ok(());
+        }
+        let n = tokio::select! {
+            n = stream.read(&mut chunk) => n?,
+            _ = shutdown.changed() => continue,
+            _ = client.killed() => {
+                stream.shutdown().await?;
+                return Ok(());
+            },
+        };
+        if n == 0 {
+            log::verbose("Client closed connection");
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        stats.net_input(n);
+        client.touch();
+        client.set_buffers(buf.len(), buf.capacity() - buf.len(), 0);
+    }
+}
+
+//TLS streams buffer what is written, so every write is flushed.
+async fn send<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> Result<(), io::Error> {
+    stream.write_all(data).await?;
+    stream.flush().await
+}
 
-        match stream.try_read(&mut buf) {
-            Ok(n) => {
-                if n == 0 {
-                    println!("Connexion ended");
+//Sends a MONITOR client the commands of the other connections until it
+//disconnects. What it sends is ignored.
+async fn serve_monitor<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, handler: Handler, mut feed: Receiver<Bytes>, mut shutdown: watch::Receiver<bool>) -> Result<(), Box<dyn Error>> {
+    let client = handler.client();
+    let mut chunk = vec![0; 4096];
+    loop {
+        tokio::select! {
+            line = feed.recv() => match line {
+                Some(line) => send(&mut stream, &line).await?,
+                None => return Ok(()),
+            },
+            _ = shutdown.changed() => return Ok(()),
+            _ = client.killed() => {
+                stream.shutdown().await?;
+                return Ok(());
+            },
+            n = stream.read(&mut chunk) => {
+                if n? == 0 {
                     return Ok(());
-                } else {
-                    buf.truncate(n);
-                    println!("read some stuff");
                 }
+            },
+        }
+    }
+}
+
+//Streams the replication payload and then every propagated write to a
+//replica, while reading the REPLCONF ACKs it sends back.
+async fn serve_replica<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, mut handler: Handler, mut sync: ReplicaSync, mut buf: Vec<u8>, shared: Shared, mut shutdown: watch::Receiver<bool>) -> Result<(), Box<dyn Error>> {
+    let client = handler.client();
+    let result = async {
+        send(&mut stream, &sync.payload()).await?;
+        let mut chunk = vec![0; 4096];
+        loop {
+            tokio::select! {
+                data = sync.receiver.recv() => match data {
+                    Some(data) => send(&mut stream, &data).await?,
+                    None => return Ok(()),
+                },
+                _ = shutdown.changed() => return Ok(()),
+                _ = client.killed() => return Ok(()),
+                n = stream.read(&mut chunk) => {
+                    let n = n?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                    loop {
+                        let mut cursor = io::Cursor::new(&buf[..]);
+                        match Frame::serialize(&mut cursor) {
+                            Ok(frame) => {
+                                let len = cursor.position() as usize;
+                                buf.drain(..len);
+                                let _ = replication::block(|| handler.get_command(frame).and_then(|_| handler.execute_cmd()));
+                            },
+                            Err(e) if e == frame::INCOMPLETE => break,
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                },
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-            Err(e) => return Err(e.into()),
         }
+    }.await;
+    shared.replication.remove_replica(sync.id);
+    result
+}
 
-        let command = Frame::serialize(&mut io::Cursor::new(&buf)).unwrap();
-        handler.get_command(command).unwrap();
-        let mut response = handler.execute_cmd().unwrap();
-        println!("Result from command execution : {}", response.to_string().unwrap());
+//TESTS
 
-        stream.writable().await?;
-        match stream.try_write(&mut response.deserialize()) {
-            Ok(n) => {
-                println!("Wrote {n} chars to stream");
-            },
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-            Err(e) => return Err(e.into()),
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use tokio::time::{sleep, timeout};
+
+    const BUSY_SCRIPT: &[&str] = &["EVAL", "while true do end", "0"];
+
+    async fn start() -> (SocketAddr, Shared, task::JoinHandle<Result<(), io::Error>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let db = Arc::new(Mutex::new(Db::new()));
+        let shared = db.lock().unwrap().shared();
+        shared.busy.set_threshold_ms(50);
+        let server = task::spawn(accept_loop(Listener::Tcp(listener), db, shared.clone(), shared.shutdown.subscribe()));
+        (addr, shared, server)
+    }
+
+    async fn send_cmd(stream: &mut TcpStream, args: &[&str]) {
+        let mut frame = Frame::array();
+        for arg in args {
+            frame.push_bulk(Bytes::from(arg.to_string()));
         }
+        send(stream, &frame.deserialize()).await.unwrap();
+    }
+
+    //Replies in these tests are short enough to arrive in a single read.
+    async fn reply(stream: &mut TcpStream) -> String {
+        let mut buf = vec![0; 4096];
+        let n = timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap().unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    async fn run(stream: &mut TcpStream, args: &[&str]) -> String {
+        send_cmd(stream, args).await;
+        reply(stream).await
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn busy_script_and_script_kill_over_tcp() {
+        let (addr, _, _) = start().await;
+        let mut script = TcpStream::connect(addr).await.unwrap();
+        send_cmd(&mut script, BUSY_SCRIPT).await;
+        sleep(Duration::from_millis(200)).await;
+
+        //A new connection is accepted while the script holds the database.
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        assert!(run(&mut other, &["GET", "k"]).await.starts_with("-BUSY"));
+        assert_eq!(run(&mut other, &["SCRIPT", "KILL"]).await, "+OK\r\n");
+
+        assert!(reply(&mut script).await.contains("Script killed by user"));
+        assert_eq!(run(&mut other, &["PING"]).await, "+PONG\r\n");
+        assert_eq!(run(&mut script, &["PING"]).await, "+PONG\r\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn shutdown_nosave_stops_a_busy_script() {
+        let (addr, shared, server) = start().await;
+        let mut script = TcpStream::connect(addr).await.unwrap();
+        send_cmd(&mut script, &["SET", "k", "v"]).await;
+        assert_eq!(reply(&mut script).await, "+Ok\r\n");
+        //A script that wrote can not be killed, only shut down.
+        send_cmd(&mut script, &["EVAL", "redis.call('SET', 'k', 'w') while true do end", "0"]).await;
+        sleep(Duration::from_millis(200)).await;
+
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        assert!(run(&mut other, &["SCRIPT", "KILL"]).await.starts_with("-UNKILLABLE"));
+        send_cmd(&mut other, &["SHUTDOWN", "NOSAVE"]).await;
+        assert_eq!(reply(&mut other).await, "");
+
+        timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+        assert!(shared.shutdown.is_triggered());
     }
 }
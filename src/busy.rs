@@ -0,0 +1,188 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+//Redis' default for busy-reply-threshold (formerly lua-time-limit).
+pub const DEFAULT_THRESHOLD_MS: u64 = 5000;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Operation {
+    Script,
+    Function,
+    Command(String),
+}
+
+//Tracks the operation currently holding the database lock so that other
+//connections can answer -BUSY instead of waiting on the lock, and so that
+//an administrative command can ask the operation to stop. Long-running code
+//polls `check` at regular points and bails out once a kill was requested.
+#[derive(Debug)]
+pub struct Busy {
+    threshold_ms: AtomicU64,
+    running: Mutex<Option<(Operation, Instant)>>,
+    kill: AtomicBool,
+    wrote: AtomicBool,
+}
+
+impl Default for Busy {
+    fn default() -> Busy {
+        Busy {
+            threshold_ms: AtomicU64::new(DEFAULT_THRESHOLD_MS),
+            running: Mutex::new(None),
+            kill: AtomicBool::new(false),
+            wrote: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Busy {
+    pub fn threshold_ms(&self) -> u64 {
+        self.threshold_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn set_threshold_ms(&self, ms: u64) {
+        self.threshold_ms.store(ms, Ordering::Relaxed);
+    }
+
+    //Runs `f` as the current operation. Must be called with the database locked.
+    pub fn run<T>(&self, operation: Operation, f: impl FnOnce() -> T) -> T {
+        self.kill.store(false, Ordering::SeqCst);
+        self.wrote.store(false, Ordering::SeqCst);
+        *self.running.lock().unwrap() = Some((operation, Instant::now()));
+        let _finished = Finished(self);
+        f()
+    }
+
+    //Called by the running operation between units of work.
+    pub fn check(&self) -> Result<(), String> {
+        if self.kill.load(Ordering::SeqCst) {
+            match self.running() {
+                Some(Operation::Command(name)) => Err(format!("{} interrupted by user with SCRIPT KILL", name)),
+                _ => Err("Script killed by user with SCRIPT KILL...".to_string()),
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    //Once a script has written to the dataset, killing it would leave a
+    //half applied script behind, so it becomes unkillable.
+    pub fn note_write(&self) {
+        self.wrote.store(true, Ordering::SeqCst);
+    }
+
+    pub fn running(&self) -> Option<Operation> {
+        self.running.lock().unwrap().as_ref().map(|(op, _)| op.clone())
+    }
+
+    //The reply for a client that would have to wait on an operation that has
+    //already run for longer than the threshold.
+    pub fn busy_error(&self) -> Option<String> {
+        let running = self.running.lock().unwrap();
+        match running.as_ref() {
+            Some((op, started)) if started.elapsed().as_millis() as u64 >= self.threshold_ms() => {
+                Some(match op {
+                    Operation::Script => "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.".to_string(),
+                    Operation::Function => "BUSY Redis is busy running a script. You can only call FUNCTION KILL or SHUTDOWN NOSAVE.".to_string(),
+                    Operation::Command(name) => format!("BUSY Redis is busy running {}. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.", name),
+                })
+            },
+            _ => None,
+        }
+    }
+
+    //SHUTDOWN NOSAVE stops whatever runs, even after it wrote, since the
+    //server exits anyway.
+    pub fn interrupt(&self) {
+        self.kill.store(true, Ordering::SeqCst);
+    }
+
+    //SCRIPT KILL stops an EVAL script or a long read-only command such as
+    //KEYS; FUNCTION KILL stops an FCALL.
+    pub fn kill(&self, function: bool) -> Result<(), String> {
+        let killable = match self.running() {
+            Some(Operation::Script) | Some(Operation::Command(_)) => !function,
+            Some(Operation::Function) => function,
+            None => false,
+        };
+        if !killable {
+            return Err("NOTBUSY No scripts in execution right now.".to_string());
+        }
+        if self.wrote.load(Ordering::SeqCst) {
+            return Err("UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.".to_string());
+        }
+        self.kill.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+//HELPER FN
+
+//Clears the running operation when `run` returns, and also when it unwinds
+//from a panic, so that later callers are not answered BUSY for good.
+struct Finished<'a>(&'a Busy);
+
+impl Drop for Finished<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        self.0.kill.store(false, Ordering::SeqCst);
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::busy::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn busy_error_after_threshold() {
+        let busy = Busy::default();
+        busy.set_threshold_ms(10);
+        assert!(busy.busy_error().is_none());
+
+        busy.run(Operation::Script, || {
+            assert!(busy.busy_error().is_none());
+            thread::sleep(Duration::from_millis(20));
+            assert!(busy.busy_error().unwrap().starts_with("BUSY"));
+        });
+        assert!(busy.busy_error().is_none());
+    }
+
+    #[test]
+    fn kill_rules() {
+        let busy = Busy::default();
+        assert!(busy.kill(false).unwrap_err().starts_with("NOTBUSY"));
+
+        busy.run(Operation::Script, || {
+            assert!(busy.kill(true).is_err());
+            busy.kill(false).unwrap();
+            assert!(busy.check().is_err());
+        });
+        assert!(busy.check().is_ok());
+
+        busy.run(Operation::Function, || {
+            busy.note_write();
+            assert!(busy.kill(true).unwrap_err().starts_with("UNKILLABLE"));
+            assert!(busy.check().is_ok());
+        });
+    }
+
+    #[test]
+    fn state_is_reset_after_a_panic() {
+        let busy = Busy::default();
+        busy.set_threshold_ms(0);
+        let result = std::panic::catch_unwind(|| {
+            busy.run(Operation::Script, || {
+                busy.kill(false).unwrap();
+                panic!("script panicked");
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(busy.running(), None);
+        assert!(busy.busy_error().is_none());
+        assert!(busy.check().is_ok());
+    }
+}
@@ -0,0 +1,515 @@
+use crate::frame::Frame;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+pub const MAXCLIENTS: &str = "ERR max number of clients reached";
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Kind {
+    NORMAL,
+    REPLICA,
+    MASTER,
+    PUBSUB,
+}
+
+impl Kind {
+    pub fn parse(value: &str) -> Result<Kind, String> {
+        match value.to_lowercase().as_str() {
+            "normal" => Ok(Kind::NORMAL),
+            "replica" | "slave" => Ok(Kind::REPLICA),
+            "master" => Ok(Kind::MASTER),
+            "pubsub" => Ok(Kind::PUBSUB),
+            _ => Err(format!("Unknown client type '{}'", value)),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ReplyMode {
+    ON,
+    OFF,
+    SKIP,
+}
+
+//CLIENT PAUSE WRITE only holds back commands that may change the dataset.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum PauseMode {
+    WRITE,
+    ALL,
+}
+
+//Selects clients for CLIENT LIST and CLIENT KILL. Every filter given must
+//match.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Filter {
+    pub ids: Vec<u64>,
+    pub kind: Option<Kind>,
+    pub user: Option<String>,
+    pub addr: Option<String>,
+    pub laddr: Option<String>,
+    //Leaves out the client running the command.
+    pub skipme: bool,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ClientCmd {
+    ID,
+    INFO,
+    LIST(Filter),
+    SETNAME(String),
+    GETNAME,
+    //The legacy `CLIENT KILL addr:port` form replies OK or an error instead
+    //of a count.
+    KILL(Filter, bool),
+    PAUSE(u64, PauseMode),
+    UNPAUSE,
+    REPLY(ReplyMode),
+    NOEVICT(bool),
+    NOTOUCH(bool),
+}
+
+impl ClientCmd {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientCmd::ID => "id",
+            ClientCmd::INFO => "info",
+            ClientCmd::LIST(_) => "list",
+            ClientCmd::SETNAME(_) => "setname",
+            ClientCmd::GETNAME => "getname",
+            ClientCmd::KILL(..) => "kill",
+            ClientCmd::PAUSE(..) => "pause",
+            ClientCmd::UNPAUSE => "unpause",
+            ClientCmd::REPLY(_) => "reply",
+            ClientCmd::NOEVICT(_) => "no-evict",
+            ClientCmd::NOTOUCH(_) => "no-touch",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    addr: String,
+    laddr: String,
+    name: String,
+    user: String,
+    kind: Kind,
+    last_interaction: Instant,
+    last_cmd: String,
+    //Set while a command runs, which may block, as with WAIT or a paused
+    //write. Such clients never count as idle.
+    running: bool,
+    //Commands queued, when in MULTI.
+    multi: Option<usize>,
+    watch: usize,
+    qbuf: usize,
+    qbuf_free: usize,
+    argv_mem: usize,
+    no_evict: bool,
+    no_touch: bool,
+    monitor: bool,
+}
+
+//One connection as seen by CLIENT LIST. The handler records the commands it
+//runs and the connection loop its buffers; CLIENT KILL flags it so the
+//connection loop closes it.
+#[derive(Debug)]
+pub struct Client {
+    id: u64,
+    created: Instant,
+    state: Mutex<State>,
+    killed: AtomicBool,
+    kill: Notify,
+}
+
+impl Client {
+    fn new(id: u64, user: &str) -> Client {
+        let now = Instant::now();
+        Client {
+            id,
+            created: now,
+            state: Mutex::new(State {
+                addr: String::new(),
+                laddr: String::new(),
+                name: String::new(),
+                user: user.to_string(),
+                kind: Kind::NORMAL,
+                last_interaction: now,
+                last_cmd: "NULL".to_string(),
+                running: false,
+                multi: None,
+                watch: 0,
+                qbuf: 0,
+                qbuf_free: 0,
+                argv_mem: 0,
+                no_evict: false,
+                no_touch: false,
+                monitor: false,
+            }),
+            killed: AtomicBool::new(false),
+            kill: Notify::new(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_addresses(&self, addr: String, laddr: String) {
+        let mut state = self.state.lock().unwrap();
+        state.addr = addr;
+        state.laddr = laddr;
+    }
+
+    pub fn addr(&self) -> String {
+        self.state.lock().unwrap().addr.clone()
+    }
+
+    pub fn name(&self) -> String {
+        self.state.lock().unwrap().name.clone()
+    }
+
+    pub fn set_user(&self, user: &str) {
+        self.state.lock().unwrap().user = user.to_string();
+    }
+
+    //Marks the connection as a MONITOR feed, flag O in CLIENT LIST.
+    pub fn set_monitor(&self) {
+        self.state.lock().unwrap().monitor = true;
+    }
+
+    pub fn set_kind(&self, kind: Kind) {
+        self.state.lock().unwrap().kind = kind;
+    }
+
+    //Called for every command the connection runs, with its
+    //`command|subcommand` name.
+    pub fn record(&self, command: String) {
+        let mut state = self.state.lock().unwrap();
+        state.last_interaction = Instant::now();
+        state.last_cmd = command;
+        state.running = true;
+    }
+
+    //Called once the command returned, with the commands queued if in MULTI
+    //and the keys watched.
+    pub fn finish(&self, multi: Option<usize>, watch: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.last_interaction = Instant::now();
+        state.running = false;
+        state.multi = multi;
+        state.watch = watch;
+    }
+
+    //Data was read from the client, even if not a whole command yet.
+    pub fn touch(&self) {
+        self.state.lock().unwrap().last_interaction = Instant::now();
+    }
+
+    //Unparsed bytes, free capacity left in the read buffer, and the size of
+    //the command being run.
+    pub fn set_buffers(&self, qbuf: usize, qbuf_free: usize, argv_mem: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.qbuf = qbuf;
+        state.qbuf_free = qbuf_free;
+        state.argv_mem = argv_mem;
+    }
+
+    //Seconds since the last command.
+    pub fn idle(&self) -> u64 {
+        self.state.lock().unwrap().last_interaction.elapsed().as_secs()
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
+    }
+
+    //Resolves once the client was killed.
+    pub async fn killed(&self) {
+        while !self.is_killed() {
+            self.kill.notified().await;
+        }
+    }
+
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::SeqCst);
+        self.kill.notify_one();
+    }
+
+    //The CLIENT LIST / CLIENT INFO line. There is a single database and the
+    //replies are written straight to the socket, so db and the output
+    //buffer fields are always 0.
+    pub fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut flags = String::new();
+        match state.kind {
+            Kind::REPLICA => flags.push('S'),
+            Kind::MASTER => flags.push('M'),
+            _ => (),
+        }
+        if state.monitor {
+            flags.push('O');
+        }
+        if state.multi.is_some() {
+            flags.push('x');
+        }
+        if state.no_evict {
+            flags.push('e');
+        }
+        if state.no_touch {
+            flags.push('T');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db=0 sub=0 psub=0 multi={} watch={} qbuf={} qbuf-free={} argv-mem={} obl=0 oll=0 omem=0 tot-mem={} cmd={} user={} resp=2",
+            self.id, state.addr, state.laddr, state.name, self.created.elapsed().as_secs(),
+            state.last_interaction.elapsed().as_secs(), flags, state.multi.map(|n| n as i64).unwrap_or(-1),
+            state.watch, state.qbuf, state.qbuf_free, state.argv_mem, state.qbuf + state.qbuf_free + state.argv_mem,
+            state.last_cmd, state.user,
+        )
+    }
+
+    fn matches(&self, filter: &Filter, me: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        !(filter.skipme && self.id == me)
+            && (filter.ids.is_empty() || filter.ids.contains(&self.id))
+            && filter.kind.is_none_or(|kind| kind == state.kind)
+            && filter.user.as_ref().is_none_or(|user| *user == state.user)
+            && filter.addr.as_ref().is_none_or(|addr| *addr == state.addr)
+            && filter.laddr.as_ref().is_none_or(|laddr| *laddr == state.laddr)
+    }
+}
+
+//Every open connection, and the CLIENT PAUSE state.
+#[derive(Debug, Default)]
+pub struct Clients {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, Arc<Client>>>,
+    pause: Mutex<Option<(Instant, PauseMode)>>,
+}
+
+impl Clients {
+    //Ids start at 1 and are never reused.
+    pub fn register(&self, user: &str) -> Arc<Client> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let client = Arc::new(Client::new(id, user));
+        self.clients.lock().unwrap().insert(id, client.clone());
+        client
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn list(&self, filter: &Filter, me: u64) -> String {
+        self.clients.lock().unwrap().values()
+            .filter(|client| client.matches(filter, me))
+            .map(|client| format!("{}\n", client.info()))
+            .collect()
+    }
+
+    //Returns how many clients were killed.
+    pub fn kill(&self, filter: &Filter, me: u64) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let killed: Vec<&Arc<Client>> = clients.values().filter(|client| client.matches(filter, me)).collect();
+        for client in &killed {
+            client.kill();
+        }
+        killed.len()
+    }
+
+    //Kills the normal clients idle for longer than `timeout`, as the
+    //`timeout` setting asks. Replicas and clients running a command are left
+    //alone.
+    pub fn close_idle(&self, timeout: Duration) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut closed = 0;
+        for client in clients.values() {
+            let idle = {
+                let state = client.state.lock().unwrap();
+                state.kind == Kind::NORMAL && !state.running && state.last_interaction.elapsed() > timeout
+            };
+            if idle && !client.is_killed() {
+                client.kill();
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    //A pause already in place is only ever extended or made stricter.
+    pub fn pause(&self, timeout_ms: u64, mode: PauseMode) {
+        let mut pause = self.pause.lock().unwrap();
+        let until = Instant::now() + Duration::from_millis(timeout_ms);
+        *pause = match *pause {
+            Some((old_until, old_mode)) if old_until > Instant::now() => {
+                let mode = if old_mode == PauseMode::ALL { PauseMode::ALL } else { mode };
+                Some((old_until.max(until), mode))
+            },
+            _ => Some((until, mode)),
+        };
+    }
+
+    pub fn unpause(&self) {
+        *self.pause.lock().unwrap() = None;
+    }
+
+    //Whether a command has to wait, given whether it may write.
+    pub fn paused(&self, write: bool) -> bool {
+        let mut pause = self.pause.lock().unwrap();
+        match *pause {
+            Some((until, _)) if until <= Instant::now() => {
+                *pause = None;
+                false
+            },
+            Some((_, mode)) => write || mode == PauseMode::ALL,
+            None => false,
+        }
+    }
+}
+
+//Runs the CLIENT subcommands that do not change how the connection itself
+//replies, for `client`.
+pub fn execute(cmd: &ClientCmd, clients: &Clients, client: &Client) -> Result<Frame, String> {
+    match cmd {
+        ClientCmd::ID => Ok(Frame::Integer(client.id as i64)),
+        ClientCmd::INFO => Ok(Frame::Bulk(Bytes::from(format!("{}\n", client.info())))),
+        ClientCmd::LIST(filter) => Ok(Frame::Bulk(Bytes::from(clients.list(filter, client.id)))),
+        ClientCmd::SETNAME(name) => {
+            if name.chars().any(|c| !c.is_ascii_graphic()) {
+                return Err("Client names cannot contain spaces, newlines or special characters.".to_string());
+            }
+            client.state.lock().unwrap().name = name.clone();
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        ClientCmd::GETNAME => match client.name() {
+            name if name.is_empty() => Ok(Frame::Null),
+            name => Ok(Frame::Bulk(Bytes::from(name))),
+        },
+        ClientCmd::KILL(filter, legacy) => {
+            let killed = clients.kill(filter, client.id);
+            match (legacy, killed) {
+                (true, 0) => Err("No such client".to_string()),
+                (true, _) => Ok(Frame::Simple("OK".to_string())),
+                (false, n) => Ok(Frame::Integer(n as i64)),
+            }
+        },
+        ClientCmd::PAUSE(timeout, mode) => {
+            clients.pause(*timeout, *mode);
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        ClientCmd::UNPAUSE => {
+            clients.unpause();
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        //The server has no eviction or LRU yet, so these only mark the client.
+        ClientCmd::NOEVICT(on) => {
+            client.state.lock().unwrap().no_evict = *on;
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        ClientCmd::NOTOUCH(on) => {
+            client.state.lock().unwrap().no_touch = *on;
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        ClientCmd::REPLY(_) => Err("CLIENT REPLY is handled by the connection".to_string()),
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::client::*;
+
+    #[test]
+    fn list_and_kill_filters() {
+        let clients = Clients::default();
+        let first = clients.register("default");
+        let second = clients.register("app");
+        first.set_addresses("127.0.0.1:5000".to_string(), "127.0.0.1:6379".to_string());
+        second.set_addresses("127.0.0.1:5001".to_string(), "127.0.0.1:6379".to_string());
+        second.set_kind(Kind::REPLICA);
+        assert_eq!((first.id(), second.id(), clients.len()), (1, 2, 2));
+
+        let list = clients.list(&Filter::default(), first.id());
+        assert_eq!(list.lines().count(), 2);
+        assert!(list.starts_with("id=1 addr=127.0.0.1:5000 laddr=127.0.0.1:6379 name= "));
+        assert!(list.lines().nth(1).unwrap().contains(" flags=S "));
+        assert_eq!(clients.list(&Filter { kind: Some(Kind::NORMAL), ..Filter::default() }, 1).lines().count(), 1);
+
+        let me = Filter { user: Some("default".to_string()), skipme: true, ..Filter::default() };
+        assert_eq!(clients.kill(&me, first.id()), 0);
+        let by_addr = Filter { addr: Some("127.0.0.1:5001".to_string()), skipme: true, ..Filter::default() };
+        assert_eq!(clients.kill(&by_addr, first.id()), 1);
+        assert!(second.is_killed() && !first.is_killed());
+
+        clients.unregister(second.id());
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients.register("default").id(), 3);
+    }
+
+    #[test]
+    fn pause_modes_and_expiry() {
+        let clients = Clients::default();
+        assert!(!clients.paused(true));
+
+        clients.pause(10000, PauseMode::WRITE);
+        assert!(clients.paused(true));
+        assert!(!clients.paused(false));
+        clients.pause(10, PauseMode::ALL);
+        assert!(clients.paused(false));
+        clients.unpause();
+        assert!(!clients.paused(true));
+
+        clients.pause(10, PauseMode::ALL);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!clients.paused(true));
+    }
+
+    #[test]
+    fn idle_clients_are_closed() {
+        let clients = Clients::default();
+        let idle = clients.register("default");
+        let running = clients.register("default");
+        let replica = clients.register("default");
+        running.record("wait".to_string());
+        replica.set_kind(Kind::REPLICA);
+
+        assert_eq!(clients.close_idle(Duration::from_secs(60)), 0);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(clients.close_idle(Duration::from_millis(10)), 1);
+        assert!(idle.is_killed() && !running.is_killed() && !replica.is_killed());
+
+        running.finish(None, 0);
+        assert_eq!(clients.close_idle(Duration::from_millis(10)), 0);
+    }
+
+    #[test]
+    fn setname_and_flags() {
+        let clients = Clients::default();
+        let client = clients.register("default");
+        assert_eq!(execute(&ClientCmd::GETNAME, &clients, &client).unwrap(), Frame::Null);
+        assert!(execute(&ClientCmd::SETNAME("two words".to_string()), &clients, &client).is_err());
+        execute(&ClientCmd::SETNAME("worker".to_string()), &clients, &client).unwrap();
+        assert_eq!(execute(&ClientCmd::GETNAME, &clients, &client).unwrap(), Frame::Bulk(Bytes::from("worker")));
+
+        execute(&ClientCmd::NOEVICT(true), &clients, &client).unwrap();
+        client.record("multi".to_string());
+        client.finish(Some(0), 1);
+        assert!(client.info().contains(" flags=xe "));
+        assert!(client.info().contains(" multi=0 watch=1 "));
+    }
+}
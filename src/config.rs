@@ -0,0 +1,694 @@
+use crate::acl;
+use crate::aof::Fsync;
+use crate::busy;
+use crate::db::Db;
+use crate::frame::Frame;
+use crate::glob;
+use crate::log;
+use crate::persistence::{self, Settings};
+use crate::shutdown;
+use crate::slowlog;
+use crate::tls::{self, AuthClients};
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+//Version reported in config errors and INFO.
+pub const REDIS_VERSION: &str = "7.0.0";
+
+//Parameters known to CONFIG GET and CONFIG REWRITE, in the order they are
+//listed and appended to the file.
+const PARAMS: &[&str] = &[
+    "bind", "port", "unixsocket", "unixsocketperm", "databases", "maxclients", "timeout", "tcp-keepalive", "loglevel", "logfile",
+    "busy-reply-threshold", "replicaof", "dir", "dbfilename", "save", "appendonly", "appendfilename",
+    "appendfsync", "shutdown-timeout", "tls-port", "tls-cert-file", "tls-key-file", "tls-ca-cert-file",
+    "tls-auth-clients", "requirepass", "masteruser", "masterauth", "aclfile", "acllog-max-len",
+    "slowlog-log-slower-than", "slowlog-max-len", "latency-monitor-threshold", "metrics-port",
+];
+
+//Older names still accepted for some parameters.
+const ALIASES: &[(&str, &str)] = &[("lua-time-limit", "busy-reply-threshold"), ("slaveof", "replicaof")];
+
+//Parameters that only take effect at startup. The replication target is
+//changed with REPLICAOF instead.
+const IMMUTABLE: &[&str] = &[
+    "bind", "port", "unixsocket", "unixsocketperm", "databases", "replicaof", "tls-port", "tls-cert-file",
+    "tls-key-file", "tls-ca-cert-file", "tls-auth-clients", "aclfile", "logfile", "metrics-port",
+];
+
+const REWRITE_MARKER: &str = "# Generated by CONFIG REWRITE";
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ConfigCmd {
+    GET(Vec<String>),
+    SET(Vec<(String, String)>),
+    RESETSTAT,
+    REWRITE,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum LogLevel {
+    DEBUG,
+    VERBOSE,
+    NOTICE,
+    WARNING,
+    NOTHING,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Result<LogLevel, String> {
+        match value.to_lowercase().as_str() {
+            "debug" => Ok(LogLevel::DEBUG),
+            "verbose" => Ok(LogLevel::VERBOSE),
+            "notice" => Ok(LogLevel::NOTICE),
+            "warning" => Ok(LogLevel::WARNING),
+            "nothing" => Ok(LogLevel::NOTHING),
+            _ => Err("argument(s) must be one of the following: debug, verbose, notice, warning, nothing".to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::DEBUG => "debug",
+            LogLevel::VERBOSE => "verbose",
+            LogLevel::NOTICE => "notice",
+            LogLevel::WARNING => "warning",
+            LogLevel::NOTHING => "nothing",
+        }
+    }
+}
+
+//Server configuration, read from a redis.conf style file and the command
+//line. Persistence settings are kept in the form `Persistence` takes them.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Config {
+    pub bind: Vec<String>,
+    //0 to not listen on TCP.
+    pub port: u16,
+    pub unixsocket: Option<PathBuf>,
+    //Octal permissions of the socket file, 0 to leave them to the umask.
+    pub unixsocketperm: u32,
+    pub databases: u64,
+    pub maxclients: u64,
+    //Seconds of client inactivity before the connection is closed, 0 for never.
+    pub timeout: u64,
+    pub tcp_keepalive: u64,
+    pub loglevel: LogLevel,
+    //Empty for standard output.
+    pub logfile: String,
+    pub busy_reply_threshold: u64,
+    pub replicaof: Option<(String, u16)>,
+    //Password of the default user, empty for none.
+    pub requirepass: String,
+    //User and password a replica authenticates with to its master. Without a
+    //user, the legacy single-argument AUTH is sent.
+    pub masteruser: String,
+    pub masterauth: String,
+    //File ACL LOAD and ACL SAVE use, read at startup.
+    pub aclfile: Option<PathBuf>,
+    pub acllog_max_len: u64,
+    //Microseconds, negative to turn the slow log off.
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: u64,
+    //Milliseconds, 0 to turn the latency monitor off.
+    pub latency_monitor_threshold: u64,
+    //Port of the Prometheus /metrics listener, 0 for none.
+    pub metrics_port: u16,
+    //Seconds SHUTDOWN waits for lagging replicas.
+    pub shutdown_timeout: u64,
+    pub persistence: Settings,
+    pub tls: tls::Settings,
+    //The file the configuration was read from, if any.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: vec!["127.0.0.1".to_string()],
+            port: 6379,
+            unixsocket: None,
+            unixsocketperm: 0,
+            databases: 16,
+            maxclients: 10000,
+            timeout: 0,
+            tcp_keepalive: 300,
+            loglevel: LogLevel::NOTICE,
+            logfile: String::new(),
+            busy_reply_threshold: busy::DEFAULT_THRESHOLD_MS,
+            replicaof: None,
+            requirepass: String::new(),
+            masteruser: String::new(),
+            masterauth: String::new(),
+            aclfile: None,
+            acllog_max_len: acl::DEFAULT_LOG_MAX_LEN,
+            slowlog_log_slower_than: slowlog::DEFAULT_THRESHOLD_US,
+            slowlog_max_len: slowlog::DEFAULT_MAX_LEN,
+            latency_monitor_threshold: 0,
+            metrics_port: 0,
+            shutdown_timeout: shutdown::DEFAULT_TIMEOUT,
+            persistence: Settings::default(),
+            tls: tls::Settings::default(),
+            path: None,
+        }
+    }
+}
+
+impl Config {
+    //Parses `redis-server [/path/to/redis.conf] [--directive value ...]`.
+    //As with Redis, command-line options are applied after the file, so they
+    //override it.
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut options = args;
+        if let Some(first) = args.first() {
+            if !first.starts_with("--") {
+                let path = PathBuf::from(first);
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| format!("Fatal error, can't open config file '{}': {}", first, e))?;
+                config.load_str(&text)?;
+                config.path = Some(path);
+                options = &args[1..];
+            }
+        }
+
+        let mut text = String::new();
+        for arg in options {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    if !text.is_empty() {
+                        text.push('\n');
+                    }
+                    text.push_str(name);
+                },
+                None => {
+                    text.push(' ');
+                    text.push_str(&quote(arg));
+                },
+            }
+        }
+        config.load_str(&text)?;
+        Ok(config)
+    }
+
+    //Applies every directive of a configuration text, reporting the first
+    //bad one with its line the way Redis does.
+    pub fn load_str(&mut self, text: &str) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = split_line(line).and_then(|args| {
+                if args[0].eq_ignore_ascii_case("include") && args.len() == 2 {
+                    let text = fs::read_to_string(&args[1])
+                        .map_err(|e| format!("Can't open included file '{}': {}", args[1], e))?;
+                    self.load_str(&text)
+                } else {
+                    self.set(&args[0].to_lowercase(), &args[1..])
+                }
+            });
+            if let Err(e) = result {
+                return Err(format!(
+                    "\n*** FATAL CONFIG FILE ERROR (Redis {}) ***\nReading the configuration file, at line {}\n>>> '{}'\n{}",
+                    REDIS_VERSION, i + 1, line, e,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    //Sets one directive from its arguments.
+    pub fn set(&mut self, name: &str, args: &[String]) -> Result<(), String> {
+        match (name, args) {
+            ("bind", [_, ..]) => self.bind = args.to_vec(),
+            ("port", [port]) => self.port = parse_number(port)?,
+            ("unixsocket", [path]) if path.is_empty() => self.unixsocket = None,
+            ("unixsocket", [path]) => self.unixsocket = Some(PathBuf::from(path)),
+            ("unixsocketperm", [perm]) => {
+                self.unixsocketperm = u32::from_str_radix(perm, 8)
+                    .ok()
+                    .filter(|perm| *perm <= 0o777)
+                    .ok_or("Invalid socket file permissions")?;
+            },
+            ("databases", [n]) => {
+                self.databases = parse_number(n)?;
+                if self.databases < 1 {
+                    return Err("Invalid number of databases".to_string());
+                }
+            },
+            ("maxclients", [n]) => {
+                self.maxclients = parse_number(n)?;
+                if self.maxclients < 1 {
+                    return Err("Invalid max clients limit".to_string());
+                }
+            },
+            ("timeout", [n]) => self.timeout = parse_number(n)?,
+            ("tcp-keepalive", [n]) => self.tcp_keepalive = parse_number(n)?,
+            ("loglevel", [level]) => self.loglevel = LogLevel::parse(level)?,
+            ("logfile", [file]) => self.logfile = file.clone(),
+            ("busy-reply-threshold", [ms]) | ("lua-time-limit", [ms]) => self.busy_reply_threshold = parse_number(ms)?,
+            ("replicaof", [host, port]) | ("slaveof", [host, port]) => {
+                self.replicaof = Some((host.clone(), parse_number(port)?));
+            },
+            ("requirepass", [password]) => self.requirepass = password.clone(),
+            ("masteruser", [user]) => self.masteruser = user.clone(),
+            ("masterauth", [password]) => self.masterauth = password.clone(),
+            ("aclfile", [file]) => self.aclfile = optional_path(file),
+            ("acllog-max-len", [n]) => self.acllog_max_len = parse_number(n)?,
+            ("slowlog-log-slower-than", [us]) => self.slowlog_log_slower_than = parse_number(us)?,
+            ("slowlog-max-len", [n]) => self.slowlog_max_len = parse_number(n)?,
+            ("latency-monitor-threshold", [ms]) => self.latency_monitor_threshold = parse_number(ms)?,
+            ("metrics-port", [port]) => self.metrics_port = parse_number(port)?,
+            ("shutdown-timeout", [n]) => self.shutdown_timeout = parse_number(n)?,
+            ("dir", [dir]) => {
+                let dir = PathBuf::from(dir);
+                if !dir.is_dir() {
+                    return Err(format!("Can't chdir to '{}': No such file or directory", dir.display()));
+                }
+                self.persistence.dir = dir;
+            },
+            ("dbfilename", [file]) => {
+                if file.contains('/') {
+                    return Err("dbfilename can't be a path, just a filename".to_string());
+                }
+                self.persistence.dbfilename = file.clone();
+            },
+            ("save", [rule]) if rule.is_empty() => self.persistence.save_rules.clear(),
+            ("save", [_, _, ..]) if args.len().is_multiple_of(2) => self.persistence.save_rules = parse_save_rules(args)?,
+            ("appendonly", [value]) => self.persistence.appendonly = parse_bool(value)?,
+            ("appendfilename", [file]) => {
+                if file.contains('/') {
+                    return Err("appendfilename can't be a path, just a filename".to_string());
+                }
+                self.persistence.appendfilename = file.clone();
+            },
+            ("appendfsync", [policy]) => self.persistence.appendfsync = Fsync::parse(policy)?,
+            ("tls-port", [port]) => self.tls.port = parse_number(port)?,
+            ("tls-cert-file", [file]) => self.tls.cert_file = optional_path(file),
+            ("tls-key-file", [file]) => self.tls.key_file = optional_path(file),
+            ("tls-ca-cert-file", [file]) => self.tls.ca_cert_file = optional_path(file),
+            ("tls-auth-clients", [value]) => self.tls.auth_clients = AuthClients::parse(value)?,
+            _ => return Err("Bad directive or wrong number of arguments".to_string()),
+        }
+        Ok(())
+    }
+
+    //The arguments a directive would be written with. An empty list means
+    //the directive is unset and left out of the file.
+    pub fn args(&self, name: &str) -> Option<Vec<String>> {
+        let settings = &self.persistence;
+        let args = match canonical(name) {
+            "bind" => self.bind.clone(),
+            "port" => vec![self.port.to_string()],
+            "unixsocket" => self.unixsocket.iter().map(|path| path.display().to_string()).collect(),
+            "unixsocketperm" => vec![format!("{:o}", self.unixsocketperm)],
+            "databases" => vec![self.databases.to_string()],
+            "maxclients" => vec![self.maxclients.to_string()],
+            "timeout" => vec![self.timeout.to_string()],
+            "tcp-keepalive" => vec![self.tcp_keepalive.to_string()],
+            "loglevel" => vec![self.loglevel.name().to_string()],
+            "logfile" => vec![self.logfile.clone()],
+            "busy-reply-threshold" => vec![self.busy_reply_threshold.to_string()],
+            "replicaof" => match &self.replicaof {
+                Some((host, port)) => vec![host.clone(), port.to_string()],
+                None => vec![],
+            },
+            "requirepass" => vec![self.requirepass.clone()],
+            "masteruser" => vec![self.masteruser.clone()],
+            "masterauth" => vec![self.masterauth.clone()],
+            "aclfile" => vec![path_arg(&self.aclfile)],
+            "acllog-max-len" => vec![self.acllog_max_len.to_string()],
+            "slowlog-log-slower-than" => vec![self.slowlog_log_slower_than.to_string()],
+            "slowlog-max-len" => vec![self.slowlog_max_len.to_string()],
+            "latency-monitor-threshold" => vec![self.latency_monitor_threshold.to_string()],
+            "metrics-port" => vec![self.metrics_port.to_string()],
+            "shutdown-timeout" => vec![self.shutdown_timeout.to_string()],
+            "dir" => vec![settings.dir.display().to_string()],
+            "dbfilename" => vec![settings.dbfilename.clone()],
+            "save" if settings.save_rules.is_empty() => vec![String::new()],
+            "save" => settings.save_rules.iter()
+                .flat_map(|(seconds, changes)| [seconds.to_string(), changes.to_string()])
+                .collect(),
+            "appendonly" => vec![if settings.appendonly { "yes" } else { "no" }.to_string()],
+            "appendfilename" => vec![settings.appendfilename.clone()],
+            "appendfsync" => vec![settings.appendfsync.name().to_string()],
+            "tls-port" => vec![self.tls.port.to_string()],
+            "tls-cert-file" => vec![path_arg(&self.tls.cert_file)],
+            "tls-key-file" => vec![path_arg(&self.tls.key_file)],
+            "tls-ca-cert-file" => vec![path_arg(&self.tls.ca_cert_file)],
+            "tls-auth-clients" => vec![self.tls.auth_clients.name().to_string()],
+            _ => return None,
+        };
+        Some(args)
+    }
+
+    //CONFIG GET: every parameter matching one of the glob patterns, once.
+    pub fn matching(&self, patterns: &[String]) -> Vec<(String, String)> {
+        let mut names: Vec<&str> = Vec::new();
+        for pattern in patterns {
+            let pattern = pattern.to_lowercase();
+            for name in PARAMS {
+                if glob::matches(&pattern, name) && !names.contains(name) {
+                    names.push(name);
+                }
+            }
+            if let Some((alias, _)) = ALIASES.iter().find(|(alias, _)| *alias == pattern) {
+                if !names.contains(alias) {
+                    names.push(alias);
+                }
+            }
+        }
+        names.into_iter()
+            .filter_map(|name| Some((name.to_string(), self.args(name)?.join(" "))))
+            .collect()
+    }
+
+    //CONFIG SET: applies every change to a copy, so that a single bad value
+    //leaves the configuration untouched.
+    pub fn updated(&self, changes: &[(String, String)]) -> Result<Config, String> {
+        let mut config = self.clone();
+        let mut seen = HashSet::new();
+        for (name, value) in changes {
+            let fail = |reason: &str| format!("CONFIG SET failed (possibly related to argument '{}') - {}", name, reason);
+            let param = canonical(name);
+            if !PARAMS.contains(&param) {
+                return Err(fail("Unknown option or number of arguments for CONFIG SET"));
+            }
+            if IMMUTABLE.contains(&param) {
+                return Err(fail("can't set immutable config"));
+            }
+            if !seen.insert(param) {
+                return Err(fail("duplicate parameter"));
+            }
+            let args: Vec<String> = match param {
+                "save" | "bind" if !value.trim().is_empty() => value.split_whitespace().map(|a| a.to_string()).collect(),
+                _ => vec![value.clone()],
+            };
+            config.set(param, &args).map_err(|e| fail(&e))?;
+        }
+        Ok(config)
+    }
+
+    //CONFIG REWRITE: updates the directives already in the file in place,
+    //keeping comments and unknown lines, drops their duplicates and appends
+    //the non-default parameters the file did not mention.
+    pub fn rewrite(&self) -> Result<(), String> {
+        let path = self.path.as_ref().ok_or("The server is running without a config file")?;
+        let old = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Rewriting config file: {}", e)),
+        };
+
+        let mut written = HashSet::new();
+        let mut lines = Vec::new();
+        for line in old.lines() {
+            let param = match split_line(line.trim()) {
+                Ok(args) if !line.trim().starts_with('#') => canonical(&args[0].to_lowercase()).to_string(),
+                _ => {
+                    lines.push(line.to_string());
+                    continue;
+                },
+            };
+            if !PARAMS.contains(&param.as_str()) {
+                lines.push(line.to_string());
+            } else if written.insert(param.clone()) {
+                lines.extend(self.line(&param));
+            }
+        }
+
+        let default = Config::default();
+        let added: Vec<String> = PARAMS.iter()
+            .filter(|name| !written.contains(**name) && self.args(name) != default.args(name))
+            .filter_map(|name| self.line(name))
+            .collect();
+        if !added.is_empty() {
+            if !lines.iter().any(|line| line == REWRITE_MARKER) {
+                lines.push(REWRITE_MARKER.to_string());
+            }
+            lines.extend(added);
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        fs::write(path, text).map_err(|e| format!("Rewriting config file: {}", e))
+    }
+
+    fn line(&self, name: &str) -> Option<String> {
+        let args = self.args(name)?;
+        if args.is_empty() {
+            return None;
+        }
+        let args: Vec<String> = args.iter().map(|arg| quote(arg)).collect();
+        Some(format!("{} {}", name, args.join(" ")))
+    }
+}
+
+pub fn execute(cmd: &ConfigCmd, db: &Db) -> Result<Frame, String> {
+    let registry = db.config();
+    match cmd {
+        ConfigCmd::GET(patterns) => {
+            let mut output = Frame::array();
+            for (name, value) in registry.lock().unwrap().matching(patterns) {
+                output.push_bulk(Bytes::from(name));
+                output.push_bulk(Bytes::from(value));
+            }
+            Ok(output)
+        },
+        ConfigCmd::SET(changes) => {
+            let old = registry.lock().unwrap().clone();
+            let new = old.updated(changes)?;
+            apply(db, &old, &new)?;
+            *registry.lock().unwrap() = new;
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        ConfigCmd::RESETSTAT => {
+            db.stats().reset();
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        ConfigCmd::REWRITE => {
+            registry.lock().unwrap().rewrite()?;
+            Ok(Frame::Simple("OK".to_string()))
+        },
+    }
+}
+
+//HELPER FN
+
+//Pushes a new configuration to the subsystems that keep their own copy.
+//Only turning the AOF on can fail, in which case the old settings are put back.
+fn apply(db: &Db, old: &Config, new: &Config) -> Result<(), String> {
+    let persistence = db.persistence();
+    persistence.set_settings(Settings { appendonly: old.persistence.appendonly, ..new.persistence.clone() });
+    if new.persistence.appendonly != old.persistence.appendonly {
+        if let Err(e) = persistence::set_appendonly(db, new.persistence.appendonly) {
+            persistence.set_settings(old.persistence.clone());
+            return Err(e);
+        }
+    }
+    db.busy().set_threshold_ms(new.busy_reply_threshold);
+    log::set_level(new.loglevel);
+    if new.requirepass != old.requirepass {
+        db.acl().set_default_password(&new.requirepass);
+    }
+    db.acl().set_log_max_len(new.acllog_max_len);
+    db.slowlog().set_config(new.slowlog_log_slower_than, new.slowlog_max_len);
+    db.latency().set_threshold(new.latency_monitor_threshold);
+    Ok(())
+}
+
+fn optional_path(value: &str) -> Option<PathBuf> {
+    Some(PathBuf::from(value)).filter(|_| !value.is_empty())
+}
+
+fn path_arg(path: &Option<PathBuf>) -> String {
+    path.as_ref().map(|path| path.display().to_string()).unwrap_or_default()
+}
+
+fn canonical(name: &str) -> &str {
+    match ALIASES.iter().find(|(alias, _)| *alias == name) {
+        Some((_, param)) => param,
+        None => name,
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse::<T>().map_err(|_| format!("argument couldn't be parsed into an integer: '{}'", value))
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+//`save 3600 1 300 100` is a list of <seconds> <changes> pairs.
+fn parse_save_rules(args: &[String]) -> Result<Vec<(u64, u64)>, String> {
+    args.chunks(2)
+        .map(|pair| Ok((parse_number(&pair[0])?, parse_number(&pair[1])?)))
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(|_| "Invalid save parameters".to_string())
+}
+
+//Splits a config line into arguments, honouring double and single quotes
+//and the usual escapes inside double quotes.
+fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let quote = match chars.peek() {
+            None => break,
+            Some('"') | Some('\'') => chars.next(),
+            Some(_) => None,
+        };
+        let mut arg = String::new();
+        loop {
+            match (chars.next(), quote) {
+                (None, Some(_)) => return Err("Unbalanced quotes in configuration line".to_string()),
+                (None, None) => break,
+                (Some(c), Some(q)) if c == q => {
+                    if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                        return Err("Unbalanced quotes in configuration line".to_string());
+                    }
+                    break;
+                },
+                (Some('\\'), Some('"')) => match chars.next() {
+                    Some('n') => arg.push('\n'),
+                    Some('r') => arg.push('\r'),
+                    Some('t') => arg.push('\t'),
+                    Some(c) => arg.push(c),
+                    None => return Err("Unbalanced quotes in configuration line".to_string()),
+                },
+                (Some(c), None) if c.is_whitespace() => break,
+                (Some(c), _) => arg.push(c),
+            }
+        }
+        args.push(arg);
+    }
+    if args.is_empty() {
+        return Err("Bad directive or wrong number of arguments".to_string());
+    }
+    Ok(args)
+}
+
+//Command-line values are turned back into config text, so those with spaces
+//or quotes must be quoted.
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\') {
+        arg.to_string()
+    } else {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn file_then_command_line() {
+        let path = std::env::temp_dir().join(format!("my-redis-config-{}.conf", std::process::id()));
+        fs::write(&path, "# comment\nport 7000\nbind 0.0.0.0 ::1\nsave 900 1 300 10\n\nappendonly yes\nlogfile \"/tmp/my redis.log\"\n").unwrap();
+
+        let config = Config::from_args(&args(&[path.to_str().unwrap(), "--port", "7001", "--save", ""])).unwrap();
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.bind, vec!["0.0.0.0".to_string(), "::1".to_string()]);
+        assert!(config.persistence.save_rules.is_empty());
+        assert!(config.persistence.appendonly);
+        assert_eq!(config.logfile, "/tmp/my redis.log");
+        assert_eq!(config.path, Some(path.clone()));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn save_rules_and_quotes() {
+        let mut config = Config::default();
+        config.load_str("save 900 1\ndbfilename 'my dump.rdb'\nloglevel warning").unwrap();
+        assert_eq!(config.persistence.save_rules, vec![(900, 1)]);
+        assert_eq!(config.persistence.dbfilename, "my dump.rdb");
+        assert_eq!(config.loglevel, LogLevel::WARNING);
+
+        config.load_str("unixsocket /tmp/redis.sock\nunixsocketperm 700").unwrap();
+        assert_eq!(config.unixsocket, Some(PathBuf::from("/tmp/redis.sock")));
+        assert_eq!(config.unixsocketperm, 0o700);
+        assert_eq!(config.args("unixsocketperm"), Some(vec!["700".to_string()]));
+        assert!(config.load_str("unixsocketperm 800").is_err());
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let mut config = Config::default();
+        let err = config.load_str("port 6379\nfoo bar").unwrap_err();
+        assert!(err.contains("at line 2"));
+        assert!(err.contains(">>> 'foo bar'"));
+        assert!(err.contains("Bad directive"));
+
+        assert!(config.load_str("port notanumber").unwrap_err().contains("integer"));
+        assert!(config.load_str("appendonly maybe").unwrap_err().contains("'yes' or 'no'"));
+        assert!(config.load_str("port 1 2").is_err());
+        assert!(config.load_str("logfile \"unterminated").is_err());
+        assert!(Config::from_args(&args(&["--nosuchoption", "1"])).is_err());
+    }
+
+    #[test]
+    fn config_set_is_all_or_nothing() {
+        let config = Config::default();
+        let changes = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+            list.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+        };
+
+        let updated = config.updated(&changes(&[("timeout", "30"), ("save", "60 5 10 100"), ("lua-time-limit", "100")])).unwrap();
+        assert_eq!(updated.timeout, 30);
+        assert_eq!(updated.persistence.save_rules, vec![(60, 5), (10, 100)]);
+        assert_eq!(updated.busy_reply_threshold, 100);
+
+        let err = config.updated(&changes(&[("timeout", "30"), ("appendonly", "maybe")])).unwrap_err();
+        assert!(err.contains("'appendonly'"));
+        assert!(config.updated(&changes(&[("port", "1")])).unwrap_err().contains("immutable"));
+        assert!(config.updated(&changes(&[("timeout", "1"), ("timeout", "2")])).unwrap_err().contains("duplicate"));
+        assert!(config.updated(&changes(&[("nosuchparam", "1")])).is_err());
+
+        let got = updated.matching(&["*out".to_string(), "save".to_string(), "timeout".to_string()]);
+        assert_eq!(got, vec![
+            ("timeout".to_string(), "30".to_string()),
+            ("shutdown-timeout".to_string(), "10".to_string()),
+            ("save".to_string(), "60 5 10 100".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn rewrite_keeps_comments() {
+        let path = std::env::temp_dir().join(format!("my-redis-rewrite-{}.conf", std::process::id()));
+        fs::write(&path, "# my settings\nport 7000\n\n# persistence\nsave 900 1\nsave 300 10\n# end\n").unwrap();
+
+        let mut config = Config::from_args(&args(&[path.to_str().unwrap()])).unwrap();
+        config.persistence.save_rules = vec![(60, 1)];
+        config.timeout = 5;
+        config.logfile = "my log.txt".to_string();
+        config.rewrite().unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text, "# my settings\nport 7000\n\n# persistence\nsave 60 1\n# end\n# Generated by CONFIG REWRITE\ntimeout 5\nlogfile \"my log.txt\"\n");
+
+        //Rewriting an unchanged configuration is a no-op.
+        Config::from_args(&args(&[path.to_str().unwrap()])).unwrap().rewrite().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+
+        fs::remove_file(path).unwrap();
+        assert!(Config::default().rewrite().is_err());
+    }
+}
@@ -0,0 +1,568 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use crate::acl::Acl;
+use crate::busy::Busy;
+use crate::client::Clients;
+use crate::config::Config;
+use crate::latency::Latency;
+use crate::monitor::Monitors;
+use crate::persistence::Persistence;
+use crate::replication::Replication;
+use crate::shutdown::Shutdown;
+use crate::slowlog::Slowlog;
+use crate::stats::Stats;
+use crate::rdb::Snapshot;
+use crate::glob;
+use crate::function::{FunctionInfo, Library};
+use crate::script::sha1_hex;
+
+//A stored value. Only strings can be written by commands for now; the
+//collection types come from RDB files imported from Redis.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Value {
+    String(String),
+    List(Vec<String>),
+    Set(HashSet<String>),
+    Hash(HashMap<String, String>),
+    //Sorted by score, then member.
+    ZSet(Vec<(String, f64)>),
+}
+
+//The server state that lives next to the keyspace. It is taken out of the
+//database once, so that connections, the cron and the shutdown can use it
+//without waiting on the database lock while a script runs.
+#[derive(Debug, Clone)]
+pub struct Shared {
+    pub busy: Arc<Busy>,
+    pub persistence: Arc<Persistence>,
+    pub replication: Arc<Replication>,
+    pub config: Arc<Mutex<Config>>,
+    pub shutdown: Arc<Shutdown>,
+    pub acl: Arc<Acl>,
+    pub clients: Arc<Clients>,
+    pub stats: Arc<Stats>,
+    pub slowlog: Arc<Slowlog>,
+    pub monitors: Arc<Monitors>,
+    pub latency: Arc<Latency>,
+}
+
+//Keyspace shared by every connection. Each write stamps the key with a
+//new version so that WATCH can tell whether a key changed since it was watched.
+#[derive(Debug, Default)]
+pub struct Db {
+    entries: HashMap<String, Value>,
+    //Absolute expiry times in unix milliseconds.
+    expires: HashMap<String, u64>,
+    versions: HashMap<String, u64>,
+    counter: u64,
+    scripts: HashMap<String, String>,
+    libraries: BTreeMap<String, Library>,
+    busy: Arc<Busy>,
+    persistence: Arc<Persistence>,
+    replication: Arc<Replication>,
+    config: Arc<Mutex<Config>>,
+    shutdown: Arc<Shutdown>,
+    acl: Arc<Acl>,
+    clients: Arc<Clients>,
+    stats: Arc<Stats>,
+    slowlog: Arc<Slowlog>,
+    monitors: Arc<Monitors>,
+    latency: Arc<Latency>,
+    //Writes held back while a transaction or script runs, so they are
+    //propagated together.
+    propagation: Option<Vec<Vec<u8>>>,
+}
+
+//Flushing more keys than this frees the old entries on a separate thread,
+//like Redis' lazyfree, so the lock is not held while deallocating.
+const LAZY_FREE_THRESHOLD: usize = 64;
+
+//How many keys a scan visits between checks for SCRIPT KILL.
+const CHECK_INTERVAL: usize = 1000;
+
+impl Db {
+    pub fn new() -> Db {
+        Db::default()
+    }
+
+    //The string stored at `key`, if there is one.
+    pub fn get(&self, key: &str) -> Option<&String> {
+        match self.value(key) {
+            Some(Value::String(val)) => Some(val),
+            _ => None,
+        }
+    }
+
+    //Expired keys read as missing until `purge_expired` removes them.
+    pub fn value(&self, key: &str) -> Option<&Value> {
+        if self.is_expired(key) {
+            None
+        } else {
+            self.entries.get(key)
+        }
+    }
+
+    //Like SET, this discards any expiry the key had.
+    pub fn set(&mut self, key: String, val: String) {
+        self.touch(&key);
+        self.expires.remove(&key);
+        self.entries.insert(key, Value::String(val));
+    }
+
+    pub fn expire_at(&self, key: &str) -> Option<u64> {
+        self.expires.get(key).copied()
+    }
+
+    //Sets the absolute expiry time of `key`, if it exists.
+    pub fn set_expire_at(&mut self, key: &str, at: u64) -> bool {
+        self.expire_if_needed(key);
+        if !self.entries.contains_key(key) {
+            return false;
+        }
+        self.touch(key);
+        self.expires.insert(key.to_string(), at);
+        true
+    }
+
+    //The collection writes below create the key when it is missing and
+    //return None when it holds another type.
+
+    //Appends to a list, returning its new length.
+    pub fn rpush(&mut self, key: &str, items: &[String]) -> Option<usize> {
+        self.expire_if_needed(key);
+        let len = match self.entries.entry(key.to_string()).or_insert_with(|| Value::List(Vec::new())) {
+            Value::List(list) => {
+                list.extend_from_slice(items);
+                list.len()
+            },
+            _ => return None,
+        };
+        self.touch(key);
+        Some(len)
+    }
+
+    //Returns how many of the members were not in the set yet.
+    pub fn sadd(&mut self, key: &str, members: &[String]) -> Option<usize> {
+        self.expire_if_needed(key);
+        let added = match self.entries.entry(key.to_string()).or_insert_with(|| Value::Set(HashSet::new())) {
+            Value::Set(set) => members.iter().filter(|m| set.insert((*m).clone())).count(),
+            _ => return None,
+        };
+        self.touch(key);
+        Some(added)
+    }
+
+    //Returns how many of the fields are new.
+    pub fn hset(&mut self, key: &str, pairs: &[(String, String)]) -> Option<usize> {
+        self.expire_if_needed(key);
+        let added = match self.entries.entry(key.to_string()).or_insert_with(|| Value::Hash(HashMap::new())) {
+            Value::Hash(hash) => pairs.iter().filter(|(f, v)| hash.insert(f.clone(), v.clone()).is_none()).count(),
+            _ => return None,
+        };
+        self.touch(key);
+        Some(added)
+    }
+
+    //Keeps the members sorted by score, then member. Returns how many of
+    //them are new; the others get their score updated.
+    pub fn zadd(&mut self, key: &str, members: &[(f64, String)]) -> Option<usize> {
+        self.expire_if_needed(key);
+        let added = match self.entries.entry(key.to_string()).or_insert_with(|| Value::ZSet(Vec::new())) {
+            Value::ZSet(zset) => {
+                let mut added = 0;
+                for (score, member) in members {
+                    match zset.iter().position(|(m, _)| m == member) {
+                        Some(i) => {
+                            zset.remove(i);
+                        },
+                        None => added += 1,
+                    }
+                    let at = zset.partition_point(|(m, s)| (*s, m.as_str()) < (*score, member.as_str()));
+                    zset.insert(at, (member.clone(), *score));
+                }
+                added
+            },
+            _ => return None,
+        };
+        self.touch(key);
+        Some(added)
+    }
+
+    //Removes `key` if it has expired, like Redis' lazy expiry on access, so
+    //that WATCH sees the change without waiting for the cron.
+    pub fn expire_if_needed(&mut self, key: &str) -> bool {
+        if !self.is_expired(key) {
+            return false;
+        }
+        self.touch(key);
+        self.expires.remove(key);
+        self.entries.remove(key);
+        self.stats.expired(1);
+        true
+    }
+
+    //Removes the keys whose expiry time has passed. Called by the server cron.
+    pub fn purge_expired(&mut self) -> usize {
+        let start = Instant::now();
+        let now = now_ms();
+        let expired: Vec<String> = self.expires.iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.touch(key);
+            self.expires.remove(key);
+            self.entries.remove(key);
+        }
+        self.stats.expired(expired.len());
+        self.latency.add_sample_if_needed("expire-cycle", start.elapsed());
+        expired.len()
+    }
+
+    pub fn flush(&mut self) {
+        self.expires.clear();
+        let old = std::mem::take(&mut self.entries);
+        for key in old.keys() {
+            self.touch(key);
+        }
+        if old.len() > LAZY_FREE_THRESHOLD {
+            thread::spawn(move || drop(old));
+        }
+    }
+
+    //Lists matching keys, giving up if the scan is interrupted.
+    pub fn keys(&self, pattern: &str) -> Result<Vec<String>, String> {
+        let mut keys = Vec::new();
+        for (i, key) in self.entries.keys().enumerate() {
+            if i % CHECK_INTERVAL == 0 {
+                self.busy.check()?;
+            }
+            if glob::matches(pattern, key) && !self.is_expired(key) {
+                keys.push(key.clone());
+            }
+        }
+        Ok(keys)
+    }
+
+    pub fn busy(&self) -> Arc<Busy> {
+        self.busy.clone()
+    }
+
+    pub fn persistence(&self) -> Arc<Persistence> {
+        self.persistence.clone()
+    }
+
+    //The writes buffered for propagation, when a transaction or script is
+    //running.
+    pub fn propagation(&mut self) -> &mut Option<Vec<Vec<u8>>> {
+        &mut self.propagation
+    }
+
+    pub fn replication(&self) -> Arc<Replication> {
+        self.replication.clone()
+    }
+
+    //The runtime configuration, as changed by CONFIG SET.
+    pub fn config(&self) -> Arc<Mutex<Config>> {
+        self.config.clone()
+    }
+
+    pub fn shutdown(&self) -> Arc<Shutdown> {
+        self.shutdown.clone()
+    }
+
+    pub fn acl(&self) -> Arc<Acl> {
+        self.acl.clone()
+    }
+
+    //The open connections, for CLIENT LIST and CLIENT KILL.
+    pub fn clients(&self) -> Arc<Clients> {
+        self.clients.clone()
+    }
+
+    //The counters behind INFO.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    pub fn slowlog(&self) -> Arc<Slowlog> {
+        self.slowlog.clone()
+    }
+
+    //The connections that ran MONITOR.
+    pub fn monitors(&self) -> Arc<Monitors> {
+        self.monitors.clone()
+    }
+
+    //Events that took longer than latency-monitor-threshold.
+    pub fn latency(&self) -> Arc<Latency> {
+        self.latency.clone()
+    }
+
+    pub fn shared(&self) -> Shared {
+        Shared {
+            busy: self.busy(),
+            persistence: self.persistence(),
+            replication: self.replication(),
+            config: self.config(),
+            shutdown: self.shutdown(),
+            acl: self.acl(),
+            clients: self.clients(),
+            stats: self.stats(),
+            slowlog: self.slowlog(),
+            monitors: self.monitors(),
+            latency: self.latency(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            entries: self.entries.iter()
+                .filter(|(k, _)| !self.is_expired(k))
+                .map(|(k, v)| (k.clone(), v.clone(), self.expire_at(k)))
+                .collect(),
+            libraries: self.libraries.values().map(|l| l.code.clone()).collect(),
+        }
+    }
+
+    //Replaces the dataset with a loaded snapshot. Loading does not count as
+    //a change for the save rules.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), String> {
+        let mut libraries = BTreeMap::new();
+        for code in snapshot.libraries {
+            let library = Library::load(&code, &self.busy)?;
+            libraries.insert(library.name.clone(), library);
+        }
+        self.flush();
+        self.libraries = libraries;
+        for (key, val, expire) in snapshot.entries {
+            self.bump(&key);
+            if let Some(at) = expire {
+                self.expires.insert(key.clone(), at);
+            }
+            self.entries.insert(key, val);
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    //Number of keys with an expiry time.
+    pub fn expires_len(&self) -> usize {
+        self.expires.len()
+    }
+
+    //Average time to live of the keys with an expiry, in milliseconds.
+    pub fn avg_ttl(&self) -> u64 {
+        if self.expires.is_empty() {
+            return 0;
+        }
+        let now = now_ms();
+        let total: u64 = self.expires.values().map(|at| at.saturating_sub(now)).sum();
+        total / self.expires.len() as u64
+    }
+
+    //Rough size of the dataset in bytes: keys and values plus a fixed
+    //overhead per entry.
+    pub fn used_memory(&self) -> usize {
+        const OVERHEAD: usize = 64;
+        self.entries.iter().map(|(key, val)| {
+            let size = match val {
+                Value::String(s) => s.len(),
+                Value::List(items) => items.iter().map(|i| i.len() + OVERHEAD).sum(),
+                Value::Set(members) => members.iter().map(|m| m.len() + OVERHEAD).sum(),
+                Value::Hash(fields) => fields.iter().map(|(f, v)| f.len() + v.len() + OVERHEAD).sum(),
+                Value::ZSet(members) => members.iter().map(|(m, _)| m.len() + 8 + OVERHEAD).sum(),
+            };
+            key.len() + size + OVERHEAD
+        }).sum::<usize>() + self.expires.len() * OVERHEAD
+    }
+
+    //Caches a script body under its SHA1 digest, as used by EVALSHA.
+    pub fn load_script(&mut self, body: String) -> String {
+        let sha = sha1_hex(&body);
+        self.scripts.insert(sha.clone(), body);
+        sha
+    }
+
+    pub fn script(&self, sha: &str) -> Option<&String> {
+        self.scripts.get(&sha.to_lowercase())
+    }
+
+    pub fn flush_scripts(&mut self) {
+        self.scripts.clear();
+    }
+
+    pub fn libraries(&self) -> impl Iterator<Item = &Library> {
+        self.libraries.values()
+    }
+
+    //Function names are global, so a library may not register a name that
+    //another library already owns.
+    pub fn add_library(&mut self, library: Library, replace: bool) -> Result<(), String> {
+        if !replace && self.libraries.contains_key(&library.name) {
+            return Err(format!("Library '{}' already exists", library.name));
+        }
+        for other in self.libraries.values().filter(|l| l.name != library.name) {
+            if let Some(function) = library.functions.iter().find(|f| other.function(&f.name).is_some()) {
+                return Err(format!("Function {} already exists", function.name));
+            }
+        }
+        self.libraries.insert(library.name.clone(), library);
+        self.persistence.add_dirty(1);
+        Ok(())
+    }
+
+    pub fn delete_library(&mut self, name: &str) -> bool {
+        self.persistence.add_dirty(1);
+        self.libraries.remove(name).is_some()
+    }
+
+    //Swaps in a whole registry, already checked for clashing function names.
+    pub fn set_libraries(&mut self, libraries: BTreeMap<String, Library>) {
+        self.persistence.add_dirty(1);
+        self.libraries = libraries;
+    }
+
+    pub fn flush_libraries(&mut self) {
+        self.persistence.add_dirty(1);
+        self.libraries.clear();
+    }
+
+    pub fn find_function(&self, name: &str) -> Option<(&Library, &FunctionInfo)> {
+        self.libraries.values()
+            .find_map(|library| library.function(name).map(|function| (library, function)))
+    }
+
+    //Keys that were never written share version 0.
+    pub fn version(&self, key: &str) -> u64 {
+        *self.versions.get(key).unwrap_or(&0)
+    }
+
+    fn is_expired(&self, key: &str) -> bool {
+        matches!(self.expires.get(key), Some(at) if *at <= now_ms())
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.persistence.add_dirty(1);
+        self.bump(key);
+    }
+
+    //A new version for WATCH, without counting as a change to save.
+    fn bump(&mut self, key: &str) {
+        self.counter += 1;
+        self.versions.insert(key.to_string(), self.counter);
+    }
+}
+
+//HELPER FN
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{Db, Value};
+    use crate::rdb::Snapshot;
+
+    #[test]
+    fn set_bumps_version() {
+        let mut db = Db::new();
+        assert_eq!(db.version("a"), 0);
+
+        db.set("a".to_string(), "1".to_string());
+        let first = db.version("a");
+        assert!(first > 0);
+
+        db.set("a".to_string(), "2".to_string());
+        assert!(db.version("a") > first);
+        assert_eq!(db.version("b"), 0);
+    }
+
+    #[test]
+    fn flush_bumps_existing_keys() {
+        let mut db = Db::new();
+        db.set("a".to_string(), "1".to_string());
+        let before = db.version("a");
+
+        db.flush();
+
+        assert!(db.is_empty());
+        assert!(db.version("a") > before);
+        assert_eq!(db.version("b"), 0);
+    }
+
+    #[test]
+    fn expired_keys_read_as_missing() {
+        let mut db = Db::new();
+        db.restore(Snapshot {
+            entries: vec![
+                ("old".to_string(), Value::String("1".to_string()), Some(1)),
+                ("list".to_string(), Value::List(vec!["a".to_string()]), Some(u64::MAX)),
+            ],
+            libraries: vec![],
+        }).unwrap();
+
+        assert_eq!(db.get("old"), None);
+        assert_eq!(db.get("list"), None);
+        assert!(db.value("list").is_some());
+        assert_eq!(db.keys("*").unwrap(), vec!["list".to_string()]);
+
+        let before = db.version("old");
+        assert_eq!(db.purge_expired(), 1);
+        assert!(db.version("old") > before);
+        assert_eq!(db.len(), 1);
+
+        db.set("list".to_string(), "x".to_string());
+        assert_eq!(db.expire_at("list"), None);
+    }
+
+    #[test]
+    fn expired_read_bumps_version() {
+        let mut db = Db::new();
+        db.restore(Snapshot {
+            entries: vec![
+                ("old".to_string(), Value::String("1".to_string()), Some(1)),
+                ("live".to_string(), Value::String("2".to_string()), Some(u64::MAX)),
+            ],
+            libraries: vec![],
+        }).unwrap();
+        let (old, live) = (db.version("old"), db.version("live"));
+
+        assert!(db.expire_if_needed("old"));
+        assert!(db.version("old") > old);
+        assert!(!db.expire_if_needed("old"));
+        assert!(!db.expire_if_needed("live"));
+        assert_eq!(db.version("live"), live);
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn restore_bumps_versions() {
+        let mut db = Db::new();
+        db.set("replaced".to_string(), "1".to_string());
+        let (replaced, added) = (db.version("replaced"), db.version("added"));
+
+        db.restore(Snapshot {
+            entries: vec![
+                ("replaced".to_string(), Value::String("2".to_string()), None),
+                ("added".to_string(), Value::String("3".to_string()), None),
+            ],
+            libraries: vec![],
+        }).unwrap();
+
+        assert!(db.version("replaced") > replaced);
+        assert!(db.version("added") > added);
+    }
+}
@@ -0,0 +1,227 @@
+//Decoders for the compact encodings Redis stores small collections with in
+//RDB files: ziplist (Redis < 7), listpack (Redis 7) and intset. Each returns
+//the flat list of elements, integers rendered as strings.
+
+//Start of the error for an element that is not UTF-8, which callers can tell
+//apart from corruption.
+pub const NOT_UTF8: &str = "Non UTF-8 string";
+
+pub fn ziplist(data: &[u8]) -> Result<Vec<String>, String> {
+    let mut cur = Cursor { data, pos: 0 };
+    cur.take(8)?;
+    //The count comes from the file: each entry takes at least two bytes, so
+    //the capacity is bounded by what is left of the input.
+    let count = u16::from_le_bytes(cur.array::<2>()?) as usize;
+    let mut elements = Vec::with_capacity(count.min(cur.remaining() / 2));
+
+    loop {
+        if cur.peek()? == 0xff {
+            break;
+        }
+        //Length of the previous entry, only needed to walk backwards.
+        if cur.u8()? == 0xfe {
+            cur.take(4)?;
+        }
+        let enc = cur.u8()?;
+        let element = match enc >> 6 {
+            0 => cur.string((enc & 0x3f) as usize)?,
+            1 => {
+                let len = (((enc & 0x3f) as usize) << 8) | cur.u8()? as usize;
+                cur.string(len)?
+            },
+            2 => {
+                let len = u32::from_be_bytes(cur.array::<4>()?) as usize;
+                cur.string(len)?
+            },
+            _ => match enc {
+                0xc0 => i16::from_le_bytes(cur.array::<2>()?).to_string(),
+                0xd0 => i32::from_le_bytes(cur.array::<4>()?).to_string(),
+                0xe0 => i64::from_le_bytes(cur.array::<8>()?).to_string(),
+                0xf0 => {
+                    let b = cur.array::<3>()?;
+                    (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8).to_string()
+                },
+                0xfe => (cur.u8()? as i8).to_string(),
+                0xf1..=0xfd => ((enc & 0x0f) - 1).to_string(),
+                _ => return Err(format!("Unknown ziplist entry encoding {:#x}", enc)),
+            },
+        };
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+pub fn listpack(data: &[u8]) -> Result<Vec<String>, String> {
+    let mut cur = Cursor { data, pos: 0 };
+    cur.take(6)?;
+    let mut elements = Vec::new();
+
+    loop {
+        let start = cur.pos;
+        let enc = cur.u8()?;
+        let element = if enc == 0xff {
+            break;
+        } else if enc & 0x80 == 0 {
+            (enc & 0x7f).to_string()
+        } else if enc & 0xc0 == 0x80 {
+            cur.string((enc & 0x3f) as usize)?
+        } else if enc & 0xe0 == 0xc0 {
+            let value = (((enc & 0x1f) as i32) << 8) | cur.u8()? as i32;
+            (if value >= 1 << 12 { value - (1 << 13) } else { value }).to_string()
+        } else if enc & 0xf0 == 0xe0 {
+            let len = (((enc & 0x0f) as usize) << 8) | cur.u8()? as usize;
+            cur.string(len)?
+        } else {
+            match enc {
+                0xf0 => {
+                    let len = u32::from_le_bytes(cur.array::<4>()?) as usize;
+                    cur.string(len)?
+                },
+                0xf1 => i16::from_le_bytes(cur.array::<2>()?).to_string(),
+                0xf2 => {
+                    let b = cur.array::<3>()?;
+                    (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8).to_string()
+                },
+                0xf3 => i32::from_le_bytes(cur.array::<4>()?).to_string(),
+                0xf4 => i64::from_le_bytes(cur.array::<8>()?).to_string(),
+                _ => return Err(format!("Unknown listpack entry encoding {:#x}", enc)),
+            }
+        };
+        //Each entry ends with its own length, stored in 1 to 5 bytes.
+        cur.take(backlen_size(cur.pos - start))?;
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+pub fn intset(data: &[u8]) -> Result<Vec<String>, String> {
+    let mut cur = Cursor { data, pos: 0 };
+    let width = u32::from_le_bytes(cur.array::<4>()?);
+    let count = u32::from_le_bytes(cur.array::<4>()?) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(format!("Unknown intset encoding {}", width));
+    }
+    if count.saturating_mul(width as usize) > cur.remaining() {
+        return Err(format!("Intset of {} elements longer than its {} bytes", count, cur.remaining()));
+    }
+    let mut elements = Vec::with_capacity(count);
+    for _ in 0..count {
+        let element = match width {
+            2 => i16::from_le_bytes(cur.array::<2>()?).to_string(),
+            4 => i32::from_le_bytes(cur.array::<4>()?).to_string(),
+            _ => i64::from_le_bytes(cur.array::<8>()?).to_string(),
+        };
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+//HELPER FN
+
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.data.len() {
+            return Err(format!("Compact encoding truncated at byte {}", self.pos));
+        }
+        let slice = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, String> {
+        self.data.get(self.pos).copied().ok_or_else(|| format!("Compact encoding truncated at byte {}", self.pos))
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, String> {
+        let start = self.pos;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| format!("{} at byte {} of compact encoding", NOT_UTF8, start))
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::*;
+
+    #[test]
+    fn decode_ziplist() {
+        let mut data = vec![0; 8];
+        data.extend_from_slice(&3u16.to_le_bytes());
+        data.extend_from_slice(&[0, 0x02, b'h', b'i']);
+        data.extend_from_slice(&[4, 0xf3]);
+        data.extend_from_slice(&[2, 0xc0, 0x18, 0xfc]);
+        data.push(0xff);
+        assert_eq!(ziplist(&data).unwrap(), vec!["hi", "2", "-1000"]);
+        assert!(ziplist(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn decode_listpack() {
+        let mut data = vec![0; 6];
+        data.extend_from_slice(&[0x82, b'h', b'i', 3]);
+        data.extend_from_slice(&[0x07, 1]);
+        data.extend_from_slice(&[0xdf, 0xff, 2]);
+        data.extend_from_slice(&[0xf1, 0x18, 0xfc, 3]);
+        data.push(0xff);
+        assert_eq!(listpack(&data).unwrap(), vec!["hi", "7", "-1", "-1000"]);
+        data[7] = 0xff;
+        assert!(listpack(&data).unwrap_err().contains("Non UTF-8"));
+    }
+
+    #[test]
+    fn decode_intset() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&(-3i16).to_le_bytes());
+        data.extend_from_slice(&300i16.to_le_bytes());
+        assert_eq!(intset(&data).unwrap(), vec!["-3", "300"]);
+        data[0] = 3;
+        assert!(intset(&data).is_err());
+    }
+
+    #[test]
+    fn corrupt_counts_are_errors() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&1i64.to_le_bytes());
+        assert!(intset(&data).unwrap_err().contains("longer than"));
+
+        let mut data = vec![0; 8];
+        data.extend_from_slice(&u16::MAX.to_le_bytes());
+        data.extend_from_slice(&[0, 0x02, b'h', b'i']);
+        assert!(ziplist(&data).is_err());
+    }
+}
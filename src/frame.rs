@@ -1,6 +1,8 @@
 use bytes::{Buf, Bytes};
 use std::io::Cursor;
 
+pub const INCOMPLETE: &str = "Incomplete frame";
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Frame {
     Simple(String),
@@ -37,29 +39,35 @@ impl Frame {
         }
     }
 
+    pub fn push_frame(&mut self, frame: Frame) {
+        match self {
+            Frame::Array(vec) => vec.push(frame),
+            _ => panic!("not an array frame"),
+        }
+    }
+
     pub fn to_string(&mut self) -> Result<String, String> {
         match self {
             Frame::Simple(s) => Ok(s.to_string()),
             Frame::Error(s) => Ok(s.to_string()),
             Frame::Integer(i) => Ok(i.to_string()),
             Frame::Bulk(s) => {
-                let s = String::from_utf8(s.to_vec()).unwrap();
-                Ok(s)
+                String::from_utf8(s.to_vec()).map_err(|_| "Invalid UTF-8 in argument".to_string())
             },
             _ => Err("Could not convert to string".to_string()),
         }
     }
 
+    //Parses one frame starting at the cursor position, leaving the cursor
+    //right after it. Input that ends before the frame does fails with
+    //`INCOMPLETE`, so callers can tell a truncated stream from a corrupt one.
     pub fn serialize(input: &mut Cursor<&[u8]>) -> Result<Frame, String> {
+        if !input.has_remaining() {
+            return Err(INCOMPLETE.to_string());
+        }
         match input.get_u8() {
             b'+' => {
-                let mut line = Vec::new();
-                while input.remaining() != 0 {
-                    let i = input.get_u8();
-                    if i != b'\r' && i != b'\n' {
-                        line.push(i)
-                    }
-                }
+                let line = read_line(input)?;
                 if let Ok(num) = String::from_utf8(line) {
                     Ok(Frame::Simple(num))
                 } else {
@@ -67,13 +75,7 @@ impl Frame {
                 }
             },
             b'-' => {
-                let mut line = Vec::new();
-                while input.remaining() != 0 {
-                    let i = input.get_u8();
-                    if i != b'\r' && i != b'\n' {
-                        line.push(i)
-                    }
-                }
+                let line = read_line(input)?;
                 if let Ok(num) = String::from_utf8(line) {
                     Ok(Frame::Error(num))
                 } else {
@@ -81,56 +83,53 @@ impl Frame {
                 }
             },
             b':' => {
-                let mut line = Vec::new();
-                while input.remaining() != 0 {
-                    let i = input.get_u8();
-                    if i != b'\r' && i != b'\n' {
-                        line.push(i)
-                    }
+                let line = read_line(input)?;
+                match String::from_utf8(line).ok().and_then(|num| num.parse::<i64>().ok()) {
+                    Some(num) => Ok(Frame::Integer(num)),
+                    None => Err("Error parsing integer".to_string()),
                 }
-
-                let num = String::from_utf8(line).unwrap();
-                let num = num.parse::<i64>().unwrap();
-
-                Ok(Frame::Integer(num))
             },
             b'$' => {
-                let mut line = Vec::new();
-                let mut i: u8 = 0;
-                while i != b'\n' {
-                    i = input.get_u8();
-                    if i != b'\r' && i != b'\n' {
-                        line.push(i)
-                    }
+                let line = read_line(input)?;
+                let num = String::from_utf8(line).unwrap_or_default();
+                if num == "-1" {
+                    return Ok(Frame::Null);
                 }
-
-                let num = String::from_utf8(line).unwrap();
-                println!("{:?}", &num);
-                let num = num.parse::<usize>().unwrap();
+                let num = match num.parse::<usize>() {
+                    Ok(num) => num,
+                    Err(_) => return Err("Error parsing bulk string length".to_string()),
+                };
                 let n = num + 2;
 
+                if input.remaining() < n {
+                    return Err(INCOMPLETE.to_string());
+                }
+                if &input.chunk()[num..n] != b"\r\n" {
+                    return Err("Bulk string is missing its terminator".to_string());
+                }
                 let data = Bytes::copy_from_slice(&input.chunk()[..num]);
                 input.advance(n);
 
                 Ok(Frame::Bulk(data))
             },
-            b'_' => Ok(Frame::Null),
+            b'_' => {
+                read_line(input)?;
+                Ok(Frame::Null)
+            },
             b'*' => {
-                let mut line = Vec::new();
-                let mut i: u8 = 0;
-                while i != b'\n' {
-                    i = input.get_u8();
-                    if i != b'\r' && i != b'\n' {
-                        line.push(i);
-                    }
+                let line = read_line(input)?;
+                let len = String::from_utf8(line).unwrap_or_default();
+                if len == "-1" {
+                    return Ok(Frame::Null);
                 }
-
-                let len = String::from_utf8(line).unwrap();
-                let len = len.parse::<usize>().unwrap();
-                let mut out = Vec::with_capacity(len);
+                let len = match len.parse::<usize>() {
+                    Ok(len) => len,
+                    Err(_) => return Err("Error parsing array length".to_string()),
+                };
+                let mut out = Vec::with_capacity(len.min(1024));
 
                 for _ in 0..len {
-                    out.push(Frame::serialize(input).unwrap());
+                    out.push(Frame::serialize(input)?);
                 }
 
                 Ok(Frame::Array(out))
@@ -154,7 +153,7 @@ impl Frame {
                 deser_string(&mut vec.to_vec())
             },
             Frame::Null => {
-                let output: Vec<u8> = vec!('_' as u8, '\r' as u8, '\n' as u8);
+                let output: Vec<u8> = vec!(b'_', b'\r', b'\n');
                 output
             },
             Frame::Array(ref mut vec) => {
@@ -166,88 +165,97 @@ impl Frame {
 
 //HELPER FN
 
+//Reads up to the next CRLF, which is consumed but not returned.
+fn read_line(input: &mut Cursor<&[u8]>) -> Result<Vec<u8>, String> {
+    let chunk = input.chunk();
+    match chunk.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => {
+            let line = chunk[..end].to_vec();
+            input.advance(end + 2);
+            Ok(line)
+        },
+        None => Err(INCOMPLETE.to_string()),
+    }
+}
+
 //DESERIALIZATION
 
 fn deser_simple_string(s: String) -> Vec<u8> {
     let mut output: Vec<u8> = Vec::new();
-    output.push('+' as u8);
+    output.push(b'+');
     let mut strin = s.into_bytes();
     output.append(&mut strin);
-    output.push('\r' as u8);
-    output.push('\n' as u8);
+    output.push(b'\r');
+    output.push(b'\n');
     output
 }
 
 fn deser_error(s: String) -> Vec<u8> {
     let mut output: Vec<u8> = Vec::new();
-    output.push('-' as u8);
+    output.push(b'-');
     let mut strin = s.into_bytes();
     output.append(&mut strin);
-    output.push('\r' as u8);
-    output.push('\n' as u8);
+    output.push(b'\r');
+    output.push(b'\n');
     output
 }
 
 fn deser_int(val: i64) -> Vec<u8> {
     let mut output: Vec<u8> = Vec::new();
-    output.push(':' as u8);
+    output.push(b':');
     
     if val >= 0 {
-        output.push('+' as u8);
-    } else {
-        output.push('-' as u8);
+        output.push(b'+');
     }
     
     let mut strin = val.to_string().into_bytes();
     output.append(&mut strin);
-    output.push('\r' as u8);
-    output.push('\n' as u8);
+    output.push(b'\r');
+    output.push(b'\n');
     output
 }
 
-fn deser_string(mut vec: &mut Vec<u8>) -> Vec<u8> {
+fn deser_string(vec: &mut Vec<u8>) -> Vec<u8> {
     let mut length = vec.len().to_string().as_bytes().to_vec();
     let mut output: Vec<u8> = Vec::new();
-    output.push('$' as u8);
+    output.push(b'$');
     output.append(&mut length);
-    output.push('\r' as u8);
-    output.push('\n' as u8);
-    output.append(&mut vec);
-    output.push('\r' as u8);
-    output.push('\n' as u8);
+    output.push(b'\r');
+    output.push(b'\n');
+    output.append(vec);
+    output.push(b'\r');
+    output.push(b'\n');
     output
 }
 
 fn deser_array(vec: &mut Vec<Frame>) -> Vec<u8> {
     let mut output: Vec<u8> = Vec::new();
-    output.push('*' as u8);
+    output.push(b'*');
     let mut length = vec.len().to_string().as_bytes().to_vec();
     output.append(&mut length);
     output.push(b'\r');
     output.push(b'\n');
     for frame in vec {
-        let mut temp: Vec<u8> = Vec::new();
-        match frame {
+        let mut temp: Vec<u8> = match frame {
             Frame::Simple(s) => {
-                temp = deser_simple_string(s.to_string());
+                deser_simple_string(s.to_string())
             },
             Frame::Error(s) => {
-                temp = deser_error(s.to_string());
+                deser_error(s.to_string())
             },
             Frame::Integer(val) => {
-                temp = deser_int(*val);
+                deser_int(*val)
             },
             Frame::Bulk(ref mut vec) => {
-                temp = deser_string(&mut vec.to_vec());
+                deser_string(&mut vec.to_vec())
             },
             Frame::Null => {
-                let output: Vec<u8> = vec!('_' as u8, '\r' as u8, '\n' as u8);
-                temp = output;
+                vec!(b'_', b'\r', b'\n')
             },
             Frame::Array(ref mut this_vec) => {
-                temp = deser_array(this_vec);
+                deser_array(this_vec)
             },
-        }
+        };
         output.append(&mut temp);
     }
      output
@@ -312,6 +320,14 @@ mod tests {
         assert_eq!(output, expected);
     }
 
+    #[test]
+    fn negative_int_deser() {
+        let mut input = Frame::Integer(-231);
+        let output = input.deserialize();
+        let expected = ":-231\r\n".as_bytes().to_vec();
+        assert_eq!(output, expected);
+    }
+
     #[test]
     fn bulk_serialization() {
         let input = "$5\r\nhello\r\n".as_bytes();
@@ -364,4 +380,26 @@ mod tests {
         let expected = "*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n".as_bytes().to_vec();
         assert_eq!(output, expected);
     }
+
+    #[test]
+    fn incomplete_and_invalid_input() {
+        let inputs: [&[u8]; 4] = [b"", b"*2\r\n$5\r\nhello\r\n", b"$5\r\nhel", b"+OK"];
+        for input in inputs {
+            let output = Frame::serialize(&mut Cursor::new(input));
+            assert_eq!(output, Err(crate::frame::INCOMPLETE.to_string()));
+        }
+
+        assert!(Frame::serialize(&mut Cursor::new("$3\r\nhello\r\n".as_bytes())).is_err());
+        assert!(Frame::serialize(&mut Cursor::new(":abc\r\n".as_bytes())).is_err());
+    }
+
+    #[test]
+    fn consecutive_frames() {
+        let input = "+OK\r\n:-5\r\n".as_bytes();
+        let mut input_cursor = Cursor::new(input);
+
+        assert_eq!(Frame::serialize(&mut input_cursor).unwrap(), Frame::Simple("OK".to_string()));
+        assert_eq!(Frame::serialize(&mut input_cursor).unwrap(), Frame::Integer(-5));
+        assert_eq!(input_cursor.position() as usize, input.len());
+    }
 }
@@ -0,0 +1,457 @@
+use crate::busy::Busy;
+use crate::db::Db;
+use crate::frame::Frame;
+use crate::glob;
+use crate::handler::Caller;
+use crate::script;
+use bytes::Bytes;
+use mlua::{HookTriggers, Lua, Table};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum FunctionCmd {
+    LOAD(String, bool),
+    DELETE(String),
+    LIST(Option<String>, bool),
+    DUMP,
+    RESTORE(String, RestorePolicy),
+    FLUSH,
+    KILL,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RestorePolicy {
+    APPEND,
+    REPLACE,
+    FLUSH,
+}
+
+#[derive(Clone)]
+pub struct Library {
+    pub name: String,
+    pub code: String,
+    pub functions: Vec<FunctionInfo>,
+    //The state the code was loaded in, which keeps the registered callbacks
+    //for FCALL.
+    lua: Arc<Mutex<Lua>>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub flags: Vec<String>,
+}
+
+//How long the library body may run in FUNCTION LOAD, as in Redis.
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+const FLAGS: [&str; 5] = ["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+//Gives library code a `redis.register_function` that records callbacks and
+//flags in a global table, accepting both the positional and the table form.
+const PRELUDE: &str = r#"
+__registered = {}
+redis.register_function = function(name, callback)
+    local flags = {}
+    if type(name) == 'table' then
+        callback = name.callback
+        flags = name.flags or {}
+        name = name.function_name
+    end
+    if type(name) ~= 'string' or type(callback) ~= 'function' then
+        error('wrong arguments given to redis.register_function')
+    end
+    if __registered[name] ~= nil then
+        error('Function ' .. name .. ' already exists')
+    end
+    __registered[name] = { callback = callback, flags = flags }
+end
+"#;
+
+impl Library {
+    //Parses the `#!lua name=<library>` header and runs the code once, in a
+    //sandboxed state, to collect the functions it registers. `redis.call` is
+    //not available here, and the body is stopped after LOAD_TIMEOUT.
+    pub fn load(code: &str, busy: &Arc<Busy>) -> Result<Library, String> {
+        let (name, body) = parse_header(code)?;
+
+        let lua = script::sandbox().map_err(|e| format!("Error registering functions: {}", script::error_message(&e)))?;
+        let started = Instant::now();
+        let load_busy = busy.clone();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(script::HOOK_INTERVAL), move |_, _| {
+            load_busy.check().map_err(mlua::Error::RuntimeError)?;
+            if started.elapsed() > LOAD_TIMEOUT {
+                return Err(mlua::Error::RuntimeError("FUNCTION LOAD timeout".to_string()));
+            }
+            Ok(())
+        });
+        let functions = register(&lua, body).map_err(|e| format!("Error registering functions: {}", script::error_message(&e)))?;
+        if functions.is_empty() {
+            return Err("No functions registered".to_string());
+        }
+        for function in &functions {
+            if let Some(flag) = function.flags.iter().find(|f| !FLAGS.contains(&f.as_str())) {
+                return Err(format!("Unknown flag given: {}", flag));
+            }
+        }
+
+        //From here on the state only runs FCALL, which FUNCTION KILL can stop.
+        let busy = busy.clone();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(script::HOOK_INTERVAL), move |_, _| {
+            busy.check().map_err(mlua::Error::RuntimeError)
+        });
+
+        Ok(Library {
+            name,
+            code: code.to_string(),
+            functions,
+            lua: Arc::new(Mutex::new(lua)),
+        })
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionInfo> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+}
+
+impl PartialEq for Library {
+    fn eq(&self, other: &Library) -> bool {
+        self.name == other.name && self.code == other.code && self.functions == other.functions
+    }
+}
+
+impl fmt::Debug for Library {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Library")
+            .field("name", &self.name)
+            .field("code", &self.code)
+            .field("functions", &self.functions)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FunctionInfo {
+    pub fn read_only(&self) -> bool {
+        self.flags.iter().any(|f| f == "no-writes")
+    }
+}
+
+pub fn execute(cmd: &FunctionCmd, db: &mut Db) -> Result<Frame, String> {
+    match cmd {
+        FunctionCmd::LOAD(code, replace) => {
+            let library = Library::load(code, &db.busy())?;
+            let name = library.name.clone();
+            db.add_library(library, *replace)?;
+            Ok(Frame::Bulk(Bytes::from(name)))
+        },
+        FunctionCmd::DELETE(name) => {
+            if db.delete_library(name) {
+                Ok(Frame::Simple("OK".to_string()))
+            } else {
+                Err("Library not found".to_string())
+            }
+        },
+        FunctionCmd::LIST(pattern, with_code) => {
+            let mut output = Frame::array();
+            for library in db.libraries() {
+                if let Some(pattern) = pattern {
+                    if !glob::matches(pattern, &library.name) {
+                        continue;
+                    }
+                }
+                output.push_frame(list_entry(library, *with_code));
+            }
+            Ok(output)
+        },
+        FunctionCmd::DUMP => Ok(Frame::Bulk(Bytes::from(dump(db)))),
+        FunctionCmd::RESTORE(payload, policy) => {
+            restore(db, payload, *policy)?;
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        FunctionCmd::FLUSH => {
+            db.flush_libraries();
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        FunctionCmd::KILL => Err("FUNCTION KILL is handled outside of the database lock".to_string()),
+    }
+}
+
+//Calls a registered function with the same atomicity as EVAL. FCALL_RO and
+//functions flagged `no-writes` run with write commands refused.
+pub fn fcall(db: &mut Db, name: &str, keys: &[String], args: &[String], read_only: bool, caller: Option<&Caller>) -> Result<Frame, String> {
+    let (lua, function_read_only) = match db.find_function(name) {
+        Some((library, function)) => (library.lua.clone(), function.read_only()),
+        None => return Err("Function not found".to_string()),
+    };
+    if read_only && !function_read_only {
+        return Err("Can not execute a script with write flag using *_ro command.".to_string());
+    }
+
+    let lua = lua.lock().unwrap();
+    script::run_in(&lua, db, read_only || function_read_only, caller, |lua| {
+        let registered: Table = lua.globals().get("__registered")?;
+        let entry: Table = registered.get(name)?;
+        let callback: mlua::Function = entry.get("callback")?;
+        callback.call((keys, args))
+    })
+}
+
+//Serializes every library as a RESP array of bulk strings holding the code,
+//which FUNCTION RESTORE reloads through the same path as FUNCTION LOAD. This
+//is not the RDB based payload of Redis' FUNCTION DUMP: it only round-trips
+//between servers of this implementation, and Redis' payloads are refused.
+pub fn dump(db: &Db) -> Vec<u8> {
+    let mut frame = Frame::array();
+    for library in db.libraries() {
+        frame.push_bulk(Bytes::from(library.code.clone()));
+    }
+    frame.deserialize()
+}
+
+pub fn restore(db: &mut Db, payload: &str, policy: RestorePolicy) -> Result<(), String> {
+    if !payload.starts_with('*') {
+        return Err("payload version or checksum are wrong".to_string());
+    }
+    let busy = db.busy();
+    let mut libraries = Vec::new();
+    match Frame::serialize(&mut Cursor::new(payload.as_bytes()))? {
+        Frame::Array(vec) => {
+            for mut frame in vec {
+                libraries.push(Library::load(&frame.to_string()?, &busy)?);
+            }
+        },
+        _ => return Err("payload version or checksum are wrong".to_string()),
+    }
+
+    //The registry the restore leads to is checked as a whole before it
+    //replaces the current one, so an error leaves the libraries untouched.
+    let mut registry: BTreeMap<String, Library> = match policy {
+        RestorePolicy::FLUSH => BTreeMap::new(),
+        _ => db.libraries().map(|l| (l.name.clone(), l.clone())).collect(),
+    };
+    for library in libraries {
+        if policy == RestorePolicy::APPEND && registry.contains_key(&library.name) {
+            return Err(format!("Library {} already exists", library.name));
+        }
+        registry.insert(library.name.clone(), library);
+    }
+    let mut owners = HashMap::new();
+    for library in registry.values() {
+        for function in &library.functions {
+            if owners.insert(&function.name, &library.name).is_some() {
+                return Err(format!("Function {} already exists", function.name));
+            }
+        }
+    }
+    db.set_libraries(registry);
+    Ok(())
+}
+
+//HELPER FN
+
+fn parse_header(code: &str) -> Result<(String, &str), String> {
+    let (header, body) = code.split_once('\n').unwrap_or((code, ""));
+    let header = match header.strip_prefix("#!") {
+        Some(header) => header,
+        None => return Err("Missing library metadata".to_string()),
+    };
+
+    let mut parts = header.split_whitespace();
+    match parts.next() {
+        Some("lua") => (),
+        Some(engine) => return Err(format!("Engine '{}' not found", engine)),
+        None => return Err("Missing library metadata".to_string()),
+    }
+
+    let mut name = None;
+    for part in parts {
+        match part.split_once('=') {
+            Some(("name", value)) => name = Some(value.to_string()),
+            _ => return Err(format!("Invalid metadata value given: {}", part)),
+        }
+    }
+    match name {
+        Some(name) if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+            Ok((name, body))
+        },
+        Some(_) => Err("Library names can only contain letters, numbers, or underscores(_)".to_string()),
+        None => Err("Library name was not given".to_string()),
+    }
+}
+
+//Runs the library body with the prelude installed and returns what it registered.
+fn register(lua: &Lua, body: &str) -> mlua::Result<Vec<FunctionInfo>> {
+    let globals = lua.globals();
+    if !globals.contains_key("redis")? {
+        globals.set("redis", lua.create_table()?)?;
+    }
+    lua.load(PRELUDE).exec()?;
+    lua.load(body).exec()?;
+
+    let registered: Table = globals.get("__registered")?;
+    let mut functions = Vec::new();
+    for pair in registered.pairs::<String, Table>() {
+        let (name, entry) = pair?;
+        let flags: Vec<String> = entry.get("flags")?;
+        functions.push(FunctionInfo { name, flags });
+    }
+    functions.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(functions)
+}
+
+fn list_entry(library: &Library, with_code: bool) -> Frame {
+    let mut entry = Frame::array();
+    entry.push_bulk(Bytes::from("library_name"));
+    entry.push_bulk(Bytes::from(library.name.clone()));
+    entry.push_bulk(Bytes::from("engine"));
+    entry.push_bulk(Bytes::from("LUA"));
+    entry.push_bulk(Bytes::from("functions"));
+
+    let mut functions = Frame::array();
+    for function in &library.functions {
+        let mut info = Frame::array();
+        info.push_bulk(Bytes::from("name"));
+        info.push_bulk(Bytes::from(function.name.clone()));
+        info.push_bulk(Bytes::from("description"));
+        info.push_frame(Frame::Null);
+        info.push_bulk(Bytes::from("flags"));
+        let mut flags = Frame::array();
+        for flag in &function.flags {
+            flags.push_bulk(Bytes::from(flag.clone()));
+        }
+        info.push_frame(flags);
+        functions.push_frame(info);
+    }
+    entry.push_frame(functions);
+
+    if with_code {
+        entry.push_bulk(Bytes::from("library_code"));
+        entry.push_bulk(Bytes::from(library.code.clone()));
+    }
+    entry
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::db::Db;
+    use crate::frame::Frame;
+    use crate::busy::Busy;
+    use crate::function::*;
+    use std::sync::Arc;
+
+    const LIB: &str = "#!lua name=mylib\n\
+        redis.register_function('setter', function(keys, args) return redis.call('SET', keys[1], args[1]) end)\n\
+        redis.register_function{function_name='getter', callback=function(keys) return redis.call('GET', keys[1]) end, flags={'no-writes'}}";
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn load_collects_functions_and_flags() {
+        let busy = Arc::new(Busy::default());
+        let library = Library::load(LIB, &busy).unwrap();
+
+        assert_eq!(library.name, "mylib");
+        assert_eq!(library.functions.len(), 2);
+        assert!(library.function("getter").unwrap().read_only());
+        assert!(!library.function("setter").unwrap().read_only());
+    }
+
+    #[test]
+    fn load_rejects_bad_metadata() {
+        let busy = Arc::new(Busy::default());
+        assert!(Library::load("return 1", &busy).is_err());
+        assert!(Library::load("#!js name=lib\n", &busy).is_err());
+        assert!(Library::load("#!lua\nredis.register_function('f', function() end)", &busy).is_err());
+        assert!(Library::load("#!lua name=empty\nlocal x = 1", &busy).is_err());
+        assert!(Library::load("#!lua name=l\nredis.register_function{function_name='f', callback=function() end, flags={'bogus'}}", &busy).is_err());
+    }
+
+    #[test]
+    fn fcall_and_fcall_ro() {
+        let mut db = Db::new();
+        execute(&FunctionCmd::LOAD(LIB.to_string(), false), &mut db).unwrap();
+        assert!(execute(&FunctionCmd::LOAD(LIB.to_string(), false), &mut db).is_err());
+        execute(&FunctionCmd::LOAD(LIB.to_string(), true), &mut db).unwrap();
+
+        fcall(&mut db, "setter", &strings(&["k"]), &strings(&["v"]), false, None).unwrap();
+        let output = fcall(&mut db, "getter", &strings(&["k"]), &[], true, None).unwrap();
+        assert_eq!(output, Frame::Bulk(Bytes::from("v")));
+
+        assert!(fcall(&mut db, "setter", &strings(&["k"]), &strings(&["w"]), true, None).is_err());
+        assert!(fcall(&mut db, "missing", &[], &[], false, None).is_err());
+    }
+
+    #[test]
+    fn dump_and_restore() {
+        let mut db = Db::new();
+        execute(&FunctionCmd::LOAD(LIB.to_string(), false), &mut db).unwrap();
+        let payload = String::from_utf8(dump(&db)).unwrap();
+
+        let mut other = Db::new();
+        restore(&mut other, &payload, RestorePolicy::APPEND).unwrap();
+        assert!(restore(&mut other, &payload, RestorePolicy::APPEND).is_err());
+        restore(&mut other, &payload, RestorePolicy::REPLACE).unwrap();
+        assert_eq!(other.libraries().count(), 1);
+
+        execute(&FunctionCmd::DELETE("mylib".to_string()), &mut other).unwrap();
+        assert!(execute(&FunctionCmd::DELETE("mylib".to_string()), &mut other).is_err());
+    }
+
+    #[test]
+    fn failed_restore_changes_nothing() {
+        let mut db = Db::new();
+        execute(&FunctionCmd::LOAD(LIB.to_string(), false), &mut db).unwrap();
+        let first = "#!lua name=first\nredis.register_function('one', function() return 1 end)";
+        execute(&FunctionCmd::LOAD(first.to_string(), false), &mut db).unwrap();
+
+        let mut payload = Frame::array();
+        payload.push_bulk(Bytes::from("#!lua name=first\nredis.register_function('two', function() return 2 end)"));
+        payload.push_bulk(Bytes::from("#!lua name=second\nredis.register_function('setter', function() return 3 end)"));
+        let payload = String::from_utf8(payload.deserialize()).unwrap();
+
+        let error = restore(&mut db, &payload, RestorePolicy::REPLACE).unwrap_err();
+        assert_eq!(error, "Function setter already exists");
+        let names: Vec<&str> = db.libraries().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["first", "mylib"]);
+        assert!(db.find_function("one").is_some());
+        assert!(db.find_function("two").is_none());
+
+        restore(&mut db, &payload, RestorePolicy::FLUSH).unwrap();
+        let names: Vec<&str> = db.libraries().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn load_is_sandboxed_and_bounded() {
+        let busy = Arc::new(Busy::default());
+        let code = "#!lua name=l\nredis.register_function('f', function() return {type(io), type(os), type(loadfile)} end)";
+        let mut db = Db::new();
+        execute(&FunctionCmd::LOAD(code.to_string(), false), &mut db).unwrap();
+        let output = fcall(&mut db, "f", &[], &[], false, None).unwrap();
+        assert_eq!(output, Frame::Array(vec![Frame::Bulk(Bytes::from("nil")); 3]));
+
+        let err = Library::load("#!lua name=x\nwhile true do end", &busy).unwrap_err();
+        assert!(err.contains("timeout"));
+    }
+
+    #[test]
+    fn library_state_is_kept_between_calls() {
+        let code = "#!lua name=counter\n\
+            local n = 0\n\
+            redis.register_function('next', function() n = n + 1 return n end)";
+        let mut db = Db::new();
+        execute(&FunctionCmd::LOAD(code.to_string(), false), &mut db).unwrap();
+
+        assert_eq!(fcall(&mut db, "next", &[], &[], false, None).unwrap(), Frame::Integer(1));
+        assert_eq!(fcall(&mut db, "next", &[], &[], false, None).unwrap(), Frame::Integer(2));
+    }
+}
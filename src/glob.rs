@@ -0,0 +1,90 @@
+//Glob-style matching with the same syntax Redis uses for KEYS and friends:
+//`*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape.
+pub fn matches(pattern: &str, input: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let input: Vec<char> = input.chars().collect();
+    match_from(&pattern, &input)
+}
+
+//HELPER FN
+
+fn match_from(pattern: &[char], input: &[char]) -> bool {
+    match pattern.first() {
+        None => input.is_empty(),
+        Some('*') => {
+            (0..=input.len()).any(|i| match_from(&pattern[1..], &input[i..]))
+        },
+        Some('?') => !input.is_empty() && match_from(&pattern[1..], &input[1..]),
+        Some('[') => {
+            if input.is_empty() {
+                return false;
+            }
+            match match_class(&pattern[1..], input[0]) {
+                Some((true, rest)) => match_from(rest, &input[1..]),
+                _ => false,
+            }
+        },
+        Some('\\') if pattern.len() > 1 => {
+            !input.is_empty() && input[0] == pattern[1] && match_from(&pattern[2..], &input[1..])
+        },
+        Some(c) => !input.is_empty() && input[0] == *c && match_from(&pattern[1..], &input[1..]),
+    }
+}
+
+//Matches one character against a `[...]` class, returning whether it matched
+//and the pattern left after the closing bracket.
+fn match_class(pattern: &[char], c: char) -> Option<(bool, &[char])> {
+    let (negate, mut i) = match pattern.first() {
+        Some('^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    while i < pattern.len() {
+        match pattern[i] {
+            ']' => return Some((matched != negate, &pattern[i + 1..])),
+            '\\' if i + 1 < pattern.len() => {
+                matched |= pattern[i + 1] == c;
+                i += 2;
+            },
+            start if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' => {
+                let end = pattern[i + 2];
+                let (low, high) = if start <= end { (start, end) } else { (end, start) };
+                matched |= low <= c && c <= high;
+                i += 3;
+            },
+            other => {
+                matched |= other == c;
+                i += 1;
+            },
+        }
+    }
+    None
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::glob::matches;
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("h*llo", "heeello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("max*", "maxclients"));
+        assert!(!matches("max*", "timeout"));
+    }
+
+    #[test]
+    fn glob_classes_and_escapes() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+    }
+}
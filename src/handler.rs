@@ -1,214 +1,1959 @@
-use std::collections::HashMap;
+use crate::acl::{self, AclCmd, KeyAccess};
+use crate::busy::{Busy, Operation};
+use crate::client::{self, Client, ClientCmd, Filter, Kind, PauseMode, ReplyMode};
+use crate::config::{self, ConfigCmd};
+use crate::db::{Db, Shared, Value};
 use crate::frame::Frame;
+use crate::function::{self, FunctionCmd, RestorePolicy};
+use crate::info;
+use crate::latency::{self, LatencyCmd};
+use crate::log;
+use crate::persistence;
+use crate::replication::{self, ReplicaSync};
+use crate::script::{self, ScriptCmd};
+use crate::shutdown;
+use crate::slowlog::{self, SlowlogCmd};
 use std::str;
 use bytes::Bytes;
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Receiver;
 
-#[derive(PartialEq, Debug)]
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+pub const READONLY: &str = "READONLY You can't write against a read only replica.";
+pub const NOAUTH: &str = "NOAUTH Authentication required.";
+pub const WRONGPASS: &str = "WRONGPASS invalid username-password pair or user is disabled.";
+
+#[derive(PartialEq, Debug, Clone)]
 pub enum Command {
     PING,
     GET ( String ),
-    SET (String, Frame),
+    SET (String, String),
+    RPUSH (String, Vec<String>),
+    SADD (String, Vec<String>),
+    HSET (String, Vec<(String, String)>),
+    ZADD (String, Vec<(f64, String)>),
+    PEXPIREAT (String, u64),
+    KEYS (String),
+    FLUSHDB,
+    FLUSHALL,
+    MULTI,
+    EXEC,
+    DISCARD,
+    WATCH (Vec<String>),
+    UNWATCH,
+    EVAL (String, Vec<String>, Vec<String>),
+    EVALSHA (String, Vec<String>, Vec<String>),
+    SCRIPT (ScriptCmd),
+    FUNCTION (FunctionCmd),
+    FCALL (String, Vec<String>, Vec<String>, bool),
+    SAVE,
+    BGSAVE,
+    LASTSAVE,
+    BGREWRITEAOF,
+    //None stands for REPLICAOF NO ONE.
+    REPLICAOF(Option<(String, u16)>),
+    REPLCONF(Vec<String>),
+    PSYNC(String, i64),
+    WAIT(u64, u64),
+    ROLE,
+    INFO(Vec<String>),
+    CONFIG(ConfigCmd),
+    SHUTDOWN(shutdown::Options),
+    //The username is None for the legacy AUTH <password> form.
+    AUTH(Option<String>, String),
+    ACL(AclCmd),
+    CLIENT(ClientCmd),
+    SLOWLOG(SlowlogCmd),
+    LATENCY(LatencyCmd),
+    MONITOR,
     NULL,
 }
 
+impl Command {
+    //Commands that modify the dataset. Scripts are not listed since their
+    //writes go through `redis.call` and are checked one by one.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::SET(..)
+                | Command::RPUSH(..)
+                | Command::SADD(..)
+                | Command::HSET(..)
+                | Command::ZADD(..)
+                | Command::PEXPIREAT(..)
+                | Command::FLUSHDB
+                | Command::FLUSHALL
+                | Command::FUNCTION(FunctionCmd::LOAD(..))
+                | Command::FUNCTION(FunctionCmd::DELETE(_))
+                | Command::FUNCTION(FunctionCmd::RESTORE(..))
+                | Command::FUNCTION(FunctionCmd::FLUSH)
+        )
+    }
+
+    //Commands scripts may not call: those that run scripts, change the state
+    //of the connection or administer the server.
+    pub fn is_noscript(&self) -> bool {
+        matches!(
+            self,
+            Command::MULTI
+                | Command::EXEC
+                | Command::DISCARD
+                | Command::WATCH(_)
+                | Command::UNWATCH
+                | Command::EVAL(..)
+                | Command::EVALSHA(..)
+                | Command::SCRIPT(_)
+                | Command::FUNCTION(_)
+                | Command::FCALL(..)
+                | Command::SAVE
+                | Command::BGSAVE
+                | Command::BGREWRITEAOF
+                | Command::REPLICAOF(_)
+                | Command::REPLCONF(_)
+                | Command::PSYNC(..)
+                | Command::WAIT(..)
+                | Command::CONFIG(_)
+                | Command::SHUTDOWN(_)
+                | Command::AUTH(..)
+                | Command::ACL(_)
+                | Command::CLIENT(_)
+                | Command::SLOWLOG(_)
+                | Command::LATENCY(_)
+                | Command::MONITOR
+        )
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::PING => "ping",
+            Command::GET(_) => "get",
+            Command::SET(..) => "set",
+            Command::RPUSH(..) => "rpush",
+            Command::SADD(..) => "sadd",
+            Command::HSET(..) => "hset",
+            Command::ZADD(..) => "zadd",
+            Command::PEXPIREAT(..) => "pexpireat",
+            Command::KEYS(_) => "keys",
+            Command::FLUSHDB => "flushdb",
+            Command::FLUSHALL => "flushall",
+            Command::MULTI => "multi",
+            Command::EXEC => "exec",
+            Command::DISCARD => "discard",
+            Command::WATCH(_) => "watch",
+            Command::UNWATCH => "unwatch",
+            Command::EVAL(..) => "eval",
+            Command::EVALSHA(..) => "evalsha",
+            Command::SCRIPT(_) => "script",
+            Command::FUNCTION(_) => "function",
+            Command::FCALL(.., false) => "fcall",
+            Command::FCALL(.., true) => "fcall_ro",
+            Command::SAVE => "save",
+            Command::BGSAVE => "bgsave",
+            Command::LASTSAVE => "lastsave",
+            Command::BGREWRITEAOF => "bgrewriteaof",
+            Command::REPLICAOF(_) => "replicaof",
+            Command::REPLCONF(_) => "replconf",
+            Command::PSYNC(..) => "psync",
+            Command::WAIT(..) => "wait",
+            Command::ROLE => "role",
+            Command::INFO(_) => "info",
+            Command::CONFIG(_) => "config",
+            Command::SHUTDOWN(_) => "shutdown",
+            Command::AUTH(..) => "auth",
+            Command::ACL(_) => "acl",
+            Command::CLIENT(_) => "client",
+            Command::SLOWLOG(_) => "slowlog",
+            Command::LATENCY(_) => "latency",
+            Command::MONITOR => "monitor",
+            Command::NULL => "null",
+        }
+    }
+
+    //The subcommand ACL rules can name as `command|subcommand`.
+    pub fn subcommand(&self) -> Option<&'static str> {
+        match self {
+            Command::SCRIPT(ScriptCmd::LOAD(_)) => Some("load"),
+            Command::SCRIPT(ScriptCmd::EXISTS(_)) => Some("exists"),
+            Command::SCRIPT(ScriptCmd::FLUSH) => Some("flush"),
+            Command::SCRIPT(ScriptCmd::KILL) => Some("kill"),
+            Command::FUNCTION(FunctionCmd::LOAD(..)) => Some("load"),
+            Command::FUNCTION(FunctionCmd::DELETE(_)) => Some("delete"),
+            Command::FUNCTION(FunctionCmd::LIST(..)) => Some("list"),
+            Command::FUNCTION(FunctionCmd::DUMP) => Some("dump"),
+            Command::FUNCTION(FunctionCmd::RESTORE(..)) => Some("restore"),
+            Command::FUNCTION(FunctionCmd::FLUSH) => Some("flush"),
+            Command::FUNCTION(FunctionCmd::KILL) => Some("kill"),
+            Command::CONFIG(ConfigCmd::GET(_)) => Some("get"),
+            Command::CONFIG(ConfigCmd::SET(_)) => Some("set"),
+            Command::CONFIG(ConfigCmd::RESETSTAT) => Some("resetstat"),
+            Command::CONFIG(ConfigCmd::REWRITE) => Some("rewrite"),
+            Command::ACL(cmd) => Some(cmd.name()),
+            Command::CLIENT(cmd) => Some(cmd.name()),
+            Command::SLOWLOG(cmd) => Some(cmd.name()),
+            Command::LATENCY(cmd) => Some(cmd.name()),
+            _ => None,
+        }
+    }
+
+    //`command|subcommand`, as shown by CLIENT LIST and the ACL errors.
+    pub fn full_name(&self) -> String {
+        match self.subcommand() {
+            Some(sub) => format!("{}|{}", self.name(), sub),
+            None => self.name().to_string(),
+        }
+    }
+
+    //The keys the command touches, for the ACL key patterns. Scripts are
+    //checked against the keys they declare.
+    pub fn keys(&self) -> Vec<KeyAccess<'_>> {
+        let (keys, read, write): (Vec<&String>, bool, bool) = match self {
+            Command::GET(key) => (vec![key], true, false),
+            Command::SET(key, _)
+            | Command::RPUSH(key, _)
+            | Command::SADD(key, _)
+            | Command::HSET(key, _)
+            | Command::ZADD(key, _)
+            | Command::PEXPIREAT(key, _) => (vec![key], false, true),
+            Command::WATCH(keys) => (keys.iter().collect(), true, false),
+            Command::EVAL(_, keys, _) | Command::EVALSHA(_, keys, _) => (keys.iter().collect(), true, true),
+            Command::FCALL(_, keys, _, read_only) => (keys.iter().collect(), true, !read_only),
+            _ => (vec![], false, false),
+        };
+        keys.into_iter().map(|key| KeyAccess { key, read, write }).collect()
+    }
+
+    //The command as a client would send it, for the write commands that get
+    //propagated to the AOF.
+    pub fn to_frame(&self) -> Option<Frame> {
+        let args: Vec<String> = match self {
+            Command::SET(key, val) => vec!["SET".to_string(), key.clone(), val.clone()],
+            Command::RPUSH(key, items) => [vec!["RPUSH".to_string(), key.clone()], items.clone()].concat(),
+            Command::SADD(key, members) => [vec!["SADD".to_string(), key.clone()], members.clone()].concat(),
+            Command::HSET(key, pairs) => {
+                let mut args = vec!["HSET".to_string(), key.clone()];
+                for (field, val) in pairs {
+                    args.push(field.clone());
+                    args.push(val.clone());
+                }
+                args
+            },
+            Command::ZADD(key, members) => {
+                let mut args = vec!["ZADD".to_string(), key.clone()];
+                for (score, member) in members {
+                    args.push(score.to_string());
+                    args.push(member.clone());
+                }
+                args
+            },
+            Command::PEXPIREAT(key, at) => vec!["PEXPIREAT".to_string(), key.clone(), at.to_string()],
+            Command::FLUSHDB => vec!["FLUSHDB".to_string()],
+            Command::FLUSHALL => vec!["FLUSHALL".to_string()],
+            Command::FUNCTION(FunctionCmd::LOAD(code, true)) => {
+                vec!["FUNCTION".to_string(), "LOAD".to_string(), "REPLACE".to_string(), code.clone()]
+            },
+            Command::FUNCTION(FunctionCmd::LOAD(code, false)) => {
+                vec!["FUNCTION".to_string(), "LOAD".to_string(), code.clone()]
+            },
+            Command::FUNCTION(FunctionCmd::DELETE(name)) => {
+                vec!["FUNCTION".to_string(), "DELETE".to_string(), name.clone()]
+            },
+            Command::FUNCTION(FunctionCmd::RESTORE(payload, policy)) => {
+                let policy = match policy {
+                    RestorePolicy::APPEND => "APPEND",
+                    RestorePolicy::REPLACE => "REPLACE",
+                    RestorePolicy::FLUSH => "FLUSH",
+                };
+                vec!["FUNCTION".to_string(), "RESTORE".to_string(), payload.clone(), policy.to_string()]
+            },
+            Command::FUNCTION(FunctionCmd::FLUSH) => vec!["FUNCTION".to_string(), "FLUSH".to_string()],
+            _ => return None,
+        };
+        let mut frame = Frame::array();
+        for arg in args {
+            frame.push_bulk(Bytes::from(arg));
+        }
+        Some(frame)
+    }
+
+    fn operation(&self) -> Operation {
+        match self {
+            Command::EVAL(..) | Command::EVALSHA(..) => Operation::Script,
+            Command::FCALL(..) => Operation::Function,
+            cmd => Operation::Command(cmd.name().to_uppercase()),
+        }
+    }
+}
+
+//The connection a command runs for. The commands its scripts call are checked
+//against the ACL of this user. Commands replayed from the AOF or sent by a
+//master have no caller.
+pub struct Caller<'a> {
+    pub user: &'a str,
+    pub client: &'a Client,
+}
+
 #[derive(Debug)]
 pub struct Handler {
     command: Command,
-    db: Arc<Mutex<HashMap<String, String>>>,
+    //The command as received, for the slow log.
+    argv: Frame,
+    db: Arc<Mutex<Db>>,
+    shared: Shared,
+    transaction: Option<Transaction>,
+    watched: Vec<(String, u64)>,
+    peer_ip: String,
+    //Set when the connection is a replica: the port it announced with
+    //REPLCONF, and its registration once PSYNC was answered.
+    listening_port: u16,
+    replica: Option<u64>,
+    sync: Option<ReplicaSync>,
+    //Set once SHUTDOWN succeeded: the connection is closed without a reply.
+    closing: bool,
+    //Connections start authenticated as the default user when it has no
+    //password.
+    authenticated: bool,
+    user: String,
+    client: Arc<Client>,
+    //Set once MONITOR was answered: the connection then only streams the
+    //commands of the others.
+    monitor: Option<Receiver<Bytes>>,
+    //CLIENT REPLY state: OFF silences every reply, SKIP the next few.
+    replies_off: bool,
+    skip_replies: u8,
+}
+
+//Commands queued between MULTI and EXEC. `aborted` is set when a command
+//fails to parse while queueing, so that EXEC refuses to run the block.
+#[derive(Debug, Default)]
+struct Transaction {
+    queue: Vec<Command>,
+    aborted: bool,
 }
 
 impl Handler {
-    pub fn new(database: Arc<Mutex<HashMap<String, String>>>) -> Handler {
+    pub fn new(database: Arc<Mutex<Db>>) -> Handler {
+        let shared = database.lock().unwrap().shared();
+        Handler::with_shared(database, shared)
+    }
+
+    //For the server, which must not wait on the database lock to accept a
+    //connection while a script holds it.
+    pub fn with_shared(database: Arc<Mutex<Db>>, shared: Shared) -> Handler {
+        let authenticated = !shared.acl.auth_required();
+        let client = shared.clients.register(acl::DEFAULT_USER);
         Handler {
             command: Command::NULL,
+            argv: Frame::Null,
             db: database,
+            shared,
+            transaction: None,
+            watched: Vec::new(),
+            peer_ip: "127.0.0.1".to_string(),
+            listening_port: 0,
+            replica: None,
+            sync: None,
+            closing: false,
+            authenticated,
+            user: acl::DEFAULT_USER.to_string(),
+            client,
+            monitor: None,
+            replies_off: false,
+            skip_replies: 0,
+        }
+    }
+
+    //Unix socket clients have no peer address and show the socket path
+    //instead, as `path:0`.
+    pub fn set_addresses(&mut self, peer: Option<SocketAddr>, local: String) {
+        if let Some(addr) = peer {
+            self.peer_ip = addr.ip().to_string();
+        }
+        let addr = peer.map(|addr| addr.to_string()).unwrap_or(local.clone());
+        self.client.set_addresses(addr, local);
+    }
+
+    pub fn client(&self) -> Arc<Client> {
+        self.client.clone()
+    }
+
+    //Whether the reply to the command just run is to be sent, as set by
+    //CLIENT REPLY.
+    pub fn should_reply(&mut self) -> bool {
+        if self.skip_replies > 0 {
+            self.skip_replies -= 1;
+            return false;
         }
+        !self.replies_off
+    }
+
+    //After PSYNC was answered, the connection stops serving commands and
+    //streams to the replica instead.
+    pub fn take_sync(&mut self) -> Option<ReplicaSync> {
+        self.sync.take()
+    }
+
+    pub fn take_monitor(&mut self) -> Option<Receiver<Bytes>> {
+        self.monitor.take()
     }
     
+    pub fn closing(&self) -> bool {
+        self.closing
+    }
+
     pub fn get_command(&mut self, frame: Frame) -> Result<(), String> {
-        match frame {
-            Frame::Array(mut vec) => {
-                if vec.len() > 3 {
-                    return Err("Too many arguments in command".to_string());
+        let argv = frame.clone();
+        match parse_command(frame) {
+            Ok(cmd) => {
+                self.command = cmd;
+                self.argv = argv;
+                Ok(())
+            },
+            Err(e) => {
+                self.flag_transaction();
+                Err(e)
+            },
+        }
+    }
+
+    //A command refused while queueing makes EXEC discard the transaction.
+    fn flag_transaction(&mut self) {
+        if let Some(tx) = &mut self.transaction {
+            tx.aborted = true;
+        }
+    }
+    
+    //Commands refused by NOAUTH or the ACL count as rejected calls. Queued
+    //commands are counted when EXEC runs them. The time spent paused by
+    //CLIENT PAUSE is not part of a command's duration. Slow commands are
+    //latency events, "fast-command" for those in the fast category.
+    pub fn execute_cmd(&mut self) -> Result<Frame, String> {
+        let name = self.command.full_name();
+        self.client.record(name.clone());
+        let result = match self.check_access() {
+            Err(e) => {
+                self.shared.stats.reject(&name);
+                self.flag_transaction();
+                Err(e)
+            },
+            Ok(()) => {
+                if self.shared.monitors.is_active() {
+                    self.shared.monitors.feed(&self.argv, &self.client.addr());
+                }
+                self.wait_while_paused();
+                let start = Instant::now();
+                let result = self.dispatch();
+                let elapsed = start.elapsed();
+                if result != Ok(Frame::Simple("QUEUED".to_string())) {
+                    self.shared.stats.call(&name, elapsed, result.is_ok());
+                    self.shared.slowlog.record(elapsed, &self.argv, &self.client);
+                    let fast = acl::has_category(self.command.name(), self.command.subcommand(), "fast");
+                    self.shared.latency.add_sample_if_needed(if fast { "fast-command" } else { "command" }, elapsed);
+                }
+                result
+            },
+        };
+        let multi = self.transaction.as_ref().map(|tx| tx.queue.len());
+        self.client.finish(multi, self.watched.len());
+        result
+    }
+
+    fn check_access(&self) -> Result<(), String> {
+        if matches!(self.command, Command::AUTH(..)) {
+            return Ok(());
+        }
+        if !self.authenticated && self.shared.acl.auth_required() {
+            return Err(NOAUTH.to_string());
+        }
+        self.check_permissions()
+    }
+
+    fn dispatch(&mut self) -> Result<Frame, String> {
+        match self.command.clone() {
+            Command::MULTI => {
+                if self.transaction.is_some() {
+                    Err("MULTI calls can not be nested".to_string())
                 } else {
-                    match &vec[0] {
-                        Frame::Simple(cmd) => {
-                            if cmd.to_uppercase() == "PING".to_string() {
-                                self.command = Command::PING;
-                                return Ok(());
-                            } else {
-                                return Err("Unknown simple command".to_string());
-                            }
-                        },
-                        Frame::Bulk(cmd) => {
-                            match str::from_utf8(&cmd.to_ascii_uppercase()).unwrap() {
-                                "GET" => {
-                                    if vec.len() != 2 {
-                                        return Err("incorrect number of arguments for GET command".to_string());
-                                    } else {
-                                        self.command = Command::GET( vec[1].to_string().unwrap() );
-                                        Ok(())
+                    self.transaction = Some(Transaction::default());
+                    Ok(Frame::Simple("OK".to_string()))
+                }
+            },
+            Command::EXEC => {
+                let watched = std::mem::take(&mut self.watched);
+                match self.transaction.take() {
+                    None => Err("EXEC without MULTI".to_string()),
+                    Some(tx) if tx.aborted => {
+                        Err("EXECABORT Transaction discarded because of previous errors.".to_string())
+                    },
+                    Some(tx) => {
+                        let mut db = lock_db(&self.db, &self.shared.busy)?;
+                        for (key, _) in &watched {
+                            db.expire_if_needed(key);
+                        }
+                        if watched.iter().any(|(key, version)| db.version(key) != *version) {
+                            return Ok(Frame::Null);
+                        }
+                        let mut replies = Frame::array();
+                        self.shared.busy.run(Operation::Command("EXEC".to_string()), || {
+                            atomically(&mut db, |db| {
+                                for cmd in &tx.queue {
+                                    let start = Instant::now();
+                                    let result = run_cmd(cmd, db, Some(&self.caller()));
+                                    self.shared.stats.call(&cmd.full_name(), start.elapsed(), result.is_ok());
+                                    match result {
+                                        Ok(frame) => replies.push_frame(frame),
+                                        Err(e) => replies.push_frame(Frame::Error(e)),
                                     }
-                                },
-                                "SET" => {
-                                    if vec.len() != 3 {
-                                        return Err("incorrect number of arguments for SET command".to_string());
-                                    } else {
-                                        self.command = Command::SET ( 
-                                            vec[1].to_string().unwrap(),
-                                            vec[2].clone(),
-                                        );
-                                        Ok(())
+                                }
+                            })
+                        });
+                        Ok(replies)
+                    },
+                }
+            },
+            Command::DISCARD => {
+                self.watched.clear();
+                if self.transaction.take().is_some() {
+                    Ok(Frame::Simple("OK".to_string()))
+                } else {
+                    Err("DISCARD without MULTI".to_string())
+                }
+            },
+            Command::WATCH(keys) => {
+                if self.transaction.is_some() {
+                    return Err("WATCH inside MULTI is not allowed".to_string());
+                }
+                let mut db = lock_db(&self.db, &self.shared.busy)?;
+                for key in keys {
+                    db.expire_if_needed(&key);
+                    let version = db.version(&key);
+                    self.watched.push((key, version));
+                }
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::UNWATCH => {
+                if self.transaction.is_none() {
+                    self.watched.clear();
+                }
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::REPLICAOF(_) | Command::REPLCONF(_) | Command::PSYNC(..) | Command::WAIT(..) | Command::SHUTDOWN(_)
+            | Command::ACL(_) | Command::CLIENT(_) | Command::MONITOR
+            | Command::SCRIPT(ScriptCmd::KILL) | Command::FUNCTION(FunctionCmd::KILL) if self.transaction.is_some() => {
+                self.flag_transaction();
+                Err("Command not allowed inside a transaction".to_string())
+            },
+            Command::SCRIPT(ScriptCmd::KILL) => {
+                self.shared.busy.kill(false)?;
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::FUNCTION(FunctionCmd::KILL) => {
+                self.shared.busy.kill(true)?;
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::REPLICAOF(None) => {
+                self.shared.replication.become_master();
+                self.shared.config.lock().unwrap().replicaof = None;
+                log::notice("MASTER MODE enabled");
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::REPLICAOF(Some((host, port))) => {
+                replication::replicaof(self.db.clone(), self.shared.clone(), host.clone(), port)?;
+                self.shared.config.lock().unwrap().replicaof = Some((host.clone(), port));
+                log::notice(&format!("REPLICAOF {}:{} enabled", host, port));
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::REPLCONF(args) => {
+                match (args[0].to_lowercase().as_str(), args.get(1)) {
+                    ("listening-port", Some(port)) => {
+                        self.listening_port = port.parse::<u16>().map_err(|_| "Invalid listening port".to_string())?;
+                    },
+                    ("ack", Some(offset)) => {
+                        let offset = offset.parse::<u64>().map_err(|_| "Invalid offset".to_string())?;
+                        if let Some(id) = self.replica {
+                            self.shared.replication.ack(id, offset);
+                        }
+                    },
+                    ("capa", _) | ("getack", _) => (),
+                    (option, _) => return Err(format!("Unrecognized REPLCONF option: {}", option)),
+                }
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::PSYNC(replid, offset) => {
+                let db = lock_db(&self.db, &self.shared.busy)?;
+                let (reply, sync) = self.shared.replication.psync(&db, &replid, offset, self.peer_ip.clone(), self.listening_port);
+                self.replica = Some(sync.id);
+                self.sync = Some(sync);
+                self.client.set_kind(Kind::REPLICA);
+                Ok(reply)
+            },
+            Command::WAIT(numreplicas, timeout) => {
+                let acked = self.shared.replication.wait(numreplicas as usize, timeout)?;
+                Ok(Frame::Integer(acked as i64))
+            },
+            Command::AUTH(username, password) => {
+                if username.is_none() && self.shared.acl.default_nopass() {
+                    return Err("AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string());
+                }
+                let username = username.unwrap_or(acl::DEFAULT_USER.to_string());
+                if !self.shared.acl.authenticate(&username, &password) {
+                    self.shared.acl.log("auth", "toplevel", "AUTH", &username, self.client_info());
+                    return Err(WRONGPASS.to_string());
+                }
+                self.authenticated = true;
+                self.client.set_user(&username);
+                self.user = username;
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::ACL(cmd) => {
+                let aclfile = self.shared.config.lock().unwrap().aclfile.clone();
+                let reply = acl::execute(&cmd, &self.shared.acl, &self.user, aclfile.as_deref())?;
+                //Connections of deleted users are closed, this one included
+                //once it got its reply.
+                if let AclCmd::DELUSER(names) = cmd {
+                    for user in names {
+                        self.shared.clients.kill(&Filter { user: Some(user), ..Filter::default() }, self.client.id());
+                    }
+                }
+                Ok(reply)
+            },
+            Command::CLIENT(ClientCmd::REPLY(mode)) => {
+                match mode {
+                    ReplyMode::ON => {
+                        self.replies_off = false;
+                        self.skip_replies = 0;
+                    },
+                    ReplyMode::OFF => self.replies_off = true,
+                    //This reply and the next one.
+                    ReplyMode::SKIP => self.skip_replies = 2,
+                }
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::CLIENT(cmd) => client::execute(&cmd, &self.shared.clients, &self.client),
+            Command::MONITOR => {
+                if self.replica.is_none() {
+                    self.monitor = Some(self.shared.monitors.subscribe());
+                    self.client.set_monitor();
+                }
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::SHUTDOWN(options) => {
+                shutdown::prepare(&self.db, &self.shared, options)?;
+                self.closing = !options.abort;
+                Ok(Frame::Simple("OK".to_string()))
+            },
+            Command::NULL => Err("Tried to execute null command".to_string()),
+            cmd => {
+                if cmd.is_write() && !self.shared.replication.is_master() {
+                    self.flag_transaction();
+                    return Err(READONLY.to_string());
+                }
+                if let Some(tx) = &mut self.transaction {
+                    tx.queue.push(cmd);
+                    return Ok(Frame::Simple("QUEUED".to_string()));
+                }
+                let mut db = lock_db(&self.db, &self.shared.busy)?;
+                self.shared.busy.run(cmd.operation(), || run_cmd(&cmd, &mut db, Some(&self.caller())))
+            },
+        }
+    }
+
+    //Refuses commands the connection's user is not allowed to run, logging
+    //the attempt to the ACL LOG.
+    fn check_permissions(&self) -> Result<(), String> {
+        let cmd = &self.command;
+        if let Err(denial) = self.shared.acl.check(&self.user, cmd.name(), cmd.subcommand(), &cmd.keys()) {
+            let context = if self.transaction.is_some() { "multi" } else { "toplevel" };
+            self.shared.acl.log(denial.reason(), context, denial.object(), &self.user, self.client_info());
+            return Err(denial.error(&self.user));
+        }
+        Ok(())
+    }
+
+    fn client_info(&self) -> String {
+        self.client.info()
+    }
+
+    fn caller(&self) -> Caller<'_> {
+        Caller { user: &self.user, client: &self.client }
+    }
+
+    //Holds the command back while CLIENT PAUSE applies to it. Replicas and
+    //CLIENT itself are never paused, so that the pause can be lifted.
+    fn wait_while_paused(&self) {
+        if self.replica.is_some() || matches!(self.command, Command::CLIENT(_)) {
+            return;
+        }
+        let write = match &self.command {
+            Command::EVAL(..) | Command::EVALSHA(..) | Command::FCALL(.., false) => true,
+            Command::EXEC => self.transaction.as_ref().is_some_and(|tx| tx.queue.iter().any(may_write)),
+            cmd => may_write(cmd),
+        };
+        if self.shared.clients.paused(write) {
+            replication::block(|| {
+                while self.shared.clients.paused(write) && !self.client.is_killed() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            });
+        }
+    }
+}
+
+impl Drop for Handler {
+    fn drop(&mut self) {
+        self.shared.clients.unregister(self.client.id());
+    }
+}
+
+//HELPER FN
+
+//Scripts may write too, unless run read-only.
+fn may_write(cmd: &Command) -> bool {
+    cmd.is_write() || matches!(cmd, Command::EVAL(..) | Command::EVALSHA(..) | Command::FCALL(.., false))
+}
+
+//Waits for the database lock, unless whoever holds it has been running for
+//longer than the busy threshold, in which case the caller gets -BUSY.
+//Commands that were waiting while the server shut down are refused.
+pub(crate) fn lock_db<'a>(db: &'a Mutex<Db>, busy: &Busy) -> Result<MutexGuard<'a, Db>, String> {
+    loop {
+        let guard = match db.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                if let Some(e) = busy.busy_error() {
+                    return Err(e);
+                }
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            },
+        };
+        if guard.shutdown().is_triggered() {
+            return Err(shutdown::SHUTTING_DOWN.to_string());
+        }
+        return Ok(guard);
+    }
+}
+
+pub(crate) fn parse_command(frame: Frame) -> Result<Command, String> {
+    match frame {
+        Frame::Array(mut vec) => {
+            if vec.is_empty() {
+                Err("Empty command".to_string())
+            } else {
+                match vec[0].clone() {
+                    Frame::Simple(cmd) => {
+                        if cmd.to_uppercase() == "PING" {
+                            Ok(Command::PING)
+                        } else {
+                            Err("Unknown simple command".to_string())
+                        }
+                    },
+                    Frame::Bulk(cmd) => {
+                        let name = cmd.to_ascii_uppercase();
+                        let name = str::from_utf8(&name)
+                            .map_err(|_| format!("Unknown command: {}", String::from_utf8_lossy(&cmd)))?;
+                        match name {
+                            "GET" => {
+                                if vec.len() != 2 {
+                                    Err("incorrect number of arguments for GET command".to_string())
+                                } else {
+                                    Ok(Command::GET( vec[1].to_string()? ))
+                                }
+                            },
+                            "SET" => {
+                                if vec.len() != 3 {
+                                    Err("incorrect number of arguments for SET command".to_string())
+                                } else {
+                                    Ok(Command::SET ( 
+                                        vec[1].to_string()?,
+                                        vec[2].to_string()?,
+                                    ))
+                                }
+                            },
+                            "RPUSH" | "SADD" => {
+                                if vec.len() < 3 {
+                                    return Err(format!("incorrect number of arguments for {} command", name));
+                                }
+                                let mut args = string_args(&mut vec[1..])?;
+                                let key = args.remove(0);
+                                Ok(if name == "RPUSH" { Command::RPUSH(key, args) } else { Command::SADD(key, args) })
+                            },
+                            "HSET" | "ZADD" => {
+                                if vec.len() < 4 || vec.len() % 2 != 0 {
+                                    return Err(format!("incorrect number of arguments for {} command", name));
+                                }
+                                let mut args = string_args(&mut vec[1..])?;
+                                let key = args.remove(0);
+                                let mut args = args.into_iter();
+                                let mut pairs = Vec::new();
+                                while let (Some(a), Some(b)) = (args.next(), args.next()) {
+                                    pairs.push((a, b));
+                                }
+                                if name == "HSET" {
+                                    return Ok(Command::HSET(key, pairs));
+                                }
+                                let mut members = Vec::new();
+                                for (score, member) in pairs {
+                                    match score.parse::<f64>() {
+                                        Ok(score) if !score.is_nan() => members.push((score, member)),
+                                        _ => return Err("value is not a valid float".to_string()),
                                     }
-                                },
-                                cmd=> return Err(format!("Unknown command: {}", cmd)),
-                            }
+                                }
+                                Ok(Command::ZADD(key, members))
+                            },
+                            "PEXPIREAT" => {
+                                if vec.len() != 3 {
+                                    return Err("incorrect number of arguments for PEXPIREAT command".to_string());
+                                }
+                                let at = vec[2].to_string()?.parse::<u64>()
+                                    .map_err(|_| "value is not an integer or out of range".to_string())?;
+                                Ok(Command::PEXPIREAT(vec[1].to_string()?, at))
+                            },
+                            "PING" => no_args(&vec, Command::PING),
+                            "KEYS" => {
+                                if vec.len() != 2 {
+                                    Err("incorrect number of arguments for KEYS command".to_string())
+                                } else {
+                                    Ok(Command::KEYS(vec[1].to_string()?))
+                                }
+                            },
+                            "FLUSHDB" => no_args(&vec, Command::FLUSHDB),
+                            "FLUSHALL" => no_args(&vec, Command::FLUSHALL),
+                            "SAVE" => no_args(&vec, Command::SAVE),
+                            "BGSAVE" => no_args(&vec, Command::BGSAVE),
+                            "LASTSAVE" => no_args(&vec, Command::LASTSAVE),
+                            "BGREWRITEAOF" => no_args(&vec, Command::BGREWRITEAOF),
+                            "WATCH" => {
+                                if vec.len() < 2 {
+                                    Err("incorrect number of arguments for WATCH command".to_string())
+                                } else {
+                                    Ok(Command::WATCH(string_args(&mut vec[1..])?))
+                                }
+                            },
+                            "UNWATCH" => no_args(&vec, Command::UNWATCH),
+                            "EVAL" | "EVALSHA" => {
+                                if vec.len() < 3 {
+                                    return Err("incorrect number of arguments for EVAL command".to_string());
+                                }
+                                let mut args = string_args(&mut vec[1..])?;
+                                let rest = args.split_off(2);
+                                let (keys, argv) = script::split_keys(&args[1], rest)?;
+                                if cmd.eq_ignore_ascii_case(b"EVAL") {
+                                    Ok(Command::EVAL(args.remove(0), keys, argv))
+                                } else {
+                                    Ok(Command::EVALSHA(args.remove(0), keys, argv))
+                                }
+                            },
+                            "SCRIPT" => {
+                                if vec.len() < 2 {
+                                    return Err("incorrect number of arguments for SCRIPT command".to_string());
+                                }
+                                let mut args = string_args(&mut vec[1..])?;
+                                match args[0].to_uppercase().as_str() {
+                                    "LOAD" if args.len() == 2 => Ok(Command::SCRIPT(ScriptCmd::LOAD(args.remove(1)))),
+                                    "EXISTS" if args.len() >= 2 => Ok(Command::SCRIPT(ScriptCmd::EXISTS(args.split_off(1)))),
+                                    "FLUSH" if args.len() <= 2 => Ok(Command::SCRIPT(ScriptCmd::FLUSH)),
+                                    "KILL" if args.len() == 1 => Ok(Command::SCRIPT(ScriptCmd::KILL)),
+                                    sub => Err(format!("Unknown SCRIPT subcommand or wrong number of arguments for '{}'", sub)),
+                                }
+                            },
+                            "FCALL" | "FCALL_RO" => {
+                                if vec.len() < 3 {
+                                    return Err("incorrect number of arguments for FCALL command".to_string());
+                                }
+                                let mut args = string_args(&mut vec[1..])?;
+                                let rest = args.split_off(2);
+                                let (keys, argv) = script::split_keys(&args[1], rest)?;
+                                let read_only = cmd.eq_ignore_ascii_case(b"FCALL_RO");
+                                Ok(Command::FCALL(args.remove(0), keys, argv, read_only))
+                            },
+                            "FUNCTION" => {
+                                if vec.len() < 2 {
+                                    return Err("incorrect number of arguments for FUNCTION command".to_string());
+                                }
+                                let args = string_args(&mut vec[1..])?;
+                                Ok(Command::FUNCTION(parse_function(args)?))
+                            },
+                            "REPLICAOF" | "SLAVEOF" => {
+                                if vec.len() != 3 {
+                                    return Err("incorrect number of arguments for REPLICAOF command".to_string());
+                                }
+                                let args = string_args(&mut vec[1..])?;
+                                if args[0].eq_ignore_ascii_case("NO") && args[1].eq_ignore_ascii_case("ONE") {
+                                    Ok(Command::REPLICAOF(None))
+                                } else {
+                                    let port = args[1].parse::<u16>().map_err(|_| "Invalid master port".to_string())?;
+                                    Ok(Command::REPLICAOF(Some((args[0].clone(), port))))
+                                }
+                            },
+                            "REPLCONF" => {
+                                if vec.len() < 2 {
+                                    return Err("incorrect number of arguments for REPLCONF command".to_string());
+                                }
+                                Ok(Command::REPLCONF(string_args(&mut vec[1..])?))
+                            },
+                            "PSYNC" => {
+                                if vec.len() != 3 {
+                                    return Err("incorrect number of arguments for PSYNC command".to_string());
+                                }
+                                let args = string_args(&mut vec[1..])?;
+                                let offset = args[1].parse::<i64>().map_err(|_| "Invalid PSYNC offset".to_string())?;
+                                Ok(Command::PSYNC(args[0].clone(), offset))
+                            },
+                            "WAIT" => {
+                                if vec.len() != 3 {
+                                    return Err("incorrect number of arguments for WAIT command".to_string());
+                                }
+                                let args = string_args(&mut vec[1..])?;
+                                let numreplicas = args[0].parse::<u64>().map_err(|_| "value is not an integer or out of range".to_string())?;
+                                let timeout = args[1].parse::<u64>().map_err(|_| "timeout is not an integer or out of range".to_string())?;
+                                Ok(Command::WAIT(numreplicas, timeout))
+                            },
+                            "ROLE" => no_args(&vec, Command::ROLE),
+                            "MONITOR" => no_args(&vec, Command::MONITOR),
+                            "INFO" => Ok(Command::INFO(string_args(&mut vec[1..])?)),
+                            "CONFIG" => {
+                                if vec.len() < 2 {
+                                    return Err("incorrect number of arguments for CONFIG command".to_string());
+                                }
+                                let args = string_args(&mut vec[1..])?;
+                                Ok(Command::CONFIG(parse_config(args)?))
+                            },
+                            "AUTH" => {
+                                let mut args = string_args(&mut vec[1..])?;
+                                match args.len() {
+                                    1 => Ok(Command::AUTH(None, args.remove(0))),
+                                    2 => Ok(Command::AUTH(Some(args.remove(0)), args.remove(0))),
+                                    _ => Err("incorrect number of arguments for AUTH command".to_string()),
+                                }
+                            },
+                            "ACL" => {
+                                if vec.len() < 2 {
+                                    return Err("incorrect number of arguments for ACL command".to_string());
+                                }
+                                let args = string_args(&mut vec[1..])?;
+                                Ok(Command::ACL(parse_acl(args)?))
+                            },
+                            "CLIENT" => {
+                                if vec.len() < 2 {
+                                    return Err("incorrect number of arguments for CLIENT command".to_string());
+                                }
+                                let args = string_args(&mut vec[1..])?;
+                                Ok(Command::CLIENT(parse_client(args)?))
+                            },
+                            "SLOWLOG" => {
+                                if vec.len() < 2 {
+                                    return Err("incorrect number of arguments for SLOWLOG command".to_string());
+                                }
+                                let args = string_args(&mut vec[1..])?;
+                                Ok(Command::SLOWLOG(parse_slowlog(args)?))
+                            },
+                            "LATENCY" => {
+                                if vec.len() < 2 {
+                                    return Err("incorrect number of arguments for LATENCY command".to_string());
+                                }
+                                let args = string_args(&mut vec[1..])?;
+                                Ok(Command::LATENCY(parse_latency(args)?))
+                            },
+                            "SHUTDOWN" => {
+                                let args = string_args(&mut vec[1..])?;
+                                Ok(Command::SHUTDOWN(parse_shutdown(&args)?))
+                            },
+                            "MULTI" => no_args(&vec, Command::MULTI),
+                            "EXEC" => no_args(&vec, Command::EXEC),
+                            "DISCARD" => no_args(&vec, Command::DISCARD),
+                            cmd => Err(format!("Unknown command: {}", cmd)),
                         }
-                        _ => return Err("Unexpected frame".to_string()),
                     }
+                    _ => Err("Unexpected frame".to_string()),
                 }
             }
-            _ => Err("Unexpected frame".to_string()),
         }
+        _ => Err("Unexpected frame".to_string()),
     }
-    
-    pub fn execute_cmd(&mut self) -> Result<Frame, String> {
-        match &self.command {
-            Command::PING => {
-                Ok(Frame::Simple("PONG".to_string()))
-            },
-            Command::GET(key) => {
-                if let Some(val) = self.db.lock().unwrap().get(key) {
+}
+
+fn no_args(vec: &[Frame], cmd: Command) -> Result<Command, String> {
+    if vec.len() != 1 {
+        Err(format!("incorrect number of arguments for {:?} command", cmd))
+    } else {
+        Ok(cmd)
+    }
+}
+
+fn parse_function(mut args: Vec<String>) -> Result<FunctionCmd, String> {
+    let options: Vec<String> = args[1..].iter().map(|a| a.to_uppercase()).collect();
+    match args[0].to_uppercase().as_str() {
+        "LOAD" => {
+            match options.as_slice() {
+                [_] => Ok(FunctionCmd::LOAD(args.remove(1), false)),
+                [replace, _] if replace == "REPLACE" => Ok(FunctionCmd::LOAD(args.remove(2), true)),
+                _ => Err("Unknown option given to FUNCTION LOAD".to_string()),
+            }
+        },
+        "DELETE" if args.len() == 2 => Ok(FunctionCmd::DELETE(args.remove(1))),
+        "LIST" => {
+            let mut pattern = None;
+            let mut with_code = false;
+            let mut i = 1;
+            while i < args.len() {
+                match options[i - 1].as_str() {
+                    "WITHCODE" => with_code = true,
+                    "LIBRARYNAME" if i + 1 < args.len() => {
+                        pattern = Some(args[i + 1].clone());
+                        i += 1;
+                    },
+                    opt => return Err(format!("Unknown argument {}", opt)),
+                }
+                i += 1;
+            }
+            Ok(FunctionCmd::LIST(pattern, with_code))
+        },
+        "DUMP" if args.len() == 1 => Ok(FunctionCmd::DUMP),
+        "RESTORE" if args.len() == 2 || args.len() == 3 => {
+            let policy = match options.get(1).map(|o| o.as_str()) {
+                None | Some("APPEND") => RestorePolicy::APPEND,
+                Some("REPLACE") => RestorePolicy::REPLACE,
+                Some("FLUSH") => RestorePolicy::FLUSH,
+                Some(opt) => return Err(format!("Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE, got {}", opt)),
+            };
+            Ok(FunctionCmd::RESTORE(args.remove(1), policy))
+        },
+        "FLUSH" if args.len() <= 2 => Ok(FunctionCmd::FLUSH),
+        "KILL" if args.len() == 1 => Ok(FunctionCmd::KILL),
+        sub => Err(format!("Unknown FUNCTION subcommand or wrong number of arguments for '{}'", sub)),
+    }
+}
+
+fn parse_config(args: Vec<String>) -> Result<ConfigCmd, String> {
+    match args[0].to_uppercase().as_str() {
+        "GET" if args.len() >= 2 => Ok(ConfigCmd::GET(args[1..].to_vec())),
+        "SET" if args.len() >= 3 && args.len() % 2 == 1 => {
+            let changes = args[1..].chunks(2)
+                .map(|pair| (pair[0].to_lowercase(), pair[1].clone()))
+                .collect();
+            Ok(ConfigCmd::SET(changes))
+        },
+        "RESETSTAT" if args.len() == 1 => Ok(ConfigCmd::RESETSTAT),
+        "REWRITE" if args.len() == 1 => Ok(ConfigCmd::REWRITE),
+        sub => Err(format!("Unknown CONFIG subcommand or wrong number of arguments for '{}'", sub)),
+    }
+}
+
+fn parse_acl(mut args: Vec<String>) -> Result<AclCmd, String> {
+    match (args[0].to_uppercase().as_str(), args.len()) {
+        ("SETUSER", 2..) => {
+            let rules = args.split_off(2);
+            Ok(AclCmd::SETUSER(args.remove(1), rules))
+        },
+        ("GETUSER", 2) => Ok(AclCmd::GETUSER(args.remove(1))),
+        ("DELUSER", 2..) => Ok(AclCmd::DELUSER(args.split_off(1))),
+        ("LIST", 1) => Ok(AclCmd::LIST),
+        ("USERS", 1) => Ok(AclCmd::USERS),
+        ("WHOAMI", 1) => Ok(AclCmd::WHOAMI),
+        ("CAT", 1) => Ok(AclCmd::CAT(None)),
+        ("CAT", 2) => Ok(AclCmd::CAT(Some(args.remove(1)))),
+        ("LOG", 1) => Ok(AclCmd::LOG(None)),
+        ("LOG", 2) if args[1].eq_ignore_ascii_case("RESET") => Ok(AclCmd::LOGRESET),
+        ("LOG", 2) => {
+            let count = args[1].parse::<usize>().map_err(|_| "value is out of range, must be positive".to_string())?;
+            Ok(AclCmd::LOG(Some(count)))
+        },
+        ("LOAD", 1) => Ok(AclCmd::LOAD),
+        ("SAVE", 1) => Ok(AclCmd::SAVE),
+        (sub, _) => Err(format!("Unknown ACL subcommand or wrong number of arguments for '{}'", sub)),
+    }
+}
+
+fn parse_slowlog(args: Vec<String>) -> Result<SlowlogCmd, String> {
+    match (args[0].to_uppercase().as_str(), args.len()) {
+        ("GET", 1) => Ok(SlowlogCmd::GET(None)),
+        ("GET", 2) => {
+            let count = args[1].parse::<i64>().ok()
+                .filter(|count| *count >= -1)
+                .ok_or("count should be greater than or equal to -1")?;
+            Ok(SlowlogCmd::GET(Some(count)))
+        },
+        ("LEN", 1) => Ok(SlowlogCmd::LEN),
+        ("RESET", 1) => Ok(SlowlogCmd::RESET),
+        (sub, _) => Err(format!("Unknown SLOWLOG subcommand or wrong number of arguments for '{}'", sub)),
+    }
+}
+
+fn parse_latency(mut args: Vec<String>) -> Result<LatencyCmd, String> {
+    let sub = args.remove(0).to_uppercase();
+    match (sub.as_str(), args.len()) {
+        ("LATEST", 0) => Ok(LatencyCmd::LATEST),
+        ("HISTORY", 1) => Ok(LatencyCmd::HISTORY(args.remove(0))),
+        ("RESET", _) => Ok(LatencyCmd::RESET(args)),
+        ("HISTOGRAM", _) => Ok(LatencyCmd::HISTOGRAM(args)),
+        ("DOCTOR", 0) => Ok(LatencyCmd::DOCTOR),
+        (sub, _) => Err(format!("Unknown LATENCY subcommand or wrong number of arguments for '{}'", sub)),
+    }
+}
+
+fn parse_client(mut args: Vec<String>) -> Result<ClientCmd, String> {
+    let on_off = |value: &str| match value.to_lowercase().as_str() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("syntax error".to_string()),
+    };
+    match (args[0].to_uppercase().as_str(), args.len()) {
+        ("ID", 1) => Ok(ClientCmd::ID),
+        ("INFO", 1) => Ok(ClientCmd::INFO),
+        ("LIST", _) => {
+            let mut filter = Filter::default();
+            let mut i = 1;
+            while i < args.len() {
+                match args[i].to_uppercase().as_str() {
+                    "TYPE" if i + 1 < args.len() => {
+                        filter.kind = Some(Kind::parse(&args[i + 1])?);
+                        i += 2;
+                    },
+                    "ID" if i + 1 < args.len() => {
+                        for id in &args[i + 1..] {
+                            filter.ids.push(id.parse::<u64>().ok().filter(|id| *id > 0).ok_or("Invalid client ID")?);
+                        }
+                        i = args.len();
+                    },
+                    _ => return Err("syntax error".to_string()),
+                }
+            }
+            Ok(ClientCmd::LIST(filter))
+        },
+        ("SETNAME", 2) => Ok(ClientCmd::SETNAME(args.remove(1))),
+        ("GETNAME", 1) => Ok(ClientCmd::GETNAME),
+        ("KILL", 2) => {
+            let filter = Filter { addr: Some(args.remove(1)), ..Filter::default() };
+            Ok(ClientCmd::KILL(filter, true))
+        },
+        ("KILL", n) if n >= 3 && n % 2 == 1 => {
+            let mut filter = Filter { skipme: true, ..Filter::default() };
+            for pair in args[1..].chunks(2) {
+                let value = pair[1].clone();
+                match pair[0].to_uppercase().as_str() {
+                    "ID" => filter.ids = vec![value.parse::<u64>().ok().filter(|id| *id > 0).ok_or("client-id should be greater than 0")?],
+                    "TYPE" => filter.kind = Some(Kind::parse(&value)?),
+                    "USER" => filter.user = Some(value),
+                    "ADDR" => filter.addr = Some(value),
+                    "LADDR" => filter.laddr = Some(value),
+                    "SKIPME" => filter.skipme = match value.to_lowercase().as_str() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err("syntax error".to_string()),
+                    },
+                    _ => return Err("syntax error".to_string()),
+                }
+            }
+            Ok(ClientCmd::KILL(filter, false))
+        },
+        ("PAUSE", 2) | ("PAUSE", 3) => {
+            let timeout = args[1].parse::<u64>().map_err(|_| "timeout is not an integer or out of range".to_string())?;
+            let mode = match args.get(2).map(|mode| mode.to_uppercase()).as_deref() {
+                None | Some("ALL") => PauseMode::ALL,
+                Some("WRITE") => PauseMode::WRITE,
+                Some(_) => return Err("syntax error".to_string()),
+            };
+            Ok(ClientCmd::PAUSE(timeout, mode))
+        },
+        ("UNPAUSE", 1) => Ok(ClientCmd::UNPAUSE),
+        ("REPLY", 2) => match args[1].to_uppercase().as_str() {
+            "ON" => Ok(ClientCmd::REPLY(ReplyMode::ON)),
+            "OFF" => Ok(ClientCmd::REPLY(ReplyMode::OFF)),
+            "SKIP" => Ok(ClientCmd::REPLY(ReplyMode::SKIP)),
+            _ => Err("syntax error".to_string()),
+        },
+        ("NO-EVICT", 2) => Ok(ClientCmd::NOEVICT(on_off(&args[1])?)),
+        ("NO-TOUCH", 2) => Ok(ClientCmd::NOTOUCH(on_off(&args[1])?)),
+        (sub, _) => Err(format!("Unknown CLIENT subcommand or wrong number of arguments for '{}'", sub)),
+    }
+}
+
+fn parse_shutdown(args: &[String]) -> Result<shutdown::Options, String> {
+    let mut options = shutdown::Options::default();
+    for arg in args {
+        match arg.to_uppercase().as_str() {
+            "NOSAVE" if options.save != Some(true) => options.save = Some(false),
+            "SAVE" if options.save != Some(false) => options.save = Some(true),
+            "NOW" => options.now = true,
+            "FORCE" => options.force = true,
+            "ABORT" => options.abort = true,
+            _ => return Err("syntax error".to_string()),
+        }
+    }
+    if options.abort && args.len() > 1 {
+        return Err("syntax error".to_string());
+    }
+    Ok(options)
+}
+
+fn string_args(frames: &mut [Frame]) -> Result<Vec<String>, String> {
+    frames.iter_mut().map(|f| f.to_string()).collect()
+}
+
+//Runs a single data command against an already locked database. Used both
+//for immediate execution and for replaying a transaction queue under one lock.
+//Scripts check the commands they call against the ACL of `caller`.
+pub(crate) fn run_cmd(cmd: &Command, db: &mut Db, caller: Option<&Caller>) -> Result<Frame, String> {
+    let response = match cmd {
+        Command::PING => {
+            Ok(Frame::Simple("PONG".to_string()))
+        },
+        Command::GET(key) => {
+            let stats = db.stats();
+            db.expire_if_needed(key);
+            match db.value(key) {
+                Some(Value::String(val)) => {
+                    stats.hit();
                     Ok(Frame::Bulk(Bytes::from((*val).clone())))
-                } else {
+                },
+                Some(_) => {
+                    stats.hit();
+                    Err(WRONGTYPE.to_string())
+                },
+                None => {
+                    stats.miss();
                     Ok(Frame::Simple("Nil".to_string()))
+                },
+            }
+        },
+        Command::SET(key, val) => {
+            db.set(key.clone(), val.clone());
+            Ok(Frame::Simple("Ok".to_string()))
+        },
+        Command::RPUSH(key, items) => db.rpush(key, items).map(|n| Frame::Integer(n as i64)).ok_or(WRONGTYPE.to_string()),
+        Command::SADD(key, members) => db.sadd(key, members).map(|n| Frame::Integer(n as i64)).ok_or(WRONGTYPE.to_string()),
+        Command::HSET(key, pairs) => db.hset(key, pairs).map(|n| Frame::Integer(n as i64)).ok_or(WRONGTYPE.to_string()),
+        Command::ZADD(key, members) => db.zadd(key, members).map(|n| Frame::Integer(n as i64)).ok_or(WRONGTYPE.to_string()),
+        Command::PEXPIREAT(key, at) => Ok(Frame::Integer(db.set_expire_at(key, *at) as i64)),
+        Command::KEYS(pattern) => {
+            let mut output = Frame::array();
+            for key in db.keys(pattern)? {
+                output.push_bulk(Bytes::from(key));
+            }
+            Ok(output)
+        },
+        Command::FLUSHDB | Command::FLUSHALL => {
+            db.flush();
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        Command::EVAL(body, keys, args) => {
+            db.load_script(body.clone());
+            atomically(db, |db| script::eval(db, body, keys, args, caller))
+        },
+        Command::EVALSHA(sha, keys, args) => {
+            match db.script(sha) {
+                Some(body) => {
+                    let body = body.clone();
+                    atomically(db, |db| script::eval(db, &body, keys, args, caller))
+                },
+                None => Err("NOSCRIPT No matching script. Please use EVAL.".to_string()),
+            }
+        },
+        Command::SCRIPT(ScriptCmd::LOAD(body)) => {
+            Ok(Frame::Bulk(Bytes::from(db.load_script(body.clone()))))
+        },
+        Command::SCRIPT(ScriptCmd::EXISTS(shas)) => {
+            let mut output = Frame::array();
+            for sha in shas {
+                output.push_int(db.script(sha).is_some() as i64);
+            }
+            Ok(output)
+        },
+        Command::SCRIPT(ScriptCmd::FLUSH) => {
+            db.flush_scripts();
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        Command::FUNCTION(cmd) => function::execute(cmd, db),
+        Command::SAVE => {
+            persistence::save(db)?;
+            Ok(Frame::Simple("OK".to_string()))
+        },
+        Command::BGSAVE => {
+            persistence::bgsave(db)?;
+            Ok(Frame::Simple("Background saving started".to_string()))
+        },
+        Command::LASTSAVE => Ok(Frame::Integer(db.persistence().lastsave() as i64)),
+        Command::ROLE => Ok(db.replication().role()),
+        Command::INFO(sections) => Ok(Frame::Bulk(Bytes::from(info::info(db, sections)))),
+        Command::CONFIG(cmd) => config::execute(cmd, db),
+        Command::SLOWLOG(cmd) => slowlog::execute(cmd, &db.slowlog()),
+        Command::LATENCY(cmd) => latency::execute(cmd, &db.latency(), &db.stats()),
+        Command::BGREWRITEAOF => {
+            persistence::bgrewriteaof(db)?;
+            Ok(Frame::Simple("Background append only file rewriting started".to_string()))
+        },
+        Command::FCALL(name, keys, args, read_only) => {
+            atomically(db, |db| function::fcall(db, name, keys, args, *read_only, caller))
+        },
+        _ => Err(format!("{:?} is not allowed here", cmd)),
+    };
+    if response.is_ok() {
+        propagate(cmd, db);
+    }
+    response
+}
+
+//Replays a command read from the AOF or the master's stream. Commands
+//between MULTI and EXEC are queued in `transaction` and applied together
+//on EXEC, the way they were run.
+pub(crate) fn replay(cmd: Command, transaction: &mut Option<Vec<Command>>, db: &mut Db) -> Result<(), String> {
+    match (cmd, transaction.as_mut()) {
+        (Command::MULTI, None) => {
+            *transaction = Some(Vec::new());
+            Ok(())
+        },
+        (Command::MULTI, Some(_)) => Err("MULTI calls can not be nested".to_string()),
+        (Command::EXEC, None) => Err("EXEC without MULTI".to_string()),
+        (Command::EXEC, Some(_)) => {
+            let queue = transaction.take().unwrap_or_default();
+            atomically(db, |db| {
+                let mut result = Ok(());
+                for cmd in &queue {
+                    if let Err(e) = run_cmd(cmd, db, None) {
+                        result = result.and(Err(e));
+                    }
                 }
-            },
-            Command::SET(key, val) => {
-                self.db.lock().unwrap().insert((*key).clone(), val.clone().to_string().unwrap());
-                Ok(Frame::Simple("Ok".to_string()))
-            },
-            Command::NULL => Err("Tried to execute null command".to_string()),
+                result
+            })
+        },
+        (cmd, Some(queue)) => {
+            queue.push(cmd);
+            Ok(())
+        },
+        (cmd, None) => run_cmd(&cmd, db, None).map(|_| ()),
+    }
+}
+
+//Runs `f` with the writes it makes held back, then propagates them wrapped
+//in MULTI ... EXEC when there is more than one, so that a transaction or a
+//script is never replayed half done. Nested calls, like a script queued in
+//a transaction, leave that to the outermost one.
+pub(crate) fn atomically<T>(db: &mut Db, f: impl FnOnce(&mut Db) -> T) -> T {
+    if db.propagation().is_some() {
+        return f(db);
+    }
+    *db.propagation() = Some(Vec::new());
+    let result = f(db);
+    let writes = db.propagation().take().unwrap_or_default();
+    if writes.len() > 1 {
+        feed(db, &command_bytes("MULTI"));
+        for bytes in &writes {
+            feed(db, bytes);
         }
+        feed(db, &command_bytes("EXEC"));
+    } else if let Some(bytes) = writes.first() {
+        feed(db, bytes);
+    }
+    result
+}
+
+//Appends a successful write to the AOF and, on a master, to the replication
+//stream. Scripts are propagated through the writes they make, since those
+//pass through `run_cmd` as well. Replicas forward their master's stream as is.
+fn propagate(cmd: &Command, db: &mut Db) {
+    if let Some(mut frame) = cmd.to_frame() {
+        let bytes = frame.deserialize();
+        match db.propagation() {
+            Some(writes) => writes.push(bytes),
+            None => feed(db, &bytes),
+        }
+    }
+}
+
+fn feed(db: &Db, bytes: &[u8]) {
+    let start = Instant::now();
+    db.persistence().feed_aof(bytes);
+    db.latency().add_sample_if_needed("aof-write", start.elapsed());
+    let replication = db.replication();
+    if replication.is_master() {
+        replication.feed(bytes);
     }
 }
 
+fn command_bytes(name: &str) -> Vec<u8> {
+    let mut frame = Frame::array();
+    frame.push_bulk(Bytes::from(name.to_string()));
+    frame.deserialize()
+}
+
 //TESTS
-/*
+
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
     use bytes::Bytes;
+    use crate::db::{Db, Value};
+    use crate::rdb::Snapshot;
     use crate::Handler;
-    use crate::handler::Command;
+    use crate::handler::{Command, NOAUTH, WRONGPASS};
     use crate::frame::Frame;
+
+    fn new_handler() -> Handler {
+        Handler::new(Arc::new(Mutex::new(Db::new())))
+    }
+
+    fn bulk_cmd(args: &[&str]) -> Frame {
+        let mut frame = Frame::array();
+        for arg in args {
+            frame.push_bulk(Bytes::from(arg.to_string()));
+        }
+        frame
+    }
+
+    fn run(handler: &mut Handler, args: &[&str]) -> Result<Frame, String> {
+        handler.get_command(bulk_cmd(args))?;
+        handler.execute_cmd()
+    }
     
     #[test]
     fn handler_ping_command_frame() {
-        let mut db1 = HashMap::new();
-        let mut db2 = HashMap::new();
         let ping = "PING".to_string();
         let mut input = Frame::array();
         input.push_simple(ping);
 
-        let mut handler = Handler::new(&mut db1);
+        let mut handler = new_handler();
         handler.get_command(input).unwrap();
 
-        let expected = Handler{ 
-            command: Command::PING, 
-            db: &mut db2,
-        };
-
-        assert_eq!(handler.command, expected.command);
+        assert_eq!(handler.command, Command::PING);
     }
 
     #[test]
     fn handler_get_command_frame() {
-        let mut db1 = HashMap::new();
-        let mut db2 = HashMap::new();
-        let cmd = Bytes::from("GET");
-        let name = Bytes::from("test");
-        let mut input = Frame::array();
-        input.push_bulk(cmd);
-        input.push_bulk(name);
-
-        let mut handler = Handler::new(&mut db1);
-        handler.get_command(input).unwrap();
-
-        let expected = Handler {
-            command: Command::GET (
-                "test".to_string(),
-            ),
-            db: &mut db2,
-        };
+        let mut handler = new_handler();
+        handler.get_command(bulk_cmd(&["GET", "test"])).unwrap();
 
-        assert_eq!(handler.command, expected.command);
+        assert_eq!(handler.command, Command::GET("test".to_string()));
     }
 
     #[test]
     fn handler_set_command_frame() {
-        let mut db1 = HashMap::new();
-        let mut db2 = HashMap::new();
-        let cmd = Bytes::from("SET");
-        let name = Bytes::from("test");
-        let val = Bytes::from("testval");
-        let mut input = Frame::array();
-        input.push_bulk(cmd);
-        input.push_bulk(name);
-        input.push_bulk(val);
+        let mut handler = new_handler();
+        handler.get_command(bulk_cmd(&["SET", "test", "testval"])).unwrap();
 
-        let mut handler = Handler::new(&mut db1);
-        handler.get_command(input).unwrap();
+        let expected = Command::SET (
+            "test".to_string(),
+            "testval".to_string(),
+        );
 
-        let expected = Handler {
-            command: Command::SET (
-                "test".to_string(),
-                Frame::Bulk(Bytes::from("testval")),
-            ),
-            db: &mut db2,
-        };
+        assert_eq!(handler.command, expected);
+    }
 
-        assert_eq!(handler.command, expected.command);
+    #[test]
+    fn invalid_utf8_is_an_error() {
+        let mut handler = new_handler();
+        let mut name = Frame::array();
+        name.push_bulk(Bytes::from(&b"\xffGET"[..]));
+        assert!(handler.get_command(name).is_err());
+
+        let mut key = Frame::array();
+        key.push_bulk(Bytes::from("GET"));
+        key.push_bulk(Bytes::from(&b"\xc3\x28"[..]));
+        assert!(handler.get_command(key).is_err());
+
+        let mut value = Frame::array();
+        value.push_bulk(Bytes::from("SET"));
+        value.push_bulk(Bytes::from("k"));
+        value.push_bulk(Bytes::from(&b"\xff"[..]));
+        assert_eq!(handler.get_command(value), Err("Invalid UTF-8 in argument".to_string()));
     }
-    
+
     #[test]
     fn handler_execute_command_test() {
-        let mut db1 = HashMap::new();
-        let mut db2 = HashMap::new();
-        let mut db3 = HashMap::new();
+        let mut handler = new_handler();
 
-        let ping_handler = Handler{ 
-            command: Command::PING, 
-            db: &mut db1,
-        };
+        let ping_output = run(&mut handler, &["PING"]).unwrap();
+        let get_output = run(&mut handler, &["GET", "test"]).unwrap();
+        let set_output = run(&mut handler, &["SET", "test", "testval"]).unwrap();
 
-        let get_handler = Handler {
-            command: Command::GET (
-                "test".to_string(),
-            ),
-            db: &mut db2,
-        };
+        assert_eq!(ping_output, Frame::Simple("PONG".to_string()));
+        assert_eq!(get_output, Frame::Simple("Nil".to_string()));
+        assert_eq!(set_output, Frame::Simple("Ok".to_string()));
+    }
+
+    #[test]
+    fn multi_exec_runs_queue() {
+        let mut handler = new_handler();
+
+        assert_eq!(run(&mut handler, &["MULTI"]).unwrap(), Frame::Simple("OK".to_string()));
+        assert_eq!(run(&mut handler, &["SET", "a", "1"]).unwrap(), Frame::Simple("QUEUED".to_string()));
+        assert_eq!(run(&mut handler, &["GET", "a"]).unwrap(), Frame::Simple("QUEUED".to_string()));
+
+        let expected = Frame::Array(vec![
+            Frame::Simple("Ok".to_string()),
+            Frame::Bulk(Bytes::from("1")),
+        ]);
+        assert_eq!(run(&mut handler, &["EXEC"]).unwrap(), expected);
+        assert!(run(&mut handler, &["EXEC"]).is_err());
+    }
+
+    #[test]
+    fn multi_syntax_error_aborts_exec() {
+        let mut handler = new_handler();
+
+        run(&mut handler, &["MULTI"]).unwrap();
+        run(&mut handler, &["SET", "a", "1"]).unwrap();
+        assert!(run(&mut handler, &["GET"]).is_err());
+
+        let err = run(&mut handler, &["EXEC"]).unwrap_err();
+        assert!(err.starts_with("EXECABORT"));
+        assert_eq!(run(&mut handler, &["GET", "a"]).unwrap(), Frame::Simple("Nil".to_string()));
+    }
+
+    #[test]
+    fn rejected_commands_abort_exec() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let mut admin = Handler::new(db.clone());
+        let mut app = Handler::new(db);
+
+        run(&mut admin, &["MULTI"]).unwrap();
+        run(&mut admin, &["SET", "a", "1"]).unwrap();
+        assert_eq!(run(&mut admin, &["SCRIPT", "KILL"]), Err("Command not allowed inside a transaction".to_string()));
+        assert!(run(&mut admin, &["FUNCTION", "KILL"]).is_err());
+        assert!(run(&mut admin, &["EXEC"]).unwrap_err().starts_with("EXECABORT"));
+
+        run(&mut admin, &["ACL", "SETUSER", "app", "on", ">pw", "+@all", "-flushall", "~*"]).unwrap();
+        run(&mut app, &["AUTH", "app", "pw"]).unwrap();
+        run(&mut app, &["MULTI"]).unwrap();
+        run(&mut app, &["SET", "a", "2"]).unwrap();
+        assert!(run(&mut app, &["FLUSHALL"]).unwrap_err().starts_with("NOPERM"));
+        assert!(run(&mut app, &["EXEC"]).unwrap_err().starts_with("EXECABORT"));
+        assert_eq!(run(&mut admin, &["GET", "a"]).unwrap(), Frame::Simple("Nil".to_string()));
+    }
+
+    #[test]
+    fn discard_drops_queue() {
+        let mut handler = new_handler();
+
+        assert!(run(&mut handler, &["DISCARD"]).is_err());
+        run(&mut handler, &["MULTI"]).unwrap();
+        assert!(run(&mut handler, &["MULTI"]).is_err());
+        run(&mut handler, &["SET", "a", "1"]).unwrap();
+        assert_eq!(run(&mut handler, &["DISCARD"]).unwrap(), Frame::Simple("OK".to_string()));
+        assert_eq!(run(&mut handler, &["GET", "a"]).unwrap(), Frame::Simple("Nil".to_string()));
+    }
+
+    #[test]
+    fn watch_aborts_exec_on_foreign_write() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let mut first = Handler::new(db.clone());
+        let mut second = Handler::new(db);
+
+        run(&mut first, &["WATCH", "a", "b"]).unwrap();
+        run(&mut first, &["MULTI"]).unwrap();
+        run(&mut first, &["SET", "a", "mine"]).unwrap();
+        run(&mut second, &["SET", "b", "theirs"]).unwrap();
+
+        assert_eq!(run(&mut first, &["EXEC"]).unwrap(), Frame::Null);
+        assert_eq!(run(&mut first, &["GET", "a"]).unwrap(), Frame::Simple("Nil".to_string()));
+    }
+
+    #[test]
+    fn watch_flushdb_and_unwatch() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let mut first = Handler::new(db.clone());
+        let mut second = Handler::new(db);
+
+        run(&mut second, &["SET", "a", "1"]).unwrap();
+        run(&mut first, &["WATCH", "a"]).unwrap();
+        run(&mut second, &["FLUSHDB"]).unwrap();
+        run(&mut first, &["MULTI"]).unwrap();
+        assert!(run(&mut first, &["WATCH", "a"]).is_err());
+        assert_eq!(run(&mut first, &["EXEC"]).unwrap(), Frame::Null);
+
+        run(&mut first, &["WATCH", "a"]).unwrap();
+        run(&mut second, &["SET", "a", "2"]).unwrap();
+        run(&mut first, &["UNWATCH"]).unwrap();
+        run(&mut first, &["MULTI"]).unwrap();
+        run(&mut first, &["SET", "a", "3"]).unwrap();
+        assert_eq!(run(&mut first, &["EXEC"]).unwrap(), Frame::Array(vec![Frame::Simple("Ok".to_string())]));
+    }
+
+    #[test]
+    fn watched_key_expiring_aborts_exec() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        db.lock().unwrap().restore(Snapshot {
+            entries: vec![("a".to_string(), Value::String("1".to_string()), Some(now + 50))],
+            libraries: vec![],
+        }).unwrap();
+        let mut handler = Handler::new(db);
+
+        run(&mut handler, &["WATCH", "a"]).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        run(&mut handler, &["MULTI"]).unwrap();
+        run(&mut handler, &["SET", "b", "1"]).unwrap();
+        assert_eq!(run(&mut handler, &["EXEC"]).unwrap(), Frame::Null);
+    }
+
+    #[test]
+    fn eval_and_script_cache() {
+        let mut handler = new_handler();
+
+        let output = run(&mut handler, &["EVAL", "return ARGV[1]", "0", "hi"]).unwrap();
+        assert_eq!(output, Frame::Bulk(Bytes::from("hi")));
+
+        let sha = run(&mut handler, &["SCRIPT", "LOAD", "return KEYS[1]"]).unwrap().to_string().unwrap();
+        let output = run(&mut handler, &["EVALSHA", &sha, "1", "key"]).unwrap();
+        assert_eq!(output, Frame::Bulk(Bytes::from("key")));
+
+        let expected = Frame::Array(vec![Frame::Integer(1), Frame::Integer(0)]);
+        assert_eq!(run(&mut handler, &["SCRIPT", "EXISTS", &sha, "nope"]).unwrap(), expected);
 
-        let set_handler = Handler {
-            command: Command::SET (
-                "test".to_string(),
-                Frame::Bulk(Bytes::from("testval")),
-            ),
-            db: &mut db3,
+        run(&mut handler, &["SCRIPT", "FLUSH"]).unwrap();
+        let err = run(&mut handler, &["EVALSHA", &sha, "0"]).unwrap_err();
+        assert!(err.starts_with("NOSCRIPT"));
+    }
+
+    #[test]
+    fn function_load_and_fcall() {
+        let mut handler = new_handler();
+        let code = "#!lua name=lib\nredis.register_function('echo', function(keys, args) return args[1] end)";
+
+        assert_eq!(run(&mut handler, &["FUNCTION", "LOAD", code]).unwrap(), Frame::Bulk(Bytes::from("lib")));
+        assert!(run(&mut handler, &["FUNCTION", "LOAD", code]).is_err());
+        run(&mut handler, &["FUNCTION", "LOAD", "REPLACE", code]).unwrap();
+
+        let output = run(&mut handler, &["FCALL", "echo", "0", "hi"]).unwrap();
+        assert_eq!(output, Frame::Bulk(Bytes::from("hi")));
+        assert!(run(&mut handler, &["FCALL_RO", "echo", "0", "hi"]).is_err());
+
+        if let Frame::Array(libraries) = run(&mut handler, &["FUNCTION", "LIST", "LIBRARYNAME", "l*"]).unwrap() {
+            assert_eq!(libraries.len(), 1);
+        } else {
+            panic!("expected an array reply");
+        }
+        run(&mut handler, &["FUNCTION", "FLUSH"]).unwrap();
+        assert!(run(&mut handler, &["FCALL", "echo", "0"]).is_err());
+    }
+
+    #[test]
+    fn keys_and_flushall() {
+        let mut handler = new_handler();
+        run(&mut handler, &["SET", "user:1", "a"]).unwrap();
+        run(&mut handler, &["SET", "user:2", "b"]).unwrap();
+        run(&mut handler, &["SET", "other", "c"]).unwrap();
+
+        match run(&mut handler, &["KEYS", "user:*"]).unwrap() {
+            Frame::Array(keys) => assert_eq!(keys.len(), 2),
+            _ => panic!("expected an array reply"),
+        }
+        run(&mut handler, &["FLUSHALL"]).unwrap();
+        assert_eq!(run(&mut handler, &["KEYS", "*"]).unwrap(), Frame::Array(vec![]));
+    }
+
+    #[test]
+    fn get_on_imported_collection_is_wrongtype() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        db.lock().unwrap().restore(Snapshot {
+            entries: vec![("list".to_string(), Value::List(vec!["a".to_string()]), None)],
+            libraries: vec![],
+        }).unwrap();
+        let mut handler = Handler::new(db);
+
+        assert!(run(&mut handler, &["GET", "list"]).unwrap_err().starts_with("WRONGTYPE"));
+        run(&mut handler, &["SET", "list", "x"]).unwrap();
+        assert_eq!(run(&mut handler, &["GET", "list"]).unwrap(), Frame::Bulk(Bytes::from("x")));
+    }
+
+    #[test]
+    fn collection_writes() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let mut handler = Handler::new(db.clone());
+
+        assert_eq!(run(&mut handler, &["RPUSH", "l", "a", "b"]).unwrap(), Frame::Integer(2));
+        assert_eq!(run(&mut handler, &["SADD", "s", "x", "x", "y"]).unwrap(), Frame::Integer(2));
+        assert_eq!(run(&mut handler, &["HSET", "h", "f", "1", "g", "2"]).unwrap(), Frame::Integer(2));
+        assert_eq!(run(&mut handler, &["HSET", "h", "f", "3"]).unwrap(), Frame::Integer(0));
+        assert_eq!(run(&mut handler, &["ZADD", "z", "2", "b", "1", "a"]).unwrap(), Frame::Integer(2));
+        assert_eq!(run(&mut handler, &["ZADD", "z", "3", "a"]).unwrap(), Frame::Integer(0));
+        assert!(run(&mut handler, &["ZADD", "z", "nan", "c"]).is_err());
+        assert!(run(&mut handler, &["HSET", "h", "f"]).is_err());
+        assert!(run(&mut handler, &["RPUSH", "s", "a"]).unwrap_err().starts_with("WRONGTYPE"));
+        assert_eq!(run(&mut handler, &["PEXPIREAT", "l", "4102444800000"]).unwrap(), Frame::Integer(1));
+        assert_eq!(run(&mut handler, &["PEXPIREAT", "missing", "4102444800000"]).unwrap(), Frame::Integer(0));
+
+        let db = db.lock().unwrap();
+        assert_eq!(db.value("l"), Some(&Value::List(vec!["a".to_string(), "b".to_string()])));
+        assert_eq!(db.value("z"), Some(&Value::ZSet(vec![("b".to_string(), 2.0), ("a".to_string(), 3.0)])));
+        assert_eq!(db.expire_at("l"), Some(4102444800000));
+    }
+
+    #[test]
+    fn transactions_and_scripts_propagate_atomically() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let mut handler = Handler::new(db.clone());
+        let (_, mut sync) = {
+            let db = db.lock().unwrap();
+            db.replication().psync(&db, "?", -1, "127.0.0.1".to_string(), 6380)
+        };
+        let mut propagated = |expected: &[&[&str]]| {
+            let mut stream = Vec::new();
+            while let Ok(bytes) = sync.receiver.try_recv() {
+                stream.extend_from_slice(&bytes);
+            }
+            let expected: Vec<u8> = expected.iter().flat_map(|args| bulk_cmd(args).deserialize()).collect();
+            assert_eq!(stream, expected);
         };
 
-        let ping_output = ping_handler.execute_cmd().unwrap();
-        let get_output = get_handler.execute_cmd().unwrap();
-        let set_output = set_handler.execute_cmd().unwrap();
+        run(&mut handler, &["MULTI"]).unwrap();
+        run(&mut handler, &["SET", "a", "1"]).unwrap();
+        run(&mut handler, &["GET", "a"]).unwrap();
+        run(&mut handler, &["SET", "b", "2"]).unwrap();
+        run(&mut handler, &["EXEC"]).unwrap();
+        propagated(&[&["MULTI"], &["SET", "a", "1"], &["SET", "b", "2"], &["EXEC"]]);
+
+        run(&mut handler, &["MULTI"]).unwrap();
+        run(&mut handler, &["SET", "a", "3"]).unwrap();
+        run(&mut handler, &["EXEC"]).unwrap();
+        propagated(&[&["SET", "a", "3"]]);
+
+        run(&mut handler, &["EVAL", "redis.call('SET', 'x', '1') redis.call('SET', 'y', '2')", "0"]).unwrap();
+        propagated(&[&["MULTI"], &["SET", "x", "1"], &["SET", "y", "2"], &["EXEC"]]);
+
+        run(&mut handler, &["MULTI"]).unwrap();
+        run(&mut handler, &["SET", "a", "4"]).unwrap();
+        run(&mut handler, &["EVAL", "redis.call('SET', 'z', '1')", "0"]).unwrap();
+        run(&mut handler, &["EXEC"]).unwrap();
+        propagated(&[&["MULTI"], &["SET", "a", "4"], &["SET", "z", "1"], &["EXEC"]]);
+
+        run(&mut handler, &["EVAL", "return 1", "0"]).unwrap();
+        propagated(&[]);
+    }
+
+    #[test]
+    fn config_set_applies_to_running_server() {
+        let mut handler = new_handler();
+        run(&mut handler, &["CONFIG", "SET", "busy-reply-threshold", "250", "appendfsync", "always"]).unwrap();
+        assert_eq!(handler.shared.busy.threshold_ms(), 250);
+        assert_eq!(run(&mut handler, &["CONFIG", "GET", "appendf*"]).unwrap(), Frame::Array(vec![
+            Frame::Bulk(Bytes::from("appendfilename")),
+            Frame::Bulk(Bytes::from("appendonly.aof")),
+            Frame::Bulk(Bytes::from("appendfsync")),
+            Frame::Bulk(Bytes::from("always")),
+        ]));
+
+        //A bad value anywhere rejects the whole call.
+        assert!(run(&mut handler, &["CONFIG", "SET", "busy-reply-threshold", "10", "appendfsync", "sometimes"]).is_err());
+        assert_eq!(handler.shared.busy.threshold_ms(), 250);
+        assert!(run(&mut handler, &["CONFIG", "REWRITE"]).is_err());
+    }
+
+    #[test]
+    fn shutdown_closes_without_reply() {
+        let mut handler = new_handler();
+        assert!(run(&mut handler, &["SHUTDOWN", "SAVE", "NOSAVE"]).is_err());
+        assert!(run(&mut handler, &["SHUTDOWN", "ABORT", "NOW"]).is_err());
+        assert_eq!(run(&mut handler, &["SHUTDOWN", "ABORT"]), Err("No shutdown in progress.".to_string()));
+        assert!(!handler.closing());
+
+        run(&mut handler, &["SHUTDOWN", "NOSAVE", "NOW"]).unwrap();
+        assert!(handler.closing());
+        assert!(handler.db.lock().unwrap().shutdown().is_triggered());
+    }
+
+    #[test]
+    fn noauth_until_auth() {
+        let db = Db::new();
+        db.config().lock().unwrap().requirepass = "secret".to_string();
+        db.acl().set_default_password("secret");
+        let mut handler = Handler::new(Arc::new(Mutex::new(db)));
+
+        assert_eq!(run(&mut handler, &["GET", "a"]), Err(NOAUTH.to_string()));
+        assert_eq!(run(&mut handler, &["AUTH", "wrong"]), Err(WRONGPASS.to_string()));
+        assert_eq!(run(&mut handler, &["AUTH", "nobody", "secret"]), Err(WRONGPASS.to_string()));
+        assert_eq!(run(&mut handler, &["MULTI"]), Err(NOAUTH.to_string()));
+
+        run(&mut handler, &["AUTH", "default", "secret"]).unwrap();
+        run(&mut handler, &["SET", "a", "1"]).unwrap();
+
+        let mut open = new_handler();
+        assert!(run(&mut open, &["AUTH", "secret"]).unwrap_err().contains("without any password configured"));
+        run(&mut open, &["AUTH", "default", "anything"]).unwrap();
+    }
+
+    #[test]
+    fn acl_user_restrictions() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let mut admin = Handler::new(db.clone());
+        let mut app = Handler::new(db);
+
+        run(&mut admin, &["ACL", "SETUSER", "app", "on", ">pw", "+@read", "+set", "~app:*", "%R~shared:*"]).unwrap();
+        assert!(run(&mut admin, &["ACL", "SETUSER", "app", "+nosuch"]).unwrap_err().contains("'+nosuch'"));
+        run(&mut app, &["AUTH", "app", "pw"]).unwrap();
+        assert_eq!(run(&mut app, &["ACL", "WHOAMI"]), Err("NOPERM User app has no permissions to run the 'acl|whoami' command".to_string()));
+
+        run(&mut app, &["SET", "app:1", "x"]).unwrap();
+        run(&mut app, &["GET", "shared:1"]).unwrap();
+        assert_eq!(run(&mut app, &["SET", "shared:1", "x"]), Err("NOPERM No permissions to access a key".to_string()));
+        run(&mut app, &["MULTI"]).unwrap_err();
+        assert!(run(&mut app, &["FLUSHALL"]).unwrap_err().starts_with("NOPERM"));
+        assert_eq!(run(&mut app, &["AUTH", "app", "wrong"]), Err(WRONGPASS.to_string()));
+
+        //Newest first: the failed AUTH, then the denied commands and key.
+        match run(&mut admin, &["ACL", "LOG"]).unwrap() {
+            Frame::Array(entries) => {
+                assert_eq!(entries.len(), 5);
+                match &entries[0] {
+                    Frame::Array(fields) => assert_eq!(fields[3], Frame::Bulk(Bytes::from("auth"))),
+                    _ => panic!("expected a log entry"),
+                }
+            },
+            _ => panic!("expected an array reply"),
+        }
+        run(&mut admin, &["ACL", "LOG", "RESET"]).unwrap();
+        assert_eq!(run(&mut admin, &["ACL", "LOG"]).unwrap(), Frame::Array(vec![]));
+
+        assert_eq!(run(&mut admin, &["ACL", "DELUSER", "app", "nobody"]).unwrap(), Frame::Integer(1));
+        assert!(run(&mut app, &["GET", "app:1"]).unwrap_err().starts_with("NOPERM"));
+        assert!(run(&mut admin, &["ACL", "DELUSER", "default"]).is_err());
+    }
+
+    #[test]
+    fn scripts_call_with_the_caller_acl() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let mut admin = Handler::new(db.clone());
+        let mut app = Handler::new(db);
+
+        run(&mut admin, &["ACL", "SETUSER", "app", "on", ">pw", "+@read", "+eval", "+set", "~app:*"]).unwrap();
+        run(&mut app, &["AUTH", "app", "pw"]).unwrap();
+
+        let write = "return redis.call('SET', KEYS[1], 'x')";
+        assert_eq!(run(&mut app, &["EVAL", write, "1", "app:1"]).unwrap(), Frame::Simple("Ok".to_string()));
+        //The key used is not the one declared.
+        let sneaky = "return redis.call('SET', 'secret', 'x')";
+        assert!(run(&mut app, &["EVAL", sneaky, "1", "app:1"]).unwrap_err().contains("NOPERM"));
+        assert!(run(&mut app, &["EVAL", "return redis.call('FLUSHALL')", "0"]).unwrap_err().contains("NOPERM"));
+        assert_eq!(run(&mut admin, &["GET", "secret"]).unwrap(), Frame::Simple("Nil".to_string()));
+
+        match run(&mut admin, &["ACL", "LOG", "1"]).unwrap() {
+            Frame::Array(entries) => match &entries[0] {
+                Frame::Array(fields) => assert_eq!(fields[5], Frame::Bulk(Bytes::from("lua"))),
+                _ => panic!("expected a log entry"),
+            },
+            _ => panic!("expected an array reply"),
+        }
+
+        //Even the default user cannot administer the server from a script.
+        for cmd in ["redis.call('CONFIG', 'SET', 'timeout', '5')", "redis.call('SHUTDOWN', 'NOSAVE')", "redis.call('MULTI')"] {
+            let err = run(&mut admin, &["EVAL", cmd, "0"]).unwrap_err();
+            assert!(err.contains("not allowed from script"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn client_registry_and_reply_modes() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let mut first = Handler::new(db.clone());
+        let mut second = Handler::new(db.clone());
+        let id = second.client().id().to_string();
+
+        assert_eq!(run(&mut first, &["CLIENT", "ID"]).unwrap(), Frame::Integer(1));
+        run(&mut second, &["CLIENT", "SETNAME", "worker"]).unwrap();
+        run(&mut second, &["MULTI"]).unwrap();
+        let list = run(&mut first, &["CLIENT", "LIST", "ID", &id]).unwrap().to_string().unwrap();
+        assert!(list.contains(" name=worker ") && list.contains(" flags=x ") && list.contains(" cmd=multi "));
+        let info = run(&mut first, &["CLIENT", "INFO"]).unwrap().to_string().unwrap();
+        assert!(info.starts_with("id=1 ") && info.contains(" cmd=client|info "));
+
+        assert!(run(&mut first, &["CLIENT", "KILL", "1.2.3.4:5"]).is_err());
+        assert_eq!(run(&mut first, &["CLIENT", "KILL", "ID", &id]).unwrap(), Frame::Integer(1));
+        assert!(second.client().is_killed());
+        drop(second);
+        assert_eq!(db.lock().unwrap().clients().len(), 1);
+
+        run(&mut first, &["CLIENT", "REPLY", "SKIP"]).unwrap();
+        assert!(!first.should_reply());
+        run(&mut first, &["PING"]).unwrap();
+        assert!(!first.should_reply());
+        run(&mut first, &["PING"]).unwrap();
+        assert!(first.should_reply());
+        run(&mut first, &["CLIENT", "REPLY", "OFF"]).unwrap();
+        assert!(!first.should_reply());
+        run(&mut first, &["CLIENT", "REPLY", "ON"]).unwrap();
+        assert!(first.should_reply());
+    }
+
+    #[test]
+    fn client_pause_write_holds_writes() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let mut admin = Handler::new(db.clone());
+        let mut writer = Handler::new(db);
+
+        run(&mut admin, &["CLIENT", "PAUSE", "150", "WRITE"]).unwrap();
+        let start = std::time::Instant::now();
+        run(&mut writer, &["GET", "a"]).unwrap();
+        assert!(start.elapsed().as_millis() < 100);
+        run(&mut writer, &["SET", "a", "1"]).unwrap();
+        assert!(start.elapsed().as_millis() >= 150);
+
+        run(&mut admin, &["CLIENT", "PAUSE", "10000"]).unwrap();
+        run(&mut admin, &["CLIENT", "UNPAUSE"]).unwrap();
+        run(&mut writer, &["GET", "a"]).unwrap();
+    }
+
+    #[test]
+    fn slowlog_records_commands() {
+        let mut handler = new_handler();
+        run(&mut handler, &["CONFIG", "SET", "slowlog-log-slower-than", "0"]).unwrap();
+        run(&mut handler, &["CLIENT", "SETNAME", "tester"]).unwrap();
+        run(&mut handler, &["SET", "a", "1"]).unwrap();
+        assert!(run(&mut handler, &["SLOWLOG", "GET", "-2"]).is_err());
+
+        match run(&mut handler, &["SLOWLOG", "GET", "1"]).unwrap() {
+            Frame::Array(entries) => {
+                assert_eq!(entries.len(), 1);
+                match &entries[0] {
+                    Frame::Array(fields) => {
+                        assert_eq!(fields[0], Frame::Integer(2));
+                        assert_eq!(fields[3], bulk_cmd(&["SET", "a", "1"]));
+                        assert_eq!(fields[5], Frame::Bulk(Bytes::from("tester")));
+                    },
+                    other => panic!("unexpected entry {:?}", other),
+                }
+            },
+            other => panic!("unexpected reply {:?}", other),
+        }
+        assert_eq!(run(&mut handler, &["SLOWLOG", "LEN"]).unwrap(), Frame::Integer(4));
+        run(&mut handler, &["SLOWLOG", "RESET"]).unwrap();
+        assert_eq!(run(&mut handler, &["SLOWLOG", "LEN"]).unwrap(), Frame::Integer(1));
+    }
+
+    #[test]
+    fn latency_commands() {
+        let mut handler = new_handler();
+        assert!(run(&mut handler, &["LATENCY", "HISTORY"]).is_err());
+        assert!(run(&mut handler, &["LATENCY", "LATEST", "x"]).is_err());
+        assert_eq!(run(&mut handler, &["LATENCY", "LATEST"]).unwrap(), Frame::array());
+        assert_eq!(run(&mut handler, &["LATENCY", "RESET", "command"]).unwrap(), Frame::Integer(0));
+        run(&mut handler, &["SET", "a", "1"]).unwrap();
+        match run(&mut handler, &["LATENCY", "HISTOGRAM", "set", "get"]).unwrap() {
+            Frame::Array(items) => {
+                assert_eq!(items.len(), 2);
+                assert_eq!(items[0], Frame::Bulk(Bytes::from("set")));
+            },
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn busy_script_and_script_kill() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        db.lock().unwrap().busy().set_threshold_ms(20);
+        let mut looping = Handler::new(db.clone());
+        let mut other = Handler::new(db);
+
+        assert!(run(&mut other, &["SCRIPT", "KILL"]).unwrap_err().starts_with("NOTBUSY"));
 
-        let ping_expected = Frame::Simple("PONG".to_string());
-        let get_expected = Frame::Simple("Nil".to_string());
-        let set_expected = Frame::Simple("Ok".to_string());
+        let script = std::thread::spawn(move || run(&mut looping, &["EVAL", "while true do end", "0"]));
+        std::thread::sleep(std::time::Duration::from_millis(100));
 
-        assert_eq!(ping_output, ping_expected);
-        assert_eq!(get_output, get_expected);
-        assert_eq!(set_output, set_expected);
+        assert!(run(&mut other, &["GET", "a"]).unwrap_err().starts_with("BUSY"));
+        assert_eq!(run(&mut other, &["SCRIPT", "KILL"]).unwrap(), Frame::Simple("OK".to_string()));
+        assert!(script.join().unwrap().is_err());
+        assert_eq!(run(&mut other, &["GET", "a"]).unwrap(), Frame::Simple("Nil".to_string()));
     }
 }
-*/
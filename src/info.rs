@@ -0,0 +1,207 @@
+use crate::config::REDIS_VERSION;
+use crate::db::Db;
+use std::fs;
+
+//Sections in the order INFO prints them. commandstats is only shown when
+//asked for, or with `all` and `everything`.
+const SECTIONS: &[&str] = &[
+    "server", "clients", "memory", "persistence", "stats", "replication", "cpu", "commandstats", "keyspace",
+];
+
+//Renders INFO [section ...] in Redis' text format: a `# Title` line per
+//section followed by `field:value` lines, sections separated by a blank line.
+pub fn info(db: &Db, sections: &[String]) -> String {
+    let mut sections: Vec<String> = sections.iter().map(|s| s.to_lowercase()).collect();
+    if sections.is_empty() {
+        sections.push("default".to_string());
+    }
+    let wanted = |name: &str| {
+        sections.iter().any(|s| s == name || s == "all" || s == "everything")
+            || (name != "commandstats" && sections.iter().any(|s| s == "default"))
+    };
+    let mut output = Vec::new();
+    for name in SECTIONS {
+        if wanted(name) {
+            output.push(section(db, name));
+        }
+    }
+    output.join("\r\n")
+}
+
+//HELPER FN
+
+fn section(db: &Db, name: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    match name {
+        "server" => {
+            let config = db.config();
+            let config = config.lock().unwrap();
+            let stats = db.stats();
+            let uptime = stats.uptime().as_secs();
+            lines.push("# Server".to_string());
+            lines.push(format!("redis_version:{}", REDIS_VERSION));
+            lines.push("redis_mode:standalone".to_string());
+            lines.push(format!("os:{} {}", capitalize(std::env::consts::OS), std::env::consts::ARCH));
+            lines.push(format!("arch_bits:{}", usize::BITS));
+            lines.push(format!("process_id:{}", std::process::id()));
+            lines.push(format!("run_id:{}", stats.run_id()));
+            lines.push(format!("tcp_port:{}", config.port));
+            lines.push(format!("server_time_usec:{}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_micros()));
+            lines.push(format!("uptime_in_seconds:{}", uptime));
+            lines.push(format!("uptime_in_days:{}", uptime / 86400));
+            lines.push(format!("executable:{}", std::env::current_exe().map(|p| p.display().to_string()).unwrap_or_default()));
+            lines.push(format!("config_file:{}", config.path.as_ref().map(|p| p.display().to_string()).unwrap_or_default()));
+        },
+        "clients" => {
+            lines.push("# Clients".to_string());
+            lines.push(format!("connected_clients:{}", db.clients().len()));
+            lines.push(format!("maxclients:{}", db.config().lock().unwrap().maxclients));
+        },
+        "memory" => {
+            let used = db.used_memory() as u64;
+            let rss = rss_bytes();
+            lines.push("# Memory".to_string());
+            lines.push(format!("used_memory:{}", used));
+            lines.push(format!("used_memory_human:{}", human_bytes(used)));
+            lines.push(format!("used_memory_rss:{}", rss));
+            lines.push(format!("used_memory_rss_human:{}", human_bytes(rss)));
+            lines.push("maxmemory:0".to_string());
+            lines.push("maxmemory_human:0B".to_string());
+            lines.push("maxmemory_policy:noeviction".to_string());
+        },
+        "persistence" => {
+            let persistence = db.persistence();
+            lines.push("# Persistence".to_string());
+            lines.push("loading:0".to_string());
+            lines.push(format!("rdb_changes_since_last_save:{}", persistence.dirty()));
+            lines.push(format!("rdb_bgsave_in_progress:{}", persistence.bgsave_in_progress() as u8));
+            lines.push(format!("rdb_last_save_time:{}", persistence.lastsave()));
+            lines.push(format!("rdb_last_bgsave_status:{}", if persistence.last_bgsave_ok() { "ok" } else { "err" }));
+            lines.push(format!("aof_enabled:{}", persistence.aof_enabled() as u8));
+            lines.push(format!("aof_rewrite_in_progress:{}", persistence.aof_rewrite_in_progress() as u8));
+        },
+        "stats" => {
+            let stats = db.stats();
+            let [ops, input, output] = stats.instantaneous();
+            let counters = stats.counters();
+            lines.push("# Stats".to_string());
+            for (field, value) in &counters[..2] {
+                lines.push(format!("{}:{}", field, value));
+            }
+            lines.push(format!("instantaneous_ops_per_sec:{}", ops.round()));
+            for (field, value) in &counters[2..] {
+                lines.push(format!("{}:{}", field, value));
+            }
+            lines.push(format!("instantaneous_input_kbps:{:.2}", input / 1024.0));
+            lines.push(format!("instantaneous_output_kbps:{:.2}", output / 1024.0));
+        },
+        //The replication section already ends with a line break.
+        "replication" => return db.replication().info(),
+        "cpu" => {
+            let (sys, user) = cpu_seconds();
+            lines.push("# CPU".to_string());
+            lines.push(format!("used_cpu_sys:{:.6}", sys));
+            lines.push(format!("used_cpu_user:{:.6}", user));
+        },
+        "commandstats" => {
+            lines.push("# Commandstats".to_string());
+            for (name, stats) in db.stats().commands() {
+                let per_call = if stats.calls > 0 { stats.usec as f64 / stats.calls as f64 } else { 0.0 };
+                lines.push(format!(
+                    "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}",
+                    name, stats.calls, stats.usec, per_call, stats.rejected_calls, stats.failed_calls,
+                ));
+            }
+        },
+        "keyspace" => {
+            lines.push("# Keyspace".to_string());
+            if !db.is_empty() {
+                lines.push(format!("db0:keys={},expires={},avg_ttl={}", db.len(), db.expires_len(), db.avg_ttl()));
+            }
+        },
+        _ => (),
+    }
+    lines.join("\r\n") + "\r\n"
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+//Like Redis: 512B, 1.50K, 3.25M.
+fn human_bytes(bytes: u64) -> String {
+    let units = [("G", 1u64 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+    for (unit, size) in units {
+        if bytes >= size {
+            return format!("{:.2}{}", bytes as f64 / size as f64, unit);
+        }
+    }
+    format!("{}B", bytes)
+}
+
+//Resident set size, from /proc where there is one. Also used by the metrics
+//endpoint.
+pub(crate) fn rss_bytes() -> u64 {
+    let pages = fs::read_to_string("/proc/self/statm").ok()
+        .and_then(|statm| statm.split_whitespace().nth(1).and_then(|pages| pages.parse::<u64>().ok()))
+        .unwrap_or(0);
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    pages * page_size.max(0) as u64
+}
+
+//System and user CPU time of the process, in seconds.
+fn cpu_seconds() -> (f64, f64) {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return (0.0, 0.0);
+    }
+    let seconds = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1_000_000.0;
+    (seconds(usage.ru_stime), seconds(usage.ru_utime))
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::db::Db;
+    use crate::info::*;
+
+    fn titles(text: &str) -> Vec<&str> {
+        text.lines().filter(|line| line.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn section_selection() {
+        let db = Db::new();
+        let default = info(&db, &[]);
+        assert_eq!(titles(&default), vec!["# Server", "# Clients", "# Memory", "# Persistence", "# Stats", "# Replication", "# CPU", "# Keyspace"]);
+        assert!(default.contains("\r\n\r\n# Clients\r\n"));
+
+        assert!(titles(&info(&db, &["all".to_string()])).contains(&"# Commandstats"));
+        assert!(titles(&info(&db, &["everything".to_string()])).contains(&"# Commandstats"));
+        assert_eq!(titles(&info(&db, &["KEYSPACE".to_string(), "cpu".to_string()])), vec!["# CPU", "# Keyspace"]);
+        assert_eq!(info(&db, &["nosuchsection".to_string()]), "");
+    }
+
+    #[test]
+    fn keyspace_and_commandstats() {
+        let mut db = Db::new();
+        assert_eq!(info(&db, &["keyspace".to_string()]), "# Keyspace\r\n");
+        db.set("a".to_string(), "1".to_string());
+        db.set("b".to_string(), "2".to_string());
+        db.stats().call("get", Duration::from_micros(15), true);
+        db.stats().reject("set");
+
+        assert_eq!(info(&db, &["keyspace".to_string()]), "# Keyspace\r\ndb0:keys=2,expires=0,avg_ttl=0\r\n");
+        let commandstats = info(&db, &["commandstats".to_string()]);
+        assert!(commandstats.contains("cmdstat_get:calls=1,usec=15,usec_per_call=15.00,rejected_calls=0,failed_calls=0\r\n"));
+        assert!(commandstats.contains("cmdstat_set:calls=0,usec=0,usec_per_call=0.00,rejected_calls=1,failed_calls=0\r\n"));
+        assert_eq!(human_bytes(512), "512B");
+        assert_eq!(human_bytes(1536), "1.50K");
+    }
+}
@@ -0,0 +1,314 @@
+use crate::frame::Frame;
+use crate::stats::Stats;
+use bytes::Bytes;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//Samples kept per event, like Redis' LATENCY_TS_LEN.
+const HISTORY_LEN: usize = 160;
+
+const DISABLED: &str = "I'm sorry, Dave, I can't do that. Latency monitoring is disabled in this Redis instance. You may use \"CONFIG SET latency-monitor-threshold <milliseconds>.\" in order to enable it.\n";
+const NO_SPIKES: &str = "Dave, no latency spike was observed during the lifetime of this Redis instance, not in the slightest bit. I honestly think you ought to sleep tonight.\n";
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum LatencyCmd {
+    LATEST,
+    HISTORY(String),
+    //Every event when empty.
+    RESET(Vec<String>),
+    //Every command when empty.
+    HISTOGRAM(Vec<String>),
+    DOCTOR,
+}
+
+impl LatencyCmd {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LatencyCmd::LATEST => "latest",
+            LatencyCmd::HISTORY(_) => "history",
+            LatencyCmd::RESET(_) => "reset",
+            LatencyCmd::HISTOGRAM(_) => "histogram",
+            LatencyCmd::DOCTOR => "doctor",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+struct Sample {
+    //Unix seconds.
+    time: u64,
+    ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct Event {
+    //Oldest first, one sample per second at most.
+    history: VecDeque<Sample>,
+    max: u64,
+}
+
+//The latency monitor: events such as command execution, background save
+//snapshots or the expire cycle that took at least latency-monitor-threshold
+//milliseconds.
+#[derive(Debug, Default)]
+pub struct Latency {
+    //0 turns the monitor off.
+    threshold_ms: AtomicU64,
+    events: Mutex<BTreeMap<String, Event>>,
+}
+
+impl Latency {
+    pub fn set_threshold(&self, ms: u64) {
+        self.threshold_ms.store(ms, Ordering::Relaxed);
+    }
+
+    pub fn add_sample_if_needed(&self, event: &str, duration: Duration) {
+        let threshold = self.threshold_ms.load(Ordering::Relaxed);
+        let ms = duration.as_millis() as u64;
+        if threshold > 0 && ms >= threshold {
+            self.add_sample(event, now_secs(), ms);
+        }
+    }
+
+    //Samples within the same second are merged, keeping the worst.
+    fn add_sample(&self, event: &str, time: u64, ms: u64) {
+        let mut events = self.events.lock().unwrap();
+        let event = events.entry(event.to_string()).or_default();
+        event.max = event.max.max(ms);
+        match event.history.back_mut() {
+            Some(last) if last.time == time => last.ms = last.ms.max(ms),
+            _ => {
+                event.history.push_back(Sample { time, ms });
+                if event.history.len() > HISTORY_LEN {
+                    event.history.pop_front();
+                }
+            },
+        }
+    }
+
+    //Returns how many of the events had samples.
+    pub fn reset(&self, names: &[String]) -> usize {
+        let mut events = self.events.lock().unwrap();
+        if names.is_empty() {
+            let count = events.len();
+            events.clear();
+            return count;
+        }
+        names.iter().filter(|name| events.remove(name.as_str()).is_some()).count()
+    }
+
+    //LATENCY DOCTOR: statistics about each event, and advice for the kinds
+    //of events seen.
+    fn doctor(&self) -> String {
+        let events = self.events.lock().unwrap();
+        if events.is_empty() {
+            return if self.threshold_ms.load(Ordering::Relaxed) == 0 { DISABLED } else { NO_SPIKES }.to_string();
+        }
+        let mut report = String::from("Dave, I have observed latency spikes in this Redis instance. You don't mind talking about it, do you Dave?\n\n");
+        let mut advice = Vec::new();
+        for (i, (name, event)) in events.iter().enumerate() {
+            let samples: Vec<u64> = event.history.iter().map(|sample| sample.ms).collect();
+            let avg = samples.iter().sum::<u64>() as f64 / samples.len() as f64;
+            let deviation = samples.iter().map(|ms| (*ms as f64 - avg).abs()).sum::<f64>() / samples.len() as f64;
+            let period = match (event.history.front(), event.history.back()) {
+                (Some(first), Some(last)) if samples.len() > 1 => (last.time - first.time) as f64 / (samples.len() - 1) as f64,
+                _ => 0.0,
+            };
+            report.push_str(&format!(
+                "{}. {}: {} latency spikes (average {:.0}ms, mean deviation {:.0}ms, period {:.2} sec). Worst all time event {}ms.\n",
+                i + 1, name, samples.len(), avg, deviation, period, event.max,
+            ));
+            let tip = match name.as_str() {
+                "command" | "fast-command" => "- Check your Slow Log to understand what are the commands you are running which are too slow to execute. Please check https://redis.io/commands/slowlog for more information.",
+                "fork" => "- Background saves copy the dataset while holding the database lock, which blocks every client. With a large dataset consider saving less often, or only on a replica.",
+                "expire-cycle" => "- Many keys are expiring at the same time. Consider adding some randomness to the expire times so that they are spread over time.",
+                name if name.starts_with("aof-") => "- Writing or syncing the AOF is slow. Consider using appendfsync everysec instead of always, or a faster disk.",
+                _ => continue,
+            };
+            if !advice.contains(&tip) {
+                advice.push(tip);
+            }
+        }
+        if !advice.is_empty() {
+            report.push_str("\nI have a few advices for you:\n\n");
+            for tip in advice {
+                report.push_str(tip);
+                report.push('\n');
+            }
+        }
+        report
+    }
+}
+
+pub fn execute(cmd: &LatencyCmd, latency: &Latency, stats: &Stats) -> Result<Frame, String> {
+    match cmd {
+        LatencyCmd::LATEST => {
+            let mut output = Frame::array();
+            for (name, event) in latency.events.lock().unwrap().iter() {
+                if let Some(last) = event.history.back() {
+                    let mut entry = Frame::array();
+                    entry.push_bulk(Bytes::from(name.clone()));
+                    entry.push_int(last.time as i64);
+                    entry.push_int(last.ms as i64);
+                    entry.push_int(event.max as i64);
+                    output.push_frame(entry);
+                }
+            }
+            Ok(output)
+        },
+        LatencyCmd::HISTORY(name) => {
+            let mut output = Frame::array();
+            if let Some(event) = latency.events.lock().unwrap().get(name) {
+                for sample in &event.history {
+                    let mut entry = Frame::array();
+                    entry.push_int(sample.time as i64);
+                    entry.push_int(sample.ms as i64);
+                    output.push_frame(entry);
+                }
+            }
+            Ok(output)
+        },
+        LatencyCmd::RESET(names) => Ok(Frame::Integer(latency.reset(names) as i64)),
+        LatencyCmd::HISTOGRAM(names) => {
+            let names: Vec<String> = names.iter().map(|name| name.to_lowercase()).collect();
+            let mut output = Frame::array();
+            for (name, command) in stats.commands() {
+                let wanted = names.is_empty()
+                    || names.iter().any(|wanted| *wanted == name || name.strip_prefix(wanted.as_str()).is_some_and(|rest| rest.starts_with('|')));
+                if !wanted || command.calls == 0 {
+                    continue;
+                }
+                let mut histogram = Frame::array();
+                let mut cumulative = 0;
+                for (i, count) in command.histogram.iter().enumerate() {
+                    if *count > 0 {
+                        cumulative += count;
+                        histogram.push_int(1i64 << i);
+                        histogram.push_int(cumulative as i64);
+                    }
+                }
+                let mut entry = Frame::array();
+                entry.push_bulk(Bytes::from("calls"));
+                entry.push_int(command.calls as i64);
+                entry.push_bulk(Bytes::from("histogram_usec"));
+                entry.push_frame(histogram);
+                output.push_bulk(Bytes::from(name));
+                output.push_frame(entry);
+            }
+            Ok(output)
+        },
+        LatencyCmd::DOCTOR => Ok(Frame::Bulk(Bytes::from(latency.doctor()))),
+    }
+}
+
+//HELPER FN
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use bytes::Bytes;
+    use crate::frame::Frame;
+    use crate::latency::*;
+    use crate::stats::Stats;
+
+    #[test]
+    fn samples_and_history() {
+        let latency = Latency::default();
+        latency.add_sample_if_needed("command", Duration::from_millis(500));
+        assert_eq!(latency.reset(&[]), 0);
+
+        latency.set_threshold(100);
+        latency.add_sample_if_needed("command", Duration::from_millis(99));
+        latency.add_sample("command", 1000, 150);
+        latency.add_sample("command", 1000, 120);
+        latency.add_sample("command", 1002, 300);
+        for time in 0..200 {
+            latency.add_sample("fork", 2000 + time, 100);
+        }
+
+        let stats = Stats::default();
+        let history = execute(&LatencyCmd::HISTORY("command".to_string()), &latency, &stats).unwrap();
+        let mut expected = Frame::array();
+        for (time, ms) in [(1000, 150), (1002, 300)] {
+            let mut sample = Frame::array();
+            sample.push_int(time);
+            sample.push_int(ms);
+            expected.push_frame(sample);
+        }
+        assert_eq!(history, expected);
+        assert_eq!(latency.events.lock().unwrap()["fork"].history.len(), HISTORY_LEN);
+
+        let latest = execute(&LatencyCmd::LATEST, &latency, &stats).unwrap();
+        let mut command = Frame::array();
+        command.push_bulk(Bytes::from("command"));
+        command.push_int(1002);
+        command.push_int(300);
+        command.push_int(300);
+        match latest {
+            Frame::Array(events) => assert_eq!(events[0], command),
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        assert_eq!(latency.reset(&["fork".to_string(), "nosuchevent".to_string()]), 1);
+        assert_eq!(execute(&LatencyCmd::RESET(vec![]), &latency, &stats).unwrap(), Frame::Integer(1));
+    }
+
+    #[test]
+    fn histogram_reply() {
+        let stats = Stats::default();
+        stats.call("set", Duration::from_micros(1), true);
+        stats.call("set", Duration::from_micros(3), true);
+        stats.call("set", Duration::from_micros(4), true);
+        stats.call("config|get", Duration::from_micros(100), true);
+        stats.reject("get");
+
+        let reply = execute(&LatencyCmd::HISTOGRAM(vec!["SET".to_string()]), &Latency::default(), &stats).unwrap();
+        let mut histogram = Frame::array();
+        for value in [1, 1, 4, 3] {
+            histogram.push_int(value);
+        }
+        let mut set = Frame::array();
+        set.push_bulk(Bytes::from("calls"));
+        set.push_int(3);
+        set.push_bulk(Bytes::from("histogram_usec"));
+        set.push_frame(histogram);
+        let mut expected = Frame::array();
+        expected.push_bulk(Bytes::from("set"));
+        expected.push_frame(set);
+        assert_eq!(reply, expected);
+
+        match execute(&LatencyCmd::HISTOGRAM(vec!["config".to_string()]), &Latency::default(), &stats).unwrap() {
+            Frame::Array(items) => assert_eq!(items[0], Frame::Bulk(Bytes::from("config|get"))),
+            other => panic!("unexpected reply {:?}", other),
+        }
+        match execute(&LatencyCmd::HISTOGRAM(vec![]), &Latency::default(), &stats).unwrap() {
+            Frame::Array(items) => assert_eq!(items.len(), 4),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn doctor_report() {
+        let latency = Latency::default();
+        assert_eq!(latency.doctor(), DISABLED);
+        latency.set_threshold(10);
+        assert_eq!(latency.doctor(), NO_SPIKES);
+
+        latency.add_sample("command", 100, 10);
+        latency.add_sample("command", 104, 30);
+        latency.add_sample("aof-write", 100, 50);
+        let report = latency.doctor();
+        assert!(report.contains("1. aof-write: 1 latency spikes (average 50ms, mean deviation 0ms, period 0.00 sec). Worst all time event 50ms.\n"));
+        assert!(report.contains("2. command: 2 latency spikes (average 20ms, mean deviation 10ms, period 4.00 sec). Worst all time event 30ms.\n"));
+        assert!(report.contains("- Check your Slow Log"));
+        assert!(report.contains("appendfsync everysec"));
+    }
+}
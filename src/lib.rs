@@ -1,7 +1,56 @@
+pub mod acl;
+
+pub mod aof;
+
+pub mod busy;
+
+pub mod client;
+
+pub mod config;
+
+pub mod db;
+pub use db::{Db, Shared};
+
+pub mod encoding;
+
+pub mod function;
+
 pub mod frame;
 pub use frame::Frame;
 
+pub mod glob;
+
 pub mod handler;
 pub use handler::Handler;
 
+pub mod info;
+
+pub mod latency;
+
+pub mod log;
+
+pub mod lualib;
+
+pub mod lzf;
+
+pub mod metrics;
+
+pub mod monitor;
+
 pub mod parser;
+
+pub mod persistence;
+
+pub mod rdb;
+
+pub mod replication;
+
+pub mod script;
+
+pub mod shutdown;
+
+pub mod slowlog;
+
+pub mod stats;
+
+pub mod tls;
@@ -0,0 +1,124 @@
+use crate::config::LogLevel;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//The server log, set up from loglevel and logfile. Lines look like Redis':
+//`pid:role day month year time level-mark message`.
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::NOTICE as u8);
+//Empty for standard output.
+static LOGFILE: Mutex<String> = Mutex::new(String::new());
+//M for a master, S for a replica.
+static ROLE: AtomicU8 = AtomicU8::new(b'M');
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+//Fails if the log file cannot be opened, which stops the server at startup
+//like in Redis.
+pub fn configure(level: LogLevel, logfile: &str) -> Result<(), String> {
+    if !logfile.is_empty() {
+        open(logfile).map_err(|e| format!("Can't open the log file: {}", e))?;
+    }
+    *LOGFILE.lock().unwrap() = logfile.to_string();
+    set_level(level);
+    Ok(())
+}
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn set_replica(replica: bool) {
+    ROLE.store(if replica { b'S' } else { b'M' }, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level != LogLevel::NOTHING && level as u8 >= LEVEL.load(Ordering::Relaxed)
+}
+
+//The log file is reopened for every line, so that it can be rotated under
+//the running server.
+pub fn log(level: LogLevel, message: &str) {
+    if !enabled(level) {
+        return;
+    }
+    let line = format_line(std::process::id(), ROLE.load(Ordering::Relaxed) as char, SystemTime::now(), level, message);
+    let logfile = LOGFILE.lock().unwrap();
+    let _ = if logfile.is_empty() {
+        io::stdout().lock().write_all(line.as_bytes())
+    } else {
+        open(&logfile).and_then(|mut file| file.write_all(line.as_bytes()))
+    };
+}
+
+pub fn debug(message: &str) {
+    log(LogLevel::DEBUG, message);
+}
+
+pub fn verbose(message: &str) {
+    log(LogLevel::VERBOSE, message);
+}
+
+pub fn notice(message: &str) {
+    log(LogLevel::NOTICE, message);
+}
+
+pub fn warning(message: &str) {
+    log(LogLevel::WARNING, message);
+}
+
+//HELPER FN
+
+fn open(path: &str) -> io::Result<std::fs::File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn format_line(pid: u32, role: char, time: SystemTime, level: LogLevel, message: &str) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&seconds, &mut tm) };
+    let mark = match level {
+        LogLevel::DEBUG => '.',
+        LogLevel::VERBOSE => '-',
+        LogLevel::NOTICE => '*',
+        _ => '#',
+    };
+    format!(
+        "{}:{} {:02} {} {} {:02}:{:02}:{:02}.{:03} {} {}\n",
+        pid, role, tm.tm_mday, MONTHS[tm.tm_mon as usize % 12], tm.tm_year + 1900,
+        tm.tm_hour, tm.tm_min, tm.tm_sec, since_epoch.subsec_millis(), mark, message,
+    )
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::config::LogLevel;
+    use crate::log::*;
+
+    #[test]
+    fn line_format() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let line = format_line(42, 'M', time, LogLevel::NOTICE, "Ready to accept connections");
+        assert!(line.starts_with("42:M "));
+        assert!(line.ends_with(".123 * Ready to accept connections\n"));
+        assert!(line.contains(" Nov 2023 "));
+        assert!(format_line(1, 'S', time, LogLevel::WARNING, "x").ends_with(" # x\n"));
+        assert!(format_line(1, 'S', time, LogLevel::VERBOSE, "x").ends_with(" - x\n"));
+        assert!(format_line(1, 'S', time, LogLevel::DEBUG, "x").ends_with(" . x\n"));
+    }
+
+    #[test]
+    fn levels_filter_lines() {
+        assert!(enabled(LogLevel::NOTICE));
+        assert!(enabled(LogLevel::WARNING));
+        assert!(!enabled(LogLevel::VERBOSE));
+        assert!(!enabled(LogLevel::NOTHING));
+        assert!(configure(LogLevel::NOTICE, "/nonexistent/dir/redis.log").is_err());
+    }
+}
@@ -0,0 +1,609 @@
+use mlua::{LightUserData, Lua, Table, Value, Variadic};
+
+//The libraries Redis preloads into its Lua scripts besides the standard
+//ones: cjson, cmsgpack and bit, with the behaviour of the C modules.
+pub fn install(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    globals.set("cjson", cjson(lua)?)?;
+    globals.set("cmsgpack", cmsgpack(lua)?)?;
+    globals.set("bit", bit(lua)?)?;
+    Ok(())
+}
+
+//Nested tables deeper than this are refused by cjson.
+const JSON_MAX_DEPTH: usize = 1000;
+
+//Tables nested deeper than this are packed as nil by cmsgpack.
+const MSGPACK_MAX_NESTING: usize = 16;
+
+fn cjson(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let cjson = lua.create_table()?;
+    cjson.set("encode", lua.create_function(|lua, value: Value| {
+        let mut output = Vec::new();
+        json_encode(&value, 0, &mut output).map_err(mlua::Error::RuntimeError)?;
+        lua.create_string(&output)
+    })?)?;
+    cjson.set("decode", lua.create_function(|lua, text: mlua::String| {
+        let mut parser = JsonParser { data: text.as_bytes(), pos: 0 };
+        let value = parser.value(lua, 0)?;
+        parser.whitespace();
+        if parser.pos < parser.data.len() {
+            return Err(parser.error("the end"));
+        }
+        Ok(value)
+    })?)?;
+    cjson.set("null", Value::LightUserData(LightUserData(std::ptr::null_mut())))?;
+    Ok(cjson)
+}
+
+fn cmsgpack(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let cmsgpack = lua.create_table()?;
+    cmsgpack.set("pack", lua.create_function(|lua, values: Variadic<Value>| {
+        if values.is_empty() {
+            return Err(mlua::Error::RuntimeError("MessagePack pack needs input.".to_string()));
+        }
+        let mut output = Vec::new();
+        for value in values.iter() {
+            msgpack_encode(value, 0, &mut output);
+        }
+        lua.create_string(&output)
+    })?)?;
+    cmsgpack.set("unpack", lua.create_function(|lua, data: mlua::String| {
+        let mut cur = MsgpackCursor { data: data.as_bytes(), pos: 0 };
+        let mut values = Variadic::new();
+        while cur.pos < cur.data.len() {
+            values.push(cur.value(lua)?);
+        }
+        Ok(values)
+    })?)?;
+    Ok(cmsgpack)
+}
+
+//LuaBitOp: every operation works on 32 bit integers and returns a signed result.
+fn bit(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let bit = lua.create_table()?;
+    bit.set("tobit", lua.create_function(|_, x: f64| Ok(tobit(x) as f64))?)?;
+    bit.set("bnot", lua.create_function(|_, x: f64| Ok(!tobit(x) as f64))?)?;
+    bit.set("bswap", lua.create_function(|_, x: f64| Ok(tobit(x).swap_bytes() as f64))?)?;
+    bit.set("tohex", lua.create_function(|_, (x, n): (f64, Option<i64>)| {
+        let n = n.unwrap_or(8);
+        let hex = if n < 0 { format!("{:08X}", tobit(x)) } else { format!("{:08x}", tobit(x)) };
+        let digits = n.unsigned_abs().min(8) as usize;
+        Ok(hex[8 - digits..].to_string())
+    })?)?;
+    let fold = |op: fn(i32, i32) -> i32| move |_: &Lua, (x, rest): (f64, Variadic<f64>)| {
+        Ok(rest.iter().fold(tobit(x), |acc, y| op(acc, tobit(*y))) as f64)
+    };
+    bit.set("band", lua.create_function(fold(|a, b| a & b))?)?;
+    bit.set("bor", lua.create_function(fold(|a, b| a | b))?)?;
+    bit.set("bxor", lua.create_function(fold(|a, b| a ^ b))?)?;
+    let shift = |op: fn(i32, u32) -> i32| move |_: &Lua, (x, n): (f64, f64)| {
+        Ok(op(tobit(x), (tobit(n) & 31) as u32) as f64)
+    };
+    bit.set("lshift", lua.create_function(shift(|x, n| x.wrapping_shl(n)))?)?;
+    bit.set("rshift", lua.create_function(shift(|x, n| ((x as u32) >> n) as i32))?)?;
+    bit.set("arshift", lua.create_function(shift(|x, n| x >> n))?)?;
+    bit.set("rol", lua.create_function(shift(|x, n| x.rotate_left(n)))?)?;
+    bit.set("ror", lua.create_function(shift(|x, n| x.rotate_right(n)))?)?;
+    Ok(bit)
+}
+
+//HELPER FN
+
+//Numbers wrap around to 32 bits, rounding like LuaBitOp does.
+fn tobit(x: f64) -> i32 {
+    if x.is_finite() {
+        x.round().rem_euclid(4294967296.0) as u32 as i32
+    } else {
+        0
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Number(n) => Some(*n),
+        _ => None,
+    }
+}
+
+//The number of keys and the highest one of a table whose keys are all
+//positive integers, or None when it has any other key.
+fn integer_keys(table: &Table) -> mlua::Result<Option<(usize, usize)>> {
+    let mut count = 0;
+    let mut max = 0;
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, _) = pair?;
+        match number(&key) {
+            Some(n) if n >= 1.0 && n.fract() == 0.0 => {
+                count += 1;
+                max = max.max(n as usize);
+            },
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some((count, max)))
+}
+
+//Formats like C's "%.14g", as cjson does.
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e14 {
+        return (n as i64).to_string();
+    }
+    let sci = format!("{:.13e}", n);
+    let (mantissa, exp) = sci.split_once('e').unwrap_or((&sci, "0"));
+    let exp: i32 = exp.parse().unwrap_or(0);
+    let trim = |s: &str| if s.contains('.') { s.trim_end_matches('0').trim_end_matches('.').to_string() } else { s.to_string() };
+    if !(-4..14).contains(&exp) {
+        format!("{}e{}{:02}", trim(mantissa), if exp < 0 { '-' } else { '+' }, exp.abs())
+    } else {
+        trim(&format!("{:.*}", (13 - exp) as usize, n))
+    }
+}
+
+fn json_encode(value: &Value, depth: usize, output: &mut Vec<u8>) -> Result<(), String> {
+    match value {
+        Value::Nil => output.extend_from_slice(b"null"),
+        Value::LightUserData(data) if data.0.is_null() => output.extend_from_slice(b"null"),
+        Value::Boolean(b) => output.extend_from_slice(if *b { b"true" } else { b"false" }),
+        Value::Integer(_) | Value::Number(_) => {
+            let n = number(value).unwrap_or_default();
+            if !n.is_finite() {
+                return Err("Cannot serialise number: must not be NaN or Inf".to_string());
+            }
+            output.extend_from_slice(format_number(n).as_bytes());
+        },
+        Value::String(s) => json_string(s.as_bytes(), output),
+        Value::Table(table) => {
+            if depth >= JSON_MAX_DEPTH {
+                return Err(format!("Cannot serialise, excessive nesting ({})", depth + 1));
+            }
+            let error = |e: mlua::Error| e.to_string();
+            match integer_keys(table).map_err(error)? {
+                //Holes become null, unless there are too many of them.
+                Some((count, max)) if max > count * 2 && max > 10 => {
+                    return Err("Cannot serialise table: excessively sparse array".to_string());
+                },
+                Some((_, len)) if len > 0 => {
+                    output.push(b'[');
+                    for i in 1..=len {
+                        if i > 1 {
+                            output.push(b',');
+                        }
+                        json_encode(&table.raw_get::<_, Value>(i).map_err(error)?, depth + 1, output)?;
+                    }
+                    output.push(b']');
+                },
+                _ => {
+                    output.push(b'{');
+                    let mut first = true;
+                    for pair in table.clone().pairs::<Value, Value>() {
+                        let (key, val) = pair.map_err(error)?;
+                        if !first {
+                            output.push(b',');
+                        }
+                        first = false;
+                        match &key {
+                            Value::String(s) => json_string(s.as_bytes(), output),
+                            key => match number(key) {
+                                Some(n) => json_string(format_number(n).as_bytes(), output),
+                                None => return Err("Cannot serialise table: table key must be a number or string".to_string()),
+                            },
+                        }
+                        output.push(b':');
+                        json_encode(&val, depth + 1, output)?;
+                    }
+                    output.push(b'}');
+                },
+            }
+        },
+        other => return Err(format!("Cannot serialise {}: type not supported", other.type_name())),
+    }
+    Ok(())
+}
+
+fn json_string(bytes: &[u8], output: &mut Vec<u8>) {
+    output.push(b'"');
+    for &b in bytes {
+        match b {
+            b'"' => output.extend_from_slice(b"\\\""),
+            b'\\' => output.extend_from_slice(b"\\\\"),
+            b'/' => output.extend_from_slice(b"\\/"),
+            b'\n' => output.extend_from_slice(b"\\n"),
+            b'\r' => output.extend_from_slice(b"\\r"),
+            b'\t' => output.extend_from_slice(b"\\t"),
+            0x08 => output.extend_from_slice(b"\\b"),
+            0x0c => output.extend_from_slice(b"\\f"),
+            0..=0x1f | 0x7f => output.extend_from_slice(format!("\\u{:04x}", b).as_bytes()),
+            b => output.push(b),
+        }
+    }
+    output.push(b'"');
+}
+
+struct JsonParser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn error(&self, expected: &str) -> mlua::Error {
+        let found = match self.data.get(self.pos) {
+            Some(_) => "invalid token",
+            None => "the end",
+        };
+        mlua::Error::RuntimeError(format!("Expected {} but found {} at character {}", expected, found, self.pos + 1))
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.data.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn literal(&mut self, word: &[u8]) -> bool {
+        if self.data[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value<'lua>(&mut self, lua: &'lua Lua, depth: usize) -> mlua::Result<Value<'lua>> {
+        if depth >= JSON_MAX_DEPTH {
+            return Err(mlua::Error::RuntimeError(format!("Found too many nested data structures ({}) at character {}", depth + 1, self.pos + 1)));
+        }
+        self.whitespace();
+        match self.data.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let table = lua.create_table()?;
+                self.whitespace();
+                if self.literal(b"}") {
+                    return Ok(Value::Table(table));
+                }
+                loop {
+                    self.whitespace();
+                    if self.data.get(self.pos) != Some(&b'"') {
+                        return Err(self.error("object key string"));
+                    }
+                    let key = self.string()?;
+                    self.whitespace();
+                    if !self.literal(b":") {
+                        return Err(self.error("colon"));
+                    }
+                    let value = self.value(lua, depth + 1)?;
+                    table.raw_set(lua.create_string(&key)?, value)?;
+                    self.whitespace();
+                    if self.literal(b"}") {
+                        return Ok(Value::Table(table));
+                    }
+                    if !self.literal(b",") {
+                        return Err(self.error("comma or object end"));
+                    }
+                }
+            },
+            Some(b'[') => {
+                self.pos += 1;
+                let table = lua.create_table()?;
+                self.whitespace();
+                if self.literal(b"]") {
+                    return Ok(Value::Table(table));
+                }
+                for i in 1.. {
+                    let value = self.value(lua, depth + 1)?;
+                    table.raw_set(i, value)?;
+                    self.whitespace();
+                    if self.literal(b"]") {
+                        break;
+                    }
+                    if !self.literal(b",") {
+                        return Err(self.error("comma or array end"));
+                    }
+                }
+                Ok(Value::Table(table))
+            },
+            Some(b'"') => Ok(Value::String(lua.create_string(&self.string()?)?)),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while matches!(self.data.get(self.pos), Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')) {
+                    self.pos += 1;
+                }
+                let text = std::str::from_utf8(&self.data[start..self.pos]).unwrap_or_default();
+                match text.parse::<f64>() {
+                    Ok(n) => Ok(Value::Number(n)),
+                    Err(_) => {
+                        self.pos = start;
+                        Err(self.error("value"))
+                    },
+                }
+            },
+            _ if self.literal(b"true") => Ok(Value::Boolean(true)),
+            _ if self.literal(b"false") => Ok(Value::Boolean(false)),
+            _ if self.literal(b"null") => Ok(Value::LightUserData(LightUserData(std::ptr::null_mut()))),
+            _ => Err(self.error("value")),
+        }
+    }
+
+    //Reads a string starting at its opening quote, decoding escapes to UTF-8.
+    fn string(&mut self) -> mlua::Result<Vec<u8>> {
+        self.pos += 1;
+        let mut output = Vec::new();
+        loop {
+            let b = *self.data.get(self.pos).ok_or_else(|| self.error("string end"))?;
+            self.pos += 1;
+            match b {
+                b'"' => return Ok(output),
+                b'\\' => {
+                    let escape = *self.data.get(self.pos).ok_or_else(|| self.error("escape"))?;
+                    self.pos += 1;
+                    match escape {
+                        b'"' | b'\\' | b'/' => output.push(escape),
+                        b'b' => output.push(0x08),
+                        b'f' => output.push(0x0c),
+                        b'n' => output.push(b'\n'),
+                        b'r' => output.push(b'\r'),
+                        b't' => output.push(b'\t'),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&code) && self.literal(b"\\u") {
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error("low surrogate"));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            let c = char::from_u32(code).ok_or_else(|| self.error("unicode escape"))?;
+                            output.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        },
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.error("escape"));
+                        },
+                    }
+                },
+                b => output.push(b),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> mlua::Result<u32> {
+        let digits = self.data.get(self.pos..self.pos + 4).ok_or_else(|| self.error("unicode escape"))?;
+        let code = std::str::from_utf8(digits).ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("unicode escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+fn msgpack_encode(value: &Value, depth: usize, output: &mut Vec<u8>) {
+    match value {
+        Value::Boolean(b) => output.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Integer(_) | Value::Number(_) => {
+            let n = number(value).unwrap_or_default();
+            if n.fract() == 0.0 && n >= i64::MIN as f64 && n < 18446744073709551616.0 {
+                msgpack_int(n, output);
+            } else if (n as f32) as f64 == n || n.is_nan() {
+                output.push(0xca);
+                output.extend_from_slice(&(n as f32).to_be_bytes());
+            } else {
+                output.push(0xcb);
+                output.extend_from_slice(&n.to_be_bytes());
+            }
+        },
+        Value::String(s) => {
+            let bytes = s.as_bytes();
+            msgpack_header(bytes.len(), [0xa0, 0xd9, 0xda, 0xdb], 32, output);
+            output.extend_from_slice(bytes);
+        },
+        Value::Table(table) if depth < MSGPACK_MAX_NESTING => {
+            //Only tables with keys 1..n and no holes are arrays, the empty one included.
+            match integer_keys(table) {
+                Ok(Some((count, len))) if count == len => {
+                    msgpack_header(len, [0x90, 0, 0xdc, 0xdd], 16, output);
+                    for i in 1..=len {
+                        msgpack_encode(&table.raw_get::<_, Value>(i).unwrap_or(Value::Nil), depth + 1, output);
+                    }
+                },
+                _ => {
+                    let pairs: Vec<(Value, Value)> = table.clone().pairs().filter_map(Result::ok).collect();
+                    msgpack_header(pairs.len(), [0x80, 0, 0xde, 0xdf], 16, output);
+                    for (key, val) in &pairs {
+                        msgpack_encode(key, depth + 1, output);
+                        msgpack_encode(val, depth + 1, output);
+                    }
+                },
+            }
+        },
+        _ => output.push(0xc0),
+    }
+}
+
+fn msgpack_int(n: f64, output: &mut Vec<u8>) {
+    if n >= 0.0 {
+        let n = n as u64;
+        match n {
+            0..=0x7f => output.push(n as u8),
+            0x80..=0xff => output.extend_from_slice(&[0xcc, n as u8]),
+            0x100..=0xffff => {
+                output.push(0xcd);
+                output.extend_from_slice(&(n as u16).to_be_bytes());
+            },
+            0x10000..=0xffff_ffff => {
+                output.push(0xce);
+                output.extend_from_slice(&(n as u32).to_be_bytes());
+            },
+            _ => {
+                output.push(0xcf);
+                output.extend_from_slice(&n.to_be_bytes());
+            },
+        }
+    } else {
+        let n = n as i64;
+        if n >= -32 {
+            output.push(n as i8 as u8);
+        } else if n >= i8::MIN as i64 {
+            output.extend_from_slice(&[0xd0, n as i8 as u8]);
+        } else if n >= i16::MIN as i64 {
+            output.push(0xd1);
+            output.extend_from_slice(&(n as i16).to_be_bytes());
+        } else if n >= i32::MIN as i64 {
+            output.push(0xd2);
+            output.extend_from_slice(&(n as i32).to_be_bytes());
+        } else {
+            output.push(0xd3);
+            output.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+//Writes a length with the fix, 8, 16 or 32 bit form of a type. Arrays and
+//maps have no 8 bit form, marked by a 0.
+fn msgpack_header(len: usize, markers: [u8; 4], fix_limit: usize, output: &mut Vec<u8>) {
+    if len < fix_limit {
+        output.push(markers[0] | len as u8);
+    } else if len <= 0xff && markers[1] != 0 {
+        output.extend_from_slice(&[markers[1], len as u8]);
+    } else if len <= 0xffff {
+        output.push(markers[2]);
+        output.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        output.push(markers[3]);
+        output.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+struct MsgpackCursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> MsgpackCursor<'a> {
+    fn take(&mut self, n: usize) -> mlua::Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(mlua::Error::RuntimeError("Missing bytes in input.".to_string()));
+        }
+        let slice = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> mlua::Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn len(&mut self, size: usize) -> mlua::Result<usize> {
+        Ok(match size {
+            1 => self.take(1)?[0] as usize,
+            2 => u16::from_be_bytes(self.array()?) as usize,
+            _ => u32::from_be_bytes(self.array()?) as usize,
+        })
+    }
+
+    fn value<'lua>(&mut self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+        let marker = self.take(1)?[0];
+        let number = |n: f64| Ok(Value::Number(n));
+        match marker {
+            0x00..=0x7f => number(marker as f64),
+            0xe0..=0xff => number(marker as i8 as f64),
+            0xc0 => Ok(Value::Nil),
+            0xc2 => Ok(Value::Boolean(false)),
+            0xc3 => Ok(Value::Boolean(true)),
+            0xca => number(f32::from_be_bytes(self.array()?) as f64),
+            0xcb => number(f64::from_be_bytes(self.array()?)),
+            0xcc => number(self.take(1)?[0] as f64),
+            0xcd => number(u16::from_be_bytes(self.array()?) as f64),
+            0xce => number(u32::from_be_bytes(self.array()?) as f64),
+            0xcf => number(u64::from_be_bytes(self.array()?) as f64),
+            0xd0 => number(self.take(1)?[0] as i8 as f64),
+            0xd1 => number(i16::from_be_bytes(self.array()?) as f64),
+            0xd2 => number(i32::from_be_bytes(self.array()?) as f64),
+            0xd3 => number(i64::from_be_bytes(self.array()?) as f64),
+            0xa0..=0xbf | 0xd9 | 0xda | 0xdb | 0xc4 | 0xc5 | 0xc6 => {
+                let len = match marker {
+                    0xa0..=0xbf => (marker & 0x1f) as usize,
+                    0xd9 | 0xc4 => self.len(1)?,
+                    0xda | 0xc5 => self.len(2)?,
+                    _ => self.len(4)?,
+                };
+                Ok(Value::String(lua.create_string(self.take(len)?)?))
+            },
+            0x90..=0x9f | 0xdc | 0xdd => {
+                let len = match marker {
+                    0x90..=0x9f => (marker & 0x0f) as usize,
+                    0xdc => self.len(2)?,
+                    _ => self.len(4)?,
+                };
+                let table = lua.create_table()?;
+                for i in 1..=len {
+                    table.raw_set(i, self.value(lua)?)?;
+                }
+                Ok(Value::Table(table))
+            },
+            0x80..=0x8f | 0xde | 0xdf => {
+                let len = match marker {
+                    0x80..=0x8f => (marker & 0x0f) as usize,
+                    0xde => self.len(2)?,
+                    _ => self.len(4)?,
+                };
+                let table = lua.create_table()?;
+                for _ in 0..len {
+                    let key = self.value(lua)?;
+                    let val = self.value(lua)?;
+                    if key != Value::Nil {
+                        table.raw_set(key, val)?;
+                    }
+                }
+                Ok(Value::Table(table))
+            },
+            _ => Err(mlua::Error::RuntimeError("Bad data format in input.".to_string())),
+        }
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+    use crate::lualib::*;
+
+    fn eval(script: &str) -> mlua::Result<String> {
+        let lua = Lua::new();
+        install(&lua)?;
+        lua.load(script).eval()
+    }
+
+    #[test]
+    fn cjson_round_trip() {
+        assert_eq!(eval("return cjson.encode({1, 'a\\n/', true, 0.5, 1e20})").unwrap(), "[1,\"a\\n\\/\",true,0.5,1e+20]");
+        assert_eq!(eval("return cjson.encode({a={}})").unwrap(), "{\"a\":{}}");
+        let script = "local t = cjson.decode('{\"k\": [1, 2.5, null, \"\\\\u00e9\"]}') \
+                      return table.concat({t.k[1], t.k[2], tostring(t.k[3] == cjson.null), t.k[4]}, ',')";
+        assert_eq!(eval(script).unwrap(), "1,2.5,true,é");
+        assert!(eval("return cjson.decode('{\"a\":')").unwrap_err().to_string().contains("Expected"));
+        assert!(eval("return cjson.encode({f=print})").is_err());
+    }
+
+    #[test]
+    fn cmsgpack_round_trip() {
+        let script = "local s = cmsgpack.pack({1, -1, 300, 'abc', 1.5, {x=true}}) \
+                      local t = cmsgpack.unpack(s) \
+                      return table.concat({#s, t[1], t[2], t[3], t[4], t[5], tostring(t[6].x)}, ',')";
+        assert_eq!(eval(script).unwrap(), "19,1,-1,300,abc,1.5,true");
+        assert_eq!(eval("local s = cmsgpack.pack('a', 1) return table.concat({string.byte(s, 1, -1)}, ' ')").unwrap(), "161 97 1");
+        assert!(eval("return cmsgpack.unpack('\\205')").unwrap_err().to_string().contains("Missing bytes"));
+    }
+
+    #[test]
+    fn bit_operations() {
+        let script = "return table.concat({bit.band(0xff, 0x0f), bit.bor(1, 2, 4), bit.bxor(3, 1), bit.bnot(0), \
+                      bit.lshift(1, 31), bit.rshift(-1, 28), bit.arshift(-16, 2), bit.tobit(0xffffffff + 2), \
+                      bit.tohex(255), bit.tohex(-1, -4), bit.rol(1, 33)}, ',')";
+        assert_eq!(eval(script).unwrap(), "15,7,2,-1,-2147483648,15,-4,1,000000ff,FFFF,2");
+    }
+}
@@ -0,0 +1,92 @@
+//A back reference takes 3 input bytes and copies at most 264 bytes, so no
+//input expands by more than this.
+const MAX_EXPANSION: usize = 88;
+
+//Decompressor for the LZF format Redis uses for large strings in RDB files
+//when rdbcompression is on. The expected length comes from the file, so it
+//is checked against the input before anything is allocated.
+pub fn decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>, String> {
+    if expected_len > input.len().saturating_mul(MAX_EXPANSION) {
+        return Err(format!("LZF input of {} bytes can not decompress to {} bytes", input.len(), expected_len));
+    }
+    let mut output: Vec<u8> = Vec::with_capacity(expected_len);
+    let mut ip = 0;
+
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < 32 {
+            //Literal run of ctrl + 1 bytes.
+            let run = ctrl + 1;
+            if ip + run > input.len() {
+                return Err("LZF literal run past the end of input".to_string());
+            }
+            if output.len() + run > expected_len {
+                return Err(format!("LZF decompressed past the expected {} bytes", expected_len));
+            }
+            output.extend_from_slice(&input[ip..ip + run]);
+            ip += run;
+        } else {
+            //Back reference: copy len bytes from earlier in the output.
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(ip).ok_or("LZF back reference past the end of input")? as usize;
+                ip += 1;
+            }
+            len += 2;
+            let low = *input.get(ip).ok_or("LZF back reference past the end of input")? as usize;
+            ip += 1;
+            let distance = ((ctrl & 0x1f) << 8) + low + 1;
+            if distance > output.len() {
+                return Err("LZF back reference before the start of output".to_string());
+            }
+            if output.len() + len > expected_len {
+                return Err(format!("LZF decompressed past the expected {} bytes", expected_len));
+            }
+            let start = output.len() - distance;
+            for i in 0..len {
+                let byte = output[start + i];
+                output.push(byte);
+            }
+        }
+    }
+
+    if output.len() != expected_len {
+        return Err(format!("LZF decompressed to {} bytes, expected {}", output.len(), expected_len));
+    }
+    Ok(output)
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::lzf::*;
+
+    #[test]
+    fn literal_and_back_reference() {
+        //"abcabcabcabc": a literal "abc" then a 9 byte copy from distance 3.
+        let input = [2, b'a', b'b', b'c', (7 << 5), 0, 2];
+        assert_eq!(decompress(&input, 12).unwrap(), b"abcabcabcabc".to_vec());
+
+        let input = [2, b'a', b'b', b'c', (1 << 5), 2];
+        assert_eq!(decompress(&input, 6).unwrap(), b"abcabc".to_vec());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(decompress(&[5, b'a'], 6).is_err());
+        assert!(decompress(&[0, b'a', (1 << 5), 9], 4).is_err());
+        assert!(decompress(&[0, b'a'], 2).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_lengths() {
+        //A length no input of this size can produce is refused up front.
+        assert!(decompress(&[0, b'a'], usize::MAX).unwrap_err().contains("can not decompress"));
+        assert!(decompress(&[0, b'a'], 1 << 40).is_err());
+        //Output is not allowed to grow past the declared length.
+        let input = [2, b'a', b'b', b'c', (7 << 5), 200, 2];
+        assert!(decompress(&input, 12).unwrap_err().contains("past the expected"));
+    }
+}
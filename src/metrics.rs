@@ -0,0 +1,266 @@
+use crate::busy::Busy;
+use crate::db::Db;
+use crate::info;
+use crate::log;
+use crate::stats::HISTOGRAM_BUCKETS;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+//Requests are small: anything bigger, or slower to arrive, is dropped.
+const MAX_REQUEST: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+//The metrics listener: answers `GET /metrics` with `render`, one request per
+//connection.
+pub async fn serve(listener: TcpListener, db: Arc<Mutex<Db>>, busy: Arc<Busy>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let db = db.clone();
+                let busy = busy.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, db, busy).await {
+                        log::verbose(&format!("Metrics request failed: {}", e));
+                    }
+                });
+            },
+            Err(e) => log::warning(&format!("Metrics listener accept error: {}", e)),
+        }
+    }
+}
+
+//The server state in the OpenMetrics text format.
+pub fn render(db: &Db) -> String {
+    let mut out = String::new();
+    let stats = db.stats();
+    let counters = stats.counters();
+    let counter = |name: &str| counters.iter().find(|(field, _)| *field == name).map(|(_, value)| *value).unwrap_or(0);
+
+    family(&mut out, "redis_uptime_seconds", "gauge", "Seconds since the server started.");
+    sample(&mut out, "redis_uptime_seconds", "", stats.uptime().as_secs());
+    family(&mut out, "redis_connected_clients", "gauge", "Open client connections.");
+    sample(&mut out, "redis_connected_clients", "", db.clients().len());
+    family(&mut out, "redis_connections_received", "counter", "Connections accepted.");
+    sample(&mut out, "redis_connections_received_total", "", counter("total_connections_received"));
+    family(&mut out, "redis_commands_processed", "counter", "Commands processed.");
+    sample(&mut out, "redis_commands_processed_total", "", counter("total_commands_processed"));
+
+    let commands = stats.commands();
+    family(&mut out, "redis_commands", "counter", "Calls per command.");
+    for (name, command) in &commands {
+        sample(&mut out, "redis_commands_total", &cmd_label(name), command.calls);
+    }
+    family(&mut out, "redis_commands_failed", "counter", "Calls per command that replied with an error.");
+    for (name, command) in &commands {
+        sample(&mut out, "redis_commands_failed_total", &cmd_label(name), command.failed_calls);
+    }
+    family(&mut out, "redis_commands_rejected", "counter", "Calls per command refused before running.");
+    for (name, command) in &commands {
+        sample(&mut out, "redis_commands_rejected_total", &cmd_label(name), command.rejected_calls);
+    }
+    //The power of two buckets of LATENCY HISTOGRAM, in seconds. The last
+    //bucket holds everything slower and only shows in +Inf.
+    family(&mut out, "redis_command_duration_seconds", "histogram", "Command execution time.");
+    for (name, command) in &commands {
+        let mut cumulative = 0;
+        for (i, count) in command.histogram.iter().enumerate().take(HISTOGRAM_BUCKETS - 1) {
+            cumulative += count;
+            let le = (1u64 << i) as f64 / 1_000_000.0;
+            sample(&mut out, "redis_command_duration_seconds_bucket", &format!("{},le=\"{}\"", cmd_label(name), le), cumulative);
+        }
+        sample(&mut out, "redis_command_duration_seconds_bucket", &format!("{},le=\"+Inf\"", cmd_label(name)), command.calls);
+        sample(&mut out, "redis_command_duration_seconds_sum", &cmd_label(name), command.usec as f64 / 1_000_000.0);
+        sample(&mut out, "redis_command_duration_seconds_count", &cmd_label(name), command.calls);
+    }
+
+    //There is a single database, db0.
+    family(&mut out, "redis_db_keys", "gauge", "Keys per database.");
+    sample(&mut out, "redis_db_keys", "db=\"db0\"", db.len());
+    family(&mut out, "redis_db_keys_expiring", "gauge", "Keys with an expiry per database.");
+    sample(&mut out, "redis_db_keys_expiring", "db=\"db0\"", db.expires_len());
+
+    family(&mut out, "redis_memory_used_bytes", "gauge", "Memory used by the dataset.");
+    let _ = writeln!(out, "# UNIT redis_memory_used_bytes bytes");
+    sample(&mut out, "redis_memory_used_bytes", "", db.used_memory());
+    family(&mut out, "redis_memory_rss_bytes", "gauge", "Resident set size of the process.");
+    let _ = writeln!(out, "# UNIT redis_memory_rss_bytes bytes");
+    sample(&mut out, "redis_memory_rss_bytes", "", info::rss_bytes());
+
+    family(&mut out, "redis_expired_keys", "counter", "Keys removed because they expired.");
+    sample(&mut out, "redis_expired_keys_total", "", counter("expired_keys"));
+    family(&mut out, "redis_evicted_keys", "counter", "Keys evicted because of maxmemory.");
+    sample(&mut out, "redis_evicted_keys_total", "", counter("evicted_keys"));
+    family(&mut out, "redis_keyspace_hits", "counter", "Lookups that found the key.");
+    sample(&mut out, "redis_keyspace_hits_total", "", counter("keyspace_hits"));
+    family(&mut out, "redis_keyspace_misses", "counter", "Lookups that did not find the key.");
+    sample(&mut out, "redis_keyspace_misses_total", "", counter("keyspace_misses"));
+
+    let persistence = db.persistence();
+    family(&mut out, "redis_rdb_changes_since_last_save", "gauge", "Writes since the last successful save.");
+    sample(&mut out, "redis_rdb_changes_since_last_save", "", persistence.dirty());
+    family(&mut out, "redis_rdb_bgsave_in_progress", "gauge", "Whether a background save is running.");
+    sample(&mut out, "redis_rdb_bgsave_in_progress", "", persistence.bgsave_in_progress() as u8);
+    family(&mut out, "redis_rdb_last_save_timestamp_seconds", "gauge", "Unix time of the last successful save.");
+    sample(&mut out, "redis_rdb_last_save_timestamp_seconds", "", persistence.lastsave());
+    family(&mut out, "redis_rdb_last_bgsave_status", "gauge", "1 if the last background save succeeded.");
+    sample(&mut out, "redis_rdb_last_bgsave_status", "", persistence.last_bgsave_ok() as u8);
+    family(&mut out, "redis_aof_enabled", "gauge", "Whether the append only file is on.");
+    sample(&mut out, "redis_aof_enabled", "", persistence.aof_enabled() as u8);
+    family(&mut out, "redis_aof_rewrite_in_progress", "gauge", "Whether an AOF rewrite is running.");
+    sample(&mut out, "redis_aof_rewrite_in_progress", "", persistence.aof_rewrite_in_progress() as u8);
+
+    out.push_str("# EOF\n");
+    out
+}
+
+//HELPER FN
+
+async fn respond(mut stream: TcpStream, db: Arc<Mutex<Db>>, busy: Arc<Busy>) -> Result<(), String> {
+    let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await
+        .map_err(|_| "timed out reading the request".to_string())??;
+    let (status, content_type, body) = match request_line(&request) {
+        Some(("GET", "/metrics")) => match render_when_free(&db, &busy).await {
+            Some(body) => ("200 OK", CONTENT_TYPE, body),
+            None => ("503 Service Unavailable", "text/plain", "Busy\n".to_string()),
+        },
+        Some(("GET", _)) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        Some(_) => ("405 Method Not Allowed", "text/plain", "Method Not Allowed\n".to_string()),
+        None => ("400 Bad Request", "text/plain", "Bad Request\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body,
+    );
+    stream.write_all(response.as_bytes()).await.map_err(|e| e.to_string())?;
+    stream.shutdown().await.map_err(|e| e.to_string())
+}
+
+//Reads up to the end of the request headers. The body, if any, is ignored.
+//Waits for the database like a command would, without holding up the worker,
+//and gives up once whoever holds it counts as busy.
+async fn render_when_free(db: &Mutex<Db>, busy: &Busy) -> Option<String> {
+    loop {
+        match db.try_lock() {
+            Ok(db) => return Some(render(&db)),
+            Err(TryLockError::Poisoned(e)) => return Some(render(&e.into_inner())),
+            Err(TryLockError::WouldBlock) => (),
+        }
+        if busy.busy_error().is_some() {
+            return None;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<String, String> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST {
+            return Err("request too large".to_string());
+        }
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+//The method and path, without any query string.
+fn request_line(request: &str) -> Option<(&str, &str)> {
+    let mut parts = request.lines().next()?.split_whitespace();
+    let method = parts.next()?;
+    let target = parts.next()?;
+    parts.next()?.starts_with("HTTP/").then_some(())?;
+    Some((method, target.split('?').next().unwrap_or(target)))
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn cmd_label(name: &str) -> String {
+    format!("cmd=\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::db::Db;
+    use crate::metrics::*;
+
+    async fn scrape(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn text_format() {
+        let mut db = Db::new();
+        db.set("a".to_string(), "1".to_string());
+        db.stats().call("get", Duration::from_micros(3), true);
+        db.stats().call("get", Duration::from_micros(100), false);
+        db.stats().reject("set");
+
+        let text = render(&db);
+        assert!(text.ends_with("# EOF\n"));
+        assert!(text.contains("# TYPE redis_commands counter\n"));
+        assert!(text.contains("redis_commands_total{cmd=\"get\"} 2\n"));
+        assert!(text.contains("redis_commands_failed_total{cmd=\"get\"} 1\n"));
+        assert!(text.contains("redis_commands_rejected_total{cmd=\"set\"} 1\n"));
+        assert!(text.contains("redis_command_duration_seconds_bucket{cmd=\"get\",le=\"0.000002\"} 0\n"));
+        assert!(text.contains("redis_command_duration_seconds_bucket{cmd=\"get\",le=\"0.000004\"} 1\n"));
+        assert!(text.contains("redis_command_duration_seconds_bucket{cmd=\"get\",le=\"0.000128\"} 2\n"));
+        assert!(text.contains("redis_command_duration_seconds_bucket{cmd=\"get\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("redis_command_duration_seconds_sum{cmd=\"get\"} 0.000103\n"));
+        assert!(text.contains("redis_db_keys{db=\"db0\"} 1\n"));
+        assert!(text.contains("redis_evicted_keys_total 0\n"));
+        assert!(text.contains("redis_rdb_last_bgsave_status 1\n"));
+        assert_eq!(cmd_label("a\"b"), "cmd=\"a\\\"b\"");
+    }
+
+    #[tokio::test]
+    async fn local_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let db = Arc::new(Mutex::new(Db::new()));
+        db.lock().unwrap().set("k".to_string(), "v".to_string());
+        let busy = db.lock().unwrap().busy();
+        tokio::spawn(serve(listener, db, busy));
+
+        let response = scrape(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("Content-Type: {}\r\n", CONTENT_TYPE)));
+        assert!(response.contains("redis_db_keys{db=\"db0\"} 1\n"));
+        assert!(response.ends_with("# EOF\n"));
+
+        assert!(scrape(addr, "GET /other HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404 "));
+        assert!(scrape(addr, "POST /metrics HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 405 "));
+        assert!(scrape(addr, "nonsense\r\n\r\n").await.starts_with("HTTP/1.1 400 "));
+    }
+}
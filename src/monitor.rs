@@ -0,0 +1,159 @@
+use crate::frame::Frame;
+use crate::log;
+use crate::slowlog;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+
+//Lines a monitor may have pending before it is disconnected, in the spirit of
+//Redis' client output buffer limit, so a stalled client cannot make the
+//server's memory grow while traffic continues.
+pub const MAX_PENDING: usize = 10_000;
+
+//Connections that ran MONITOR. Each gets a line per command processed by the
+//other connections.
+#[derive(Debug, Default)]
+pub struct Monitors {
+    //Checked before formatting anything, so that commands cost nothing more
+    //while no one is monitoring.
+    count: AtomicUsize,
+    feeds: Mutex<Vec<Sender<Bytes>>>,
+}
+
+impl Monitors {
+    pub fn subscribe(&self) -> Receiver<Bytes> {
+        let (sender, receiver) = mpsc::channel(MAX_PENDING);
+        let mut feeds = self.feeds.lock().unwrap();
+        feeds.push(sender);
+        self.count.store(feeds.len(), Ordering::Relaxed);
+        receiver
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.count.load(Ordering::Relaxed) > 0
+    }
+
+    //Sends the command to every monitor, forgetting those that went away or
+    //fell behind; the connection of the latter closes once its backlog is out.
+    //`addr` is the client address, or `lua` for commands run by scripts.
+    pub fn feed(&self, argv: &Frame, addr: &str) {
+        if !self.is_active() {
+            return;
+        }
+        let line = Bytes::from(format_line(now_us(), addr, &slowlog::redacted_args(argv)));
+        let mut feeds = self.feeds.lock().unwrap();
+        feeds.retain(|feed| match feed.try_send(line.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warning("Closing a MONITOR client for overcoming of output buffer limits.");
+                false
+            },
+            Err(TrySendError::Closed(_)) => false,
+        });
+        self.count.store(feeds.len(), Ordering::Relaxed);
+    }
+}
+
+//HELPER FN
+
+//`+1339518083.107412 [0 127.0.0.1:60866] "keys" "*"`, as a status reply.
+fn format_line(time_us: u64, addr: &str, args: &[Bytes]) -> String {
+    let mut line = format!("+{}.{:06} [0 {}]", time_us / 1_000_000, time_us % 1_000_000, addr);
+    for arg in args {
+        line.push(' ');
+        line.push_str(&repr(arg));
+    }
+    line.push_str("\r\n");
+    line
+}
+
+//Quotes an argument the way Redis' sdscatrepr does, so that binary data and
+//line breaks cannot end the status line early.
+fn repr(arg: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for byte in arg {
+        match byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            b if b.is_ascii_graphic() || *b == b' ' => quoted.push(*b as char),
+            b => quoted.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn now_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::frame::Frame;
+    use crate::monitor::*;
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    #[test]
+    fn lines_are_escaped() {
+        let args = [Bytes::from("set"), Bytes::from("k\"ey"), Bytes::from(&b"a\r\n\x00\xff\\ b"[..])];
+        assert_eq!(
+            format_line(1339518083107412, "127.0.0.1:60866", &args),
+            "+1339518083.107412 [0 127.0.0.1:60866] \"set\" \"k\\\"ey\" \"a\\r\\n\\x00\\xff\\\\ b\"\r\n",
+        );
+        assert_eq!(format_line(5, "lua", &[Bytes::from("get")]), "+0.000005 [0 lua] \"get\"\r\n");
+    }
+
+    #[test]
+    fn feeds_subscribers_until_they_leave() {
+        let monitors = Monitors::default();
+        let mut argv = Frame::array();
+        argv.push_bulk(Bytes::from("AUTH"));
+        argv.push_bulk(Bytes::from("secret"));
+        monitors.feed(&argv, "127.0.0.1:1");
+        assert!(!monitors.is_active());
+
+        let mut first = monitors.subscribe();
+        let second = monitors.subscribe();
+        monitors.feed(&argv, "127.0.0.1:1");
+        let line = first.try_recv().unwrap();
+        assert!(line.ends_with(b"[0 127.0.0.1:1] \"AUTH\" \"(redacted)\"\r\n"));
+
+        drop(second);
+        monitors.feed(&argv, "lua");
+        assert!(first.try_recv().is_ok());
+        assert!(monitors.is_active());
+        drop(first);
+        monitors.feed(&argv, "lua");
+        assert!(!monitors.is_active());
+    }
+
+    #[test]
+    fn drops_subscribers_that_fall_behind() {
+        let monitors = Monitors::default();
+        let mut argv = Frame::array();
+        argv.push_bulk(Bytes::from("PING"));
+        let mut slow = monitors.subscribe();
+        for _ in 0..MAX_PENDING {
+            monitors.feed(&argv, "127.0.0.1:1");
+        }
+        assert!(monitors.is_active());
+
+        monitors.feed(&argv, "127.0.0.1:1");
+        assert!(!monitors.is_active());
+        for _ in 0..MAX_PENDING {
+            assert!(slow.try_recv().is_ok());
+        }
+        assert_eq!(slow.try_recv(), Err(TryRecvError::Disconnected));
+    }
+}
@@ -2,9 +2,7 @@ use crate::frame::Frame;
 use bytes::Bytes;
 
 pub fn parse(input: String) -> Result<Frame, String> {
-    let mut input: Vec<String> = input.clone().split(' ').map(String::from).collect();
-    let last_elem = input.len() - 1;
-    input[last_elem].pop();
+    let input: Vec<String> = split_args(input.trim_end_matches(['\r', '\n']));
     let mut output = Frame::array();
     let binding = input[0].to_uppercase().clone();
     let cmd = binding.as_str();
@@ -36,10 +34,79 @@ pub fn parse(input: String) -> Result<Frame, String> {
                 Ok(output)
             }
         },
+        "WATCH" => {
+            if input.len() < 2 {
+                Err("Incorrect number of arguments for WATCH command".to_string())
+            } else {
+                output.push_bulk(Bytes::from("WATCH"));
+                for key in &input[1..] {
+                    output.push_bulk(Bytes::from(key.clone()));
+                }
+                Ok(output)
+            }
+        },
+        "EVAL" | "EVALSHA" | "SCRIPT" | "FUNCTION" | "FCALL" | "FCALL_RO" | "REPLICAOF" | "SLAVEOF" | "WAIT" | "CONFIG" | "AUTH" | "ACL" | "CLIENT" | "SLOWLOG" | "LATENCY" => {
+            if input.len() < 2 {
+                Err(format!("Incorrect number of arguments for {} command", cmd))
+            } else {
+                output.push_bulk(Bytes::from(cmd.to_string()));
+                for arg in &input[1..] {
+                    output.push_bulk(Bytes::from(arg.clone()));
+                }
+                Ok(output)
+            }
+        },
+        "KEYS" => {
+            if input.len() != 2 {
+                Err("Incorrect number of arguments for KEYS command".to_string())
+            } else {
+                output.push_bulk(Bytes::from("KEYS"));
+                output.push_bulk(Bytes::from(input[1].clone()));
+                Ok(output)
+            }
+        },
+        "MULTI" | "EXEC" | "DISCARD" | "UNWATCH" | "FLUSHDB" | "FLUSHALL" | "SAVE" | "BGSAVE" | "LASTSAVE" | "BGREWRITEAOF" | "ROLE" | "MONITOR" => {
+            if input.len() != 1 {
+                Err(format!("Incorrect number of arguments for {} command", cmd))
+            } else {
+                output.push_bulk(Bytes::from(cmd.to_string()));
+                Ok(output)
+            }
+        },
+        "SHUTDOWN" => {
+            output.push_bulk(Bytes::from("SHUTDOWN"));
+            for arg in &input[1..] {
+                output.push_bulk(Bytes::from(arg.clone()));
+            }
+            Ok(output)
+        },
+        "INFO" => {
+            output.push_bulk(Bytes::from("INFO"));
+            for arg in &input[1..] {
+                output.push_bulk(Bytes::from(arg.clone()));
+            }
+            Ok(output)
+        },
         _ => Err(format!("Unknown command: {}", cmd)),
     }
 }
 
+//Splits on spaces, keeping double quoted arguments (such as Lua scripts) whole.
+fn split_args(input: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ' ' if !quoted => args.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    args.push(current);
+    args
+}
+
 //
 //TESTS
 
@@ -84,4 +151,18 @@ mod tests {
 
         assert_eq!(expected, output);
     }
+
+    #[test]
+    fn parse_eval_test() {
+        let input = "eval \"return redis.call('GET', KEYS[1])\" 1 key\n".to_string();
+        let output = parse(input).unwrap();
+
+        let mut expected = Frame::array();
+        expected.push_bulk(Bytes::from("EVAL"));
+        expected.push_bulk(Bytes::from("return redis.call('GET', KEYS[1])"));
+        expected.push_bulk(Bytes::from("1"));
+        expected.push_bulk(Bytes::from("key"));
+
+        assert_eq!(expected, output);
+    }
 }
@@ -0,0 +1,455 @@
+use crate::aof::{self, AofWriter, Fsync};
+use crate::db::{Db, Shared};
+use crate::log;
+use crate::rdb::{self, Snapshot};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Settings {
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    //`save <seconds> <changes>` rules: snapshot when at least `changes`
+    //writes happened and `seconds` passed since the last save.
+    pub save_rules: Vec<(u64, u64)>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: Fsync,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_string(),
+            save_rules: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: Fsync::EVERYSEC,
+        }
+    }
+}
+
+//Persistence bookkeeping shared between the database and background savers.
+//It lives outside the database lock so that a BGSAVE thread can report back.
+#[derive(Debug)]
+pub struct Persistence {
+    settings: Mutex<Settings>,
+    dirty: AtomicU64,
+    lastsave: AtomicU64,
+    bgsave_in_progress: AtomicBool,
+    last_bgsave_ok: AtomicBool,
+    aof: Mutex<Option<AofWriter>>,
+    aof_rewrite_in_progress: AtomicBool,
+}
+
+impl Default for Persistence {
+    fn default() -> Persistence {
+        Persistence {
+            settings: Mutex::new(Settings::default()),
+            dirty: AtomicU64::new(0),
+            lastsave: AtomicU64::new(now()),
+            bgsave_in_progress: AtomicBool::new(false),
+            last_bgsave_ok: AtomicBool::new(true),
+            aof: Mutex::new(None),
+            aof_rewrite_in_progress: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Persistence {
+    pub fn settings(&self) -> Settings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn set_settings(&self, settings: Settings) {
+        if let Some(writer) = self.aof.lock().unwrap().as_mut() {
+            writer.set_fsync(settings.appendfsync);
+        }
+        *self.settings.lock().unwrap() = settings;
+    }
+
+    pub fn aof_path(&self) -> PathBuf {
+        let settings = self.settings.lock().unwrap();
+        settings.dir.join(&settings.appendfilename)
+    }
+
+    pub fn aof_enabled(&self) -> bool {
+        self.aof.lock().unwrap().is_some()
+    }
+
+    pub fn aof_rewrite_in_progress(&self) -> bool {
+        self.aof_rewrite_in_progress.load(Ordering::SeqCst)
+    }
+
+    //Appends a write command to the AOF, if it is enabled.
+    pub fn feed_aof(&self, bytes: &[u8]) {
+        if let Some(writer) = self.aof.lock().unwrap().as_mut() {
+            if let Err(e) = writer.feed(bytes) {
+                log::warning(&e);
+            }
+        }
+    }
+
+    pub fn rdb_path(&self) -> PathBuf {
+        let settings = self.settings.lock().unwrap();
+        settings.dir.join(&settings.dbfilename)
+    }
+
+    //Number of writes since the last successful save.
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    pub fn add_dirty(&self, n: u64) {
+        self.dirty.fetch_add(n, Ordering::SeqCst);
+    }
+
+    pub fn lastsave(&self) -> u64 {
+        self.lastsave.load(Ordering::SeqCst)
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress.load(Ordering::SeqCst)
+    }
+
+    pub fn last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok.load(Ordering::SeqCst)
+    }
+
+    //Writes made while the snapshot was being written still count as dirty.
+    fn saved(&self, dirty_at_snapshot: u64) {
+        self.dirty.fetch_sub(dirty_at_snapshot, Ordering::SeqCst);
+        self.lastsave.store(now(), Ordering::SeqCst);
+    }
+
+    fn rule_matches(&self) -> bool {
+        let dirty = self.dirty();
+        let elapsed = now().saturating_sub(self.lastsave());
+        let settings = self.settings.lock().unwrap();
+        settings.save_rules.iter().any(|(seconds, changes)| dirty >= *changes && elapsed >= *seconds)
+    }
+}
+
+//SAVE: writes the snapshot while the caller holds the database lock.
+pub fn save(db: &Db) -> Result<(), String> {
+    let persistence = db.persistence();
+    if persistence.bgsave_in_progress() {
+        return Err("Background save already in progress".to_string());
+    }
+    let dirty = persistence.dirty();
+    write_snapshot(&db.snapshot(), &persistence.rdb_path())?;
+    persistence.saved(dirty);
+    Ok(())
+}
+
+//BGSAVE: copies the dataset under the lock, then encodes and writes it on a
+//separate thread so clients are only held up for the copy.
+pub fn bgsave(db: &Db) -> Result<(), String> {
+    let persistence = db.persistence();
+    if persistence.bgsave_in_progress.swap(true, Ordering::SeqCst) {
+        return Err("Background save already in progress".to_string());
+    }
+    let snapshot = timed_snapshot(db);
+    let dirty = persistence.dirty();
+    let path = persistence.rdb_path();
+
+    thread::spawn(move || {
+        match write_snapshot(&snapshot, &path) {
+            Ok(()) => {
+                persistence.saved(dirty);
+                persistence.last_bgsave_ok.store(true, Ordering::SeqCst);
+                log::notice("Background saving terminated with success");
+            },
+            Err(e) => {
+                persistence.last_bgsave_ok.store(false, Ordering::SeqCst);
+                log::warning(&format!("Background saving error: {}", e));
+            },
+        }
+        persistence.bgsave_in_progress.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+//Loads the dataset at startup: from the AOF when appendonly is on and the
+//file exists, otherwise from the RDB file. Returns whether a file was found.
+pub fn load(db: &mut Db) -> Result<bool, String> {
+    let persistence = db.persistence();
+    let aof_path = persistence.aof_path();
+    if persistence.settings().appendonly && aof_path.exists() {
+        let loaded = aof::load(&aof_path, db)?;
+        log::notice(&format!("Reading the remaining AOF tail... {} commands loaded", loaded.commands));
+        persistence.dirty.store(0, Ordering::SeqCst);
+        return Ok(true);
+    }
+
+    let path = persistence.rdb_path();
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(format!("Error opening {}: {}", path.display(), e)),
+    };
+    let snapshot = rdb::decode(&data)?;
+    db.restore(snapshot)?;
+    Ok(true)
+}
+
+//Opens the AOF for appending after the dataset was loaded. Without an
+//existing file, one is first written from the loaded dataset.
+pub fn start_aof(db: &Db) -> Result<(), String> {
+    let persistence = db.persistence();
+    let path = persistence.aof_path();
+    if !path.exists() {
+        aof::write_rewrite(&db.snapshot(), &path)?;
+    }
+    let writer = AofWriter::open(&path, persistence.settings().appendfsync)?;
+    *persistence.aof.lock().unwrap() = Some(writer);
+    Ok(())
+}
+
+//Switches `appendonly` at runtime. Turning it on rewrites the AOF from the
+//current dataset first, since an existing file may be stale.
+pub fn set_appendonly(db: &Db, on: bool) -> Result<(), String> {
+    let persistence = db.persistence();
+    let mut settings = persistence.settings();
+    if on && !persistence.aof_enabled() {
+        if persistence.aof_rewrite_in_progress() {
+            return Err("Background append only file rewriting already in progress".to_string());
+        }
+        let path = persistence.aof_path();
+        let tmp = path.with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        aof::write_rewrite(&db.snapshot(), &tmp)?;
+        fs::rename(&tmp, &path).map_err(|e| format!("Error renaming the rewritten AOF: {}", e))?;
+        let writer = AofWriter::open(&path, settings.appendfsync)?;
+        *persistence.aof.lock().unwrap() = Some(writer);
+    } else if !on {
+        if let Some(mut writer) = persistence.aof.lock().unwrap().take() {
+            writer.sync()?;
+        }
+    }
+    settings.appendonly = on;
+    persistence.set_settings(settings);
+    Ok(())
+}
+
+//BGREWRITEAOF: writes the minimal command list for a copy of the dataset on
+//a separate thread. Writes arriving meanwhile still go to the old AOF and are
+//also buffered, then appended to the new file before it replaces the old one.
+pub fn bgrewriteaof(db: &Db) -> Result<(), String> {
+    let persistence = db.persistence();
+    if persistence.aof_rewrite_in_progress.swap(true, Ordering::SeqCst) {
+        return Err("Background append only file rewriting already in progress".to_string());
+    }
+    let snapshot = timed_snapshot(db);
+    let path = persistence.aof_path();
+    let tmp = path.with_file_name(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+    if let Some(writer) = persistence.aof.lock().unwrap().as_mut() {
+        writer.start_rewrite();
+    }
+
+    thread::spawn(move || {
+        let result = aof::write_rewrite(&snapshot, &tmp).and_then(|_| {
+            match persistence.aof.lock().unwrap().as_mut() {
+                Some(writer) => writer.finish_rewrite(&tmp, &path),
+                None => fs::rename(&tmp, &path).map_err(|e| e.to_string()),
+            }
+        });
+        match result {
+            Ok(()) => log::notice("Background AOF rewrite terminated with success"),
+            Err(e) => {
+                if let Some(writer) = persistence.aof.lock().unwrap().as_mut() {
+                    writer.abort_rewrite();
+                }
+                let _ = fs::remove_file(&tmp);
+                log::warning(&format!("Background AOF rewrite error: {}", e));
+            },
+        }
+        persistence.aof_rewrite_in_progress.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+//Called periodically by the server to apply the save rules and the
+//`appendfsync everysec` policy.
+//The save rules are left for a later run while something holds the database.
+pub fn cron(db: &Mutex<Db>, shared: &Shared) {
+    let persistence = &shared.persistence;
+    if let Some(writer) = persistence.aof.lock().unwrap().as_mut() {
+        let start = Instant::now();
+        if let Err(e) = writer.sync_if_due() {
+            log::warning(&e);
+        }
+        shared.latency.add_sample_if_needed("aof-fsync", start.elapsed());
+    }
+    let db = match db.try_lock() {
+        Ok(db) => db,
+        Err(_) => return,
+    };
+    if !persistence.bgsave_in_progress() && persistence.rule_matches() {
+        log::notice(&format!("{} changes since last save, saving...", persistence.dirty()));
+        if let Err(e) = bgsave(&db) {
+            log::warning(&format!("Background saving error: {}", e));
+        }
+    }
+}
+
+//Final persistence before the server exits: the AOF is synced, and the
+//dataset saved when asked to or, by default, when save rules are configured.
+//A background save still running is waited for first.
+pub fn shutdown(db: &Db, requested: Option<bool>) -> Result<(), String> {
+    let persistence = db.persistence();
+    sync_aof(&persistence)?;
+    if requested.unwrap_or(!persistence.settings().save_rules.is_empty()) {
+        while persistence.bgsave_in_progress() {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        log::notice("Saving the final RDB snapshot before exiting.");
+        save(db).map_err(|e| format!("Error trying to save the DB, can't exit: {}", e))?;
+        log::notice("DB saved on disk");
+    }
+    Ok(())
+}
+
+//Flushes the AOF to disk, if it is on.
+pub fn sync_aof(persistence: &Persistence) -> Result<(), String> {
+    if let Some(writer) = persistence.aof.lock().unwrap().as_mut() {
+        writer.sync()?;
+    }
+    Ok(())
+}
+
+//Writes `data` to `path` and flushes it to disk, for a temporary file that
+//is about to be renamed over the real one.
+pub(crate) fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+//Flushes the directory holding `path`, so that a rename into it survives a
+//power loss.
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::File::open(dir)?.sync_all(),
+        _ => fs::File::open(".")?.sync_all(),
+    }
+}
+
+//HELPER FN
+
+//Writes to a temporary file first so a crash never leaves a truncated dump.
+fn write_snapshot(snapshot: &Snapshot, path: &PathBuf) -> Result<(), String> {
+    let tmp = path.with_file_name(format!("temp-{}-{:?}.rdb", std::process::id(), thread::current().id()));
+    write_synced(&tmp, &rdb::encode(snapshot)).map_err(|e| format!("Failed opening the RDB file {}: {}", tmp.display(), e))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Error moving temp DB file on the final destination: {}", e))?;
+    sync_dir(path).map_err(|e| format!("Failed to fsync the directory of {}: {}", path.display(), e))
+}
+
+//The dataset copy made under the lock is what blocks clients during a
+//background save, so it is the "fork" latency event.
+fn timed_snapshot(db: &Db) -> Snapshot {
+    let start = Instant::now();
+    let snapshot = db.snapshot();
+    db.latency().add_sample_if_needed("fork", start.elapsed());
+    snapshot
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::persistence::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn temp_settings(name: &str) -> Settings {
+        let dir = std::env::temp_dir().join(format!("my-redis-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        Settings {
+            dir,
+            save_rules: vec![],
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let settings = temp_settings("save");
+        let mut db = Db::new();
+        db.persistence().set_settings(settings.clone());
+        db.set("a".to_string(), "1".to_string());
+        assert_eq!(db.persistence().dirty(), 1);
+
+        save(&db).unwrap();
+        assert_eq!(db.persistence().dirty(), 0);
+
+        let mut loaded = Db::new();
+        loaded.persistence().set_settings(settings.clone());
+        assert!(load(&mut loaded).unwrap());
+        assert_eq!(loaded.get("a"), Some(&"1".to_string()));
+
+        fs::remove_dir_all(settings.dir).unwrap();
+    }
+
+    #[test]
+    fn save_rules_trigger_bgsave() {
+        let mut settings = temp_settings("cron");
+        settings.save_rules = vec![(0, 2)];
+        let db = Mutex::new(Db::new());
+        let shared = db.lock().unwrap().shared();
+        db.lock().unwrap().persistence().set_settings(settings.clone());
+
+        db.lock().unwrap().set("a".to_string(), "1".to_string());
+        cron(&db, &shared);
+        assert!(!settings.dir.join("dump.rdb").exists());
+
+        db.lock().unwrap().set("b".to_string(), "2".to_string());
+        cron(&db, &shared);
+        let persistence = db.lock().unwrap().persistence();
+        while persistence.bgsave_in_progress() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(persistence.last_bgsave_ok());
+        assert_eq!(persistence.dirty(), 0);
+        assert!(settings.dir.join("dump.rdb").exists());
+
+        fs::remove_dir_all(settings.dir).unwrap();
+    }
+
+    #[test]
+    fn appendonly_toggle_and_rewrite() {
+        let settings = temp_settings("aof");
+        let mut db = Db::new();
+        db.persistence().set_settings(settings.clone());
+        db.set("a".to_string(), "1".to_string());
+
+        set_appendonly(&db, true).unwrap();
+        db.set("b".to_string(), "2".to_string());
+        db.persistence().feed_aof(b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n");
+        bgrewriteaof(&db).unwrap();
+        while db.persistence().aof_rewrite_in_progress() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        set_appendonly(&db, false).unwrap();
+
+        let mut loaded = Db::new();
+        let mut aof_settings = settings.clone();
+        aof_settings.appendonly = true;
+        loaded.persistence().set_settings(aof_settings);
+        assert!(load(&mut loaded).unwrap());
+        assert_eq!(loaded.get("a"), Some(&"1".to_string()));
+        assert_eq!(loaded.get("b"), Some(&"2".to_string()));
+
+        fs::remove_dir_all(settings.dir).unwrap();
+    }
+}
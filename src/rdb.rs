@@ -0,0 +1,791 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::db::Value;
+use crate::encoding;
+use crate::log;
+use crate::lzf;
+
+//Snapshots are written in the RDB format of Redis 7 so that standard tools
+//such as redis-check-rdb can read them. Files from Redis 7.4 (version 12)
+//load too, as long as they do not use hash field expiry.
+pub const RDB_VERSION: u32 = 11;
+const MAX_READ_VERSION: u32 = 12;
+
+pub const OPCODE_SLOT_INFO: u8 = 244;
+pub const OPCODE_FUNCTION2: u8 = 245;
+pub const OPCODE_FUNCTION_PRE_GA: u8 = 246;
+pub const OPCODE_MODULE_AUX: u8 = 247;
+pub const OPCODE_IDLE: u8 = 248;
+pub const OPCODE_FREQ: u8 = 249;
+pub const OPCODE_AUX: u8 = 250;
+pub const OPCODE_RESIZEDB: u8 = 251;
+pub const OPCODE_EXPIRETIME_MS: u8 = 252;
+pub const OPCODE_EXPIRETIME: u8 = 253;
+pub const OPCODE_SELECTDB: u8 = 254;
+pub const OPCODE_EOF: u8 = 255;
+
+pub const TYPE_STRING: u8 = 0;
+pub const TYPE_LIST: u8 = 1;
+pub const TYPE_SET: u8 = 2;
+pub const TYPE_ZSET: u8 = 3;
+pub const TYPE_HASH: u8 = 4;
+pub const TYPE_ZSET_2: u8 = 5;
+pub const TYPE_MODULE_2: u8 = 7;
+pub const TYPE_LIST_ZIPLIST: u8 = 10;
+pub const TYPE_SET_INTSET: u8 = 11;
+pub const TYPE_ZSET_ZIPLIST: u8 = 12;
+pub const TYPE_HASH_ZIPLIST: u8 = 13;
+pub const TYPE_LIST_QUICKLIST: u8 = 14;
+pub const TYPE_STREAM_LISTPACKS: u8 = 15;
+pub const TYPE_HASH_LISTPACK: u8 = 16;
+pub const TYPE_ZSET_LISTPACK: u8 = 17;
+pub const TYPE_LIST_QUICKLIST_2: u8 = 18;
+pub const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+pub const TYPE_SET_LISTPACK: u8 = 20;
+pub const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const ENC_INT8: u64 = 0;
+const ENC_INT16: u64 = 1;
+const ENC_INT32: u64 = 2;
+const ENC_LZF: u64 = 3;
+
+//Module values are walked through a self-describing list of typed fields.
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_SINT: u64 = 1;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_FLOAT: u64 = 3;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+
+//Quicklist 2 nodes hold either a single plain element or a listpack.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+//Everything a snapshot carries, detached from the live database so that it
+//can be encoded and written without holding the lock.
+#[derive(PartialEq, Debug, Default, Clone)]
+pub struct Snapshot {
+    //Key, value and expiry time in unix milliseconds.
+    pub entries: Vec<(String, Value, Option<u64>)>,
+    pub libraries: Vec<String>,
+}
+
+pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::new();
+    output.extend_from_slice(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+
+    let ctime = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    write_aux(&mut output, "redis-ver", "7.0.0");
+    write_aux(&mut output, "redis-bits", "64");
+    write_aux(&mut output, "ctime", &ctime.to_string());
+    write_aux(&mut output, "aof-base", "0");
+
+    for library in &snapshot.libraries {
+        output.push(OPCODE_FUNCTION2);
+        write_string(&mut output, library.as_bytes());
+    }
+
+    if !snapshot.entries.is_empty() {
+        output.push(OPCODE_SELECTDB);
+        write_length(&mut output, 0);
+        output.push(OPCODE_RESIZEDB);
+        write_length(&mut output, snapshot.entries.len() as u64);
+        write_length(&mut output, snapshot.entries.iter().filter(|(_, _, expire)| expire.is_some()).count() as u64);
+        for (key, val, expire) in &snapshot.entries {
+            if let Some(at) = expire {
+                output.push(OPCODE_EXPIRETIME_MS);
+                output.extend_from_slice(&at.to_le_bytes());
+            }
+            write_value(&mut output, key, val);
+        }
+    }
+
+    output.push(OPCODE_EOF);
+    let checksum = crc64(0, &output);
+    output.extend_from_slice(&checksum.to_le_bytes());
+    output
+}
+
+pub fn decode(data: &[u8]) -> Result<Snapshot, String> {
+    decode_prefix(data).map(|(snapshot, _)| snapshot)
+}
+
+//Decodes an RDB payload that may be followed by other data, as in an AOF
+//with an RDB preamble, and returns where the payload ended. Keys of other
+//databases than 0, values we cannot store (streams, module types) and
+//entries with binary strings are skipped with a warning.
+pub fn decode_prefix(data: &[u8]) -> Result<(Snapshot, usize), String> {
+    let mut reader = Reader::new(data);
+    let version = reader.header()?;
+
+    let mut snapshot = Snapshot::default();
+    let mut db = 0;
+    let mut expire = None;
+    let mut other_dbs = 0;
+    let mut binary = 0;
+    loop {
+        let offset = reader.pos;
+        match reader.u8()? {
+            OPCODE_EOF => break,
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            },
+            OPCODE_MODULE_AUX => {
+                let module = reader.module_aux()?;
+                log::warning(&format!("Skipping aux data of module {:#x} at offset {}", module, offset));
+            },
+            OPCODE_FUNCTION2 => {
+                reader.binary = false;
+                let code = reader.utf8()?;
+                if reader.binary {
+                    log::warning(&format!("Skipping function library with non UTF-8 code at offset {}", offset));
+                } else {
+                    snapshot.libraries.push(code);
+                }
+            },
+            OPCODE_FUNCTION_PRE_GA => {
+                return Err(format!("Pre-GA function format at offset {} is not supported", offset));
+            },
+            OPCODE_SELECTDB => {
+                db = reader.length()?;
+            },
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            },
+            OPCODE_SLOT_INFO => {
+                reader.length()?;
+                reader.length()?;
+                reader.length()?;
+            },
+            OPCODE_EXPIRETIME_MS => {
+                expire = Some(reader.u64_le()?);
+            },
+            OPCODE_EXPIRETIME => {
+                expire = Some(reader.u32_le()? as u64 * 1000);
+            },
+            OPCODE_IDLE => {
+                reader.length()?;
+            },
+            OPCODE_FREQ => {
+                reader.u8()?;
+            },
+            value_type => {
+                reader.binary = false;
+                let key = reader.utf8()?;
+                match reader.value(value_type)? {
+                    Some(_) if db != 0 => other_dbs += 1,
+                    Some(_) if reader.binary => binary += 1,
+                    Some(value) => snapshot.entries.push((key, value, expire)),
+                    None => log::warning(&format!("Skipping key '{}' of unsupported type {} at offset {}", key, value_type, offset)),
+                }
+                expire = None;
+            },
+        }
+    }
+    if other_dbs > 0 {
+        log::warning(&format!("Skipped {} keys stored in databases other than 0", other_dbs));
+    }
+    if binary > 0 {
+        log::warning(&format!("Skipped {} keys with a non UTF-8 name or value, which are not supported", binary));
+    }
+    //Checksums were introduced with RDB version 5.
+    if version >= 5 {
+        reader.checksum()?;
+    }
+    Ok((snapshot, reader.pos))
+}
+
+//CRC-64/Jones as used by Redis for RDB checksums (reflected, no final xor).
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95ac9329ac4bc9b5;
+    for byte in data {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ POLY;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+//Cursor over an RDB payload with the primitive decoders shared by the
+//loader and the offline checker.
+pub struct Reader<'a> {
+    pub data: &'a [u8],
+    pub pos: usize,
+    //Set once a string that is not UTF-8 was read, so that the entry holding
+    //it can be skipped.
+    binary: bool,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0, binary: false }
+    }
+
+    //Checks the magic string and returns the RDB version.
+    pub fn header(&mut self) -> Result<u32, String> {
+        let magic = self.bytes(9)?;
+        if &magic[..5] != b"REDIS" {
+            return Err("Wrong signature trying to load DB from file".to_string());
+        }
+        let version = String::from_utf8_lossy(&magic[5..]).parse::<u32>()
+            .map_err(|_| "Invalid RDB version number".to_string())?;
+        if !(1..=MAX_READ_VERSION).contains(&version) {
+            return Err(format!("Can't handle RDB format version {}", version));
+        }
+        Ok(version)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32_le(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64_le(&mut self) -> Result<u64, String> {
+        let b = self.bytes(8)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.data.len() {
+            return Err(format!("Unexpected EOF reading RDB file at offset {}", self.pos));
+        }
+        let slice = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    //Returns the length and whether it was actually a special encoding tag.
+    pub fn length_or_encoding(&mut self) -> Result<(u64, bool), String> {
+        let first = self.u8()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3f) as u64, false)),
+            1 => {
+                let second = self.u8()?;
+                Ok(((((first & 0x3f) as u64) << 8) | second as u64, false))
+            },
+            2 => match first {
+                0x80 => {
+                    let b = self.bytes(4)?;
+                    Ok((u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as u64, false))
+                },
+                0x81 => {
+                    let b = self.bytes(8)?;
+                    Ok((u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]), false))
+                },
+                _ => Err(format!("Unknown length encoding {} in RDB at offset {}", first, self.pos - 1)),
+            },
+            _ => Ok(((first & 0x3f) as u64, true)),
+        }
+    }
+
+    pub fn length(&mut self) -> Result<u64, String> {
+        match self.length_or_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(format!("Unexpected string encoding at offset {}", self.pos - 1)),
+        }
+    }
+
+    pub fn string(&mut self) -> Result<Vec<u8>, String> {
+        match self.length_or_encoding()? {
+            (len, false) => Ok(self.bytes(len as usize)?.to_vec()),
+            (ENC_INT8, true) => Ok((self.u8()? as i8).to_string().into_bytes()),
+            (ENC_INT16, true) => {
+                let b = self.bytes(2)?;
+                Ok(i16::from_le_bytes([b[0], b[1]]).to_string().into_bytes())
+            },
+            (ENC_INT32, true) => {
+                let b = self.bytes(4)?;
+                Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]).to_string().into_bytes())
+            },
+            (ENC_LZF, true) => {
+                let compressed_len = self.length()? as usize;
+                let len = self.length()? as usize;
+                lzf::decompress(self.bytes(compressed_len)?, len)
+                    .map_err(|e| format!("{} at offset {}", e, self.pos))
+            },
+            (enc, true) => Err(format!("Unknown RDB string encoding type {} at offset {}", enc, self.pos - 1)),
+        }
+    }
+
+    //Reads the value of a key of the given type. Streams and module values
+    //are walked over and yield None.
+    pub fn value(&mut self, value_type: u8) -> Result<Option<Value>, String> {
+        let value = match value_type {
+            TYPE_STRING => Value::String(self.utf8()?),
+            TYPE_LIST => {
+                let len = self.length()?;
+                Value::List((0..len).map(|_| self.utf8()).collect::<Result<_, _>>()?)
+            },
+            TYPE_SET => {
+                let len = self.length()?;
+                Value::Set((0..len).map(|_| self.utf8()).collect::<Result<_, _>>()?)
+            },
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.length()?;
+                let mut members = Vec::new();
+                for _ in 0..len {
+                    let member = self.utf8()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        f64::from_bits(self.u64_le()?)
+                    } else {
+                        self.double()?
+                    };
+                    members.push((member, score));
+                }
+                zset(members)
+            },
+            TYPE_HASH => {
+                let len = self.length()?;
+                let mut fields = HashMap::new();
+                for _ in 0..len {
+                    fields.insert(self.utf8()?, self.utf8()?);
+                }
+                Value::Hash(fields)
+            },
+            TYPE_LIST_ZIPLIST => Value::List(self.compact(encoding::ziplist)?),
+            TYPE_LIST_QUICKLIST => {
+                let nodes = self.length()?;
+                let mut items = Vec::new();
+                for _ in 0..nodes {
+                    items.append(&mut self.compact(encoding::ziplist)?);
+                }
+                Value::List(items)
+            },
+            TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.length()?;
+                let mut items = Vec::new();
+                for _ in 0..nodes {
+                    match self.length()? {
+                        QUICKLIST_NODE_PLAIN => items.push(self.utf8()?),
+                        QUICKLIST_NODE_PACKED => items.append(&mut self.compact(encoding::listpack)?),
+                        other => return Err(format!("Unknown quicklist node container {} at offset {}", other, self.pos)),
+                    }
+                }
+                Value::List(items)
+            },
+            TYPE_SET_INTSET => Value::Set(encoding::intset(&self.string()?)?.into_iter().collect()),
+            TYPE_SET_LISTPACK => Value::Set(self.compact(encoding::listpack)?.into_iter().collect()),
+            TYPE_ZSET_ZIPLIST => zset_from_pairs(self.compact(encoding::ziplist)?)?,
+            TYPE_ZSET_LISTPACK => zset_from_pairs(self.compact(encoding::listpack)?)?,
+            TYPE_HASH_ZIPLIST => hash_from_pairs(self.compact(encoding::ziplist)?)?,
+            TYPE_HASH_LISTPACK => hash_from_pairs(self.compact(encoding::listpack)?)?,
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                self.skip_stream(value_type)?;
+                return Ok(None);
+            },
+            TYPE_MODULE_2 => {
+                self.length()?;
+                self.skip_module_value()?;
+                return Ok(None);
+            },
+            other => return Err(format!("Unknown RDB value type {} at offset {}", other, self.pos)),
+        };
+        Ok(Some(value))
+    }
+
+    //Skips a MODULE_AUX payload and returns the module id.
+    pub fn module_aux(&mut self) -> Result<u64, String> {
+        let module = self.length()?;
+        self.length()?;
+        self.length()?;
+        self.skip_module_value()?;
+        Ok(module)
+    }
+
+    //Keys and values are kept as text. A binary string flags the entry it
+    //belongs to instead of being silently mangled; the lossy text returned
+    //for it is only good for messages.
+    fn utf8(&mut self) -> Result<String, String> {
+        match String::from_utf8(self.string()?) {
+            Ok(s) => Ok(s),
+            Err(e) => {
+                self.binary = true;
+                Ok(String::from_utf8_lossy(e.as_bytes()).to_string())
+            },
+        }
+    }
+
+    //Decodes a compact encoding, flagging binary elements like `utf8`.
+    fn compact(&mut self, decode: fn(&[u8]) -> Result<Vec<String>, String>) -> Result<Vec<String>, String> {
+        match decode(&self.string()?) {
+            Err(e) if e.starts_with(encoding::NOT_UTF8) => {
+                self.binary = true;
+                Ok(Vec::new())
+            },
+            result => result,
+        }
+    }
+
+    //Scores of the original ZSET type are stored as text with special
+    //lengths for the infinities and NaN.
+    fn double(&mut self) -> Result<f64, String> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let text = self.bytes(len as usize)?;
+                String::from_utf8_lossy(text).parse::<f64>()
+                    .map_err(|_| format!("Invalid double value at offset {}", self.pos))
+            },
+        }
+    }
+
+    fn skip_module_value(&mut self) -> Result<(), String> {
+        loop {
+            match self.length()? {
+                MODULE_OPCODE_EOF => return Ok(()),
+                MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                    self.length()?;
+                },
+                MODULE_OPCODE_FLOAT => {
+                    self.bytes(4)?;
+                },
+                MODULE_OPCODE_DOUBLE => {
+                    self.bytes(8)?;
+                },
+                MODULE_OPCODE_STRING => {
+                    self.string()?;
+                },
+                other => return Err(format!("Unknown module value opcode {} at offset {}", other, self.pos)),
+            }
+        }
+    }
+
+    fn skip_stream(&mut self, value_type: u8) -> Result<(), String> {
+        let nodes = self.length()?;
+        for _ in 0..nodes {
+            self.string()?;
+            self.string()?;
+        }
+        //Length and last id, then the first id, max deleted id and entries
+        //added counter since version 2.
+        let fields = if value_type == TYPE_STREAM_LISTPACKS { 3 } else { 8 };
+        for _ in 0..fields {
+            self.length()?;
+        }
+        let groups = self.length()?;
+        for _ in 0..groups {
+            self.string()?;
+            self.length()?;
+            self.length()?;
+            if value_type != TYPE_STREAM_LISTPACKS {
+                self.length()?;
+            }
+            let pending = self.length()?;
+            for _ in 0..pending {
+                self.bytes(16 + 8)?;
+                self.length()?;
+            }
+            let consumers = self.length()?;
+            for _ in 0..consumers {
+                self.string()?;
+                self.bytes(if value_type == TYPE_STREAM_LISTPACKS_3 { 16 } else { 8 })?;
+                let pending = self.length()?;
+                self.bytes(16 * pending as usize)?;
+            }
+        }
+        Ok(())
+    }
+
+    //Verifies the CRC64 trailer that follows the EOF opcode. A zero checksum
+    //means the writer had checksums disabled.
+    pub fn checksum(&mut self) -> Result<(), String> {
+        let end = self.pos;
+        let b = self.bytes(8)?;
+        let expected = u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
+        if expected != 0 && expected != crc64(0, &self.data[..end]) {
+            return Err("Wrong RDB checksum".to_string());
+        }
+        Ok(())
+    }
+}
+
+//HELPER FN
+
+fn write_value(output: &mut Vec<u8>, key: &str, val: &Value) {
+    match val {
+        Value::String(s) => {
+            output.push(TYPE_STRING);
+            write_string(output, key.as_bytes());
+            write_string(output, s.as_bytes());
+        },
+        Value::List(items) => {
+            output.push(TYPE_LIST);
+            write_string(output, key.as_bytes());
+            write_length(output, items.len() as u64);
+            for item in items {
+                write_string(output, item.as_bytes());
+            }
+        },
+        Value::Set(items) => {
+            output.push(TYPE_SET);
+            write_string(output, key.as_bytes());
+            write_length(output, items.len() as u64);
+            for item in items {
+                write_string(output, item.as_bytes());
+            }
+        },
+        Value::Hash(fields) => {
+            output.push(TYPE_HASH);
+            write_string(output, key.as_bytes());
+            write_length(output, fields.len() as u64);
+            for (field, value) in fields {
+                write_string(output, field.as_bytes());
+                write_string(output, value.as_bytes());
+            }
+        },
+        Value::ZSet(members) => {
+            output.push(TYPE_ZSET_2);
+            write_string(output, key.as_bytes());
+            write_length(output, members.len() as u64);
+            for (member, score) in members {
+                write_string(output, member.as_bytes());
+                output.extend_from_slice(&score.to_bits().to_le_bytes());
+            }
+        },
+    }
+}
+
+fn zset(mut members: Vec<(String, f64)>) -> Value {
+    members.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal).then_with(|| a.0.cmp(&b.0)));
+    Value::ZSet(members)
+}
+
+//Compact zsets and hashes store their pairs as consecutive elements.
+fn zset_from_pairs(elements: Vec<String>) -> Result<Value, String> {
+    let mut members = Vec::new();
+    for pair in elements.chunks(2) {
+        match pair {
+            [member, score] => {
+                let score = score.parse::<f64>().map_err(|_| format!("Invalid zset score '{}'", score))?;
+                members.push((member.clone(), score));
+            },
+            _ => return Err("Odd number of elements in a compact zset".to_string()),
+        }
+    }
+    Ok(zset(members))
+}
+
+fn hash_from_pairs(elements: Vec<String>) -> Result<Value, String> {
+    let mut fields = HashMap::new();
+    for pair in elements.chunks(2) {
+        match pair {
+            [field, value] => {
+                fields.insert(field.clone(), value.clone());
+            },
+            _ => return Err("Odd number of elements in a compact hash".to_string()),
+        }
+    }
+    Ok(Value::Hash(fields))
+}
+
+fn write_aux(output: &mut Vec<u8>, key: &str, val: &str) {
+    output.push(OPCODE_AUX);
+    write_string(output, key.as_bytes());
+    write_string(output, val.as_bytes());
+}
+
+fn write_length(output: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        output.push(len as u8);
+    } else if len < 1 << 14 {
+        output.push(0x40 | (len >> 8) as u8);
+        output.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        output.push(0x80);
+        output.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        output.push(0x81);
+        output.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+//Strings that are the canonical form of a small integer are stored with the
+//integer encodings, as Redis does.
+fn write_string(output: &mut Vec<u8>, bytes: &[u8]) {
+    if let Some(value) = as_int(bytes) {
+        if let Ok(v) = i8::try_from(value) {
+            output.push(0xc0 | ENC_INT8 as u8);
+            output.push(v as u8);
+            return;
+        } else if let Ok(v) = i16::try_from(value) {
+            output.push(0xc0 | ENC_INT16 as u8);
+            output.extend_from_slice(&v.to_le_bytes());
+            return;
+        } else if let Ok(v) = i32::try_from(value) {
+            output.push(0xc0 | ENC_INT32 as u8);
+            output.extend_from_slice(&v.to_le_bytes());
+            return;
+        }
+    }
+    write_length(output, bytes.len() as u64);
+    output.extend_from_slice(bytes);
+}
+
+fn as_int(bytes: &[u8]) -> Option<i64> {
+    if bytes.is_empty() || bytes.len() > 11 {
+        return None;
+    }
+    let s = std::str::from_utf8(bytes).ok()?;
+    let value = s.parse::<i64>().ok()?;
+    if value.to_string() == s {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::rdb::*;
+
+    #[test]
+    fn crc64_check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    fn string(val: &str) -> Value {
+        Value::String(val.to_string())
+    }
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let snapshot = Snapshot {
+            entries: vec![
+                ("key".to_string(), string("value"), None),
+                ("small".to_string(), string("-12"), Some(4102444800000)),
+                ("medium".to_string(), string("30000"), None),
+                ("large".to_string(), string("2000000000"), None),
+                ("padded".to_string(), string("007"), None),
+                ("long".to_string(), string(&"x".repeat(20000)), None),
+                ("list".to_string(), Value::List(vec!["a".to_string(), "1".to_string()]), None),
+                ("set".to_string(), Value::Set(["a".to_string(), "b".to_string()].into()), None),
+                ("hash".to_string(), Value::Hash([("f".to_string(), "v".to_string())].into()), None),
+                ("zset".to_string(), Value::ZSet(vec![("a".to_string(), -1.5), ("b".to_string(), 2.0)]), None),
+            ],
+            libraries: vec!["#!lua name=lib\nredis.register_function('f', function() return 1 end)".to_string()],
+        };
+        let data = encode(&snapshot);
+
+        assert!(data.starts_with(b"REDIS0011"));
+        assert_eq!(decode(&data).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn decode_stock_encodings() {
+        let mut data = b"REDIS0009".to_vec();
+        write_aux(&mut data, "redis-ver", "6.2.6");
+        data.push(OPCODE_MODULE_AUX);
+        for n in [0x1234, 2, 0, MODULE_OPCODE_UINT, 7, MODULE_OPCODE_EOF] {
+            write_length(&mut data, n);
+        }
+        data.push(OPCODE_SELECTDB);
+        write_length(&mut data, 0);
+
+        data.push(OPCODE_EXPIRETIME_MS);
+        data.extend_from_slice(&4102444800000u64.to_le_bytes());
+        data.push(TYPE_LIST_QUICKLIST);
+        write_string(&mut data, b"list");
+        write_length(&mut data, 1);
+        let mut ziplist = vec![0; 8];
+        ziplist.extend_from_slice(&2u16.to_le_bytes());
+        ziplist.extend_from_slice(&[0, 0x01, b'a', 3, 0xf2, 0xff]);
+        write_string(&mut data, &ziplist);
+
+        data.push(OPCODE_IDLE);
+        write_length(&mut data, 5);
+        data.push(TYPE_SET_INTSET);
+        write_string(&mut data, b"set");
+        let mut intset = Vec::new();
+        for n in [2u32, 2] {
+            intset.extend_from_slice(&n.to_le_bytes());
+        }
+        intset.extend_from_slice(&[1, 0, 2, 0]);
+        write_string(&mut data, &intset);
+
+        data.push(TYPE_HASH_LISTPACK);
+        write_string(&mut data, b"hash");
+        let mut listpack = vec![0; 6];
+        listpack.extend_from_slice(&[0x81, b'f', 2, 0x81, b'v', 2, 0xff]);
+        write_string(&mut data, &listpack);
+
+        data.push(TYPE_STRING);
+        write_string(&mut data, b"lzf");
+        data.push(0xc0 | ENC_LZF as u8);
+        let compressed = [2, b'a', b'b', b'c', 7 << 5, 0, 2];
+        write_length(&mut data, compressed.len() as u64);
+        write_length(&mut data, 12);
+        data.extend_from_slice(&compressed);
+
+        data.push(OPCODE_SELECTDB);
+        write_length(&mut data, 1);
+        data.push(TYPE_STRING);
+        write_string(&mut data, b"other");
+        write_string(&mut data, b"x");
+        data.push(OPCODE_EOF);
+        let checksum = crc64(0, &data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+
+        let snapshot = decode(&data).unwrap();
+        assert_eq!(snapshot.entries, vec![
+            ("list".to_string(), Value::List(vec!["a".to_string(), "1".to_string()]), Some(4102444800000)),
+            ("set".to_string(), Value::Set(["1".to_string(), "2".to_string()].into()), None),
+            ("hash".to_string(), Value::Hash([("f".to_string(), "v".to_string())].into()), None),
+            ("lzf".to_string(), string("abcabcabcabc"), None),
+        ]);
+    }
+
+    #[test]
+    fn decode_rejects_corruption() {
+        let snapshot = Snapshot {
+            entries: vec![("key".to_string(), string("value"), None)],
+            libraries: vec![],
+        };
+        let mut data = encode(&snapshot);
+        let len = data.len();
+
+        assert!(decode(&data[..len - 4]).is_err());
+        data[len - 12] ^= 0xff;
+        assert!(decode(&data).is_err());
+        assert!(decode(b"NOTREDIS0").is_err());
+    }
+
+    #[test]
+    fn decode_skips_binary_strings() {
+        let mut data = b"REDIS0011".to_vec();
+        for (key, val) in [(&b"key"[..], &b"\xff\xfe"[..]), (b"\xc3", b"value"), (b"text", b"ok")] {
+            data.push(TYPE_STRING);
+            write_string(&mut data, key);
+            write_string(&mut data, val);
+        }
+        data.push(TYPE_SET_LISTPACK);
+        write_string(&mut data, b"set");
+        let mut listpack = vec![0; 6];
+        listpack.extend_from_slice(&[0x81, 0xff, 2, 0xff]);
+        write_string(&mut data, &listpack);
+        data.push(OPCODE_FUNCTION2);
+        write_string(&mut data, b"#!lua name=\xff");
+        data.push(OPCODE_EOF);
+        let checksum = crc64(0, &data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+
+        let snapshot = decode(&data).unwrap();
+        assert_eq!(snapshot.entries, vec![("text".to_string(), string("ok"), None)]);
+        assert!(snapshot.libraries.is_empty());
+    }
+}
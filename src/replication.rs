@@ -0,0 +1,726 @@
+use crate::busy::Busy;
+use crate::db::{Db, Shared};
+use crate::frame::{self, Frame};
+use crate::handler::{self, parse_command, Command};
+use crate::log;
+use crate::persistence;
+use crate::shutdown;
+use crate::rdb::{self, Snapshot};
+use crate::script::sha1_hex;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+//Redis' default repl-backlog-size.
+pub const BACKLOG_SIZE: usize = 1024 * 1024;
+
+//Masters ping their replicas this often so that idle links show activity.
+pub const PING_PERIOD: Duration = Duration::from_secs(10);
+
+//Replicas report their offset this often.
+pub const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum Role {
+    Master,
+    Replica {
+        host: String,
+        port: u16,
+        link: Link,
+        task: Option<JoinHandle<()>>,
+    },
+}
+
+//State of a replica's link to its master, as reported by ROLE.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Link {
+    CONNECT,
+    CONNECTING,
+    SYNC,
+    CONNECTED,
+}
+
+impl Link {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Link::CONNECT => "connect",
+            Link::CONNECTING => "connecting",
+            Link::SYNC => "sync",
+            Link::CONNECTED => "connected",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ReplicaInfo {
+    id: u64,
+    ip: String,
+    port: u16,
+    sender: UnboundedSender<Bytes>,
+    ack_offset: u64,
+    last_ack: Instant,
+}
+
+//Offsets count the bytes of the replication stream since the replication id
+//was created. The backlog keeps the tail of that stream so that a replica
+//that reconnects with a known id and offset only gets what it missed.
+#[derive(Debug)]
+struct State {
+    role: Role,
+    replid: String,
+    replid2: String,
+    second_offset: i64,
+    offset: u64,
+    backlog: VecDeque<u8>,
+    backlog_first_byte: u64,
+    replicas: Vec<ReplicaInfo>,
+    next_id: u64,
+    last_ping: Instant,
+    last_io: Instant,
+    listening_port: u16,
+}
+
+#[derive(Debug)]
+pub struct Replication {
+    state: Mutex<State>,
+}
+
+impl Default for Replication {
+    fn default() -> Replication {
+        Replication {
+            state: Mutex::new(State {
+                role: Role::Master,
+                replid: new_replid(),
+                replid2: "0".repeat(40),
+                second_offset: -1,
+                offset: 0,
+                backlog: VecDeque::new(),
+                backlog_first_byte: 1,
+                replicas: Vec::new(),
+                next_id: 1,
+                last_ping: Instant::now(),
+                last_io: Instant::now(),
+                listening_port: 6379,
+            }),
+        }
+    }
+}
+
+//What the connection of a replica that issued PSYNC sends before the live
+//stream: the dataset for a full resync, or the missed part of the backlog.
+#[derive(Debug)]
+pub struct ReplicaSync {
+    pub id: u64,
+    snapshot: Option<Snapshot>,
+    backlog: Vec<u8>,
+    pub receiver: UnboundedReceiver<Bytes>,
+}
+
+impl ReplicaSync {
+    //A full resync sends the RDB as a bulk string without the trailing CRLF.
+    pub fn payload(&self) -> Vec<u8> {
+        match &self.snapshot {
+            Some(snapshot) => {
+                let data = rdb::encode(snapshot);
+                let mut payload = format!("${}\r\n", data.len()).into_bytes();
+                payload.extend_from_slice(&data);
+                payload
+            },
+            None => self.backlog.clone(),
+        }
+    }
+}
+
+impl Replication {
+    pub fn is_master(&self) -> bool {
+        matches!(self.state.lock().unwrap().role, Role::Master)
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    pub fn set_listening_port(&self, port: u16) {
+        self.state.lock().unwrap().listening_port = port;
+    }
+
+    //Appends to the replication stream: the backlog, then every replica.
+    pub fn feed(&self, bytes: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.offset += bytes.len() as u64;
+        state.backlog.extend(bytes);
+        if state.backlog.len() > BACKLOG_SIZE {
+            let excess = state.backlog.len() - BACKLOG_SIZE;
+            state.backlog.drain(..excess);
+            state.backlog_first_byte += excess as u64;
+        }
+        if !state.replicas.is_empty() {
+            let chunk = Bytes::copy_from_slice(bytes);
+            state.replicas.retain(|replica| replica.sender.send(chunk.clone()).is_ok());
+        }
+    }
+
+    //Answers PSYNC and registers the replica. Must be called with the database
+    //locked so that the snapshot and the stream that follows line up.
+    pub fn psync(&self, db: &Db, replid: &str, offset: i64, ip: String, port: u16) -> (Frame, ReplicaSync) {
+        let mut state = self.state.lock().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let known_id = replid == state.replid || (replid == state.replid2 && offset <= state.second_offset);
+        let in_backlog = offset >= state.backlog_first_byte as i64 && offset <= state.offset as i64 + 1;
+        let partial = known_id && in_backlog;
+        let (reply, sync) = if partial {
+            let skip = (offset as u64 - state.backlog_first_byte) as usize;
+            let backlog = state.backlog.iter().skip(skip).copied().collect();
+            let reply = Frame::Simple(format!("CONTINUE {}", state.replid));
+            (reply, ReplicaSync { id, snapshot: None, backlog, receiver })
+        } else {
+            let reply = Frame::Simple(format!("FULLRESYNC {} {}", state.replid, state.offset));
+            (reply, ReplicaSync { id, snapshot: Some(db.snapshot()), backlog: Vec::new(), receiver })
+        };
+
+        //A replica that continues has processed everything before its offset.
+        let ack_offset = if partial { offset as u64 - 1 } else { 0 };
+        state.replicas.push(ReplicaInfo { id, ip, port, sender, ack_offset, last_ack: Instant::now() });
+        (reply, sync)
+    }
+
+    pub fn ack(&self, id: u64, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|r| r.id == id) {
+            replica.ack_offset = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    pub fn remove_replica(&self, id: u64) {
+        self.state.lock().unwrap().replicas.retain(|r| r.id != id);
+    }
+
+    pub fn replicas(&self) -> usize {
+        self.state.lock().unwrap().replicas.len()
+    }
+
+    //Asks the replicas to report their offset right away.
+    pub fn request_acks(&self) {
+        self.feed(&command(&["REPLCONF", "GETACK", "*"]));
+    }
+
+    //Number of replicas that acknowledged at least `offset`.
+    pub fn acked(&self, offset: u64) -> usize {
+        self.state.lock().unwrap().replicas.iter().filter(|r| r.ack_offset >= offset).count()
+    }
+
+    //WAIT: asks the replicas for their offset and blocks until `numreplicas`
+    //of them processed everything written so far, or the timeout (0 waits
+    //forever) expires.
+    pub fn wait(&self, numreplicas: usize, timeout_ms: u64) -> Result<usize, String> {
+        if !self.is_master() {
+            return Err("WAIT cannot be used with replica instances".to_string());
+        }
+        let target = self.offset();
+        if self.acked(target) >= numreplicas {
+            return Ok(self.acked(target));
+        }
+        self.request_acks();
+
+        let start = Instant::now();
+        block(|| loop {
+            let acked = self.acked(target);
+            if acked >= numreplicas || (timeout_ms > 0 && start.elapsed() >= Duration::from_millis(timeout_ms)) {
+                return Ok(acked);
+            }
+            thread::sleep(Duration::from_millis(10));
+        })
+    }
+
+    //REPLICAOF host port. Stops the link to a previous master, if any; the
+    //caller then spawns `run_replica` and hands over its task.
+    pub fn become_replica(&self, host: String, port: u16) {
+        let mut state = self.state.lock().unwrap();
+        if let Role::Replica { task: Some(task), .. } = &state.role {
+            task.abort();
+        }
+        state.role = Role::Replica { host, port, link: Link::CONNECT, task: None };
+        log::set_replica(true);
+    }
+
+    pub fn set_replica_task(&self, handle: JoinHandle<()>) {
+        if let Role::Replica { task, .. } = &mut self.state.lock().unwrap().role {
+            *task = Some(handle);
+        }
+    }
+
+    //REPLICAOF NO ONE. The old id stays valid as a second id so that the
+    //other replicas of the former master can continue from the promoted one.
+    pub fn become_master(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Role::Replica { task: Some(task), .. } = &state.role {
+            task.abort();
+        }
+        if !matches!(state.role, Role::Master) {
+            state.replid2 = std::mem::replace(&mut state.replid, new_replid());
+            state.second_offset = state.offset as i64 + 1;
+        }
+        state.role = Role::Master;
+        log::set_replica(false);
+    }
+
+    //Sends PING into the stream when it has been idle for `PING_PERIOD`.
+    pub fn cron(&self) {
+        let due = {
+            let state = self.state.lock().unwrap();
+            matches!(state.role, Role::Master) && !state.replicas.is_empty() && state.last_ping.elapsed() >= PING_PERIOD
+        };
+        if due {
+            self.feed(&command(&["PING"]));
+            self.state.lock().unwrap().last_ping = Instant::now();
+        }
+    }
+
+    pub fn role(&self) -> Frame {
+        let state = self.state.lock().unwrap();
+        let mut output = Frame::array();
+        match &state.role {
+            Role::Master => {
+                output.push_bulk(Bytes::from("master"));
+                output.push_int(state.offset as i64);
+                let mut replicas = Frame::array();
+                for replica in &state.replicas {
+                    let mut entry = Frame::array();
+                    entry.push_bulk(Bytes::from(replica.ip.clone()));
+                    entry.push_bulk(Bytes::from(replica.port.to_string()));
+                    entry.push_bulk(Bytes::from(replica.ack_offset.to_string()));
+                    replicas.push_frame(entry);
+                }
+                output.push_frame(replicas);
+            },
+            Role::Replica { host, port, link, .. } => {
+                output.push_bulk(Bytes::from("slave"));
+                output.push_bulk(Bytes::from(host.clone()));
+                output.push_int(*port as i64);
+                output.push_bulk(Bytes::from(link.name()));
+                output.push_int(state.offset as i64);
+            },
+        }
+        output
+    }
+
+    //The `# Replication` section of INFO.
+    pub fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut lines = vec!["# Replication".to_string()];
+        match &state.role {
+            Role::Master => {
+                lines.push("role:master".to_string());
+                lines.push(format!("connected_slaves:{}", state.replicas.len()));
+                for (i, replica) in state.replicas.iter().enumerate() {
+                    lines.push(format!(
+                        "slave{}:ip={},port={},state=online,offset={},lag={}",
+                        i, replica.ip, replica.port, replica.ack_offset, replica.last_ack.elapsed().as_secs(),
+                    ));
+                }
+            },
+            Role::Replica { host, port, link, .. } => {
+                lines.push("role:slave".to_string());
+                lines.push(format!("master_host:{}", host));
+                lines.push(format!("master_port:{}", port));
+                lines.push(format!("master_link_status:{}", if *link == Link::CONNECTED { "up" } else { "down" }));
+                lines.push(format!("master_last_io_seconds_ago:{}", state.last_io.elapsed().as_secs()));
+                lines.push(format!("master_sync_in_progress:{}", (*link == Link::SYNC) as u8));
+                lines.push(format!("slave_repl_offset:{}", state.offset));
+                lines.push("slave_read_only:1".to_string());
+                lines.push(format!("connected_slaves:{}", state.replicas.len()));
+            },
+        }
+        lines.push(format!("master_replid:{}", state.replid));
+        lines.push(format!("master_replid2:{}", state.replid2));
+        lines.push(format!("master_repl_offset:{}", state.offset));
+        lines.push(format!("second_repl_offset:{}", state.second_offset));
+        lines.push("repl_backlog_active:1".to_string());
+        lines.push(format!("repl_backlog_size:{}", BACKLOG_SIZE));
+        lines.push(format!("repl_backlog_first_byte_offset:{}", state.backlog_first_byte));
+        lines.push(format!("repl_backlog_histlen:{}", state.backlog.len()));
+        lines.join("\r\n") + "\r\n"
+    }
+
+    fn set_link(&self, new_link: Link) {
+        let mut state = self.state.lock().unwrap();
+        if let Role::Replica { link, .. } = &mut state.role {
+            *link = new_link;
+        }
+        state.last_io = Instant::now();
+    }
+
+    fn touch_io(&self) {
+        self.state.lock().unwrap().last_io = Instant::now();
+    }
+
+    //Replicas always offer their own history, which the master accepts when
+    //it shares the id (e.g. after a reconnection or a failover).
+    fn psync_args(&self) -> (String, u64) {
+        let state = self.state.lock().unwrap();
+        (state.replid.clone(), state.offset + 1)
+    }
+
+    fn full_resync(&self, replid: String, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replid = replid;
+        state.replid2 = "0".repeat(40);
+        state.second_offset = -1;
+        state.offset = offset;
+        state.backlog.clear();
+        state.backlog_first_byte = offset + 1;
+    }
+
+    fn continued(&self, replid: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replid) = replid {
+            if replid != state.replid {
+                state.replid2 = std::mem::replace(&mut state.replid, replid.to_string());
+                state.second_offset = state.offset as i64 + 1;
+            }
+        }
+    }
+}
+
+//Turns the server into a replica of host:port and starts the link task.
+pub fn replicaof(db: Arc<Mutex<Db>>, shared: Shared, host: String, port: u16) -> Result<(), String> {
+    let runtime = Handle::try_current().map_err(|e| e.to_string())?;
+    let replication = shared.replication.clone();
+    replication.become_replica(host.clone(), port);
+    let task = runtime.spawn(run_replica(db, shared, host, port));
+    replication.set_replica_task(task);
+    Ok(())
+}
+
+//Replica side: keeps a link to the master, reconnecting every second after a
+//failure. Reconnections try a partial resync first.
+pub async fn run_replica(db: Arc<Mutex<Db>>, shared: Shared, host: String, port: u16) {
+    let replication = shared.replication.clone();
+    loop {
+        replication.set_link(Link::CONNECTING);
+        if let Err(e) = sync_with_master(&db, &shared, &host, port).await {
+            log::notice(&format!("Connection with master {}:{} lost: {}", host, port, e));
+        }
+        replication.set_link(Link::CONNECT);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn sync_with_master(db: &Arc<Mutex<Db>>, shared: &Shared, host: &str, port: u16) -> Result<(), String> {
+    let replication = &shared.replication;
+    let mut stream = TcpStream::connect((host, port)).await.map_err(|e| e.to_string())?;
+    let mut buf = Vec::new();
+
+    let (masteruser, masterauth) = {
+        let config = shared.config.lock().unwrap();
+        (config.masteruser.clone(), config.masterauth.clone())
+    };
+    if !masterauth.is_empty() {
+        if masteruser.is_empty() {
+            handshake(&mut stream, &mut buf, &["AUTH", &masterauth]).await?;
+        } else {
+            handshake(&mut stream, &mut buf, &["AUTH", &masteruser, &masterauth]).await?;
+        }
+    }
+    handshake(&mut stream, &mut buf, &["PING"]).await?;
+    let listening_port = replication.state.lock().unwrap().listening_port.to_string();
+    handshake(&mut stream, &mut buf, &["REPLCONF", "listening-port", &listening_port]).await?;
+    handshake(&mut stream, &mut buf, &["REPLCONF", "capa", "psync2"]).await?;
+
+    let (replid, offset) = replication.psync_args();
+    send(&mut stream, &["PSYNC", &replid, &offset.to_string()]).await?;
+    match read_frame(&mut stream, &mut buf).await? {
+        Frame::Simple(line) if line.starts_with("FULLRESYNC") => {
+            let parts: Vec<&str> = line.split(' ').collect();
+            let (replid, offset) = match parts.as_slice() {
+                [_, replid, offset] => (replid.to_string(), offset.parse::<u64>().map_err(|e| e.to_string())?),
+                _ => return Err(format!("Bad FULLRESYNC reply: {}", line)),
+            };
+            replication.set_link(Link::SYNC);
+            let data = read_rdb(&mut stream, &mut buf).await?;
+            let snapshot = rdb::decode(&data)?;
+            {
+                let mut db = lock(db, &shared.busy)?;
+                db.restore(snapshot)?;
+                if db.persistence().aof_enabled() {
+                    persistence::bgrewriteaof(&db)?;
+                }
+            }
+            replication.full_resync(replid, offset);
+            log::notice("MASTER <-> REPLICA sync: Finished with success");
+        },
+        Frame::Simple(line) if line.starts_with("CONTINUE") => {
+            replication.continued(line.split(' ').nth(1));
+            log::notice("MASTER <-> REPLICA sync: Master accepted a Partial Resynchronization");
+        },
+        Frame::Error(e) => return Err(e),
+        other => return Err(format!("Unexpected reply to PSYNC: {:?}", other)),
+    }
+    replication.set_link(Link::CONNECTED);
+
+    let mut ack = tokio::time::interval(ACK_PERIOD);
+    let mut chunk = vec![0; 4096];
+    let mut transaction = None;
+    loop {
+        while let Some((frame, len)) = parse_frame(&buf)? {
+            let raw: Vec<u8> = buf.drain(..len).collect();
+            if is_getack(&frame) {
+                send(&mut stream, &["REPLCONF", "ACK", &replication.offset().to_string()]).await?;
+            } else {
+                apply(db, &shared.busy, frame, &mut transaction);
+            }
+            replication.feed(&raw);
+        }
+        tokio::select! {
+            n = stream.read(&mut chunk) => {
+                match n.map_err(|e| e.to_string())? {
+                    0 => return Err("connection closed by master".to_string()),
+                    n => buf.extend_from_slice(&chunk[..n]),
+                }
+                replication.touch_io();
+            },
+            _ = ack.tick() => {
+                send(&mut stream, &["REPLCONF", "ACK", &replication.offset().to_string()]).await?;
+            },
+        }
+    }
+}
+
+//HELPER FN
+
+//Runs a blocking wait without stalling the other tasks of a multi-threaded
+//runtime, which on a single core would otherwise never get to run.
+pub fn block<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+//Locks the database from the link task without stalling the runtime. Unlike
+//a client, the link waits out a busy script instead of getting BUSY, since
+//dropping the master's writes would leave the replica behind.
+fn lock<'a>(db: &'a Mutex<Db>, busy: &Busy) -> Result<MutexGuard<'a, Db>, String> {
+    block(|| loop {
+        match handler::lock_db(db, busy) {
+            Err(e) if e != shutdown::SHUTTING_DOWN => thread::sleep(Duration::from_millis(10)),
+            result => return result,
+        }
+    })
+}
+
+fn new_replid() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    sha1_hex(&format!("{}:{}:{:?}", nanos, std::process::id(), thread::current().id()))
+}
+
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut frame = Frame::array();
+    for arg in args {
+        frame.push_bulk(Bytes::from(arg.to_string()));
+    }
+    frame.deserialize()
+}
+
+fn is_getack(frame: &Frame) -> bool {
+    match frame {
+        Frame::Array(args) => matches!(
+            args.as_slice(),
+            [Frame::Bulk(cmd), Frame::Bulk(sub), ..]
+                if cmd.eq_ignore_ascii_case(b"REPLCONF") && sub.eq_ignore_ascii_case(b"GETACK")
+        ),
+        _ => false,
+    }
+}
+
+//Commands from the master bypass the read-only check of client writes. A
+//transaction is applied under one lock once its EXEC arrives.
+fn apply(db: &Mutex<Db>, busy: &Busy, frame: Frame, transaction: &mut Option<Vec<Command>>) {
+    let result = parse_command(frame).and_then(|cmd| {
+        let mut db = lock(db, busy)?;
+        handler::replay(cmd, transaction, &mut db)
+    });
+    if let Err(e) = result {
+        log::warning(&format!("Error applying a command from the master: {}", e));
+    }
+}
+
+fn parse_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, String> {
+    let mut cursor = Cursor::new(buf);
+    match Frame::serialize(&mut cursor) {
+        Ok(frame) => Ok(Some((frame, cursor.position() as usize))),
+        Err(e) if e == frame::INCOMPLETE => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+async fn send(stream: &mut TcpStream, args: &[&str]) -> Result<(), String> {
+    stream.write_all(&command(args)).await.map_err(|e| e.to_string())
+}
+
+async fn read_more(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<(), String> {
+    let mut chunk = vec![0; 4096];
+    match stream.read(&mut chunk).await.map_err(|e| e.to_string())? {
+        0 => Err("connection closed by master".to_string()),
+        n => {
+            buf.extend_from_slice(&chunk[..n]);
+            Ok(())
+        },
+    }
+}
+
+async fn read_frame(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<Frame, String> {
+    loop {
+        if let Some((frame, len)) = parse_frame(buf)? {
+            buf.drain(..len);
+            return Ok(frame);
+        }
+        read_more(stream, buf).await?;
+    }
+}
+
+async fn handshake(stream: &mut TcpStream, buf: &mut Vec<u8>, args: &[&str]) -> Result<(), String> {
+    send(stream, args).await?;
+    match read_frame(stream, buf).await? {
+        Frame::Error(e) => Err(format!("{} replied: {}", args[0], e)),
+        _ => Ok(()),
+    }
+}
+
+async fn read_rdb(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<Vec<u8>, String> {
+    let header_end = loop {
+        if let Some(i) = buf.windows(2).position(|w| w == b"\r\n") {
+            break i;
+        }
+        read_more(stream, buf).await?;
+    };
+    if buf.first() != Some(&b'$') {
+        return Err("Bad bulk length from master".to_string());
+    }
+    let len = String::from_utf8_lossy(&buf[1..header_end]).parse::<usize>()
+        .map_err(|_| "Bad bulk length from master".to_string())?;
+    while buf.len() < header_end + 2 + len {
+        read_more(stream, buf).await?;
+    }
+    let data = buf[header_end + 2..header_end + 2 + len].to_vec();
+    buf.drain(..header_end + 2 + len);
+    Ok(data)
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::busy::Operation;
+    use crate::db::Db;
+    use crate::replication::*;
+
+    #[test]
+    fn full_then_partial_resync() {
+        let mut db = Db::new();
+        db.set("a".to_string(), "1".to_string());
+        let replication = Replication::default();
+        replication.feed(b"hello");
+
+        let (reply, sync) = replication.psync(&db, "?", -1, "127.0.0.1".to_string(), 6380);
+        let replid = match reply {
+            Frame::Simple(line) => line.split(' ').nth(1).unwrap().to_string(),
+            _ => panic!("expected a status reply"),
+        };
+        assert!(sync.payload().starts_with(b"$"));
+        assert_eq!(sync.snapshot.as_ref().unwrap().entries.len(), 1);
+
+        let (reply, mut sync) = replication.psync(&db, &replid, 3, "127.0.0.1".to_string(), 6381);
+        assert_eq!(reply, Frame::Simple(format!("CONTINUE {}", replid)));
+        assert_eq!(sync.payload(), b"llo".to_vec());
+
+        replication.feed(b"!");
+        assert_eq!(sync.receiver.try_recv().unwrap(), Bytes::from("!"));
+
+        let (reply, _) = replication.psync(&db, &replid, 100, "127.0.0.1".to_string(), 6382);
+        assert!(matches!(reply, Frame::Simple(line) if line.starts_with("FULLRESYNC")));
+    }
+
+    #[test]
+    fn acks_and_wait() {
+        let db = Db::new();
+        let replication = Replication::default();
+        assert_eq!(replication.wait(0, 0).unwrap(), 0);
+
+        let (_, sync) = replication.psync(&db, "?", -1, "127.0.0.1".to_string(), 6380);
+        replication.feed(b"data");
+        assert_eq!(replication.acked(4), 0);
+        assert_eq!(replication.wait(1, 20).unwrap(), 0);
+
+        replication.ack(sync.id, 100);
+        assert_eq!(replication.wait(1, 0).unwrap(), 1);
+
+        replication.remove_replica(sync.id);
+        assert_eq!(replication.acked(0), 0);
+    }
+
+    #[test]
+    fn backlog_is_capped() {
+        let replication = Replication::default();
+        replication.feed(&vec![b'x'; BACKLOG_SIZE]);
+        replication.feed(b"tail");
+
+        let info = replication.info();
+        assert!(info.contains(&format!("repl_backlog_first_byte_offset:{}", 5)));
+        assert!(info.contains(&format!("master_repl_offset:{}", BACKLOG_SIZE + 4)));
+    }
+
+    #[test]
+    fn link_lock_waits_for_busy_scripts_and_survives_poisoning() {
+        let db = Arc::new(Mutex::new(Db::new()));
+        let busy = Arc::new(Busy::default());
+        busy.set_threshold_ms(0);
+        let (locked, wait) = std::sync::mpsc::channel();
+        let holder = {
+            let (db, busy) = (db.clone(), busy.clone());
+            thread::spawn(move || {
+                let _db = db.lock().unwrap();
+                busy.run(Operation::Script, || {
+                    locked.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(50));
+                });
+            })
+        };
+        wait.recv().unwrap();
+        assert!(busy.busy_error().is_some());
+        assert!(lock(&db, &busy).is_ok());
+        holder.join().unwrap();
+
+        let poisoner = db.clone();
+        let _ = thread::spawn(move || {
+            let _db = poisoner.lock().unwrap();
+            panic!("poisoning the lock");
+        }).join();
+        assert!(db.is_poisoned());
+        assert!(lock(&db, &busy).is_ok());
+    }
+
+    #[test]
+    fn become_master_keeps_old_id() {
+        let replication = Replication::default();
+        let (old, _) = replication.psync_args();
+        replication.become_replica("127.0.0.1".to_string(), 6379);
+        assert!(!replication.is_master());
+
+        replication.become_master();
+        assert!(replication.is_master());
+        assert!(replication.info().contains(&format!("master_replid2:{}", old)));
+    }
+}
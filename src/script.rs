@@ -0,0 +1,377 @@
+use crate::db::Db;
+use crate::frame::Frame;
+use crate::handler::{parse_command, run_cmd, Caller, READONLY};
+use crate::log;
+use crate::lualib;
+use bytes::Bytes;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+use std::cell::RefCell;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ScriptCmd {
+    LOAD(String),
+    EXISTS(Vec<String>),
+    FLUSH,
+    KILL,
+}
+
+pub fn sha1_hex(script: &str) -> String {
+    sha1_smol::Sha1::from(script).digest().to_string()
+}
+
+//Runs a script against an already locked database, so the whole script is
+//atomic with respect to other connections.
+pub fn eval(db: &mut Db, script: &str, keys: &[String], args: &[String], caller: Option<&Caller>) -> Result<Frame, String> {
+    run(db, false, caller, |lua| {
+        let globals = lua.globals();
+        globals.set("KEYS", keys)?;
+        globals.set("ARGV", args)?;
+        lua.load(script).eval()
+    })
+}
+
+//Sets up a Lua state whose `redis` table dispatches into the command layer,
+//then converts whatever `body` returns into a reply. With `read_only`, write
+//commands called from the script are refused. The commands are checked
+//against the ACL of `caller`, if there is one.
+pub(crate) fn run<F>(db: &mut Db, read_only: bool, caller: Option<&Caller>, body: F) -> Result<Frame, String>
+where
+    F: for<'lua> FnOnce(&'lua Lua) -> mlua::Result<Value<'lua>>,
+{
+    let lua = sandbox().map_err(|e| format!("Error running script: {}", error_message(&e)))?;
+    let busy = db.busy();
+    lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |_, _| {
+        busy.check().map_err(mlua::Error::RuntimeError)
+    });
+    run_in(&lua, db, read_only, caller, body)
+}
+
+//Like `run`, in a state that outlives the call, such as a function library's.
+//The `redis` table the state already has, if any, is kept.
+pub(crate) fn run_in<F>(lua: &Lua, db: &mut Db, read_only: bool, caller: Option<&Caller>, body: F) -> Result<Frame, String>
+where
+    F: for<'lua> FnOnce(&'lua Lua) -> mlua::Result<Value<'lua>>,
+{
+    let db = RefCell::new(db);
+
+    let result = lua.scope(|scope| {
+        let redis = match lua.globals().get("redis")? {
+            Value::Table(redis) => redis,
+            _ => lua.create_table()?,
+        };
+
+        let call = scope.create_function(|lua, argv: Variadic<mlua::String>| {
+            match dispatch(&mut db.borrow_mut(), argv, read_only, caller) {
+                Ok(frame) => frame_to_lua(lua, frame),
+                Err(e) => Err(mlua::Error::RuntimeError(e)),
+            }
+        })?;
+        redis.set("call", call)?;
+
+        let pcall = scope.create_function(|lua, argv: Variadic<mlua::String>| {
+            match dispatch(&mut db.borrow_mut(), argv, read_only, caller) {
+                Ok(frame) => frame_to_lua(lua, frame),
+                Err(e) => frame_to_lua(lua, Frame::Error(e)),
+            }
+        })?;
+        redis.set("pcall", pcall)?;
+
+        lua.globals().set("redis", redis)?;
+
+        let value = body(lua)?;
+        Ok(lua_to_frame(value))
+    });
+
+    //The scoped functions are unusable once the scope ends.
+    if let Ok(Value::Table(redis)) = lua.globals().get::<_, Value>("redis") {
+        let _ = redis.set("call", Value::Nil);
+        let _ = redis.set("pcall", Value::Nil);
+    }
+    result.map_err(|e| format!("Error running script: {}", error_message(&e)))
+}
+
+//The message of a script error without the traceback Lua appends, on one
+//line so that it fits in an error reply.
+pub(crate) fn error_message(e: &mlua::Error) -> String {
+    let message = match e {
+        mlua::Error::CallbackError { cause, .. } => return error_message(cause),
+        mlua::Error::RuntimeError(message) | mlua::Error::SyntaxError { message, .. } => message.clone(),
+        e => e.to_string(),
+    };
+    let message = message.split("\nstack traceback:").next().unwrap_or_default();
+    one_line(message)
+}
+
+//Number of Lua instructions between checks for SCRIPT KILL.
+pub(crate) const HOOK_INTERVAL: u32 = 10000;
+
+//A Lua 5.1 state set up like Redis': only the safe standard libraries, no
+//io, os, package or debug nor the base functions that load files, plus
+//cjson, cmsgpack, bit and the `redis` helpers that do not need the database.
+pub(crate) fn sandbox() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::default())?;
+    {
+        let globals = lua.globals();
+        for name in ["loadfile", "dofile", "require"] {
+            globals.set(name, Value::Nil)?;
+        }
+        lualib::install(&lua)?;
+
+        let redis = lua.create_table()?;
+        redis.set("status_reply", lua.create_function(|lua, s: String| frame_to_lua(lua, Frame::Simple(s)))?)?;
+        redis.set("error_reply", lua.create_function(|lua, s: String| frame_to_lua(lua, Frame::Error(s)))?)?;
+        redis.set("sha1hex", lua.create_function(|_, s: mlua::String| {
+            Ok(sha1_smol::Sha1::from(s.as_bytes()).digest().to_string())
+        })?)?;
+        for (i, level) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"].into_iter().enumerate() {
+            redis.set(level, i)?;
+        }
+        redis.set("log", lua.create_function(|_, (level, message): (i64, Variadic<mlua::String>)| {
+            if message.is_empty() {
+                return Err(mlua::Error::RuntimeError("redis.log() requires two arguments or more.".to_string()));
+            }
+            let message: Vec<String> = message.iter().map(|s| s.to_string_lossy().to_string()).collect();
+            let message = message.join(" ");
+            match level {
+                0 => log::debug(&message),
+                1 => log::verbose(&message),
+                2 => log::notice(&message),
+                3 => log::warning(&message),
+                _ => return Err(mlua::Error::RuntimeError("Invalid debug level.".to_string())),
+            }
+            Ok(())
+        })?)?;
+        globals.set("redis", redis)?;
+    }
+    Ok(lua)
+}
+
+//Splits `numkeys key... arg...` as given to EVAL and EVALSHA.
+pub fn split_keys(numkeys: &str, rest: Vec<String>) -> Result<(Vec<String>, Vec<String>), String> {
+    let numkeys = match numkeys.parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => return Err("value is not an integer or out of range".to_string()),
+    };
+    if numkeys > rest.len() {
+        return Err("Number of keys can't be greater than number of args".to_string());
+    }
+    let mut keys = rest;
+    let args = keys.split_off(numkeys);
+    Ok((keys, args))
+}
+
+//HELPER FN
+
+fn dispatch(db: &mut Db, argv: Variadic<mlua::String>, read_only: bool, caller: Option<&Caller>) -> Result<Frame, String> {
+    if argv.is_empty() {
+        return Err("Please specify at least one argument for this redis lib call".to_string());
+    }
+    let mut frame = Frame::array();
+    for arg in argv.iter() {
+        frame.push_bulk(Bytes::copy_from_slice(arg.as_bytes()));
+    }
+    let cmd = parse_command(frame.clone())?;
+    if cmd.is_noscript() {
+        return Err("This Redis command is not allowed from script".to_string());
+    }
+    if let Some(caller) = caller {
+        let acl = db.acl();
+        if let Err(denial) = acl.check(caller.user, cmd.name(), cmd.subcommand(), &cmd.keys()) {
+            acl.log(denial.reason(), "lua", denial.object(), caller.user, caller.client.info());
+            return Err(denial.error(caller.user));
+        }
+    }
+    db.monitors().feed(&frame, "lua");
+    match cmd {
+        cmd if read_only && cmd.is_write() => {
+            Err("Write commands are not allowed from read-only scripts".to_string())
+        },
+        cmd if cmd.is_write() && !db.replication().is_master() => Err(READONLY.to_string()),
+        cmd => {
+            if cmd.is_write() {
+                db.busy().note_write();
+            }
+            run_cmd(&cmd, db, None)
+        },
+    }
+}
+
+//Conversion rules follow Redis: integers become numbers, bulk strings become
+//strings, nulls become false, and status and error replies become tables
+//with a single `ok` or `err` field. The "Nil" status GET answers a missing
+//key with is a null too.
+fn frame_to_lua(lua: &Lua, frame: Frame) -> mlua::Result<Value<'_>> {
+    match frame {
+        Frame::Integer(i) => Ok(Value::Integer(i)),
+        Frame::Bulk(bytes) => Ok(Value::String(lua.create_string(&bytes)?)),
+        Frame::Null => Ok(Value::Boolean(false)),
+        Frame::Simple(s) if s == "Nil" => Ok(Value::Boolean(false)),
+        Frame::Simple(s) => {
+            let table = lua.create_table()?;
+            table.set("ok", s)?;
+            Ok(Value::Table(table))
+        },
+        Frame::Error(e) => {
+            let table = lua.create_table()?;
+            table.set("err", e)?;
+            Ok(Value::Table(table))
+        },
+        Frame::Array(vec) => {
+            let table = lua.create_table()?;
+            for (i, frame) in vec.into_iter().enumerate() {
+                table.set(i + 1, frame_to_lua(lua, frame)?)?;
+            }
+            Ok(Value::Table(table))
+        },
+    }
+}
+
+fn lua_to_frame(value: Value) -> Frame {
+    match value {
+        Value::Integer(i) => Frame::Integer(i),
+        Value::Number(n) => Frame::Integer(n as i64),
+        Value::String(s) => Frame::Bulk(Bytes::copy_from_slice(s.as_bytes())),
+        Value::Boolean(true) => Frame::Integer(1),
+        Value::Table(table) => table_to_frame(table),
+        _ => Frame::Null,
+    }
+}
+
+fn table_to_frame(table: Table) -> Frame {
+    if let Ok(Value::String(s)) = table.raw_get::<_, Value>("ok") {
+        return Frame::Simple(one_line(&s.to_string_lossy()));
+    }
+    if let Ok(Value::String(s)) = table.raw_get::<_, Value>("err") {
+        return Frame::Error(one_line(&s.to_string_lossy()));
+    }
+
+    //Like Redis, the array stops at the first nil.
+    let mut output = Frame::array();
+    for i in 1.. {
+        match table.raw_get::<_, Value>(i) {
+            Ok(Value::Nil) | Err(_) => break,
+            Ok(value) => output.push_frame(lua_to_frame(value)),
+        }
+    }
+    output
+}
+
+//Status and error replies can not span lines in RESP.
+fn one_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::db::Db;
+    use crate::frame::Frame;
+    use crate::script::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn sha1_matches_redis() {
+        assert_eq!(sha1_hex("return 1"), "e0e1f9fabfc9d4800c877a703b823ac0578ff8db");
+    }
+
+    #[test]
+    fn eval_keys_argv_and_call() {
+        let mut db = Db::new();
+        let script = "redis.call('SET', KEYS[1], ARGV[1]) return redis.call('GET', KEYS[1])";
+        let output = eval(&mut db, script, &strings(&["k"]), &strings(&["v"]), None).unwrap();
+
+        assert_eq!(output, Frame::Bulk(Bytes::from("v")));
+        assert_eq!(db.get("k"), Some(&"v".to_string()));
+    }
+
+    #[test]
+    fn eval_type_conversion() {
+        let mut db = Db::new();
+
+        let output = eval(&mut db, "return {1, 'two', 3.7, {ok='fine'}, nil, 5}", &[], &[], None).unwrap();
+        let expected = Frame::Array(vec![
+            Frame::Integer(1),
+            Frame::Bulk(Bytes::from("two")),
+            Frame::Integer(3),
+            Frame::Simple("fine".to_string()),
+        ]);
+        assert_eq!(output, expected);
+
+        assert_eq!(eval(&mut db, "return false", &[], &[], None).unwrap(), Frame::Null);
+        assert_eq!(eval(&mut db, "return true", &[], &[], None).unwrap(), Frame::Integer(1));
+        assert_eq!(eval(&mut db, "return redis.error_reply('bad')", &[], &[], None).unwrap(), Frame::Error("bad".to_string()));
+    }
+
+    #[test]
+    fn call_raises_and_pcall_returns_error() {
+        let mut db = Db::new();
+
+        assert!(eval(&mut db, "return redis.call('NOPE')", &[], &[], None).is_err());
+        let output = eval(&mut db, "return redis.pcall('NOPE')", &[], &[], None).unwrap();
+        assert!(matches!(output, Frame::Error(_)));
+        assert!(eval(&mut db, "return redis.call('EVAL', 'return 1', '0')", &[], &[], None).is_err());
+    }
+
+    #[test]
+    fn unsafe_libraries_are_missing() {
+        let mut db = Db::new();
+        let script = "return {type(io), type(os), type(loadfile), type(dofile), type(require), type(package), type(debug)}";
+        let output = eval(&mut db, script, &[], &[], None).unwrap();
+        assert_eq!(output, Frame::Array(vec![Frame::Bulk(Bytes::from("nil")); 7]));
+
+        assert!(eval(&mut db, "return io.popen('id'):read('*a')", &[], &[], None).is_err());
+        let output = eval(&mut db, "return {string.upper('a'), math.floor(2.5), table.concat({'x', 'y'})}", &[], &[], None).unwrap();
+        let expected = Frame::Array(vec![Frame::Bulk(Bytes::from("A")), Frame::Integer(2), Frame::Bulk(Bytes::from("xy"))]);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn scripts_written_for_redis() {
+        let mut db = Db::new();
+        let script = "if not redis.call('GET', 'missing') then return 'miss' end return 'hit'";
+        assert_eq!(eval(&mut db, script, &[], &[], None).unwrap(), Frame::Bulk(Bytes::from("miss")));
+        assert_eq!(eval(&mut db, "return redis.call('GET', 'missing')", &[], &[], None).unwrap(), Frame::Null);
+
+        let script = "redis.log(redis.LOG_DEBUG, 'checking', 1) \
+                      return {tostring(10/2), unpack({'a'}), redis.sha1hex('return 1'), redis.LOG_WARNING, \
+                              cjson.encode({x=1}), cmsgpack.unpack(cmsgpack.pack('m')), bit.band(6, 3)}";
+        let expected = Frame::Array(vec![
+            Frame::Bulk(Bytes::from("5")),
+            Frame::Bulk(Bytes::from("a")),
+            Frame::Bulk(Bytes::from("e0e1f9fabfc9d4800c877a703b823ac0578ff8db")),
+            Frame::Integer(3),
+            Frame::Bulk(Bytes::from("{\"x\":1}")),
+            Frame::Bulk(Bytes::from("m")),
+            Frame::Integer(2),
+        ]);
+        assert_eq!(eval(&mut db, script, &[], &[], None).unwrap(), expected);
+        assert!(eval(&mut db, "redis.log(9, 'x')", &[], &[], None).is_err());
+    }
+
+    #[test]
+    fn errors_fit_on_one_line() {
+        let mut db = Db::new();
+        let error = eval(&mut db, "error('first\\r\\nsecond')", &[], &[], None).unwrap_err();
+        assert!(error.contains("first  second"), "{}", error);
+        assert!(!error.contains('\n') && !error.contains("traceback"));
+
+        let error = eval(&mut db, "return redis.call('NOPE')", &[], &[], None).unwrap_err();
+        assert!(!error.contains('\n') && !error.contains("traceback"), "{}", error);
+        assert_eq!(eval(&mut db, "return redis.error_reply('a\\nb')", &[], &[], None).unwrap(), Frame::Error("a b".to_string()));
+    }
+
+    #[test]
+    fn split_keys_test() {
+        let (keys, args) = split_keys("1", strings(&["k", "a", "b"])).unwrap();
+        assert_eq!(keys, strings(&["k"]));
+        assert_eq!(args, strings(&["a", "b"]));
+
+        assert!(split_keys("3", strings(&["k"])).is_err());
+        assert!(split_keys("-1", strings(&["k"])).is_err());
+    }
+}
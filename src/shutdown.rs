@@ -0,0 +1,201 @@
+use crate::db::{Db, Shared};
+use crate::handler::lock_db;
+use crate::log;
+use crate::persistence;
+use crate::replication::block;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+//Redis' default shutdown-timeout, in seconds.
+pub const DEFAULT_TIMEOUT: u64 = 10;
+
+pub const ERROR: &str = "Errors trying to SHUTDOWN. Check logs.";
+pub const SHUTTING_DOWN: &str = "SHUTDOWN Server is shutting down";
+
+//SHUTDOWN [NOSAVE|SAVE] [NOW] [FORCE] [ABORT]. `save` is None when neither
+//SAVE nor NOSAVE was given, in which case the save rules decide.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub save: Option<bool>,
+    pub now: bool,
+    pub force: bool,
+    pub abort: bool,
+}
+
+//Shutdown state shared by the connections, the signal handler and the
+//accept loops. Once triggered, listeners stop accepting and connections close
+//after the command they are running.
+#[derive(Debug)]
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+    pending: AtomicBool,
+    aborted: AtomicBool,
+    exit_code: AtomicI32,
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        let (sender, _) = watch::channel(false);
+        Shutdown {
+            sender,
+            pending: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            exit_code: AtomicI32::new(0),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.sender.subscribe()
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code.load(Ordering::SeqCst)
+    }
+
+    pub fn trigger(&self, exit_code: i32) {
+        self.exit_code.store(exit_code, Ordering::SeqCst);
+        self.sender.send_replace(true);
+    }
+
+    //SHUTDOWN ABORT: stops a shutdown that is still waiting for replicas.
+    pub fn abort(&self) -> Result<(), String> {
+        if !self.pending.load(Ordering::SeqCst) || self.is_triggered() {
+            return Err("No shutdown in progress.".to_string());
+        }
+        self.aborted.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<(), String> {
+        if self.pending.swap(true, Ordering::SeqCst) {
+            return Err("Shutdown already in progress".to_string());
+        }
+        self.aborted.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+//Runs the shutdown sequence for SHUTDOWN and for SIGTERM/SIGINT: lets the
+//replicas catch up (unless NOW), then flushes the AOF and saves the dataset
+//as configured. On success the shutdown is triggered; on failure the server
+//keeps running unless FORCE was given.
+pub fn prepare(db: &Mutex<Db>, shared: &Shared, options: Options) -> Result<(), String> {
+    let shutdown = &shared.shutdown;
+    let replication = &shared.replication;
+    let timeout = shared.config.lock().unwrap().shutdown_timeout;
+    if options.abort {
+        return shutdown.abort();
+    }
+    shutdown.begin()?;
+
+    if !options.now && replication.is_master() && replication.replicas() > 0 {
+        let target = replication.offset();
+        replication.request_acks();
+        let start = Instant::now();
+        block(|| {
+            while replication.acked(target) < replication.replicas()
+                && start.elapsed() < Duration::from_secs(timeout)
+                && !shutdown.aborted.load(Ordering::SeqCst)
+            {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        if replication.acked(target) < replication.replicas() {
+            log::warning("Lagging replica(s) did not catch up before shutdown");
+        }
+    }
+
+    //The lock is held until the shutdown is triggered, so that no write can
+    //slip in after the final save. NOSAVE needs no dataset, so it neither
+    //waits for the lock nor answers -BUSY, and the shutdown then stops
+    //whatever holds it.
+    let guard = match options.save {
+        Some(false) => None,
+        _ => Some(lock_db(db, &shared.busy)),
+    };
+    let result = if shutdown.aborted.load(Ordering::SeqCst) {
+        Err("Shutdown aborted".to_string())
+    } else {
+        match &guard {
+            Some(Ok(db)) => persistence::shutdown(db, options.save),
+            Some(Err(e)) => Err(e.clone()),
+            None => persistence::sync_aof(&shared.persistence),
+        }
+    };
+    match result {
+        Err(e) if !options.force || shutdown.aborted.load(Ordering::SeqCst) => {
+            log::warning(&e);
+            shutdown.pending.store(false, Ordering::SeqCst);
+            Err(ERROR.to_string())
+        },
+        Err(e) => {
+            log::warning(&format!("{}, exiting anyway (FORCE)", e));
+            shared.busy.interrupt();
+            shutdown.trigger(1);
+            Ok(())
+        },
+        Ok(()) => {
+            shared.busy.interrupt();
+            shutdown.trigger(0);
+            Ok(())
+        },
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use crate::db::Db;
+    use crate::shutdown::*;
+
+    #[test]
+    fn abort_needs_a_pending_shutdown() {
+        let db = Mutex::new(Db::new());
+        let shared = db.lock().unwrap().shared();
+        let options = Options { abort: true, ..Options::default() };
+        assert!(prepare(&db, &shared, options).is_err());
+    }
+
+    #[test]
+    fn nosave_shutdown_triggers() {
+        let db = Mutex::new(Db::new());
+        let shared = db.lock().unwrap().shared();
+        let shutdown = db.lock().unwrap().shutdown();
+        let receiver = shutdown.subscribe();
+        assert!(!shutdown.is_triggered());
+
+        prepare(&db, &shared, Options { save: Some(false), ..Options::default() }).unwrap();
+        assert!(shutdown.is_triggered());
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(shutdown.exit_code(), 0);
+        assert!(prepare(&db, &shared, Options::default()).is_err());
+    }
+
+    #[test]
+    fn failed_save_needs_force() {
+        let db = Mutex::new(Db::new());
+        let shared = db.lock().unwrap().shared();
+        let shutdown = db.lock().unwrap().shutdown();
+        let mut settings = db.lock().unwrap().persistence().settings();
+        settings.dir = std::env::temp_dir().join("my-redis-no-such-dir");
+        db.lock().unwrap().persistence().set_settings(settings);
+
+        assert_eq!(prepare(&db, &shared, Options { save: Some(true), ..Options::default() }), Err(ERROR.to_string()));
+        assert!(!shutdown.is_triggered());
+
+        prepare(&db, &shared, Options { save: Some(true), force: true, ..Options::default() }).unwrap();
+        assert!(shutdown.is_triggered());
+        assert_eq!(shutdown.exit_code(), 1);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::client::Client;
+use crate::frame::Frame;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//Redis' defaults for slowlog-log-slower-than (microseconds) and slowlog-max-len.
+pub const DEFAULT_THRESHOLD_US: i64 = 10000;
+pub const DEFAULT_MAX_LEN: u64 = 128;
+
+//Entries keep at most this many arguments, each cut to this many bytes.
+const MAX_ARGC: usize = 32;
+const MAX_STRING: usize = 128;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum SlowlogCmd {
+    //None for the default of 10 entries, -1 for all of them.
+    GET(Option<i64>),
+    LEN,
+    RESET,
+}
+
+impl SlowlogCmd {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SlowlogCmd::GET(_) => "get",
+            SlowlogCmd::LEN => "len",
+            SlowlogCmd::RESET => "reset",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+struct Entry {
+    id: u64,
+    //Unix seconds.
+    time: u64,
+    usec: u64,
+    argv: Vec<Bytes>,
+    addr: String,
+    name: String,
+}
+
+impl Entry {
+    fn to_frame(&self) -> Frame {
+        let mut argv = Frame::array();
+        for arg in &self.argv {
+            argv.push_bulk(arg.clone());
+        }
+        let mut frame = Frame::array();
+        frame.push_int(self.id as i64);
+        frame.push_int(self.time as i64);
+        frame.push_int(self.usec as i64);
+        frame.push_frame(argv);
+        frame.push_bulk(Bytes::from(self.addr.clone()));
+        frame.push_bulk(Bytes::from(self.name.clone()));
+        frame
+    }
+}
+
+#[derive(Debug, Default)]
+struct Log {
+    //Newest first.
+    entries: VecDeque<Entry>,
+    next_id: u64,
+    max_len: u64,
+}
+
+//Commands that took longer than slowlog-log-slower-than, shared by every
+//connection.
+#[derive(Debug)]
+pub struct Slowlog {
+    //Negative disables the log, 0 logs every command.
+    threshold_us: AtomicI64,
+    log: Mutex<Log>,
+}
+
+impl Default for Slowlog {
+    fn default() -> Slowlog {
+        Slowlog {
+            threshold_us: AtomicI64::new(DEFAULT_THRESHOLD_US),
+            log: Mutex::new(Log { max_len: DEFAULT_MAX_LEN, ..Log::default() }),
+        }
+    }
+}
+
+impl Slowlog {
+    //Lowering the maximum length drops the oldest entries right away.
+    pub fn set_config(&self, threshold_us: i64, max_len: u64) {
+        self.threshold_us.store(threshold_us, Ordering::Relaxed);
+        let mut log = self.log.lock().unwrap();
+        log.max_len = max_len;
+        log.entries.truncate(max_len as usize);
+    }
+
+    //Logs the command in `argv` if it ran for long enough.
+    pub fn record(&self, duration: Duration, argv: &Frame, client: &Client) {
+        let threshold = self.threshold_us.load(Ordering::Relaxed);
+        let usec = duration.as_micros() as u64;
+        if threshold < 0 || usec < threshold as u64 {
+            return;
+        }
+        let entry = Entry {
+            id: 0,
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            usec,
+            argv: truncated_args(argv),
+            addr: client.addr(),
+            name: client.name(),
+        };
+        let mut log = self.log.lock().unwrap();
+        let id = log.next_id;
+        log.next_id += 1;
+        log.entries.push_front(Entry { id, ..entry });
+        let max_len = log.max_len as usize;
+        log.entries.truncate(max_len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.log.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    //Ids keep counting after a reset, like Redis.
+    pub fn reset(&self) {
+        self.log.lock().unwrap().entries.clear();
+    }
+}
+
+pub fn execute(cmd: &SlowlogCmd, slowlog: &Slowlog) -> Result<Frame, String> {
+    match cmd {
+        SlowlogCmd::GET(count) => {
+            let log = slowlog.log.lock().unwrap();
+            let count = match count {
+                Some(-1) => log.entries.len(),
+                Some(n) => *n as usize,
+                None => 10,
+            };
+            let mut output = Frame::array();
+            for entry in log.entries.iter().take(count) {
+                output.push_frame(entry.to_frame());
+            }
+            Ok(output)
+        },
+        SlowlogCmd::LEN => Ok(Frame::Integer(slowlog.len() as i64)),
+        SlowlogCmd::RESET => {
+            slowlog.reset();
+            Ok(Frame::Simple("OK".to_string()))
+        },
+    }
+}
+
+//The arguments of a command frame, with passwords hidden. Also used by
+//MONITOR.
+pub(crate) fn redacted_args(argv: &Frame) -> Vec<Bytes> {
+    let mut args: Vec<Bytes> = match argv {
+        Frame::Array(items) => items.iter().filter_map(|item| match item {
+            Frame::Bulk(bytes) => Some(bytes.clone()),
+            Frame::Simple(s) => Some(Bytes::from(s.clone())),
+            _ => None,
+        }).collect(),
+        _ => Vec::new(),
+    };
+    redact(&mut args);
+    args
+}
+
+//HELPER FN
+
+//The arguments as logged: at most MAX_ARGC of them, long strings cut with a
+//note of how much is missing.
+fn truncated_args(argv: &Frame) -> Vec<Bytes> {
+    let mut args = redacted_args(argv);
+    let argc = args.len();
+    if argc > MAX_ARGC {
+        args.truncate(MAX_ARGC - 1);
+        args.push(Bytes::from(format!("... ({} more arguments)", argc - MAX_ARGC + 1)));
+    }
+    for arg in args.iter_mut() {
+        if arg.len() > MAX_STRING {
+            let mut cut = arg[..MAX_STRING].to_vec();
+            cut.extend_from_slice(format!("... ({} more bytes)", arg.len() - MAX_STRING).as_bytes());
+            *arg = Bytes::from(cut);
+        }
+    }
+    args
+}
+
+//Passwords never make it to the log: AUTH arguments, ACL SETUSER password
+//rules and the values of the password parameters of CONFIG SET.
+fn redact(args: &mut [Bytes]) {
+    let redacted = Bytes::from("(redacted)");
+    let upper = |arg: &Bytes| String::from_utf8_lossy(arg).to_uppercase();
+    match args.first().map(upper).as_deref() {
+        Some("AUTH") => {
+            for arg in args.iter_mut().skip(1) {
+                *arg = redacted.clone();
+            }
+        },
+        Some("ACL") if args.get(1).map(upper).as_deref() == Some("SETUSER") => {
+            for arg in args.iter_mut().skip(3) {
+                if matches!(arg.first(), Some(b'>' | b'<' | b'#' | b'!')) {
+                    *arg = redacted.clone();
+                }
+            }
+        },
+        Some("CONFIG") if args.get(1).map(upper).as_deref() == Some("SET") => {
+            let mut i = 2;
+            while i + 1 < args.len() {
+                if matches!(upper(&args[i]).as_str(), "REQUIREPASS" | "MASTERAUTH") {
+                    args[i + 1] = redacted.clone();
+                }
+                i += 2;
+            }
+        },
+        _ => (),
+    }
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use bytes::Bytes;
+    use crate::client::Clients;
+    use crate::frame::Frame;
+    use crate::slowlog::*;
+
+    fn argv(args: &[&str]) -> Frame {
+        let mut frame = Frame::array();
+        for arg in args {
+            frame.push_bulk(Bytes::from(arg.to_string()));
+        }
+        frame
+    }
+
+    fn logged_args(slowlog: &Slowlog) -> Vec<Bytes> {
+        slowlog.log.lock().unwrap().entries[0].argv.clone()
+    }
+
+    #[test]
+    fn threshold_and_max_len() {
+        let clients = Clients::default();
+        let client = clients.register("default");
+        let slowlog = Slowlog::default();
+        slowlog.record(Duration::from_micros(9999), &argv(&["GET", "a"]), &client);
+        assert!(slowlog.is_empty());
+        slowlog.record(Duration::from_micros(10000), &argv(&["GET", "a"]), &client);
+        assert_eq!(slowlog.len(), 1);
+
+        slowlog.set_config(0, 2);
+        for key in ["b", "c", "d"] {
+            slowlog.record(Duration::ZERO, &argv(&["GET", key]), &client);
+        }
+        let entries = execute(&SlowlogCmd::GET(None), &slowlog).unwrap();
+        match entries {
+            Frame::Array(entries) => {
+                assert_eq!(entries.len(), 2);
+                match &entries[0] {
+                    Frame::Array(fields) => {
+                        assert_eq!(fields[0], Frame::Integer(3));
+                        assert_eq!(fields[3], argv(&["GET", "d"]));
+                    },
+                    other => panic!("unexpected entry {:?}", other),
+                }
+            },
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        slowlog.set_config(-1, 2);
+        slowlog.record(Duration::from_secs(1), &argv(&["GET", "e"]), &client);
+        assert_eq!(execute(&SlowlogCmd::LEN, &slowlog).unwrap(), Frame::Integer(2));
+        execute(&SlowlogCmd::RESET, &slowlog).unwrap();
+        assert!(slowlog.is_empty());
+    }
+
+    #[test]
+    fn truncation_and_redaction() {
+        let clients = Clients::default();
+        let client = clients.register("default");
+        let slowlog = Slowlog::default();
+        slowlog.set_config(0, 10);
+
+        let long = "x".repeat(130);
+        slowlog.record(Duration::ZERO, &argv(&["SET", "k", &long]), &client);
+        assert_eq!(logged_args(&slowlog)[2], Bytes::from(format!("{}... (2 more bytes)", "x".repeat(128))));
+
+        let many: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        let many: Vec<&str> = many.iter().map(|s| s.as_str()).collect();
+        slowlog.record(Duration::ZERO, &argv(&many), &client);
+        let args = logged_args(&slowlog);
+        assert_eq!(args.len(), 32);
+        assert_eq!(args[31], Bytes::from("... (9 more arguments)"));
+
+        slowlog.record(Duration::ZERO, &argv(&["auth", "user", "secret"]), &client);
+        assert_eq!(logged_args(&slowlog), vec![Bytes::from("auth"), Bytes::from("(redacted)"), Bytes::from("(redacted)")]);
+        slowlog.record(Duration::ZERO, &argv(&["ACL", "SETUSER", "bob", "on", ">pass"]), &client);
+        assert_eq!(logged_args(&slowlog)[3..], [Bytes::from("on"), Bytes::from("(redacted)")]);
+        slowlog.record(Duration::ZERO, &argv(&["CONFIG", "SET", "timeout", "5", "requirepass", "pw"]), &client);
+        assert_eq!(logged_args(&slowlog)[3..], [Bytes::from("5"), Bytes::from("requirepass"), Bytes::from("(redacted)")]);
+    }
+}
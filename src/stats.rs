@@ -0,0 +1,253 @@
+use crate::script::sha1_hex;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+//How many one-second samples the instantaneous rates are averaged over.
+const SAMPLES: usize = 16;
+
+//Latency histograms count calls in power of two buckets: bucket i holds the
+//calls that took at most 2^i microseconds, the last one everything slower.
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct CommandStats {
+    pub calls: u64,
+    pub usec: u64,
+    //Refused before running, by NOAUTH or the ACL.
+    pub rejected_calls: u64,
+    //Ran and replied with an error.
+    pub failed_calls: u64,
+    pub histogram: [u64; HISTOGRAM_BUCKETS],
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    last: Option<(Instant, [u64; 3])>,
+    rates: [VecDeque<f64>; 3],
+}
+
+//Server-wide counters behind INFO, reset by CONFIG RESETSTAT.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    run_id: String,
+    connections_received: AtomicU64,
+    rejected_connections: AtomicU64,
+    commands_processed: AtomicU64,
+    net_input_bytes: AtomicU64,
+    net_output_bytes: AtomicU64,
+    expired_keys: AtomicU64,
+    keyspace_hits: AtomicU64,
+    keyspace_misses: AtomicU64,
+    commands: Mutex<BTreeMap<String, CommandStats>>,
+    samples: Mutex<Samples>,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        Stats {
+            started: Instant::now(),
+            run_id: sha1_hex(&format!("run:{}:{}", nanos, std::process::id())),
+            connections_received: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            commands_processed: AtomicU64::new(0),
+            net_input_bytes: AtomicU64::new(0),
+            net_output_bytes: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
+            commands: Mutex::new(BTreeMap::new()),
+            samples: Mutex::new(Samples::default()),
+        }
+    }
+}
+
+impl Stats {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    //Random id of this server process.
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    pub fn connection(&self) {
+        self.connections_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn net_input(&self, bytes: usize) {
+        self.net_input_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn net_output(&self, bytes: usize) {
+        self.net_output_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn expired(&self, keys: usize) {
+        self.expired_keys.fetch_add(keys as u64, Ordering::Relaxed);
+    }
+
+    pub fn hit(&self) {
+        self.keyspace_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.keyspace_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //A command that ran, under its `command|subcommand` name.
+    pub fn call(&self, name: &str, duration: Duration, ok: bool) {
+        self.commands_processed.fetch_add(1, Ordering::Relaxed);
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(name.to_string()).or_default();
+        stats.calls += 1;
+        stats.usec += duration.as_micros() as u64;
+        stats.histogram[bucket(duration)] += 1;
+        if !ok {
+            stats.failed_calls += 1;
+        }
+    }
+
+    pub fn reject(&self, name: &str) {
+        self.commands.lock().unwrap().entry(name.to_string()).or_default().rejected_calls += 1;
+    }
+
+    pub fn commands(&self) -> BTreeMap<String, CommandStats> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    //The cumulative counters, as the INFO stats section lists them.
+    pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("total_connections_received", self.connections_received.load(Ordering::Relaxed)),
+            ("total_commands_processed", self.commands_processed.load(Ordering::Relaxed)),
+            ("total_net_input_bytes", self.net_input_bytes.load(Ordering::Relaxed)),
+            ("total_net_output_bytes", self.net_output_bytes.load(Ordering::Relaxed)),
+            ("rejected_connections", self.rejected_connections.load(Ordering::Relaxed)),
+            ("expired_keys", self.expired_keys.load(Ordering::Relaxed)),
+            //There is no maxmemory eviction, so nothing is ever evicted.
+            ("evicted_keys", 0),
+            ("keyspace_hits", self.keyspace_hits.load(Ordering::Relaxed)),
+            ("keyspace_misses", self.keyspace_misses.load(Ordering::Relaxed)),
+        ]
+    }
+
+    //Called by the server cron every second, to compute the instantaneous
+    //rates.
+    pub fn sample(&self) {
+        let now = Instant::now();
+        let current = [
+            self.commands_processed.load(Ordering::Relaxed),
+            self.net_input_bytes.load(Ordering::Relaxed),
+            self.net_output_bytes.load(Ordering::Relaxed),
+        ];
+        let mut samples = self.samples.lock().unwrap();
+        if let Some((at, previous)) = samples.last {
+            let seconds = now.duration_since(at).as_secs_f64().max(0.001);
+            for (i, rates) in samples.rates.iter_mut().enumerate() {
+                rates.push_back(current[i].saturating_sub(previous[i]) as f64 / seconds);
+                if rates.len() > SAMPLES {
+                    rates.pop_front();
+                }
+            }
+        }
+        samples.last = Some((now, current));
+    }
+
+    //Commands per second, and input and output bytes per second.
+    pub fn instantaneous(&self) -> [f64; 3] {
+        let samples = self.samples.lock().unwrap();
+        let mut rates = [0.0; 3];
+        for (i, values) in samples.rates.iter().enumerate() {
+            if !values.is_empty() {
+                rates[i] = values.iter().sum::<f64>() / values.len() as f64;
+            }
+        }
+        rates
+    }
+
+    //CONFIG RESETSTAT.
+    pub fn reset(&self) {
+        for counter in [
+            &self.connections_received, &self.rejected_connections, &self.commands_processed, &self.net_input_bytes,
+            &self.net_output_bytes, &self.expired_keys, &self.keyspace_hits, &self.keyspace_misses,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.commands.lock().unwrap().clear();
+        *self.samples.lock().unwrap() = Samples::default();
+    }
+}
+
+//HELPER FN
+
+fn bucket(duration: Duration) -> usize {
+    let usec = duration.as_micros() as u64;
+    if usec <= 1 {
+        return 0;
+    }
+    let bucket = (u64::BITS - (usec - 1).leading_zeros()) as usize;
+    bucket.min(HISTOGRAM_BUCKETS - 1)
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::*;
+
+    #[test]
+    fn calls_and_reset() {
+        let stats = Stats::default();
+        stats.call("get", Duration::from_micros(30), true);
+        stats.call("get", Duration::from_micros(10), false);
+        stats.reject("config|set");
+        stats.hit();
+
+        let commands = stats.commands();
+        let mut histogram = [0; HISTOGRAM_BUCKETS];
+        histogram[4] = 1;
+        histogram[5] = 1;
+        assert_eq!(commands["get"], CommandStats { calls: 2, usec: 40, rejected_calls: 0, failed_calls: 1, histogram });
+        assert_eq!(commands["config|set"].rejected_calls, 1);
+        assert!(stats.counters().contains(&("total_commands_processed", 2)));
+        assert!(stats.counters().contains(&("keyspace_hits", 1)));
+
+        stats.reset();
+        assert!(stats.commands().is_empty());
+        assert!(stats.counters().iter().all(|(_, value)| *value == 0));
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        assert_eq!(bucket(Duration::ZERO), 0);
+        assert_eq!(bucket(Duration::from_micros(1)), 0);
+        assert_eq!(bucket(Duration::from_micros(2)), 1);
+        assert_eq!(bucket(Duration::from_micros(3)), 2);
+        assert_eq!(bucket(Duration::from_micros(1024)), 10);
+        assert_eq!(bucket(Duration::from_micros(1025)), 11);
+        assert_eq!(bucket(Duration::from_secs(10000)), HISTOGRAM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn instantaneous_rates() {
+        let stats = Stats::default();
+        stats.sample();
+        for _ in 0..5 {
+            stats.call("ping", Duration::ZERO, true);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        stats.sample();
+        let [ops, input, _] = stats.instantaneous();
+        assert!(ops > 10.0 && ops <= 50.0);
+        assert_eq!(input, 0.0);
+    }
+}
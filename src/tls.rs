@@ -0,0 +1,229 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+//Whether clients of the TLS port must present a certificate signed by the
+//configured CA, as with Redis' tls-auth-clients.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AuthClients {
+    YES,
+    NO,
+    OPTIONAL,
+}
+
+impl AuthClients {
+    pub fn parse(value: &str) -> Result<AuthClients, String> {
+        match value.to_lowercase().as_str() {
+            "yes" => Ok(AuthClients::YES),
+            "no" => Ok(AuthClients::NO),
+            "optional" => Ok(AuthClients::OPTIONAL),
+            _ => Err("argument must be 'yes', 'no' or 'optional'".to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthClients::YES => "yes",
+            AuthClients::NO => "no",
+            AuthClients::OPTIONAL => "optional",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Settings {
+    //0 to not listen for TLS connections.
+    pub port: u16,
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    pub ca_cert_file: Option<PathBuf>,
+    pub auth_clients: AuthClients,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            port: 0,
+            cert_file: None,
+            key_file: None,
+            ca_cert_file: None,
+            auth_clients: AuthClients::YES,
+        }
+    }
+}
+
+//Server side of the TLS port. Client certificates are checked against the
+//CA file unless tls-auth-clients is off.
+pub fn acceptor(settings: &Settings) -> Result<TlsAcceptor, String> {
+    let (cert_file, key_file) = match (&settings.cert_file, &settings.key_file) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Err("Failed to configure TLS: tls-cert-file and tls-key-file are required".to_string()),
+    };
+    let builder = ServerConfig::builder();
+    let builder = match (settings.auth_clients, &settings.ca_cert_file) {
+        (AuthClients::NO, _) => builder.with_no_client_auth(),
+        (_, None) => return Err("Failed to configure TLS: tls-auth-clients requires tls-ca-cert-file".to_string()),
+        (auth, Some(ca)) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(ca)?));
+            let verifier = if auth == AuthClients::OPTIONAL { verifier.allow_unauthenticated() } else { verifier };
+            let verifier = verifier.build().map_err(|e| format!("Failed to configure TLS: {}", e))?;
+            builder.with_client_cert_verifier(verifier)
+        },
+    };
+    let config = builder.with_single_cert(load_certs(cert_file)?, load_key(key_file)?)
+        .map_err(|e| format!("Failed to configure TLS: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+//Client side, for redis-cli: the server is checked against `ca`, and
+//`identity` is the certificate and key to present for mutual TLS.
+pub fn connector(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<TlsConnector, String> {
+    let builder = ClientConfig::builder().with_root_certificates(load_roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| format!("Failed to configure TLS: {}", e))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+//Host names and IP addresses are both accepted.
+pub fn server_name(host: &str) -> Result<ServerName<'static>, String> {
+    ServerName::try_from(host.to_string()).map_err(|_| format!("Invalid TLS server name: {}", host))
+}
+
+//HELPER FN
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Failed to load {}: {}", path.display(), e))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("Failed to load {}: no certificate found", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| format!("Failed to load {}: {}", path.display(), e))?
+        .ok_or(format!("Failed to load {}: no private key found", path.display()))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+//TESTS
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::tls::*;
+
+    //A CA with a server certificate for 127.0.0.1 and a client certificate,
+    //written into a fresh temp dir.
+    fn write_certs(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("my-redis-tls-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        fs::write(dir.join("ca.crt"), ca.pem()).unwrap();
+
+        for (file, names) in [("server", vec!["127.0.0.1".to_string()]), ("client", vec!["client".to_string()])] {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(names).unwrap().signed_by(&key, &ca, &ca_key).unwrap();
+            fs::write(dir.join(format!("{}.crt", file)), cert.pem()).unwrap();
+            fs::write(dir.join(format!("{}.key", file)), key.serialize_pem()).unwrap();
+        }
+        dir
+    }
+
+    fn settings(dir: &Path, auth_clients: AuthClients) -> Settings {
+        Settings {
+            port: 0,
+            cert_file: Some(dir.join("server.crt")),
+            key_file: Some(dir.join("server.key")),
+            ca_cert_file: Some(dir.join("ca.crt")),
+            auth_clients,
+        }
+    }
+
+    //Runs one echo exchange over TLS and returns what came back.
+    async fn echo(acceptor: TlsAcceptor, connector: TlsConnector) -> Result<Vec<u8>, String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await?;
+            let mut buf = [0; 6];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.shutdown().await
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector.connect(server_name("127.0.0.1")?, stream).await.map_err(|e| e.to_string())?;
+        stream.write_all(b"PING\r\n").await.map_err(|e| e.to_string())?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.map_err(|e| e.to_string())?;
+        let _ = server.await;
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_roundtrip() {
+        let dir = write_certs("mutual");
+        let acceptor = acceptor(&settings(&dir, AuthClients::YES)).unwrap();
+        let identity = (dir.join("client.crt"), dir.join("client.key"));
+        let connector = connector(&dir.join("ca.crt"), Some((&identity.0, &identity.1))).unwrap();
+
+        assert_eq!(echo(acceptor, connector).await.unwrap(), b"PING\r\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_certificate_requirement() {
+        let dir = write_certs("auth");
+        let anonymous = connector(&dir.join("ca.crt"), None).unwrap();
+
+        let required = acceptor(&settings(&dir, AuthClients::YES)).unwrap();
+        assert!(echo(required, anonymous.clone()).await.is_err());
+
+        let optional = acceptor(&settings(&dir, AuthClients::OPTIONAL)).unwrap();
+        assert_eq!(echo(optional, anonymous).await.unwrap(), b"PING\r\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn acceptor_needs_its_files() {
+        let dir = write_certs("files");
+        assert!(acceptor(&Settings::default()).is_err());
+        assert!(acceptor(&Settings { ca_cert_file: None, ..settings(&dir, AuthClients::YES) }).is_err());
+        assert!(acceptor(&Settings { ca_cert_file: None, ..settings(&dir, AuthClients::NO) }).is_ok());
+        assert!(acceptor(&Settings { key_file: Some(dir.join("missing.key")), ..settings(&dir, AuthClients::NO) }).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}